@@ -1,26 +1,32 @@
-use crate::HOOK_MANAGER;
 use hypervisor::{
-    hook::HookType,
+    hook::{HookManager, HookType},
     svm::{events::EventInjection, utils::guest::GuestRegs, vcpu_data::VcpuData, vmexit::ExitType},
 };
 
 pub fn handle_bp_exception(vcpu: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
-    let hook_manager = unsafe { HOOK_MANAGER.as_ref().unwrap() };
+    let rip = vcpu.guest_vmcb.save_area.rip;
+    let hook_manager = vcpu.custom_data::<HookManager>().unwrap();
 
     // Find the handler address for the current instruction pointer (RIP) and
     // transfer the execution to it. If we couldn't find a hook, we inject the
     // #BP exception.
     //
-    if let Some(Some(handler)) = hook_manager
-        .find_hook_by_address(vcpu.guest_vmcb.save_area.rip)
-        .map(|hook| {
-            if let HookType::Function { inline_hook } = &hook.hook_type {
-                Some(inline_hook.handler_address())
-            } else {
-                None
-            }
-        })
-    {
+    if let Some(Some(handler)) = hook_manager.find_hook_by_address(rip).map(|hook| {
+        if let HookType::Function { inline_hook } = &hook.hook_type {
+            Some(inline_hook.handler_address())
+        } else {
+            None
+        }
+    }) {
+        // The breakpoint is at the start of the hooked function, so the return
+        // address is on top of the guest stack.
+        //
+        let rsp = vcpu.guest_vmcb.save_area.rsp;
+        match vcpu.read_guest::<u64>(rsp) {
+            Ok(return_address) => log::info!("{:#x} called from {:#x}", rip, return_address),
+            Err(error) => log::warn!("Failed to read the return address: {}", error),
+        }
+
         vcpu.guest_vmcb.save_area.rip = handler;
 
         ExitType::Continue
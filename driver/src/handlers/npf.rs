@@ -1,12 +1,16 @@
-use crate::HOOK_MANAGER;
 use hypervisor::{
+    hook::HookManager,
     svm::{
+        tlb::{self, FlushScope},
         utils::{guest::GuestRegs, paging::AccessType},
         vcpu_data::VcpuData,
-        vmcb::control_area::{NptExitInfo, TlbControl},
+        vmcb::exit_info::ExitInfo,
         vmexit::ExitType,
     },
-    utils::addresses::PhysicalAddress,
+    utils::{
+        addresses::PhysicalAddress,
+        platform::{CurrentPlatform, FatalError, Platform},
+    },
 };
 
 pub fn handle_npf(vcpu: &mut VcpuData, _regs: &mut GuestRegs) -> ExitType {
@@ -18,68 +22,81 @@ pub fn handle_npf(vcpu: &mut VcpuData, _regs: &mut GuestRegs) -> ExitType {
     // faulting guest physical address is saved in the VMCB's EXITINFO2 field;
     // EXITINFO1 delivers an error code similar to a #PF error code.
     //
-    let faulting_pa = vcpu.guest_vmcb.control_area.exit_info2;
-    let exit_info = vcpu.guest_vmcb.control_area.exit_info1;
+    let ExitInfo::NestedPageFault(fault) = vcpu.guest_vmcb.control_area.exit_info() else {
+        unreachable!()
+    };
+    let faulting_pa = fault.faulting_gpa;
+    let faulting_page = PhysicalAddress::from_pa(faulting_pa)
+        .align_down_to_base_page()
+        .as_u64();
 
-    // Page was not present so we have to map it.
+    // Page was not present so we have to map it in the nested page table that is
+    // currently used. Pages that are mapped without any access aren't present
+    // either, they are handled below like any other permission violation.
     //
-    if !exit_info.contains(NptExitInfo::PRESENT) {
-        let pa = PhysicalAddress::from_pa(faulting_pa)
-            .align_down_to_base_page()
-            .as_u64();
-
-        vcpu.shared_data()
-            .secondary_npt
-            .map_4kb(pa, pa, AccessType::ReadWrite);
-        vcpu.shared_data()
-            .primary_npt
-            .map_4kb(pa, pa, AccessType::ReadWriteExecute);
+    // Failures are fatal: The guest would fault on the page forever, and the
+    // hypervisor can't be unloaded from a nested page fault.
+    //
+    let ncr3 = vcpu.guest_vmcb.control_area.ncr3;
+    let mut npt = vcpu.shared_data().active_npt(ncr3);
+    if !fault.is_present() && npt.effective_access_type(faulting_pa).is_none() {
+        if let Err(error) = npt.map_on_demand(faulting_pa) {
+            log::error!("Failed to map {:#x}: {}", faulting_pa, error);
+            CurrentPlatform::fatal_error(FatalError::NestedPageTableMapping, faulting_pa);
+        }
 
         return ExitType::Continue;
     }
+    drop(npt);
 
     // Check if there exists a hook for the faulting page.
     // - #1 - Yes: Guest tried to execute a function inside the hooked page.
     // - #2 - No: Guest tried to execute code outside the hooked page (our hook has
     //   finished executing).
     //
-    let hook_manager = unsafe { HOOK_MANAGER.as_ref().unwrap() };
-    if let Some(hook_pa) = hook_manager
-        .find_hook(faulting_pa)
-        .map(|hook| hook.page_pa.as_u64())
-    {
-        vcpu.shared_data().secondary_npt.remap_page(
-            faulting_pa,
+    let hook_pa = vcpu
+        .custom_data::<HookManager>()
+        .and_then(|hook_manager| hook_manager.find_hook(faulting_pa))
+        .map(|hook| hook.page_pa.as_u64());
+    if let Some(hook_pa) = hook_pa {
+        if let Err(error) = vcpu.shared_data().secondary_npt.lock().remap_page(
+            faulting_page,
             hook_pa,
             AccessType::ReadWriteExecute,
-        );
+        ) {
+            log::error!("Failed to remap {:#x}: {}", faulting_pa, error);
+            CurrentPlatform::fatal_error(FatalError::NestedPageTableMapping, faulting_pa);
+        }
 
-        vcpu.guest_vmcb.control_area.ncr3 = vcpu.shared_data().secondary_pml4.as_u64();
+        let shared_data = vcpu.shared_data();
+        let (pml4, asid) = (
+            shared_data.secondary_pml4.as_u64(),
+            shared_data.secondary_asid,
+        );
+        tlb::switch_view(vcpu, pml4, asid);
     } else {
         // Just to be safe: Change the permission of the faulting pa to rwx again. I'm
         // not sure why we need this, but if we don't do it, we'll get stuck at
         // 'Launching vm'.
         //
         // TODO: Check if we need this.
-        vcpu.shared_data().primary_npt.remap_page(
-            faulting_pa,
-            faulting_pa,
+        if let Err(error) = vcpu.shared_data().primary_npt.lock().remap_page(
+            faulting_page,
+            faulting_page,
             AccessType::ReadWriteExecute,
-        );
+        ) {
+            log::error!("Failed to remap {:#x}: {}", faulting_pa, error);
+            CurrentPlatform::fatal_error(FatalError::NestedPageTableMapping, faulting_pa);
+        }
 
-        vcpu.guest_vmcb.control_area.ncr3 = vcpu.shared_data().primary_pml4.as_u64();
+        let shared_data = vcpu.shared_data();
+        let (pml4, asid) = (shared_data.primary_pml4.as_u64(), shared_data.primary_asid);
+        tlb::switch_view(vcpu, pml4, asid);
     }
 
-    // We changed the `cr3` of the guest, so we have to flush the TLB.
-    //
-    // Note: If you have an older cpu (or if you are running inside kvm),
-    // `FLUSH_GUEST_TLB` might not be supported. If that's the case, we have to use
-    // `FLUSH_ENTIRE_TLB`.
+    // The page tables have been modified, so the cached translations are stale.
     //
-    vcpu.guest_vmcb
-        .control_area
-        .tlb_control
-        .insert(TlbControl::FLUSH_GUEST_TLB);
+    tlb::flush_guest_tlb(vcpu, FlushScope::Guest);
 
     ExitType::Continue
 }
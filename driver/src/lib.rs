@@ -4,7 +4,6 @@
 #![feature(decl_macro)]
 #![feature(box_syntax)]
 #![feature(new_uninit)]
-#![feature(link_llvm_intrinsics)]
 #![allow(clippy::new_ret_no_self)]
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
@@ -42,7 +41,6 @@ pub mod lang;
 #[global_allocator]
 static GLOBAL: KernelAlloc = KernelAlloc;
 
-static mut HOOK_MANAGER: Option<HookManager> = None;
 static mut HYPERVISOR: Option<Hypervisor> = None;
 
 pub extern "system" fn driver_unload(_driver: &mut DRIVER_OBJECT) {
@@ -64,12 +62,23 @@ fn virtualize() -> Option<()> {
     // the permissions of the page tables accordingly. This will be done by the
     // `HookManager`.
     //
-    let mut primary_npt = NestedPageTable::identity_4kb(AccessType::ReadWriteExecute);
-    let mut secondary_npt = NestedPageTable::identity_4kb(AccessType::ReadWrite);
-
-    hook_manager.enable_hooks(&mut primary_npt, &mut secondary_npt);
-
-    unsafe { HOOK_MANAGER = Some(hook_manager) };
+    // Both nested page tables are lazy, so the pages are only mapped once they
+    // are accessed. See `npf::handle_npf`.
+    //
+    let mut primary_npt = NestedPageTable::lazy(AccessType::ReadWriteExecute)?;
+    let mut secondary_npt = NestedPageTable::lazy(AccessType::ReadWrite)?;
+
+    hook_manager
+        .enable_hooks(&mut primary_npt, &mut secondary_npt)
+        .ok()?;
+
+    for alias in secondary_npt.audit_aliases(&hook_manager.shadow_pages()) {
+        log::warn!(
+            "Shadow page {:#x} is also mapped at {:#x}",
+            alias.host_pa,
+            alias.guest_pa
+        );
+    }
 
     // Create the hypervisor with some handlers. If you have handlers that are in
     // another crate, you can export an array and add them via `with_handlers`.
@@ -77,13 +86,17 @@ fn virtualize() -> Option<()> {
     // I have another crate which has the handlers that harden hypervisor against
     // detection and can import them all by calling `with_handlers` once.
     //
+    // The hook manager is passed as custom data, so that the handlers can access
+    // it via `VcpuData::custom_data`.
+    //
     let mut hv = Hypervisor::builder()
         .with_handlers([
             (VmExitType::Rdtsc, rdtsc::handle_default),
             (VmExitType::Cpuid(CPUID_FEATURES), cpuid::handle_features),
-            (VmExitType::Breakpoint, bp::handle_bp_exception),
+            (VmExitType::Exception(3 /* #BP */), bp::handle_bp_exception),
             (VmExitType::NestedPageFault, npf::handle_npf),
         ])
+        .with_data(hook_manager)
         .primary_npt(primary_npt)
         .secondary_npt(secondary_npt)
         .build()?;
@@ -1,13 +1,15 @@
 use crate::{
-    svm::{nested_page_table::NestedPageTable, utils::paging::AccessType},
+    svm::{
+        nested_page_table::{reverse_map::OwnedRange, NestedPageTable, NestedPageTableError},
+        utils::paging::AccessType,
+    },
     utils::{
         addresses::PhysicalAddress,
         function_hook::FunctionHook,
-        nt::{MmGetSystemRoutineAddress, RtlCopyMemory},
+        platform::{CurrentPlatform, Platform},
     },
 };
 use alloc::{boxed::Box, vec::Vec};
-use windy::{UnicodeString, WStr};
 use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE};
 use x86_64::instructions::interrupts::without_interrupts;
 
@@ -60,9 +62,9 @@ impl Hook {
 
         without_interrupts(|| {
             unsafe {
-                RtlCopyMemory(
-                    page.as_mut_ptr() as _,
-                    page_address.as_u64() as *mut u64,
+                core::ptr::copy_nonoverlapping(
+                    page_address.as_u64() as *const u8,
+                    page.as_mut_ptr() as *mut u8,
                     BASE_PAGE_SIZE,
                 )
             };
@@ -114,19 +116,14 @@ impl Hook {
     }
 
     pub fn hook_function(name: &str, handler: *const ()) -> Option<Self> {
-        let wide_string = widestring::U16CString::from_str(name).ok()?;
-        let wide_string = unsafe { WStr::from_raw(wide_string.as_ptr()) };
-
-        let mut wide_string = UnicodeString::new(wide_string);
-        let address = unsafe { MmGetSystemRoutineAddress(wide_string.as_mut_ptr() as _) };
-        if address.is_null() {
+        let Some(address) = CurrentPlatform::system_routine_address(name) else {
             log::error!("Could not find function: {}", name);
             return None;
-        }
+        };
 
-        log::info!("Found function address of {}: {:p}", name, address);
+        log::info!("Found function address of {}: {:#x}", name, address);
 
-        Self::hook_function_ptr(address as u64, handler)
+        Self::hook_function_ptr(address, handler)
     }
 
     pub fn hook_page(address: u64) -> Option<Self> {
@@ -173,7 +170,7 @@ impl HookManager {
     /// For more information, see: [AMD-V for Hackers](https://tandasat.github.io/VXCON/AMD-V_for_Hackers.pdf)
     pub fn enable_hooks(
         &self, primary_npt: &mut NestedPageTable, secondary_npt: &mut NestedPageTable,
-    ) {
+    ) -> Result<(), NestedPageTableError> {
         for hook in &self.hooks {
             if let HookType::Function { inline_hook } = &hook.hook_type {
                 inline_hook.enable()
@@ -182,9 +179,25 @@ impl HookManager {
             let page = hook.original_pa.align_down_to_base_page().as_u64();
             let hook_page = hook.hook_pa.align_down_to_base_page().as_u64();
 
-            primary_npt.change_page_permission(page, page, AccessType::ReadWrite);
-            secondary_npt.change_page_permission(page, hook_page, AccessType::ReadWriteExecute);
+            primary_npt.change_page_permission(page, page, AccessType::ReadWrite)?;
+            secondary_npt.change_page_permission(page, hook_page, AccessType::ReadWriteExecute)?;
         }
+
+        Ok(())
+    }
+
+    /// Returns the shadow pages of the hooks. They should only be reachable
+    /// from the page that they replace, see
+    /// [`NestedPageTable::audit_aliases`].
+    pub fn shadow_pages(&self) -> Vec<OwnedRange> {
+        self.hooks
+            .iter()
+            .map(|hook| OwnedRange {
+                host_pa: hook.hook_pa.align_down_to_base_page().as_u64(),
+                size: BASE_PAGE_SIZE as u64,
+                expected_guest_pa: Some(hook.original_pa.align_down_to_base_page().as_u64()),
+            })
+            .collect()
     }
 
     /// Tries to find a hook for the specified faulting physical address.
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(let_else)]
 #![feature(decl_macro)]
 #![feature(const_deref)]
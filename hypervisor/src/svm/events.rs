@@ -1,4 +1,7 @@
-use crate::svm::vcpu_data::VcpuData;
+use crate::svm::{
+    vcpu_data::VcpuData,
+    vmcb::{control_area::InterceptMisc1, Vmcb},
+};
 use bitfield::bitfield;
 
 bitfield! {
@@ -32,38 +35,398 @@ bitfield! {
     pub get_error_code, set_error_code: 63, 32;             // [32-63]
 }
 
+// Values of the `Type` field.
+//
+pub const TYPE_INTR: u64 = 0;
+pub const TYPE_NMI: u64 = 2;
+pub const TYPE_EXCEPTION: u64 = 3;
+pub const TYPE_SOFTWARE_INTERRUPT: u64 = 4;
+
 impl EventInjection {
-    /// See `8 Exceptions and Interrupts > 8.2 Vectors > 8.2.14 #GP`.
-    pub fn gp() -> Self {
+    fn new(vector: u8, event_type: u64) -> Self {
         let mut event = EventInjection(0);
-        event.set_vector(13); // #GP
-        event.set_type(3); // Exception
-        event.set_error_code_valid(1);
+        event.set_vector(vector as u64);
+        event.set_type(event_type);
         event.set_valid(1);
 
         event
     }
 
-    pub fn bp() -> Self {
-        let mut event = EventInjection(0);
-        event.set_vector(3); // #BP
-        event.set_type(3); // Exception
-        event.set_valid(1);
+    /// Creates an exception with the specified vector. The error code is only
+    /// pushed if it's specified.
+    pub fn exception(vector: u8, error_code: Option<u32>) -> Self {
+        let mut event = Self::new(vector, TYPE_EXCEPTION);
+        if let Some(error_code) = error_code {
+            event.set_error_code_valid(1);
+            event.set_error_code(error_code as u64);
+        }
 
         event
     }
 
-    pub fn pf() -> Self {
-        let mut event = EventInjection(0);
-        event.set_vector(14); // #PF
-        event.set_type(3); // Exception
-        event.set_valid(1);
+    /// Creates an external interrupt with the specified vector.
+    pub fn external_interrupt(vector: u8) -> Self {
+        Self::new(vector, TYPE_INTR)
+    }
 
-        event
+    /// Creates a software interrupt (`INTn`) with the specified vector.
+    ///
+    /// Note: The guest rip has to point to the next instruction, because it's
+    /// pushed as the return address.
+    pub fn software_interrupt(vector: u8) -> Self {
+        Self::new(vector, TYPE_SOFTWARE_INTERRUPT)
+    }
+
+    /// See `8 Exceptions and Interrupts > 8.2 Vectors > 8.2.3 #NMI`.
+    pub fn nmi() -> Self {
+        Self::new(2, TYPE_NMI)
+    }
+
+    /// Divide-by-zero exception (#DE).
+    pub fn de() -> Self {
+        Self::exception(0, None)
+    }
+
+    /// Debug exception (#DB).
+    pub fn db() -> Self {
+        Self::exception(1, None)
+    }
+
+    /// Breakpoint exception (#BP).
+    ///
+    /// Note: The guest rip has to point to the next instruction, because it's
+    /// a trap.
+    pub fn bp() -> Self {
+        Self::exception(3, None)
+    }
+
+    /// Overflow exception (#OF).
+    ///
+    /// Note: The guest rip has to point to the next instruction, because it's
+    /// a trap.
+    pub fn of() -> Self {
+        Self::exception(4, None)
+    }
+
+    /// Bound-range exception (#BR).
+    pub fn br() -> Self {
+        Self::exception(5, None)
+    }
+
+    /// Invalid-opcode exception (#UD).
+    pub fn ud() -> Self {
+        Self::exception(6, None)
+    }
+
+    /// Device-not-available exception (#NM).
+    pub fn nm() -> Self {
+        Self::exception(7, None)
+    }
+
+    /// Double-fault exception (#DF). The error code is always zero.
+    pub fn df() -> Self {
+        Self::exception(8, Some(0))
+    }
+
+    /// Invalid-TSS exception (#TS).
+    pub fn ts(error_code: u32) -> Self {
+        Self::exception(10, Some(error_code))
+    }
+
+    /// Segment-not-present exception (#NP).
+    pub fn np(error_code: u32) -> Self {
+        Self::exception(11, Some(error_code))
+    }
+
+    /// Stack exception (#SS).
+    pub fn ss(error_code: u32) -> Self {
+        Self::exception(12, Some(error_code))
+    }
+
+    /// General-protection exception (#GP).
+    pub fn gp_with_error_code(error_code: u32) -> Self {
+        Self::exception(13, Some(error_code))
+    }
+
+    /// Page-fault exception (#PF).
+    ///
+    /// Use [`EventInjection::page_fault`] to also set the faulting address.
+    pub fn pf(error_code: u32) -> Self {
+        Self::exception(14, Some(error_code))
+    }
+
+    /// x87 floating-point exception (#MF).
+    pub fn mf() -> Self {
+        Self::exception(16, None)
+    }
+
+    /// Alignment-check exception (#AC).
+    pub fn ac(error_code: u32) -> Self {
+        Self::exception(17, Some(error_code))
+    }
+
+    /// Machine-check exception (#MC).
+    pub fn mc() -> Self {
+        Self::exception(18, None)
+    }
+
+    /// SIMD floating-point exception (#XF).
+    pub fn xf() -> Self {
+        Self::exception(19, None)
+    }
+
+    /// Control-protection exception (#CP).
+    pub fn cp(error_code: u32) -> Self {
+        Self::exception(21, Some(error_code))
     }
 
-    /// Injects the current event into the guest vmcb.
+    /// Hypervisor injection exception (#HV).
+    pub fn hv() -> Self {
+        Self::exception(28, None)
+    }
+
+    /// VMM communication exception (#VC).
+    pub fn vc(error_code: u32) -> Self {
+        Self::exception(29, Some(error_code))
+    }
+
+    /// Security exception (#SX).
+    pub fn sx(error_code: u32) -> Self {
+        Self::exception(30, Some(error_code))
+    }
+
+    /// See `8 Exceptions and Interrupts > 8.2 Vectors > 8.2.14 #GP`. The error
+    /// code is zero, which is used for all errors that are not related to a
+    /// segment selector.
+    pub fn gp() -> Self {
+        Self::gp_with_error_code(0)
+    }
+
+    /// Injects a page fault at the specified linear address. Unlike real
+    /// exceptions, injected page faults don't update CR2.
+    pub fn page_fault(data: &mut VcpuData, address: u64, error_code: u32) {
+        data.guest_vmcb.set_cr2(address);
+
+        Self::pf(error_code).inject(data);
+    }
+
+    /// Returns the event that was being delivered when the #VMEXIT occurred, if
+    /// it has to be injected again. See `15.7.2 Intercepts During IDT
+    /// Interrupt Delivery`.
+    ///
+    /// Software interrupts and the exceptions caused by `INT3` and `INTO` are
+    /// not returned, because the guest rip still points to the instruction and
+    /// executing it again raises them again.
+    pub fn interrupted(data: &VcpuData) -> Option<Self> {
+        let event = EventInjection(data.guest_vmcb.control_area.exit_int_info);
+        if event.get_valid() == 0 {
+            return None;
+        }
+
+        match (event.get_type(), event.get_vector()) {
+            (TYPE_SOFTWARE_INTERRUPT, _) => None,
+            (TYPE_EXCEPTION, 3 | 4) => None,
+            _ => Some(event),
+        }
+    }
+
+    /// Queues the current event for injection into the guest. See
+    /// [`PendingEvents`].
     pub fn inject(&self, data: &mut VcpuData) {
-        data.guest_vmcb.control_area.event_inj = self.0;
+        data.pending_events.push(EventInjection(self.0));
+    }
+}
+
+/// `V_IRQ` in `vintr`: A virtual interrupt is pending.
+const V_IRQ: u64 = 1 << 8;
+
+/// `V_IGN_TPR` in `vintr`: The virtual interrupt ignores the `TPR`.
+const V_IGN_TPR: u64 = 1 << 20;
+
+/// `IF` in `rflags`.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// `INTERRUPT_SHADOW` in `interrupt_shadow`: The guest is in an interrupt
+/// shadow (after `STI` or `MOV SS`).
+const INTERRUPT_SHADOW: u64 = 1 << 0;
+
+/// How an exception contributes to a double fault. See `8.2.9 Double-Fault
+/// Exception (#DF)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ExceptionClass {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+impl ExceptionClass {
+    fn of(event: &EventInjection) -> Self {
+        if event.get_type() != TYPE_EXCEPTION {
+            return Self::Benign;
+        }
+
+        match event.get_vector() {
+            0 | 10 | 11 | 12 | 13 => Self::Contributory,
+            14 => Self::PageFault,
+            8 => Self::DoubleFault,
+            _ => Self::Benign,
+        }
+    }
+}
+
+/// An exception occurred while delivering a #DF, which shuts the processor
+/// down.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TripleFault;
+
+/// The events that still have to be injected into the guest of a single
+/// processor.
+///
+/// Only one event can be injected per `VMRUN`, so the events are queued and
+/// injected in the order of their priority after the #VMEXIT has been handled:
+///
+/// 1. Exceptions and software interrupts. These are caused by the current
+///    instruction and only one of them can be pending. A second exception is
+///    merged into a #DF if needed.
+/// 2. NMIs, unless the guest is in an interrupt shadow.
+/// 3. External interrupts (highest vector first), once the guest can accept
+///    them (`RFLAGS.IF` set and not in an interrupt shadow).
+///
+/// Events that can't be injected right away request an interrupt window:
+/// `V_IRQ` is set and `VINTR` intercepted, which causes a #VMEXIT as soon as
+/// the guest is able to take an interrupt.
+///
+/// Note: The NMI blocking after an NMI has been delivered (until the next
+/// `IRET`) is not tracked.
+#[derive(Debug, Default)]
+pub struct PendingEvents {
+    exception: Option<EventInjection>,
+    nmi: bool,
+    interrupts: [u64; 4],
+
+    /// Set when an event caused a triple fault, see
+    /// [`PendingEvents::take_shutdown`].
+    shutdown: bool,
+}
+
+impl PendingEvents {
+    /// Adds the event to the queue.
+    ///
+    /// If the event causes a triple fault, all the pending events are
+    /// discarded and the shutdown is reported to the dispatcher, which handles
+    /// it like a `SHUTDOWN` #VMEXIT.
+    pub fn push(&mut self, event: EventInjection) {
+        match event.get_type() {
+            TYPE_NMI => self.nmi = true,
+            TYPE_INTR => {
+                let vector = event.get_vector() as usize;
+                self.interrupts[vector / 64] |= 1 << (vector % 64);
+            }
+            _ => {
+                if let Err(TripleFault) = self.push_exception(event) {
+                    self.clear();
+                    self.shutdown = true;
+                }
+            }
+        }
+    }
+
+    /// Combines the exception with the pending one. See `Table 8-7.
+    /// Double-Fault Exception Conditions`.
+    fn push_exception(&mut self, event: EventInjection) -> Result<(), TripleFault> {
+        let Some(pending) = self.exception.take() else {
+            self.exception = Some(event);
+            return Ok(());
+        };
+
+        use ExceptionClass::*;
+        self.exception = Some(
+            match (ExceptionClass::of(&pending), ExceptionClass::of(&event)) {
+                (Contributory, Contributory) | (PageFault, Contributory | PageFault) => {
+                    EventInjection::df()
+                }
+                (DoubleFault, Contributory | PageFault) => {
+                    log::error!("Triple fault: {:?} while delivering #DF", event);
+                    return Err(TripleFault);
+                }
+                // Benign exceptions are delivered one after another. The pending one is
+                // raised again when the instruction is executed again.
+                _ => event,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Returns `true` once if an event caused a triple fault since the last
+    /// call.
+    pub fn take_shutdown(&mut self) -> bool {
+        core::mem::take(&mut self.shutdown)
+    }
+
+    /// Returns `true` if there are no pending events.
+    pub fn is_empty(&self) -> bool {
+        self.exception.is_none() && !self.nmi && self.interrupts.iter().all(|bits| *bits == 0)
+    }
+
+    /// Discards all pending events.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns the highest pending external interrupt vector.
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.interrupts
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, bits)| **bits != 0)
+            .map(|(index, bits)| (index * 64 + 63 - bits.leading_zeros() as usize) as u8)
+    }
+
+    /// Writes the next event that can be injected into the vmcb and requests
+    /// an interrupt window for the remaining ones.
+    pub(crate) fn inject(&mut self, vmcb: &mut Vmcb) {
+        let shadow = vmcb.control_area.interrupt_shadow & INTERRUPT_SHADOW != 0;
+        let interrupts_enabled = vmcb.save_area.rflags & RFLAGS_IF != 0 && !shadow;
+
+        let event = if let Some(exception) = self.exception.take() {
+            Some(exception)
+        } else if self.nmi && !shadow {
+            self.nmi = false;
+            Some(EventInjection::nmi())
+        } else {
+            match self.pending_interrupt() {
+                Some(vector) if interrupts_enabled => {
+                    self.interrupts[vector as usize / 64] &= !(1 << (vector % 64));
+                    Some(EventInjection::external_interrupt(vector))
+                }
+                _ => None,
+            }
+        };
+        vmcb.control_area.event_inj = event.map(|event| event.0).unwrap_or_default();
+
+        // Request a #VMEXIT once the guest can take the next event.
+        //
+        let window = !self.is_empty();
+        let intercepted = vmcb
+            .control_area
+            .intercept_misc1
+            .contains(InterceptMisc1::INTERCEPT_VINTR);
+        if window != intercepted {
+            let vintr = vmcb.control_area.vintr;
+            if window {
+                vmcb.set_vintr(vintr | V_IRQ | V_IGN_TPR);
+            } else {
+                vmcb.set_vintr(vintr & !(V_IRQ | V_IGN_TPR));
+            }
+
+            vmcb.update_intercepts(|control_area| {
+                control_area
+                    .intercept_misc1
+                    .set(InterceptMisc1::INTERCEPT_VINTR, window)
+            });
+        }
     }
 }
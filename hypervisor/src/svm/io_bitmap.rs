@@ -0,0 +1,102 @@
+use crate::utils::alloc::PhysicalAllocator;
+use alloc::boxed::Box;
+use core::ops::RangeInclusive;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+const CHAR_BIT: u16 = 8;
+
+/// See `15.10.1 I/O Permissions Map`.
+///
+/// Every port is represented by a single bit. Accesses with a size of more
+/// than one byte check the bits of all the accessed ports, which is why the
+/// map is one page larger than needed for 65536 ports.
+#[repr(C)]
+pub struct IoBitmap {
+    pub bitmap: [u8; 3 * BASE_PAGE_SIZE],
+}
+const_assert_eq!(core::mem::size_of::<IoBitmap>(), 3 * BASE_PAGE_SIZE);
+
+impl IoBitmap {
+    pub fn new() -> Box<IoBitmap, PhysicalAllocator> {
+        let instance = Box::<Self, PhysicalAllocator>::new_zeroed_in(PhysicalAllocator);
+
+        // An all zero map is valid and doesn't intercept anything.
+        //
+        unsafe { instance.assume_init() }
+    }
+
+    pub fn hook_port(&mut self, port: u16) {
+        log::info!("Hooking port: {:x}", port);
+
+        self.set_bit(port, true);
+    }
+
+    pub fn hook_range(&mut self, ports: RangeInclusive<u16>) {
+        log::info!("Hooking ports: {:x}-{:x}", ports.start(), ports.end());
+
+        for port in ports {
+            self.set_bit(port, true);
+        }
+    }
+
+    pub fn unhook_port(&mut self, port: u16) {
+        log::info!("Unhooking port: {:x}", port);
+
+        self.set_bit(port, false);
+    }
+
+    pub fn is_hooked(&self, port: u16) -> bool {
+        self.bitmap[(port / CHAR_BIT) as usize] & (1 << (port % CHAR_BIT)) != 0
+    }
+
+    fn set_bit(&mut self, port: u16, value: bool) {
+        let byte = &mut self.bitmap[(port / CHAR_BIT) as usize];
+        if value {
+            *byte |= 1 << (port % CHAR_BIT);
+        } else {
+            *byte &= !(1 << (port % CHAR_BIT));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_offsets() {
+        let mut bitmap = IoBitmap::new();
+        bitmap.hook_port(0x0000);
+        bitmap.hook_port(0x0061);
+        bitmap.hook_port(0xFFFF);
+
+        assert_eq!(bitmap.bitmap[0], 0b0000_0001);
+        assert_eq!(bitmap.bitmap[0x61 / 8], 0b0000_0010);
+        assert_eq!(bitmap.bitmap[0x1FFF], 0b1000_0000);
+        assert!(bitmap.bitmap[0x2000..].iter().all(|&byte| byte == 0));
+
+        assert!(bitmap.is_hooked(0x0061));
+        assert!(!bitmap.is_hooked(0x0060));
+        assert!(!bitmap.is_hooked(0x0062));
+    }
+
+    #[test]
+    fn unhook() {
+        let mut bitmap = IoBitmap::new();
+        bitmap.hook_port(0x3F8);
+        bitmap.unhook_port(0x3F8);
+
+        assert!(!bitmap.is_hooked(0x3F8));
+        assert!(bitmap.bitmap.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut bitmap = IoBitmap::new();
+        bitmap.hook_range(0x3F8..=0x3FF);
+
+        assert_eq!(bitmap.bitmap[0x3F8 / 8], 0xff);
+        assert_eq!(bitmap.bitmap[0x3F8 / 8 - 1], 0);
+        assert_eq!(bitmap.bitmap[0x3F8 / 8 + 1], 0);
+    }
+}
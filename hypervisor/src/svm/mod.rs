@@ -1,17 +1,28 @@
 use crate::{
     svm::{
-        nested_page_table::NestedPageTable, shared_data::SharedData, vcpu::Vcpu,
-        vmexit::VmExitHandler,
+        nested_page_table::NestedPageTable,
+        shared_data::SharedData,
+        utils::guest::GuestRegs,
+        vcpu::Vcpu,
+        vcpu_data::VcpuData,
+        vmexit::{
+            cpuid::CPUID_UPDATE_INTERCEPTS, ExitType, VmExitHandlerFn, VmExitHandlers,
+            DEFAULT_PRIORITY,
+        },
     },
     utils::processor::{processor_count, ProcessorExecutor},
 };
 use alloc::{boxed::Box, vec::Vec};
+use core::any::Any;
+use x86::cpuid::cpuid;
 
 pub mod events;
+pub mod io_bitmap;
 pub mod msr_bitmap;
 pub mod nested_page_table;
 pub mod shared_data;
 pub mod support;
+pub mod tlb;
 pub mod utils;
 pub mod vcpu;
 pub mod vcpu_data;
@@ -29,16 +40,68 @@ pub enum VmExitType {
     Rdmsr(u32),
     Wrmsr(u32),
 
+    /// Mov from/to the control register {0}. Also includes the selective CR0
+    /// write intercept (`LMSW`, `CLTS`).
+    CrRead(u8),
+    CrWrite(u8),
+
+    /// Mov from/to the debug register {0}
+    DrRead(u8),
+    DrWrite(u8),
+
+    /// In/Out instruction with port = {0}. The handlers are also called for
+    /// accesses of more than one byte that start at a lower port, so the
+    /// accessed port has to be taken from
+    /// [`IoInfo`](crate::svm::vmcb::exit_info::IoInfo).
+    Io(u16),
+
+    /// Exception with vector = {0}. The error code, as well as CR2 (for #PF)
+    /// and DR6 (for #DB), can be retrieved via
+    /// [`ExceptionInfo`](crate::svm::vmcb::exit_info::ExceptionInfo).
+    Exception(u8),
+
     NestedPageFault,
-    Breakpoint,
     Rdtsc,
     Rdtscp,
+    Rdpmc,
     Vmcall,
+
+    Hlt,
+    Pause,
+    Invlpg,
+    Xsetbv,
+    Wbinvd,
+    Invd,
+    Monitor,
+    /// Also includes `MWAIT` with a wakeup condition.
+    Mwait,
+
+    /// Physical interrupts
+    Intr,
+    Nmi,
+    Smi,
+    Init,
+
+    /// The guest is able to take the pending interrupts. Intercepted while
+    /// there are [`PendingEvents`](crate::svm::events::PendingEvents).
+    Vintr,
+
+    /// The guest caused a triple fault.
+    Shutdown,
+
+    /// SVM instructions
+    Vmrun,
+    Vmload,
+    Vmsave,
+    Stgi,
+    Clgi,
+    Skinit,
 }
 
 #[derive(Default)]
 pub struct HypervisorBuilder {
-    vmexit_types: Vec<VmExitType>,
+    handlers: VmExitHandlers,
+    data: Option<Box<dyn Any + Send + Sync>>,
     primary_npt: Option<Box<NestedPageTable>>,
 
     #[cfg(feature = "secondary-npt")]
@@ -46,31 +109,36 @@ pub struct HypervisorBuilder {
 }
 
 impl HypervisorBuilder {
-    /// Adds the specified handler.
+    /// Adds the specified handler with the default priority. The handler can
+    /// be a function or a closure that captures some state.
     ///
-    /// Note: If a handler is already registered for the specified type, it will
-    /// be replaced.
+    /// Note: If there are already handlers for the specified type, the handler
+    /// is added to the chain. See [`VmExitHandlers`] for the order in which
+    /// they are called.
     #[must_use]
-    pub fn with_handler(mut self, vmexit_type: VmExitType, handler: VmExitHandler) -> Self {
-        self.vmexit_types.push(vmexit_type);
-
-        if vmexit::VMEXIT_HANDLERS
-            .write()
-            .insert(vmexit_type, handler)
-            .is_some()
-        {
-            log::warn!(
-                "Handler for {:?} was overwritten. Was this on purpose?",
-                vmexit_type
-            );
-        }
+    pub fn with_handler<F>(self, vmexit_type: VmExitType, handler: F) -> Self
+    where F: Fn(&mut VcpuData, &mut GuestRegs) -> ExitType + Send + Sync + 'static {
+        self.with_handler_priority(vmexit_type, DEFAULT_PRIORITY, handler)
+    }
+
+    /// Adds the specified handler. Handlers with a higher priority are called
+    /// first.
+    #[must_use]
+    pub fn with_handler_priority<F>(
+        mut self, vmexit_type: VmExitType, priority: i32, handler: F,
+    ) -> Self
+    where F: Fn(&mut VcpuData, &mut GuestRegs) -> ExitType + Send + Sync + 'static {
+        self.handlers
+            .insert_with_priority(vmexit_type, priority, handler);
 
         self
     }
 
     /// Adds multiple handlers at once.
     #[must_use]
-    pub fn with_handlers<const N: usize>(self, handlers: [(VmExitType, VmExitHandler); N]) -> Self {
+    pub fn with_handlers<const N: usize>(
+        self, handlers: [(VmExitType, VmExitHandlerFn); N],
+    ) -> Self {
         let mut instance = self;
 
         for (exit_type, handler) in handlers {
@@ -80,6 +148,17 @@ impl HypervisorBuilder {
         instance
     }
 
+    /// Sets the custom data that can be accessed by the handlers via
+    /// [`VcpuData::custom_data`].
+    ///
+    /// All processors access the data at the same time, so only shared
+    /// references are handed out. Use a lock if the data has to be modified.
+    #[must_use]
+    pub fn with_data<T: Any + Send + Sync>(mut self, data: T) -> Self {
+        self.data = Some(Box::new(data));
+        self
+    }
+
     pub fn primary_npt(mut self, npt: Box<NestedPageTable>) -> Self {
         self.primary_npt = Some(npt);
         self
@@ -105,32 +184,26 @@ impl HypervisorBuilder {
 
         // Create the shared data
         //
-        let primary_npt = self.primary_npt.unwrap_or_else(NestedPageTable::default);
+        let primary_npt = self.primary_npt.or_else(NestedPageTable::default)?;
 
         #[cfg(not(feature = "secondary-npt"))]
-        let mut shared_data = SharedData::new(primary_npt)?;
+        let mut shared_data = SharedData::new(self.handlers, primary_npt)?;
 
         #[cfg(feature = "secondary-npt")]
         let mut shared_data = {
-            let secondary_npt = self.secondary_npt.unwrap_or_else(NestedPageTable::default);
+            let secondary_npt = self.secondary_npt.or_else(NestedPageTable::default)?;
 
-            SharedData::new(primary_npt, secondary_npt)?
+            SharedData::new(self.handlers, primary_npt, secondary_npt)?
         };
+        shared_data.data = self.data;
 
-        // Set the msr permissions in the msr bitmap
-        //
-        for exit_type in self.vmexit_types {
-            match exit_type {
-                VmExitType::Msr(msr) => shared_data.msr_bitmap.hook_msr(msr),
-                VmExitType::Rdmsr(msr) => shared_data.msr_bitmap.hook_rdmsr(msr),
-                VmExitType::Wrmsr(msr) => shared_data.msr_bitmap.hook_wrmsr(msr),
-                _ => {}
-            }
-        }
+        Hypervisor::hook_msrs(&mut shared_data);
+        Hypervisor::hook_ports(&mut shared_data);
 
         Some(Hypervisor {
             shared_data,
             processors,
+            retired_handlers: Vec::new(),
         })
     }
 }
@@ -138,6 +211,12 @@ impl HypervisorBuilder {
 pub struct Hypervisor {
     shared_data: Box<SharedData>,
     processors: Vec<Vcpu>,
+
+    /// Handlers that have been replaced while not all processors could be
+    /// updated. They might still be in use, so they are only freed together
+    /// with the hypervisor. They have to stay at the same address.
+    #[allow(clippy::vec_box)]
+    retired_handlers: Vec<Box<VmExitHandlers>>,
 }
 
 impl Hypervisor {
@@ -202,6 +281,131 @@ impl Hypervisor {
     pub fn shared_data(&mut self) -> &mut SharedData {
         &mut self.shared_data
     }
+
+    /// Adds the specified handler while the processors are virtualized. The
+    /// intercepts of all processors are updated accordingly. See
+    /// [`HypervisorBuilder::with_handler_priority`].
+    pub fn add_handler<F>(&mut self, vmexit_type: VmExitType, priority: i32, handler: F) -> bool
+    where F: Fn(&mut VcpuData, &mut GuestRegs) -> ExitType + Send + Sync + 'static {
+        let mut handlers = self.shared_data.handlers.get().clone();
+        handlers.insert_with_priority(vmexit_type, priority, handler);
+
+        self.update_handlers(handlers)
+    }
+
+    /// Removes the handlers for the specified type while the processors are
+    /// virtualized. The built-in handlers can't be removed, see
+    /// [`VmExitHandlers::remove`]. Returns the number of removed handlers.
+    ///
+    /// Note: Msrs stay intercepted, but will be handled by the default
+    /// implementation. Ports are no longer intercepted.
+    pub fn remove_handler(&mut self, vmexit_type: VmExitType) -> usize {
+        let mut handlers = self.shared_data.handlers.get().clone();
+
+        let count = handlers.remove(vmexit_type);
+        if count != 0 {
+            if let VmExitType::Io(port) = vmexit_type {
+                if handlers.chain(vmexit_type).next().is_none() {
+                    self.shared_data.io_bitmap.unhook_port(port);
+                }
+            }
+
+            self.update_handlers(handlers);
+        }
+
+        count
+    }
+
+    fn update_handlers(&mut self, handlers: VmExitHandlers) -> bool {
+        // The processors might currently be using the previous handlers, so we
+        // can't free them yet.
+        //
+        let previous = unsafe { self.shared_data.handlers.replace(handlers) };
+        Self::hook_msrs(&mut self.shared_data);
+        Self::hook_ports(&mut self.shared_data);
+
+        // Force a #VMEXIT on every processor, which will then update its
+        // intercepts. Because the code is executed in the guest, we also know
+        // that the processor isn't using the previous handlers anymore.
+        //
+        let mut status = true;
+        for processor in self.processors.iter() {
+            let Some(executor) = ProcessorExecutor::switch_to_processor(processor.id()) else {
+                log::error!("Failed to switch to processor");
+                status = false;
+                continue;
+            };
+
+            cpuid!(CPUID_UPDATE_INTERCEPTS);
+
+            core::mem::drop(executor);
+        }
+
+        if status {
+            core::mem::drop(previous);
+        } else {
+            log::warn!("Not all processors have been updated, keeping the previous handlers");
+            self.retired_handlers.push(previous);
+        }
+
+        status
+    }
+
+    /// Sets the msr permissions in the msr bitmap based on the handlers.
+    fn hook_msrs(shared_data: &mut SharedData) {
+        let SharedData {
+            handlers,
+            msr_bitmap,
+            ..
+        } = shared_data;
+
+        for exit_type in handlers.get().types() {
+            let result = match *exit_type {
+                VmExitType::Msr(msr) => msr_bitmap.hook_msr(msr),
+                VmExitType::Rdmsr(msr) => msr_bitmap.hook_rdmsr(msr),
+                VmExitType::Wrmsr(msr) => msr_bitmap.hook_wrmsr(msr),
+                _ => Ok(()),
+            };
+
+            // Msrs that are not part of the bitmap are always intercepted, so the
+            // handler will still be called.
+            //
+            if let Err(error) = result {
+                log::info!("{}", error);
+            }
+        }
+    }
+
+    /// Sets the io permissions in the io bitmap based on the handlers.
+    fn hook_ports(shared_data: &mut SharedData) {
+        let SharedData {
+            handlers,
+            io_bitmap,
+            ..
+        } = shared_data;
+
+        for exit_type in handlers.get().types() {
+            if let VmExitType::Io(port) = *exit_type {
+                io_bitmap.hook_port(port);
+            }
+        }
+    }
+
+    /// Returns the custom data that has been passed to
+    /// [`HypervisorBuilder::with_data`].
+    pub fn data<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.shared_data.data.as_ref()?.downcast_ref()
+    }
+
+    /// Exports the recorded vmexits of the specified processor. See
+    /// [`Vcpu::vmexit_log`].
+    #[cfg(feature = "vmexit-recorder")]
+    pub fn vmexit_log(&self, processor: u32) -> Option<Vec<u8>> {
+        self.processors
+            .iter()
+            .find(|vcpu| vcpu.id() == processor)
+            .and_then(|vcpu| vcpu.vmexit_log())
+    }
 }
 
 impl Drop for Hypervisor {
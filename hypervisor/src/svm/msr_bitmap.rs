@@ -1,15 +1,40 @@
-use crate::utils::{
-    alloc::PhysicalAllocator,
-    nt::{RtlClearAllBits, RtlInitializeBitMap, RtlSetBits, RTL_BITMAP},
-};
+use crate::utils::alloc::PhysicalAllocator;
 use alloc::boxed::Box;
-use core::mem::MaybeUninit;
+use core::ops::RangeInclusive;
+use snafu::prelude::*;
 use x86::bits64::paging::BASE_PAGE_SIZE;
 
 const CHAR_BIT: u32 = 8;
 const BITS_PER_MSR: u32 = 2;
 const RANGE_SIZE: u32 = 0x800 * CHAR_BIT;
 
+/// The msrs that are covered by the permission map. Each range has its own
+/// vector in the map.
+const MSR_RANGES: [RangeInclusive<u32>; 3] = [
+    0x0000_0000..=0x0000_1FFF,
+    0xC000_0000..=0xC000_1FFF,
+    0xC001_0000..=0xC001_1FFF,
+];
+
+#[derive(Debug, Snafu)]
+pub enum MsrBitmapError {
+    /// Msrs outside of the architectural ranges are not part of the bitmap.
+    /// Accessing them always causes a #VMEXIT.
+    #[snafu(display("Msr {:#x} is not covered by the msr permission map", msr))]
+    UnsupportedMsr { msr: u32 },
+
+    #[snafu(display(
+        "Msr range {:#x}-{:#x} is not covered by the msr permission map",
+        start,
+        end
+    ))]
+    UnsupportedRange { start: u32, end: u32 },
+}
+
+/// See `15.11 MSR Intercepts`.
+///
+/// Every msr is represented by two bits: The lower one intercepts `rdmsr` and
+/// the upper one `wrmsr`.
 #[repr(C)]
 pub struct MsrBitmap {
     /// 0000_0000 to 0000_1FFF
@@ -24,93 +49,194 @@ pub struct MsrBitmap {
 const_assert_eq!(core::mem::size_of::<MsrBitmap>(), 2 * BASE_PAGE_SIZE);
 
 impl MsrBitmap {
-    fn initialize_bitmap(bitmap_ptr: *mut u64) {
-        let mut bitmap_header: MaybeUninit<RTL_BITMAP> = MaybeUninit::uninit();
-        let bitmap_header_ptr = bitmap_header.as_mut_ptr() as *mut _;
-
+    fn as_bytes(&self) -> &[u8] {
         unsafe {
-            RtlInitializeBitMap(
-                bitmap_header_ptr as _,
-                bitmap_ptr as _,
-                core::mem::size_of::<Self>() as u32,
-            )
+            core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>())
         }
-        unsafe { RtlClearAllBits(bitmap_header_ptr as _) }
     }
 
-    fn bitmap_header(bitmap_ptr: *mut u64) -> RTL_BITMAP {
-        // let mut bitmap_header: MaybeUninit<RTL_BITMAP> = MaybeUninit::uninit();
-        // let bitmap_header_ptr = bitmap_header.as_mut_ptr() as *mut _;
-        //
-        // unsafe {
-        //     RtlInitializeBitMap(
-        //         bitmap_header_ptr as _,
-        //         bitmap_ptr as _,
-        //         core::mem::size_of::<Self>() as u32,
-        //     )
-        // }
-        // unsafe { bitmap_header.assume_init() }
-
-        RTL_BITMAP {
-            SizeOfBitMap: core::mem::size_of::<Self>() as u32,
-            Buffer: bitmap_ptr as _,
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self as *mut _ as *mut u8, core::mem::size_of::<Self>())
         }
     }
 
+    /// Allocates the map directly in physical memory, as it's too large to be
+    /// built on the stack first.
     pub fn new() -> Box<MsrBitmap, PhysicalAllocator> {
-        let instance = Self {
-            msr_bitmap_0: [0; 0x800],
-            msr_bitmap_1: [0; 0x800],
-            msr_bitmap_2: [0; 0x800],
-            msr_bitmap_3: [0; 0x800],
-        };
-        let mut instance = Box::<Self, PhysicalAllocator>::new_in(instance, PhysicalAllocator);
-
-        Self::initialize_bitmap(instance.as_mut() as *mut _ as _);
+        let instance = Box::<Self, PhysicalAllocator>::new_zeroed_in(PhysicalAllocator);
 
-        instance
+        // An all zero map is valid and doesn't intercept anything.
+        //
+        unsafe { instance.assume_init() }
     }
 
-    pub fn hook_msr(&mut self, msr: u32) {
+    pub fn hook_msr(&mut self, msr: u32) -> Result<(), MsrBitmapError> {
         log::info!("Hooking msr: {:x}", msr);
 
-        self.hook_wrmsr(msr);
-        self.hook_rdmsr(msr);
+        self.hook_rdmsr(msr)?;
+        self.hook_wrmsr(msr)
     }
 
-    pub fn hook_rdmsr(&mut self, msr: u32) {
+    pub fn hook_rdmsr(&mut self, msr: u32) -> Result<(), MsrBitmapError> {
         log::info!("Hooking rdmsr: {:x}", msr);
 
-        let offset = Self::msr_range(msr) + Self::msr_offset(msr);
-
-        let mut bitmap_header = Self::bitmap_header(self as *mut _ as _);
-        unsafe { RtlSetBits(&mut bitmap_header as *mut _, offset, 1) };
+        self.set_bit(Self::rdmsr_bit(msr)?, true);
+        Ok(())
     }
 
-    pub fn hook_wrmsr(&mut self, msr: u32) {
+    pub fn hook_wrmsr(&mut self, msr: u32) -> Result<(), MsrBitmapError> {
         log::info!("Hooking wrmsr: {:x}", msr);
 
-        let offset = Self::msr_range(msr) + Self::msr_offset(msr);
+        self.set_bit(Self::rdmsr_bit(msr)? + 1, true);
+        Ok(())
+    }
+
+    pub fn unhook_msr(&mut self, msr: u32) -> Result<(), MsrBitmapError> {
+        log::info!("Unhooking msr: {:x}", msr);
 
-        let mut bitmap_header = Self::bitmap_header(self as *mut _ as _);
-        unsafe { RtlSetBits(&mut bitmap_header as *mut _, offset + 1, 1) };
+        self.unhook_rdmsr(msr)?;
+        self.unhook_wrmsr(msr)
     }
 
-    fn msr_offset(msr: u32) -> u32 {
-        (msr & 0xfff) * BITS_PER_MSR
+    pub fn unhook_rdmsr(&mut self, msr: u32) -> Result<(), MsrBitmapError> {
+        self.set_bit(Self::rdmsr_bit(msr)?, false);
+        Ok(())
     }
 
-    /// Returns the offset to the range for the specified MSR.
-    #[allow(clippy::identity_op)]
-    fn msr_range(msr: u32) -> u32 {
-        if (0x0000_0000..=0x0000_1FFF).contains(&msr) {
-            0
-        } else if (0xC000_0000..=0xC000_1FFF).contains(&msr) {
-            1 * RANGE_SIZE
-        } else if (0xC001_0000..=0xC001_1FFF).contains(&msr) {
-            2 * RANGE_SIZE
+    pub fn unhook_wrmsr(&mut self, msr: u32) -> Result<(), MsrBitmapError> {
+        self.set_bit(Self::rdmsr_bit(msr)? + 1, false);
+        Ok(())
+    }
+
+    /// Hooks both read and write access of all the msrs in the range. The
+    /// range must not span multiple architectural ranges.
+    pub fn hook_msr_range(&mut self, msrs: RangeInclusive<u32>) -> Result<(), MsrBitmapError> {
+        log::info!("Hooking msrs: {:x}-{:x}", msrs.start(), msrs.end());
+
+        self.set_range(msrs, true)
+    }
+
+    /// See [`MsrBitmap::hook_msr_range`].
+    pub fn unhook_msr_range(&mut self, msrs: RangeInclusive<u32>) -> Result<(), MsrBitmapError> {
+        log::info!("Unhooking msrs: {:x}-{:x}", msrs.start(), msrs.end());
+
+        self.set_range(msrs, false)
+    }
+
+    pub fn is_hooked_rdmsr(&self, msr: u32) -> Result<bool, MsrBitmapError> {
+        Ok(self.bit(Self::rdmsr_bit(msr)?))
+    }
+
+    pub fn is_hooked_wrmsr(&self, msr: u32) -> Result<bool, MsrBitmapError> {
+        Ok(self.bit(Self::rdmsr_bit(msr)? + 1))
+    }
+
+    fn set_range(&mut self, msrs: RangeInclusive<u32>, value: bool) -> Result<(), MsrBitmapError> {
+        let (start, end) = (*msrs.start(), *msrs.end());
+
+        // Both ends have to be in the same range, so that all the msrs in between
+        // are covered as well.
+        //
+        let valid = MSR_RANGES
+            .iter()
+            .any(|range| range.contains(&start) && range.contains(&end));
+        ensure!(valid, UnsupportedRangeSnafu { start, end });
+
+        for msr in msrs {
+            let bit = Self::rdmsr_bit(msr)?;
+
+            self.set_bit(bit, value);
+            self.set_bit(bit + 1, value);
+        }
+
+        Ok(())
+    }
+
+    fn bit(&self, bit: u32) -> bool {
+        self.as_bytes()[(bit / CHAR_BIT) as usize] & (1 << (bit % CHAR_BIT)) != 0
+    }
+
+    fn set_bit(&mut self, bit: u32, value: bool) {
+        let byte = &mut self.as_bytes_mut()[(bit / CHAR_BIT) as usize];
+        if value {
+            *byte |= 1 << (bit % CHAR_BIT);
         } else {
-            3 * RANGE_SIZE
+            *byte &= !(1 << (bit % CHAR_BIT));
         }
     }
+
+    /// Returns the index of the bit that intercepts `rdmsr`. The bit for
+    /// `wrmsr` is the next one.
+    fn rdmsr_bit(msr: u32) -> Result<u32, MsrBitmapError> {
+        Ok(Self::msr_range(msr)? + Self::msr_offset(msr))
+    }
+
+    fn msr_offset(msr: u32) -> u32 {
+        (msr & 0x1fff) * BITS_PER_MSR
+    }
+
+    /// Returns the offset to the range for the specified MSR.
+    fn msr_range(msr: u32) -> Result<u32, MsrBitmapError> {
+        let index = MSR_RANGES
+            .iter()
+            .position(|range| range.contains(&msr))
+            .context(UnsupportedMsrSnafu { msr })?;
+
+        Ok(index as u32 * RANGE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_offsets() {
+        let mut bitmap = MsrBitmap::new();
+        bitmap.hook_rdmsr(0x0000_0001).unwrap();
+        bitmap.hook_wrmsr(0xC000_0080).unwrap();
+        bitmap.hook_msr(0xC001_1FFF).unwrap();
+
+        assert_eq!(bitmap.msr_bitmap_0[0], 0b0000_0100);
+        assert_eq!(bitmap.msr_bitmap_1[0x80 * 2 / 8], 0b0000_0010);
+        assert_eq!(bitmap.msr_bitmap_2[0x7ff], 0b1100_0000);
+        assert!(bitmap.msr_bitmap_3.iter().all(|&byte| byte == 0));
+
+        assert!(bitmap.is_hooked_rdmsr(0x0000_0001).unwrap());
+        assert!(!bitmap.is_hooked_wrmsr(0x0000_0001).unwrap());
+        assert!(!bitmap.is_hooked_rdmsr(0xC000_0080).unwrap());
+        assert!(bitmap.is_hooked_wrmsr(0xC000_0080).unwrap());
+    }
+
+    #[test]
+    fn unhook() {
+        let mut bitmap = MsrBitmap::new();
+        bitmap.hook_msr(0xC000_0082).unwrap();
+        bitmap.unhook_rdmsr(0xC000_0082).unwrap();
+
+        assert!(!bitmap.is_hooked_rdmsr(0xC000_0082).unwrap());
+        assert!(bitmap.is_hooked_wrmsr(0xC000_0082).unwrap());
+
+        bitmap.unhook_msr(0xC000_0082).unwrap();
+        assert!(bitmap.as_bytes().iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut bitmap = MsrBitmap::new();
+        bitmap.hook_msr_range(0x0000_0000..=0x0000_0003).unwrap();
+
+        assert_eq!(bitmap.msr_bitmap_0[0], 0xff);
+        assert_eq!(bitmap.msr_bitmap_0[1], 0);
+
+        assert!(matches!(
+            bitmap.hook_msr_range(0x0000_1FFF..=0xC000_0000),
+            Err(MsrBitmapError::UnsupportedRange { .. })
+        ));
+        assert!(matches!(
+            bitmap.hook_msr(0x4000_0000),
+            Err(MsrBitmapError::UnsupportedMsr { msr: 0x4000_0000 })
+        ));
+    }
 }
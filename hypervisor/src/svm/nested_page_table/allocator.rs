@@ -0,0 +1,144 @@
+//! Allocates the pages of the nested page tables.
+//!
+//! Pages are allocated in chunks of physically contiguous memory and handed
+//! out one at a time. Tables that are no longer used are put on a free list,
+//! which is stored inside the unused tables themselves. Taking a table from
+//! the free list doesn't call into the OS, so tables can also be allocated in
+//! the host context once enough of them have been reserved.
+
+use crate::utils::alloc::PhysicalAllocator;
+use alloc::vec::Vec;
+use core::{
+    alloc::{Allocator, Layout},
+    ptr::NonNull,
+};
+use x86::bits64::paging::{BASE_PAGE_SIZE, PAGE_SIZE_ENTRIES};
+
+/// A page that holds the entries of a single PDPT, PD or PT.
+pub type Table = [u64; PAGE_SIZE_ENTRIES];
+const_assert_eq!(core::mem::size_of::<Table>(), BASE_PAGE_SIZE);
+
+/// The number of tables that are allocated at once.
+const TABLES_PER_CHUNK: usize = 64;
+
+pub struct PageTableAllocator {
+    chunks: Vec<NonNull<Table>>,
+
+    /// The first unused table. The first entry of every unused table stores
+    /// the address of the next one.
+    free_list: Option<NonNull<Table>>,
+    free_count: usize,
+
+    /// New chunks are allocated when there are no unused tables left.
+    /// Disable this before tables are allocated in the host context.
+    growable: bool,
+}
+
+impl PageTableAllocator {
+    pub const fn new() -> Self {
+        Self {
+            chunks: Vec::new(),
+            free_list: None,
+            free_count: 0,
+            growable: true,
+        }
+    }
+
+    fn chunk_layout() -> Layout {
+        Layout::new::<[Table; TABLES_PER_CHUNK]>()
+    }
+
+    /// Allocates a new chunk and adds its tables to the free list.
+    fn grow(&mut self) -> Option<()> {
+        let memory = PhysicalAllocator
+            .allocate_zeroed(Self::chunk_layout())
+            .ok()?
+            .cast::<Table>();
+        self.chunks.push(memory);
+
+        for i in 0..TABLES_PER_CHUNK {
+            unsafe { self.push_free(NonNull::new_unchecked(memory.as_ptr().add(i))) };
+        }
+
+        Some(())
+    }
+
+    unsafe fn push_free(&mut self, mut table: NonNull<Table>) {
+        table.as_mut()[0] = self
+            .free_list
+            .map(|next| next.as_ptr() as u64)
+            .unwrap_or_default();
+
+        self.free_list = Some(table);
+        self.free_count += 1;
+    }
+
+    /// Makes sure that at least `count` tables can be allocated without
+    /// allocating new chunks.
+    pub fn reserve(&mut self, count: usize) -> Option<()> {
+        while self.free_count < count {
+            self.grow()?;
+        }
+
+        Some(())
+    }
+
+    pub fn set_growable(&mut self, growable: bool) {
+        self.growable = growable;
+    }
+
+    /// Returns a zeroed table, or `None` if there are no unused tables left and
+    /// the allocator can't grow.
+    pub fn allocate(&mut self) -> Option<NonNull<Table>> {
+        if self.free_list.is_none() && self.growable {
+            self.grow()?;
+        }
+
+        let mut table = self.free_list?;
+        let next = unsafe { table.as_ref()[0] };
+
+        self.free_list = NonNull::new(next as *mut Table);
+        self.free_count -= 1;
+
+        // All the other entries have been cleared when the table was freed.
+        //
+        unsafe { table.as_mut()[0] = 0 };
+
+        Some(table)
+    }
+
+    /// Returns the table to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The table must have been allocated by this allocator, and must no
+    /// longer be referenced by any entry.
+    pub unsafe fn free(&mut self, mut table: NonNull<Table>) {
+        table.as_mut().fill(0);
+        self.push_free(table);
+    }
+
+    /// Returns the number of tables that are currently in use.
+    pub fn used_tables(&self) -> usize {
+        self.chunks.len() * TABLES_PER_CHUNK - self.free_count
+    }
+
+    /// Returns the number of bytes that have been allocated for tables.
+    pub fn allocated_size(&self) -> usize {
+        self.chunks.len() * Self::chunk_layout().size()
+    }
+}
+
+impl Default for PageTableAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PageTableAllocator {
+    fn drop(&mut self) {
+        for chunk in self.chunks.drain(..) {
+            unsafe { PhysicalAllocator.deallocate(chunk.cast(), Self::chunk_layout()) };
+        }
+    }
+}
@@ -0,0 +1,1516 @@
+use crate::{
+    svm::{
+        nested_page_table::{
+            allocator::{PageTableAllocator, Table},
+            reverse_map::{OwnedRange, ReverseMap, UnexpectedAlias},
+        },
+        utils::{
+            mtrr::{self, MemoryType, Mtrrs},
+            paging::{
+                guest_physical_address_bits, has_1gb_pages, AccessType, PFN_MASK, _1GB, _2MB,
+                _512GB,
+            },
+        },
+    },
+    utils::{
+        addresses::{physical_address, PhysicalAddress},
+        physmem_descriptor::{PhysicalMemoryDescriptor, PhysicalMemoryRange},
+    },
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::ptr::NonNull;
+use snafu::prelude::*;
+use x86::bits64::paging::{
+    pd_index, pdpt_index, pml4_index, pt_index, PAddr, PDEntry, PDFlags, PDPTEntry, PDPTFlags,
+    PML4Entry, PTEntry, VAddr, BASE_PAGE_SIZE, PAGE_SIZE_ENTRIES, PD, PDPT, PML4, PT,
+};
+
+pub mod allocator;
+pub mod reverse_map;
+pub mod snapshot;
+
+/// The number of tables that are reserved for lazy nested page tables in
+/// addition to the ones that are needed to map the physical memory. They are
+/// used for device memory (mmio) above the physical memory that can't be
+/// mapped with 1GB pages, e.g. because the memory type changes within them.
+const LAZY_RESERVED_TABLES: usize = 256;
+
+#[derive(Debug, Snafu)]
+pub enum NestedPageTableError {
+    #[snafu(display("There are no page table pages left"))]
+    OutOfMemory,
+
+    #[snafu(display(
+        "Guest physical address {:#x} exceeds the supported address width",
+        address
+    ))]
+    AddressOutOfRange { address: u64 },
+
+    #[snafu(display("1GB pages are not supported"))]
+    HugePagesUnsupported,
+
+    #[snafu(display(
+        "Range {:#x}-{:#x} ({:#x} bytes) is not page aligned",
+        guest_pa,
+        host_pa,
+        size
+    ))]
+    UnalignedRange {
+        guest_pa: u64,
+        host_pa: u64,
+        size: u64,
+    },
+
+    #[snafu(display("The reverse map is full ({} ranges)", capacity))]
+    ReverseMapFull { capacity: usize },
+}
+
+/// The nested page table of the guest.
+///
+/// Only the PML4 is part of this struct. The PDPTs, PDs and PTs are allocated
+/// by the [`PageTableAllocator`] once a range that they cover is mapped, so an
+/// empty table only needs a single page.
+///
+/// All four levels are used, so every guest physical address below
+/// [`NestedPageTable::address_limit`] can be mapped.
+///
+/// # Lazy Mode
+///
+/// Nested page tables created with [`NestedPageTable::lazy`] start out empty.
+/// The pages are mapped by the nested page fault handler once the guest
+/// accesses them for the first time. The tables that are needed for this are
+/// reserved up front, because the OS allocator can't be used in the host
+/// context. The handlers of all processors map pages into the same table,
+/// which is why the shared data keeps it behind a lock.
+///
+/// The pages use the memory type of the MTRRs, like in
+/// [`NestedPageTable::system`]. The MTRRs and the physical memory ranges are
+/// captured when the table is created, because they can't be read in the
+/// host context either.
+#[repr(C, align(4096))]
+pub struct NestedPageTable {
+    pub pml4: PML4,
+
+    allocator: PageTableAllocator,
+
+    /// The guest physical ranges that are not identity mapped.
+    reverse_map: ReverseMap,
+
+    /// How pages are mapped on demand, if this is a lazy nested page table.
+    lazy: Option<LazyMapping>,
+
+    /// The first guest physical address that can't be translated.
+    address_limit: u64,
+    has_1gb_pages: bool,
+}
+const_assert!(core::mem::align_of::<NestedPageTable>() == 4096);
+
+/// The state of a lazy nested page table that is needed to map a page.
+struct LazyMapping {
+    access_type: AccessType,
+    mtrrs: Mtrrs,
+    memory: PhysicalMemoryDescriptor,
+}
+
+/// Returns the memory type of the specified address. The physical memory
+/// ranges use the type of the MTRRs. Everything else is device memory (mmio),
+/// which is uncacheable unless the MTRRs specify another type than
+/// write-back (e.g. write-combining for a frame buffer).
+fn memory_type(mtrrs: &Mtrrs, ranges: &[PhysicalMemoryRange], address: u64) -> MemoryType {
+    let is_memory = ranges.iter().any(|range| {
+        (range.base_address..range.base_address + range.number_of_bytes).contains(&address)
+    });
+
+    match mtrrs.memory_type(address) {
+        MemoryType::WriteBack if !is_memory => MemoryType::Uncacheable,
+        memory_type => memory_type,
+    }
+}
+
+/// The position of the `PAT` bit in 4KB pages and in large pages.
+const PT_PAT_BIT: u64 = 7;
+const LARGE_PAGE_PAT_BIT: u64 = 12;
+
+/// Returns the `PAT`, `PCD` and `PWT` bits that select the host PAT entry with
+/// the specified index. See [`mtrr::pat_index`].
+fn pat_bits(pat_index: u8, pat_bit: u64) -> u64 {
+    let pat_index = pat_index as u64;
+
+    (pat_index & 0b11) << 3 | (pat_index >> 2 & 1) << pat_bit
+}
+
+/// Returns the index of the host PAT entry that is selected by an entry. This
+/// is the inverse of [`pat_bits`].
+fn entry_pat_index(entry: u64, pat_bit: u64) -> u8 {
+    ((entry >> 3 & 0b11) | (entry >> pat_bit & 1) << 2) as u8
+}
+
+/// Returns the address bits of a 1GB or 2MB page. Unlike `address()` of the
+/// entries, this doesn't include the `PAT` bit of large pages.
+fn large_page_address_mask(page_size: u64) -> u64 {
+    PFN_MASK & !(page_size - 1)
+}
+
+/// Returns the table at the specified physical address.
+///
+/// # Safety
+///
+/// The address must be the one of a table that has been allocated by the
+/// [`PageTableAllocator`] of the nested page table. The returned reference
+/// must not outlive it.
+unsafe fn table<'a, E>(address: PAddr) -> &'a [E; PAGE_SIZE_ENTRIES] {
+    &*(PhysicalAddress::from_pa(address.as_u64()).va() as *const [E; PAGE_SIZE_ENTRIES])
+}
+
+/// See [`table`].
+unsafe fn table_mut<'a, E>(address: PAddr) -> &'a mut [E; PAGE_SIZE_ENTRIES] {
+    &mut *(PhysicalAddress::from_pa(address.as_u64()).va() as *mut [E; PAGE_SIZE_ENTRIES])
+}
+
+impl NestedPageTable {
+    /// Creates a nested page table without any mappings.
+    pub fn empty() -> Box<Self> {
+        Box::new(Self {
+            pml4: [PML4Entry(0); 512],
+            allocator: PageTableAllocator::new(),
+            reverse_map: ReverseMap::new(),
+            lazy: None,
+            address_limit: 1 << guest_physical_address_bits(),
+            has_1gb_pages: has_1gb_pages(),
+        })
+    }
+
+    pub fn default() -> Option<Box<Self>> {
+        NestedPageTable::lazy(AccessType::ReadWriteExecute)
+    }
+
+    /// Creates a nested page table that maps the guest physical pages on
+    /// demand. Every page is identity mapped with the specified access type
+    /// once it's accessed for the first time.
+    ///
+    /// Enough tables to map the physical memory with 4KB pages are reserved,
+    /// so this costs about 2MB for every 1GB of physical memory. Device memory
+    /// outside of it is mapped with large pages, see
+    /// [`NestedPageTable::map_on_demand`]. For these, a PDPT for the whole
+    /// guest physical address space is reserved as well.
+    pub fn lazy(access_type: AccessType) -> Option<Box<Self>> {
+        log::info!("Building lazy nested page tables");
+
+        let mut npt = Self::empty();
+        let memory = PhysicalMemoryDescriptor::new();
+
+        // Every 2MB of physical memory needs a PT, every 1GB a PD and every 512GB of
+        // the address space a PDPT.
+        //
+        let end = memory
+            .get_ranges()
+            .iter()
+            .map(|range| range.base_address + range.number_of_bytes)
+            .max()
+            .unwrap_or_default();
+        let tables_for = |end: u64, size: u64| ((end + size - 1) / size) as usize;
+        let tables = tables_for(end, _2MB as u64)
+            + tables_for(end, _1GB)
+            + tables_for(npt.address_limit, _512GB);
+
+        npt.allocator.reserve(tables + LAZY_RESERVED_TABLES)?;
+        npt.allocator.set_growable(false);
+        log::info!(
+            "Reserved {:#x} bytes for the nested page tables",
+            npt.allocator.allocated_size()
+        );
+
+        npt.lazy = Some(LazyMapping {
+            access_type,
+            mtrrs: Mtrrs::read(),
+            memory,
+        });
+
+        Some(npt)
+    }
+
+    /// Creates the identity page table. Maps every guest physical address
+    /// to the same host physical address.
+    /// This means physical address 0x4000 in the guest will point to the
+    /// physical memory 0x4000 in the host.
+    ///
+    ///
+    /// # How it works
+    ///
+    /// The whole guest physical address space is mapped with the largest
+    /// pages that are supported (**1GB**, otherwise **2MB**). There's
+    /// multiple reasons for that:
+    /// - Smaller page table.
+    /// - Iterating is faster since we remove 1-2 iterations.
+    ///
+    /// Pros:
+    /// - Easier to implement.
+    /// - Faster to iterate (2-3 levels instead of 4).
+    ///
+    /// Cons:
+    /// - Hooking large pages is inconvenient, because we would get tons of ept
+    ///   violations. They have to be split first.
+    ///
+    /// # Other implementations
+    ///
+    /// Even though other hypervisors might be built for Intel processors, they
+    /// still need to build some kind of [SLAT](https://en.wikipedia.org/wiki/Second_Level_Address_Translation) (Second Level Address Translation Table).
+    ///
+    /// Here's a list of useful references in popular projects:
+    /// - [hvpp](https://github.com/wbenny/hvpp/blob/master/src/hvpp/hvpp/ept.cpp#L41)
+    /// - [gbhv](https://github.com/Gbps/gbhv/blob/master/gbhv/ept.c#L167)
+    pub fn identity() -> Option<Box<Self>> {
+        log::info!("Building nested page tables");
+
+        let mut npt = Self::empty();
+
+        log::info!("Mapping {:#x} bytes of physical memory", npt.address_limit);
+        npt.map_range(0, 0, npt.address_limit, AccessType::ReadWriteExecute)
+            .ok()?;
+
+        Some(npt)
+    }
+
+    pub fn identity_2mb(access_type: AccessType) -> Option<Box<Self>> {
+        log::info!("Building nested page tables with 2MB pages");
+
+        let mut npt = Self::empty();
+
+        log::info!("Mapping 512GB of physical memory");
+        for pa in (0.._512GB).step_by(_2MB) {
+            npt.map_2mb(pa, pa, access_type).ok()?;
+        }
+
+        Some(npt)
+    }
+
+    /// Builds the identity nested page table for the entire physical address
+    /// space, with the memory types of the MTRRs.
+    ///
+    /// See [`memory_type`] for the memory type of the pages.
+    pub fn system(access_type: AccessType) -> Option<Box<Self>> {
+        log::info!("Building nested page tables from the physical memory map");
+
+        let mut npt = Self::empty();
+        let limit = npt.address_limit;
+
+        let mtrrs = Mtrrs::read();
+        let desc = PhysicalMemoryDescriptor::new();
+        let ranges = desc.get_ranges();
+
+        let memory_type = |address: u64| memory_type(&mtrrs, ranges, address);
+
+        // Neither the memory type nor the kind of memory changes between two
+        // boundaries.
+        //
+        let mut boundaries = mtrrs.boundaries();
+        for range in ranges {
+            boundaries.push(range.base_address);
+            boundaries.push(range.base_address + range.number_of_bytes);
+        }
+        boundaries.push(limit);
+        boundaries.retain(|address| *address <= limit);
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        // Adjacent ranges with the same memory type are mapped at once, so that
+        // larger pages can be used.
+        //
+        let mut start = 0;
+        for &boundary in boundaries.iter().filter(|address| **address != 0) {
+            let start_type = memory_type(start);
+            if boundary != limit && memory_type(boundary) == start_type {
+                continue;
+            }
+
+            log::trace!("Mapping {:#x}-{:#x} as {:?}", start, boundary, start_type);
+            npt.map_range_with_memory_type(start, start, boundary - start, access_type, start_type)
+                .ok()?;
+
+            start = boundary;
+        }
+
+        Some(npt)
+    }
+
+    /// Returns the access type of the pages that are mapped on demand, or
+    /// `None` if this isn't a lazy nested page table.
+    pub fn lazy_access_type(&self) -> Option<AccessType> {
+        self.lazy.as_ref().map(|lazy| lazy.access_type)
+    }
+
+    /// Identity maps the 4KB page that contains the guest physical address,
+    /// because the guest accessed it for the first time. Lazy nested page
+    /// tables use their access type and the memory type of the page, others
+    /// map it with full access as write-back.
+    ///
+    /// Lazy nested page tables map device memory outside of the physical
+    /// memory ranges with the largest page that doesn't contain any memory and
+    /// has a single memory type. The reserved tables only cover the physical
+    /// memory, so 4KB pages would quickly use them up for large mmio ranges.
+    pub fn map_on_demand(&mut self, guest_pa: u64) -> Result<(), NestedPageTableError> {
+        let guest_pa = PhysicalAddress::from_pa(guest_pa)
+            .align_down_to_base_page()
+            .as_u64();
+
+        let Some(lazy) = &self.lazy else {
+            return self.map_4kb(guest_pa, guest_pa, AccessType::ReadWriteExecute);
+        };
+        let access_type = lazy.access_type;
+        let memory_type = memory_type(&lazy.mtrrs, lazy.memory.get_ranges(), guest_pa);
+
+        let is_device_page = |page_size: u64| {
+            let start = guest_pa & !(page_size - 1);
+            let end = start + page_size;
+
+            end <= self.address_limit
+                && lazy.mtrrs.has_single_type(start, end)
+                && !lazy.memory.get_ranges().iter().any(|range| {
+                    range.base_address < end && start < range.base_address + range.number_of_bytes
+                })
+        };
+        let page_sizes = [_1GB, _2MB as u64];
+        let is_device = page_sizes.map(is_device_page);
+
+        // The large page can't be used if part of it is already mapped.
+        //
+        for (page_size, is_device) in page_sizes.into_iter().zip(is_device) {
+            let start = guest_pa & !(page_size - 1);
+            if is_device && self.largest_page_size(start, start, page_size) == page_size {
+                return self.map_range_with_memory_type(
+                    start,
+                    start,
+                    page_size,
+                    access_type,
+                    memory_type,
+                );
+            }
+        }
+
+        self.map_range_with_memory_type(
+            guest_pa,
+            guest_pa,
+            BASE_PAGE_SIZE as u64,
+            access_type,
+            memory_type,
+        )
+    }
+
+    pub fn allocator(&self) -> &PageTableAllocator {
+        &self.allocator
+    }
+
+    pub fn reverse_map(&self) -> &ReverseMap {
+        &self.reverse_map
+    }
+
+    /// Returns the first guest physical address that can't be mapped. This is
+    /// based on the guest physical address width reported by the processor.
+    pub fn address_limit(&self) -> u64 {
+        self.address_limit
+    }
+
+    //
+    //
+
+    /// Splits a huge 1GB page into 512 large 2MB pages, which keep the flags of
+    /// the huge page.
+    pub fn split_1gb_to_2mb(&mut self, guest_pa: u64) -> Result<(), NestedPageTableError> {
+        log::trace!("Splitting 1gb page into 2mb pages: {:x}", guest_pa);
+
+        let guest_pa = VAddr::from(guest_pa).align_down_to_huge_page().as_u64();
+
+        let Some(pdpt_entry) = self.pdpt_entry_mut(guest_pa) else {
+            log::trace!("Page is not mapped: {:x}.", guest_pa);
+            return Ok(());
+        };
+        if !pdpt_entry.is_page() {
+            log::trace!("Page is not mapped or already split: {:x}.", guest_pa);
+            return Ok(());
+        }
+        let address_mask = large_page_address_mask(_1GB);
+        let (host_pa, flags) = (pdpt_entry.0 & address_mask, pdpt_entry.0 & !address_mask);
+
+        // Fill the new page directory before it's used, so that the pages are never
+        // unmapped. The flags of 1GB and 2MB pages are the same.
+        //
+        let pd_pa = self.allocate_table()?;
+        let pd: &mut PD = unsafe { table_mut(pd_pa) };
+        for (i, pd_entry) in pd.iter_mut().enumerate() {
+            *pd_entry = PDEntry((host_pa + (i * _2MB) as u64) | flags);
+        }
+
+        *self.pdpt_entry_mut(guest_pa).unwrap() =
+            PDPTEntry::new(pd_pa, AccessType::ReadWriteExecute.pdpt_flags());
+
+        Ok(())
+    }
+
+    /// Splits a large 2MB page into 512 smaller 4KB pages with the specified
+    /// access type. They keep the memory type of the large page.
+    ///
+    /// This is needed to apply more granular hooks and to reduce the number of
+    /// page faults that occur when the guest tries to access a page that is
+    /// hooked.
+    ///
+    /// See:
+    /// - https://github.com/wbenny/hvpp/blob/master/src/hvpp/hvpp/ept.cpp#L245
+    pub fn split_2mb_to_4kb(
+        &mut self, guest_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        log::trace!("Splitting 2mb page into 4kb pages: {:x}", guest_pa);
+
+        let guest_pa = VAddr::from(guest_pa).align_down_to_large_page();
+
+        // We can only split large pages and not page directories.
+        // If it's a page directory, it is already split.
+        //
+        let Some(pd_entry) = self.pd_entry_mut(guest_pa.as_u64()) else {
+            log::trace!("Page is not mapped: {:x}.", guest_pa);
+            return Ok(());
+        };
+        if !pd_entry.is_page() {
+            log::trace!("Page is not mapped or already split: {:x}.", guest_pa);
+            return Ok(());
+        }
+        let pd_entry = *pd_entry;
+        let host_pa = pd_entry.0 & large_page_address_mask(_2MB as u64);
+
+        // The memory type is selected by the same bits, except for the `PAT` bit which
+        // has another position.
+        //
+        let memory_type_bits = pd_entry.0 & (PDFlags::PWT | PDFlags::PCD).bits()
+            | (pd_entry.0 >> LARGE_PAGE_PAT_BIT & 1) << PT_PAT_BIT;
+
+        // Fill the new page table before it's used, so that the pages are never
+        // unmapped.
+        //
+        let pt_pa = self.allocate_table()?;
+        let pt: &mut PT = unsafe { table_mut(pt_pa) };
+        for (i, pt_entry) in pt.iter_mut().enumerate() {
+            let host_pa = host_pa + (i * BASE_PAGE_SIZE) as u64;
+            *pt_entry = PTEntry(host_pa | access_type.page_bits() | memory_type_bits);
+        }
+
+        *self.pd_entry_mut(guest_pa.as_u64()).unwrap() =
+            PDEntry::new(pt_pa, AccessType::ReadWriteExecute.pd_flags());
+
+        Ok(())
+    }
+
+    pub fn join_4kb_to_2mb(
+        &mut self, guest_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        log::trace!("Joining 4kb pages into 2mb page: {:x}", guest_pa);
+
+        let guest_pa = VAddr::from(guest_pa).align_down_to_large_page();
+
+        if self
+            .pd_entry_mut(guest_pa.as_u64())
+            .map_or(false, |pd_entry| pd_entry.is_page())
+        {
+            log::warn!(
+                "Tried to join a large page: {:x}. Only page directories can be joined.",
+                guest_pa
+            );
+            return Ok(());
+        }
+
+        // Unmap the page directory
+        //
+        self.unmap_4kb(guest_pa.as_u64())?;
+
+        // Map the unmapped physical memory again to a 2MB large page.
+        //
+        log::trace!("Mapping 2mb page: {:x}", guest_pa);
+        self.map_2mb(guest_pa.as_u64(), guest_pa.as_u64(), access_type)
+    }
+
+    //
+    //
+
+    /// Maps the guest physical range to the host physical range. Every part
+    /// of the range is mapped with the largest page that it's aligned to, as
+    /// long as there's no smaller page mapped there already.
+    pub fn map_range(
+        &mut self, guest_pa: u64, host_pa: u64, size: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        self.map_range_with_pat_index(guest_pa, host_pa, size, access_type, 0)
+    }
+
+    /// Like [`NestedPageTable::map_range`], but the pages use the host PAT
+    /// entry with the specified memory type. The other mapping functions use
+    /// the first entry, which is write-back by default.
+    pub fn map_range_with_memory_type(
+        &mut self, guest_pa: u64, host_pa: u64, size: u64, access_type: AccessType,
+        memory_type: MemoryType,
+    ) -> Result<(), NestedPageTableError> {
+        let pat_index = mtrr::pat_index(memory_type);
+
+        self.map_range_with_pat_index(guest_pa, host_pa, size, access_type, pat_index)
+    }
+
+    fn map_range_with_pat_index(
+        &mut self, guest_pa: u64, host_pa: u64, size: u64, access_type: AccessType, pat_index: u8,
+    ) -> Result<(), NestedPageTableError> {
+        ensure!(
+            (guest_pa | host_pa | size) % BASE_PAGE_SIZE as u64 == 0,
+            UnalignedRangeSnafu {
+                guest_pa,
+                host_pa,
+                size
+            }
+        );
+
+        let mut offset = 0;
+        while offset < size {
+            let (guest_pa, host_pa) = (guest_pa + offset, host_pa + offset);
+
+            let page_size = self.largest_page_size(guest_pa, host_pa, size - offset);
+            self.map_page(guest_pa, host_pa, page_size, access_type, pat_index)?;
+
+            offset += page_size;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the size of the largest page that can be used to map `size`
+    /// bytes at the specified addresses.
+    fn largest_page_size(&mut self, guest_pa: u64, host_pa: u64, size: u64) -> u64 {
+        let fits = |page_size: u64| (guest_pa | host_pa) % page_size == 0 && size >= page_size;
+
+        let pdpt_entry_free = self
+            .pdpt_entry_mut(guest_pa)
+            .map_or(true, |entry| entry.0 == 0);
+        let pd_entry_free = self
+            .pd_entry_mut(guest_pa)
+            .map_or(true, |entry| entry.0 == 0);
+
+        if self.has_1gb_pages && fits(_1GB) && pdpt_entry_free {
+            _1GB
+        } else if fits(_2MB as u64) && pd_entry_free {
+            _2MB as u64
+        } else {
+            BASE_PAGE_SIZE as u64
+        }
+    }
+
+    pub fn map_1gb(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        ensure!(self.has_1gb_pages, HugePagesUnsupportedSnafu);
+
+        self.map_page(guest_pa, host_pa, _1GB, access_type, 0)
+    }
+
+    pub fn map_2mb(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        self.map_page(guest_pa, host_pa, _2MB as u64, access_type, 0)
+    }
+
+    pub fn map_4kb(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        self.map_page(guest_pa, host_pa, BASE_PAGE_SIZE as u64, access_type, 0)
+    }
+
+    /// Maps a 1GB, 2MB or 4KB page that uses the specified host PAT entry.
+    ///
+    /// The tables that lead up to the page grant full access, so the
+    /// permissions are only decided by the page itself. Pages are written as
+    /// raw entries, because the flags can't hold the marker of pages without
+    /// any access.
+    fn map_page(
+        &mut self, guest_pa: u64, host_pa: u64, page_size: u64, access_type: AccessType,
+        pat_index: u8,
+    ) -> Result<(), NestedPageTableError> {
+        self.map_pml4(guest_pa)?;
+        if page_size == _1GB {
+            return self.map_pdpte(guest_pa, host_pa, access_type, pat_index);
+        }
+
+        self.map_pdpt(guest_pa)?;
+        if page_size == _2MB as u64 {
+            return self.map_pde(guest_pa, host_pa, access_type, pat_index);
+        }
+
+        self.map_pdt(guest_pa)?;
+        self.map_pt(guest_pa, host_pa, access_type, pat_index)
+    }
+
+    /// Allocates a new table and returns its physical address.
+    fn allocate_table(&mut self) -> Result<PAddr, NestedPageTableError> {
+        let table = self.allocator.allocate().context(OutOfMemorySnafu)?;
+
+        Ok(physical_address(table.as_ptr() as _))
+    }
+
+    fn free_table(&mut self, address: PAddr) {
+        let table = PhysicalAddress::from_pa(address.as_u64()).va() as *mut Table;
+
+        unsafe { self.allocator.free(NonNull::new_unchecked(table)) };
+    }
+
+    fn map_pml4(&mut self, guest_pa: u64) -> Result<(), NestedPageTableError> {
+        ensure!(
+            guest_pa < self.address_limit,
+            AddressOutOfRangeSnafu { address: guest_pa }
+        );
+
+        let pml4_index = pml4_index(VAddr::from(guest_pa));
+
+        if !self.pml4[pml4_index].is_present() {
+            let pa = self.allocate_table()?;
+            self.pml4[pml4_index] = PML4Entry::new(pa, AccessType::ReadWriteExecute.pml4_flags());
+        }
+
+        Ok(())
+    }
+
+    fn map_pdpt(&mut self, guest_pa: u64) -> Result<(), NestedPageTableError> {
+        let pml4_entry = self.pml4[pml4_index(VAddr::from(guest_pa))];
+        let pdpt: &mut PDPT = unsafe { table_mut(pml4_entry.address()) };
+        let pdpt_entry = &mut pdpt[pdpt_index(VAddr::from(guest_pa))];
+
+        if pdpt_entry.0 == 0 {
+            let pa = self.allocate_table()?;
+            *pdpt_entry = PDPTEntry::new(pa, AccessType::ReadWriteExecute.pdpt_flags());
+        }
+
+        Ok(())
+    }
+
+    fn map_pdpte(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType, pat_index: u8,
+    ) -> Result<(), NestedPageTableError> {
+        if self.pdpt_entry_mut(guest_pa).unwrap().0 != 0 {
+            log::warn!("Tried to map a page that is already mapped: {:x}", guest_pa);
+            return Ok(());
+        }
+
+        // The page is only mapped if it can be found again.
+        //
+        self.reverse_map.insert(guest_pa, host_pa, _1GB)?;
+        *self.pdpt_entry_mut(guest_pa).unwrap() = PDPTEntry(
+            host_pa
+                | PDPTFlags::PS.bits()
+                | access_type.page_bits()
+                | pat_bits(pat_index, LARGE_PAGE_PAT_BIT),
+        );
+
+        Ok(())
+    }
+
+    fn map_pdt(&mut self, guest_pa: u64) -> Result<(), NestedPageTableError> {
+        let pdpt_entry = *self.pdpt_entry_mut(guest_pa).unwrap();
+        if pdpt_entry.is_page() {
+            // Already mapped by a 1GB page. This is reported by `map_pde` or `map_pt`.
+            //
+            return Ok(());
+        }
+
+        let pd: &mut PD = unsafe { table_mut(pdpt_entry.address()) };
+        let pd_entry = &mut pd[pd_index(VAddr::from(guest_pa))];
+
+        if pd_entry.0 == 0 {
+            let pa = self.allocate_table()?;
+            *pd_entry = PDEntry::new(pa, AccessType::ReadWriteExecute.pd_flags());
+        }
+
+        Ok(())
+    }
+
+    fn map_pde(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType, pat_index: u8,
+    ) -> Result<(), NestedPageTableError> {
+        let Some(pd_entry) = self.pd_entry_mut(guest_pa) else {
+            log::warn!("Tried to map a page inside of a 1gb page: {:x}", guest_pa);
+            return Ok(());
+        };
+        if pd_entry.0 != 0 {
+            log::warn!("Tried to map a page that is already mapped: {:x}", guest_pa);
+            return Ok(());
+        }
+
+        self.reverse_map.insert(guest_pa, host_pa, _2MB as u64)?;
+
+        // We already have the page frame number of the physical address, so we don't
+        // need to calculate it on our own. Just pass it to the page
+        // directory entry.
+        //
+        *self.pd_entry_mut(guest_pa).unwrap() = PDEntry(
+            host_pa
+                | PDFlags::PS.bits()
+                | access_type.page_bits()
+                | pat_bits(pat_index, LARGE_PAGE_PAT_BIT),
+        );
+
+        Ok(())
+    }
+
+    fn map_pt(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType, pat_index: u8,
+    ) -> Result<(), NestedPageTableError> {
+        let Some(pt_entry) = self.pt_entry_mut(guest_pa) else {
+            log::warn!("Tried to map a page inside of a large page: {:x}", guest_pa);
+            return Ok(());
+        };
+        if pt_entry.0 != 0 {
+            log::warn!("Tried to map a page that is already mapped: {:x}", guest_pa);
+            return Ok(());
+        }
+
+        self.reverse_map
+            .insert(guest_pa, host_pa, BASE_PAGE_SIZE as u64)?;
+
+        // We already have the page frame number of the physical address, so we don't
+        // need to calculate it on our own. Just pass it to the page table
+        // entry.
+        //
+        *self.pt_entry_mut(guest_pa).unwrap() =
+            PTEntry(host_pa | access_type.page_bits() | pat_bits(pat_index, PT_PAT_BIT));
+
+        Ok(())
+    }
+
+    //
+    //
+
+    /// Removes the large page, or the page table, that maps the specified
+    /// address.
+    fn unmap_2mb(&mut self, guest_pa: u64) -> Result<(), NestedPageTableError> {
+        let Some(pd_entry) = self.pd_entry_mut(guest_pa) else {
+            return Ok(());
+        };
+        if pd_entry.0 == 0 {
+            return Ok(());
+        }
+        let (address, is_page) = (pd_entry.address(), pd_entry.is_page());
+
+        let large_page_pa = VAddr::from(guest_pa).align_down_to_large_page().as_u64();
+        self.reverse_map.remove(large_page_pa, _2MB as u64)?;
+
+        *self.pd_entry_mut(guest_pa).unwrap() = PDEntry(0);
+
+        // The page table is no longer referenced and can be reused.
+        //
+        if !is_page {
+            self.free_table(address);
+        }
+
+        Ok(())
+    }
+
+    fn unmap_4kb(&mut self, guest_pa: u64) -> Result<(), NestedPageTableError> {
+        self.unmap_2mb(guest_pa)
+    }
+
+    //
+    //
+
+    /// Returns the PDPT that maps the specified address, if it's present.
+    fn pdpt_mut(&mut self, guest_pa: u64) -> Option<&mut PDPT> {
+        let pml4_entry = self.pml4[pml4_index(VAddr::from(guest_pa))];
+
+        pml4_entry
+            .is_present()
+            .then(|| unsafe { table_mut(pml4_entry.address()) })
+    }
+
+    /// Returns the PD that maps the specified address, if it's present and not
+    /// a 1GB page.
+    fn pd_mut(&mut self, guest_pa: u64) -> Option<&mut PD> {
+        let pdpt_entry = self.pdpt_mut(guest_pa)?[pdpt_index(VAddr::from(guest_pa))];
+
+        (pdpt_entry.is_present() && !pdpt_entry.is_page())
+            .then(|| unsafe { table_mut(pdpt_entry.address()) })
+    }
+
+    /// Returns the PT that maps the specified address, if it's present and not
+    /// a 2MB page.
+    fn pt_mut(&mut self, guest_pa: u64) -> Option<&mut PT> {
+        let pd_entry = self.pd_mut(guest_pa)?[pd_index(VAddr::from(guest_pa))];
+
+        (pd_entry.is_present() && !pd_entry.is_page())
+            .then(|| unsafe { table_mut(pd_entry.address()) })
+    }
+
+    fn pdpt_entry_mut(&mut self, guest_pa: u64) -> Option<&mut PDPTEntry> {
+        Some(&mut self.pdpt_mut(guest_pa)?[pdpt_index(VAddr::from(guest_pa))])
+    }
+
+    fn pd_entry_mut(&mut self, guest_pa: u64) -> Option<&mut PDEntry> {
+        Some(&mut self.pd_mut(guest_pa)?[pd_index(VAddr::from(guest_pa))])
+    }
+
+    fn pt_entry_mut(&mut self, guest_pa: u64) -> Option<&mut PTEntry> {
+        Some(&mut self.pt_mut(guest_pa)?[pt_index(VAddr::from(guest_pa))])
+    }
+
+    //
+    //
+
+    /// Translates a guest physical address to a host physical address.
+    pub fn translate(&self, virtual_address: u64) -> Option<u64> {
+        let pml4_index = pml4_index(VAddr::from(virtual_address));
+        let pml4_entry = &self.pml4[pml4_index];
+
+        if !pml4_entry.is_present() {
+            log::trace!("PML4 entry not present");
+            return None;
+        }
+
+        let pdpt: &PDPT = unsafe { table(pml4_entry.address()) };
+        let pdpt_entry = &pdpt[pdpt_index(VAddr::from(virtual_address))];
+        if !pdpt_entry.is_present() {
+            log::trace!("PDPT entry not present");
+            return None;
+        }
+        if pdpt_entry.is_page() {
+            // 1GB page
+            let physical_address =
+                (pdpt_entry.0 & large_page_address_mask(_1GB)) + virtual_address % 0x40000000;
+            return Some(physical_address);
+        }
+
+        let pd: &PD = unsafe { table(pdpt_entry.address()) };
+        let pd_entry = &pd[pd_index(VAddr::from(virtual_address))];
+        if !pd_entry.is_present() {
+            log::trace!("PD entry not present");
+            return None;
+        }
+        if pd_entry.is_page() {
+            // 2MB page
+            let physical_address =
+                (pd_entry.0 & large_page_address_mask(_2MB as u64)) + virtual_address % 0x200000;
+            return Some(physical_address);
+        }
+
+        let pt: &PT = unsafe { table(pd_entry.address()) };
+        let pt_entry = &pt[pt_index(VAddr::from(virtual_address))];
+        if !pt_entry.is_present() {
+            log::trace!("PT entry not present");
+            return None;
+        }
+
+        // or `& 0xFFF`
+        Some(pt_entry.address().as_u64() + virtual_address % 0x1000)
+    }
+
+    /// Returns the access type that the guest actually has for the specified
+    /// guest physical address, which is the intersection of the permissions
+    /// of all the levels that map it. Returns `None` if it isn't mapped.
+    pub fn effective_access_type(&self, guest_pa: u64) -> Option<AccessType> {
+        let address = VAddr::from(guest_pa);
+
+        let pml4_entry = self.pml4[pml4_index(address)];
+        if !pml4_entry.is_present() {
+            return None;
+        }
+        let access_type = AccessType::from_entry(pml4_entry.0);
+
+        let pdpt: &PDPT = unsafe { table(pml4_entry.address()) };
+        let pdpt_entry = pdpt[pdpt_index(address)];
+        if pdpt_entry.0 == 0 {
+            return None;
+        }
+        let access_type = access_type.intersection(AccessType::from_entry(pdpt_entry.0));
+        if pdpt_entry.is_page() {
+            return Some(access_type);
+        }
+
+        let pd: &PD = unsafe { table(pdpt_entry.address()) };
+        let pd_entry = pd[pd_index(address)];
+        if pd_entry.0 == 0 {
+            return None;
+        }
+        let access_type = access_type.intersection(AccessType::from_entry(pd_entry.0));
+        if pd_entry.is_page() {
+            return Some(access_type);
+        }
+
+        let pt: &PT = unsafe { table(pd_entry.address()) };
+        let pt_entry = pt[pt_index(address)];
+        if pt_entry.0 == 0 {
+            return None;
+        }
+
+        Some(access_type.intersection(AccessType::from_entry(pt_entry.0)))
+    }
+
+    /// Returns the raw entry and the size of the page that maps the specified
+    /// guest physical address, including pages without any access.
+    fn page(&self, guest_pa: u64) -> Option<(u64, u64)> {
+        let address = VAddr::from(guest_pa);
+
+        let pml4_entry = self.pml4[pml4_index(address)];
+        if !pml4_entry.is_present() {
+            return None;
+        }
+
+        let pdpt: &PDPT = unsafe { table(pml4_entry.address()) };
+        let pdpt_entry = pdpt[pdpt_index(address)];
+        if pdpt_entry.0 == 0 {
+            return None;
+        }
+        if pdpt_entry.is_page() {
+            return Some((pdpt_entry.0, _1GB));
+        }
+
+        let pd: &PD = unsafe { table(pdpt_entry.address()) };
+        let pd_entry = pd[pd_index(address)];
+        if pd_entry.0 == 0 {
+            return None;
+        }
+        if pd_entry.is_page() {
+            return Some((pd_entry.0, _2MB as u64));
+        }
+
+        let pt: &PT = unsafe { table(pd_entry.address()) };
+        let pt_entry = pt[pt_index(address)];
+
+        (pt_entry.0 != 0).then(|| (pt_entry.0, BASE_PAGE_SIZE as u64))
+    }
+
+    /// Returns the guest physical addresses that map the specified host
+    /// physical address, including the identity mapping and pages without any
+    /// access. A host page without any aliases can't be reached by the guest.
+    pub fn aliases_of(&self, host_pa: u64) -> Vec<u64> {
+        let mut aliases = self.reverse_map.remapped_to(host_pa).collect::<Vec<_>>();
+
+        // The identity mapping only counts if the guest page hasn't been remapped.
+        //
+        let is_identity_mapped = self.page(host_pa).map_or(false, |(entry, page_size)| {
+            let address_mask = if page_size == BASE_PAGE_SIZE as u64 {
+                PFN_MASK
+            } else {
+                large_page_address_mask(page_size)
+            };
+
+            entry & address_mask == host_pa & !(page_size - 1)
+        });
+        if is_identity_mapped {
+            aliases.push(host_pa);
+        }
+
+        aliases
+    }
+
+    /// Returns the guest physical pages that map memory of the hypervisor,
+    /// except for the expected ones.
+    ///
+    /// This can be used to verify that a shadow page is only reachable from the
+    /// page that it replaces, or that the memory of the hypervisor isn't
+    /// mapped into the guest at all.
+    pub fn audit_aliases(&self, owned: &[OwnedRange]) -> Vec<UnexpectedAlias> {
+        let mut unexpected = Vec::new();
+
+        for range in owned {
+            for offset in (0..range.size).step_by(BASE_PAGE_SIZE) {
+                let host_pa = range.host_pa + offset;
+                let expected = range.expected_guest_pa.map(|guest_pa| guest_pa + offset);
+
+                unexpected.extend(
+                    self.aliases_of(host_pa)
+                        .into_iter()
+                        .filter(|guest_pa| Some(*guest_pa) != expected)
+                        .map(|guest_pa| UnexpectedAlias { guest_pa, host_pa }),
+                );
+            }
+        }
+
+        unexpected
+    }
+
+    /// Remaps the given guest physical address and changes it to the given host
+    /// physical address. The page is mapped if it isn't already. Both
+    /// addresses have to be 4KB aligned.
+    ///
+    /// Returns [`NestedPageTableError::ReverseMapFull`] if there are already
+    /// too many remapped ranges. See [`ReverseMap`].
+    // TODO: Don't pass access type here
+    pub fn remap_page(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        #[allow(deprecated)]
+        self.change_page_permission(guest_pa, host_pa, access_type)
+    }
+
+    /// Changes the permission of a single page (can be 2mb or 4kb). Pages that
+    /// are not mapped yet are mapped as 4kb page, and 1gb pages are split into
+    /// 2mb pages first.
+    ///
+    /// Both addresses have to be 4KB aligned, otherwise
+    /// [`NestedPageTableError::UnalignedRange`] is returned.
+    ///
+    /// The upper levels that lead up to the page are widened if they don't
+    /// grant the access type yet, but they are never restricted. See
+    /// [`NestedPageTable::change_all_page_flags`].
+    #[deprecated(note = "Use `change_page_flags` instead")]
+    pub fn change_page_permission(
+        &mut self, guest_pa: u64, host_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        log::trace!(
+            "Changing permission of guest page {:#x} to {:?}",
+            guest_pa,
+            access_type
+        );
+
+        // The host address is written into the entry as is, so the page offset
+        // would end up in the flags.
+        //
+        ensure!(
+            (guest_pa | host_pa) % BASE_PAGE_SIZE as u64 == 0,
+            UnalignedRangeSnafu {
+                guest_pa,
+                host_pa,
+                size: BASE_PAGE_SIZE as u64,
+            }
+        );
+
+        self.map_pml4(guest_pa)?;
+        self.map_pdpt(guest_pa)?;
+        self.split_1gb_to_2mb(guest_pa)?;
+
+        if self.pd_entry_mut(guest_pa).unwrap().is_page() {
+            log::trace!("Changing the permissions of a 2mb page");
+
+            let address_mask = large_page_address_mask(_2MB as u64);
+            let large_page_pa = VAddr::from(guest_pa).align_down_to_large_page().as_u64();
+            self.reverse_map
+                .insert(large_page_pa, host_pa & address_mask, _2MB as u64)?;
+
+            let pd_entry = self.pd_entry_mut(guest_pa).unwrap();
+            *pd_entry =
+                PDEntry((host_pa & address_mask) | (access_type.apply(pd_entry.0) & !address_mask));
+        } else {
+            log::trace!("Changing the permissions of a 4kb page");
+
+            self.map_pdt(guest_pa)?;
+            self.reverse_map
+                .insert(guest_pa, host_pa, BASE_PAGE_SIZE as u64)?;
+
+            let pt_entry = self.pt_entry_mut(guest_pa).unwrap();
+            *pt_entry = PTEntry(host_pa | (access_type.apply(pt_entry.0) & !PFN_MASK));
+        }
+
+        self.widen_table_flags(guest_pa, access_type);
+
+        Ok(())
+    }
+
+    /// Changes the flags of the pml4 entry for the specified page. This
+    /// restricts every page that is mapped by it, see
+    /// [`NestedPageTable::effective_access_type`].
+    pub fn change_pml4_flags(&mut self, guest_pa: u64, access_type: AccessType) {
+        let pml4_index = pml4_index(VAddr::from(guest_pa));
+        let pml4_entry = &mut self.pml4[pml4_index];
+        if !pml4_entry.is_present() {
+            log::warn!("PML4 entry not present: {:#x}", guest_pa);
+            return;
+        }
+
+        *pml4_entry = PML4Entry::new(pml4_entry.address(), access_type.pml4_flags());
+    }
+
+    /// Changes the flags of the pdp entry for the specified page. See
+    /// [`NestedPageTable::change_pml4_flags`].
+    pub fn change_pdpt_flags(&mut self, guest_pa: u64, access_type: AccessType) {
+        let Some(pdpt) = self.pdpt_mut(guest_pa) else {
+            log::warn!("PML4 entry not present: {:#x}", guest_pa);
+            return;
+        };
+        let pdp_entry = &mut pdpt[pdpt_index(VAddr::from(guest_pa))];
+        if !pdp_entry.is_present() {
+            log::warn!("PDPT entry not present: {:#x}", guest_pa);
+            return;
+        }
+
+        *pdp_entry = PDPTEntry::new(pdp_entry.address(), access_type.pdpt_flags());
+    }
+
+    /// Changes the permission of a single page (can be 1gb, 2mb or 4kb). The
+    /// upper levels are not changed, so the effective access type of the page
+    /// can be more restrictive than the specified one.
+    ///
+    /// The address has to be 4KB aligned, otherwise
+    /// [`NestedPageTableError::UnalignedRange`] is returned.
+    pub fn change_page_flags(
+        &mut self, guest_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        ensure!(
+            guest_pa % BASE_PAGE_SIZE as u64 == 0,
+            UnalignedRangeSnafu {
+                guest_pa,
+                host_pa: 0u64,
+                size: BASE_PAGE_SIZE as u64,
+            }
+        );
+
+        if let Some(pdpt_entry) = self
+            .pdpt_entry_mut(guest_pa)
+            .filter(|pdpt_entry| pdpt_entry.is_page())
+        {
+            log::trace!("Changing the permissions of a 1gb page");
+
+            *pdpt_entry = PDPTEntry(access_type.apply(pdpt_entry.0));
+            return Ok(());
+        }
+
+        let Some(pd_entry) = self.pd_entry_mut(guest_pa) else {
+            log::warn!("Page is not mapped: {:#x}", guest_pa);
+            return Ok(());
+        };
+        if pd_entry.is_page() {
+            log::trace!("Changing the permissions of a 2mb page");
+
+            *pd_entry = PDEntry(access_type.apply(pd_entry.0));
+        } else if let Some(pt_entry) = self
+            .pt_entry_mut(guest_pa)
+            .filter(|pt_entry| pt_entry.0 != 0)
+        {
+            log::trace!("Changing the permissions of a 4kb page");
+
+            *pt_entry = PTEntry(access_type.apply(pt_entry.0));
+        } else {
+            log::warn!("Page is not mapped: {:#x}", guest_pa);
+        }
+
+        Ok(())
+    }
+
+    /// Changes the permission of the specified page for all the page tables,
+    /// so that the page ends up with exactly this effective access type.
+    ///
+    /// The upper levels that lead up to the page are shared with up to 512GB
+    /// of other pages. Because of this, they are only widened if they don't
+    /// grant the access type yet. Restricting them would also restrict all the
+    /// other pages (e.g. making the entire page table non-executable).
+    ///
+    /// Example scenarios:
+    /// - RW npt -> change page to RWX -> the upper tables become executable
+    /// - RWX npt -> change page to RW -> only the page is changed
+    pub fn change_all_page_flags(
+        &mut self, guest_pa: u64, access_type: AccessType,
+    ) -> Result<(), NestedPageTableError> {
+        self.change_page_flags(guest_pa, access_type)?;
+        self.widen_table_flags(guest_pa, access_type);
+
+        Ok(())
+    }
+
+    /// Adds the access type to the permissions of the pml4, pdpt and pd entries
+    /// that lead up to the specified page.
+    fn widen_table_flags(&mut self, guest_pa: u64, access_type: AccessType) {
+        let widen = |entry: u64| AccessType::from_entry(entry).union(access_type);
+
+        let pml4_entry = &mut self.pml4[pml4_index(VAddr::from(guest_pa))];
+        if pml4_entry.is_present() {
+            *pml4_entry = PML4Entry::new(pml4_entry.address(), widen(pml4_entry.0).pml4_flags());
+        }
+
+        if let Some(pdpt_entry) = self
+            .pdpt_entry_mut(guest_pa)
+            .filter(|pdpt_entry| pdpt_entry.is_present() && !pdpt_entry.is_page())
+        {
+            *pdpt_entry = PDPTEntry::new(pdpt_entry.address(), widen(pdpt_entry.0).pdpt_flags());
+        }
+
+        if let Some(pd_entry) = self
+            .pd_entry_mut(guest_pa)
+            .filter(|pd_entry| pd_entry.is_present() && !pd_entry.is_page())
+        {
+            *pd_entry = PDEntry::new(pd_entry.address(), widen(pd_entry.0).pd_flags());
+        }
+    }
+
+    /// Should only be used for debugging
+    pub fn print_page_permission(&mut self, guest_pa: u64) {
+        let pd_entry = self.pd_entry_mut(guest_pa).copied();
+        let pt_entry = self.pt_entry_mut(guest_pa).copied();
+        log::info!(
+            "PDEntry: {:x?}, PTEntry: {:x?}, effective access type: {:?}",
+            pd_entry,
+            pt_entry,
+            self.effective_access_type(guest_pa)
+        );
+    }
+
+    pub fn last_pdp_index(&self) -> usize {
+        PhysicalMemoryDescriptor::new().total_size_in_gb() + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reverse_map::REVERSE_MAP_CAPACITY, *};
+
+    const _4KB: u64 = BASE_PAGE_SIZE as u64;
+
+    fn pd_entry(npt: &mut NestedPageTable, guest_pa: u64) -> PDEntry {
+        *npt.pd_entry_mut(guest_pa).unwrap()
+    }
+
+    /// A lazy nested page table for the physical memory of the mock platform
+    /// (the first 4GB).
+    fn lazy() -> Box<NestedPageTable> {
+        let mut npt = NestedPageTable::empty();
+        npt.has_1gb_pages = true;
+        npt.lazy = Some(LazyMapping {
+            access_type: AccessType::ReadWrite,
+            mtrrs: Mtrrs::disabled(),
+            memory: PhysicalMemoryDescriptor::new(),
+        });
+
+        npt
+    }
+
+    #[test]
+    fn map_4kb() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_4kb(0x1000, 0x1000, AccessType::ReadWrite).unwrap();
+        npt.map_4kb(0x3000, 0x5000, AccessType::ReadWriteExecute)
+            .unwrap();
+
+        assert_eq!(npt.translate(0x1234), Some(0x1234));
+        assert_eq!(npt.translate(0x3008), Some(0x5008));
+        assert_eq!(npt.translate(0x2000), None);
+        assert_eq!(
+            npt.effective_access_type(0x1000),
+            Some(AccessType::ReadWrite)
+        );
+        assert_eq!(npt.effective_access_type(0x2000), None);
+
+        // PDPT, PD and PT.
+        //
+        assert_eq!(npt.allocator().used_tables(), 3);
+        assert_eq!(npt.aliases_of(0x5000), [0x3000]);
+    }
+
+    #[test]
+    fn mapped_pages_are_not_replaced() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_4kb(0x1000, 0x1000, AccessType::ReadOnly).unwrap();
+        npt.map_4kb(0x1000, 0x2000, AccessType::ReadWrite).unwrap();
+
+        assert_eq!(npt.translate(0x1000), Some(0x1000));
+        assert_eq!(
+            npt.effective_access_type(0x1000),
+            Some(AccessType::ReadOnly)
+        );
+    }
+
+    #[test]
+    fn map_range_uses_large_pages() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_range(
+            _2MB as u64 - _4KB,
+            _2MB as u64 - _4KB,
+            _2MB as u64 + 2 * _4KB,
+            AccessType::ReadWriteExecute,
+        )
+        .unwrap();
+
+        assert!(!pd_entry(&mut npt, 0).is_page());
+        assert!(pd_entry(&mut npt, _2MB as u64).is_page());
+        assert!(!pd_entry(&mut npt, 2 * _2MB as u64).is_page());
+        assert_eq!(npt.translate(3 * _2MB as u64), None);
+        assert_eq!(npt.translate(2 * _2MB as u64), Some(2 * _2MB as u64));
+
+        assert!(matches!(
+            npt.map_range(0x800, 0x800, _4KB, AccessType::ReadWrite),
+            Err(NestedPageTableError::UnalignedRange { .. })
+        ));
+    }
+
+    #[test]
+    fn split_2mb_to_4kb() {
+        let mut npt = NestedPageTable::empty();
+        let (guest_pa, host_pa) = (_2MB as u64, 4 * _2MB as u64);
+        npt.map_range_with_pat_index(guest_pa, host_pa, _2MB as u64, AccessType::ReadWrite, 5)
+            .unwrap();
+
+        npt.split_2mb_to_4kb(guest_pa + 0x5000, AccessType::ReadOnly)
+            .unwrap();
+
+        assert!(!pd_entry(&mut npt, guest_pa).is_page());
+        for offset in [0, 0x5000, _2MB as u64 - _4KB] {
+            let pt_entry = *npt.pt_entry_mut(guest_pa + offset).unwrap();
+
+            assert_eq!(npt.translate(guest_pa + offset), Some(host_pa + offset));
+            assert_eq!(entry_pat_index(pt_entry.0, PT_PAT_BIT), 5);
+            assert_eq!(
+                npt.effective_access_type(guest_pa + offset),
+                Some(AccessType::ReadOnly)
+            );
+        }
+
+        // Splitting again doesn't change anything.
+        //
+        let used_tables = npt.allocator().used_tables();
+        npt.split_2mb_to_4kb(guest_pa, AccessType::ReadWrite)
+            .unwrap();
+        assert_eq!(npt.allocator().used_tables(), used_tables);
+    }
+
+    #[test]
+    fn split_1gb_to_2mb() {
+        let mut npt = NestedPageTable::empty();
+        if !npt.has_1gb_pages {
+            return;
+        }
+        npt.map_1gb(0, 0, AccessType::ReadWrite).unwrap();
+
+        npt.split_1gb_to_2mb(0x1234).unwrap();
+
+        assert!(!npt.pdpt_entry_mut(0).unwrap().is_page());
+        assert!(pd_entry(&mut npt, 0x20_0000).is_page());
+        assert_eq!(npt.translate(0x3FFF_FFFF), Some(0x3FFF_FFFF));
+        assert_eq!(
+            npt.effective_access_type(0x2000_0000),
+            Some(AccessType::ReadWrite)
+        );
+    }
+
+    #[test]
+    fn join_4kb_to_2mb() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_2mb(0, 0, AccessType::ReadWriteExecute).unwrap();
+        npt.split_2mb_to_4kb(0, AccessType::ReadWriteExecute)
+            .unwrap();
+        let used_tables = npt.allocator().used_tables();
+
+        npt.join_4kb_to_2mb(0x1000, AccessType::ReadWrite).unwrap();
+
+        assert!(pd_entry(&mut npt, 0).is_page());
+        assert_eq!(npt.translate(0x1F_FFFF), Some(0x1F_FFFF));
+        assert_eq!(npt.effective_access_type(0), Some(AccessType::ReadWrite));
+
+        // The page table is freed.
+        //
+        assert_eq!(npt.allocator().used_tables(), used_tables - 1);
+    }
+
+    #[test]
+    fn no_access_pages_are_mapped() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_4kb(0x1000, 0x1000, AccessType::ReadWrite).unwrap();
+        npt.change_page_flags(0x1000, AccessType::NoAccess).unwrap();
+
+        assert_eq!(npt.translate(0x1000), None);
+        assert_eq!(
+            npt.effective_access_type(0x1000),
+            Some(AccessType::NoAccess)
+        );
+
+        npt.change_page_flags(0x1000, AccessType::ReadOnly).unwrap();
+        assert_eq!(npt.translate(0x1000), Some(0x1000));
+    }
+
+    #[test]
+    fn change_flags_of_unaligned_page() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_4kb(0x1000, 0x1000, AccessType::ReadWrite).unwrap();
+
+        assert!(matches!(
+            npt.change_page_flags(0x1234, AccessType::ReadOnly),
+            Err(NestedPageTableError::UnalignedRange {
+                guest_pa: 0x1234,
+                ..
+            })
+        ));
+        assert_eq!(
+            npt.effective_access_type(0x1000),
+            Some(AccessType::ReadWrite)
+        );
+    }
+
+    #[test]
+    fn remap_page() {
+        let mut npt = NestedPageTable::empty();
+        npt.remap_page(0x1000, 0x5000, AccessType::ReadWrite)
+            .unwrap();
+
+        assert_eq!(npt.translate(0x1234), Some(0x5234));
+        assert_eq!(
+            npt.effective_access_type(0x1000),
+            Some(AccessType::ReadWrite)
+        );
+        assert_eq!(npt.aliases_of(0x5000), [0x1000]);
+    }
+
+    #[test]
+    fn remap_unaligned_page() {
+        let mut npt = NestedPageTable::empty();
+        assert!(matches!(
+            npt.remap_page(0x1234, 0x5000, AccessType::ReadWrite),
+            Err(NestedPageTableError::UnalignedRange { .. })
+        ));
+        assert!(matches!(
+            npt.remap_page(0x1000, 0x5234, AccessType::ReadWrite),
+            Err(NestedPageTableError::UnalignedRange { .. })
+        ));
+        assert_eq!(npt.translate(0x1000), None);
+    }
+
+    #[test]
+    fn remap_with_full_reverse_map() {
+        let mut npt = NestedPageTable::empty();
+        for i in 0..REVERSE_MAP_CAPACITY as u64 {
+            npt.remap_page(i * _4KB, 0x1000_0000 + i * _4KB, AccessType::ReadWrite)
+                .unwrap();
+        }
+
+        assert!(matches!(
+            npt.remap_page(0x20_0000, 0x2000_0000, AccessType::ReadWrite),
+            Err(NestedPageTableError::ReverseMapFull { .. })
+        ));
+        assert_eq!(npt.translate(0x20_0000), None);
+
+        // Identity mappings don't need any space.
+        //
+        npt.remap_page(0x20_0000, 0x20_0000, AccessType::ReadWrite)
+            .unwrap();
+        assert_eq!(npt.translate(0x20_0000), Some(0x20_0000));
+    }
+
+    #[test]
+    fn map_memory_on_demand() {
+        let mut npt = lazy();
+        npt.map_on_demand(0x1234).unwrap();
+
+        assert_eq!(npt.translate(0x1234), Some(0x1234));
+        assert_eq!(npt.translate(0x2000), None);
+        assert!(!pd_entry(&mut npt, 0x1000).is_page());
+    }
+
+    #[test]
+    fn map_device_memory_on_demand() {
+        let mut npt = lazy();
+        let used_tables = npt.allocator().used_tables();
+
+        // Nothing else is mapped in this 512GB range, so a 1GB page is used.
+        //
+        npt.map_on_demand(0x100_0000_1000).unwrap();
+        assert!(npt.pdpt_entry_mut(0x100_0000_0000).unwrap().is_page());
+        assert_eq!(npt.translate(0x100_3fff_f000), Some(0x100_3fff_f000));
+        assert_eq!(npt.allocator().used_tables(), used_tables + 1);
+
+        // The 1GB range is already partially mapped, but the 2MB range isn't.
+        //
+        npt.map_4kb(0x200_0000_0000, 0x200_0000_0000, AccessType::ReadWrite)
+            .unwrap();
+        npt.map_on_demand(0x200_0020_1000).unwrap();
+        assert!(pd_entry(&mut npt, 0x200_0020_0000).is_page());
+        assert_eq!(npt.translate(0x200_003f_f000), Some(0x200_003f_f000));
+
+        // Both large pages are already partially mapped.
+        //
+        npt.map_on_demand(0x200_0000_1000).unwrap();
+        assert!(!pd_entry(&mut npt, 0x200_0000_0000).is_page());
+        assert_eq!(npt.translate(0x200_0000_1000), Some(0x200_0000_1000));
+        assert_eq!(npt.translate(0x200_0000_2000), None);
+    }
+}
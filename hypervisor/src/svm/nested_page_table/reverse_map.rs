@@ -0,0 +1,188 @@
+//! Tracks which guest physical addresses map a host physical address.
+//!
+//! Only the ranges that are mapped to another host physical address than
+//! their own are stored (e.g. the shadow pages of hooks). There are far too
+//! many identity mappings to store them, so they are found by translating the
+//! host physical address instead.
+//!
+//! The ranges don't depend on the page size, so splitting and joining pages
+//! doesn't change them.
+//!
+//! The ranges are stored in an array of a fixed size, because pages are also
+//! remapped in the host context (like the shadow pages in the nested page
+//! fault handler), where no memory can be allocated.
+
+use crate::svm::nested_page_table::{NestedPageTableError, ReverseMapFullSnafu};
+use snafu::prelude::*;
+use tinyvec::ArrayVec;
+
+/// The maximum number of ranges that can be stored.
+pub const REVERSE_MAP_CAPACITY: usize = 512;
+
+/// A guest physical range that is mapped to another host physical range.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct AliasRange {
+    pub guest_pa: u64,
+    pub host_pa: u64,
+    pub size: u64,
+}
+
+impl AliasRange {
+    fn guest_end(&self) -> u64 {
+        self.guest_pa + self.size
+    }
+
+    fn contains_host(&self, host_pa: u64) -> bool {
+        (self.host_pa..self.host_pa + self.size).contains(&host_pa)
+    }
+
+    fn overlaps(&self, guest_pa: u64, end: u64) -> bool {
+        guest_pa < self.guest_end() && self.guest_pa < end
+    }
+}
+
+pub struct ReverseMap {
+    ranges: ArrayVec<[AliasRange; REVERSE_MAP_CAPACITY]>,
+}
+
+impl ReverseMap {
+    pub fn new() -> Self {
+        Self {
+            ranges: ArrayVec::new(),
+        }
+    }
+
+    /// Records that the guest physical range now maps the host physical range.
+    ///
+    /// Returns [`NestedPageTableError::ReverseMapFull`] if the range can't be
+    /// stored. Nothing is changed in this case.
+    pub fn insert(
+        &mut self, guest_pa: u64, host_pa: u64, size: u64,
+    ) -> Result<(), NestedPageTableError> {
+        let len = self.len_after_remove(guest_pa, size) + (guest_pa != host_pa) as usize;
+        ensure!(
+            len <= REVERSE_MAP_CAPACITY,
+            ReverseMapFullSnafu {
+                capacity: REVERSE_MAP_CAPACITY
+            }
+        );
+
+        self.remove_unchecked(guest_pa, size);
+        if guest_pa != host_pa {
+            self.ranges.push(AliasRange {
+                guest_pa,
+                host_pa,
+                size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the mappings of the guest physical range. Ranges that are only
+    /// partially covered are trimmed.
+    ///
+    /// Trimming a range in the middle splits it in two, so this can fail with
+    /// [`NestedPageTableError::ReverseMapFull`] as well.
+    pub fn remove(&mut self, guest_pa: u64, size: u64) -> Result<(), NestedPageTableError> {
+        ensure!(
+            self.len_after_remove(guest_pa, size) <= REVERSE_MAP_CAPACITY,
+            ReverseMapFullSnafu {
+                capacity: REVERSE_MAP_CAPACITY
+            }
+        );
+
+        self.remove_unchecked(guest_pa, size);
+
+        Ok(())
+    }
+
+    /// Returns the number of ranges after removing the guest physical range.
+    fn len_after_remove(&self, guest_pa: u64, size: u64) -> usize {
+        let end = guest_pa + size;
+
+        self.ranges
+            .iter()
+            .map(|range| {
+                if range.overlaps(guest_pa, end) {
+                    (range.guest_pa < guest_pa) as usize + (end < range.guest_end()) as usize
+                } else {
+                    1
+                }
+            })
+            .sum()
+    }
+
+    /// See [`ReverseMap::remove`]. The caller has to make sure that there's
+    /// enough space.
+    fn remove_unchecked(&mut self, guest_pa: u64, size: u64) {
+        let end = guest_pa + size;
+
+        let mut i = 0;
+        while i < self.ranges.len() {
+            let range = self.ranges[i];
+            if !range.overlaps(guest_pa, end) {
+                i += 1;
+                continue;
+            }
+
+            self.ranges.swap_remove(i);
+
+            // Keep the parts before and after the removed range.
+            //
+            if range.guest_pa < guest_pa {
+                self.ranges.push(AliasRange {
+                    size: guest_pa - range.guest_pa,
+                    ..range
+                });
+            }
+            if end < range.guest_end() {
+                self.ranges.push(AliasRange {
+                    guest_pa: end,
+                    host_pa: range.host_pa + (end - range.guest_pa),
+                    size: range.guest_end() - end,
+                });
+            }
+        }
+    }
+
+    /// Returns the guest physical addresses that are remapped to the specified
+    /// host physical address. The identity mapping is not included.
+    pub fn remapped_to(&self, host_pa: u64) -> impl Iterator<Item = u64> + '_ {
+        self.ranges
+            .iter()
+            .filter(move |range| range.contains_host(host_pa))
+            .map(move |range| range.guest_pa + (host_pa - range.host_pa))
+    }
+
+    /// Returns all the guest physical ranges that are mapped to another host
+    /// physical range, in no particular order.
+    pub fn ranges(&self) -> &[AliasRange] {
+        &self.ranges
+    }
+}
+
+impl Default for ReverseMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Host physical memory that belongs to the hypervisor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OwnedRange {
+    pub host_pa: u64,
+    pub size: u64,
+
+    /// The guest physical address that is expected to map the start of the
+    /// range, like the original page of a shadow page. Every other mapping is
+    /// unexpected, including the identity mapping.
+    pub expected_guest_pa: Option<u64>,
+}
+
+/// A guest physical page that unexpectedly maps memory of the hypervisor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnexpectedAlias {
+    pub guest_pa: u64,
+    pub host_pa: u64,
+}
@@ -0,0 +1,646 @@
+//! A coalesced view of the mappings of a [`NestedPageTable`], which can be
+//! saved, printed and compared with another one.
+//!
+//! Adjacent pages are merged into a single [`MappedRange`] if their host
+//! physical addresses are contiguous as well, and they have the same page
+//! size, effective access type and memory type. The view only depends on the
+//! entries of the table, so it can also be used in host-side tests with the
+//! mock platform.
+//!
+//! ## Snapshot format
+//!
+//! All values are stored as little-endian.
+//!
+//! ```text
+//! +--------------------+
+//! | magic (u32)        |  "NPTS"
+//! | version (u32)      |
+//! | range count (u32)  |
+//! +--------------------+
+//! | range 0            |  RANGE_SIZE bytes
+//! | ...                |
+//! +--------------------+
+//! ```
+//!
+//! Every range consists of the guest physical address, the host physical
+//! address, the size, the page size, the access type and the memory type,
+//! each stored as u64. The ranges are sorted by their guest physical address.
+
+use crate::svm::{
+    nested_page_table::{
+        entry_pat_index, large_page_address_mask, table, NestedPageTable, LARGE_PAGE_PAT_BIT,
+        PT_PAT_BIT,
+    },
+    utils::{
+        mtrr::{self, MemoryType},
+        paging::{AccessType, PFN_MASK, _1GB, _2MB, _512GB},
+    },
+};
+use alloc::vec::Vec;
+use core::fmt;
+use x86::bits64::paging::{BASE_PAGE_SIZE, PD, PDPT, PT};
+
+pub const SNAPSHOT_MAGIC: u32 = u32::from_le_bytes(*b"NPTS");
+pub const SNAPSHOT_VERSION: u32 = 1;
+pub const SNAPSHOT_HEADER_SIZE: usize = 3 * core::mem::size_of::<u32>();
+
+/// The size of a single serialized [`MappedRange`].
+pub const RANGE_SIZE: usize = 6 * core::mem::size_of::<u64>();
+
+/// Guest physical memory that is mapped to contiguous host physical memory by
+/// pages of the same kind.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MappedRange {
+    pub guest_pa: u64,
+    pub host_pa: u64,
+    pub size: u64,
+    pub page_size: u64,
+
+    /// The intersection of the permissions of all levels, see
+    /// [`NestedPageTable::effective_access_type`].
+    pub access_type: AccessType,
+    pub memory_type: MemoryType,
+}
+
+impl MappedRange {
+    pub fn guest_end(&self) -> u64 {
+        self.guest_pa + self.size
+    }
+
+    pub fn contains(&self, guest_pa: u64) -> bool {
+        (self.guest_pa..self.guest_end()).contains(&guest_pa)
+    }
+
+    /// Returns whether the other range starts right after this one and can be
+    /// merged into it.
+    fn is_continued_by(&self, other: &Self) -> bool {
+        self.guest_end() == other.guest_pa
+            && self.host_pa + self.size == other.host_pa
+            && self.page_size == other.page_size
+            && self.access_type == other.access_type
+            && self.memory_type == other.memory_type
+    }
+
+    /// Returns the part of the range that starts at the specified guest
+    /// physical address.
+    fn slice(&self, guest_pa: u64, size: u64) -> Self {
+        Self {
+            guest_pa,
+            host_pa: self.host_pa + (guest_pa - self.guest_pa),
+            size,
+            ..*self
+        }
+    }
+
+    fn access_type_to_raw(access_type: AccessType) -> u64 {
+        match access_type {
+            AccessType::NoAccess => 0,
+            AccessType::ReadOnly => 1,
+            AccessType::ReadExecute => 2,
+            AccessType::ReadWrite => 3,
+            AccessType::ReadWriteExecute => 4,
+        }
+    }
+
+    fn access_type_from_raw(raw: u64) -> Option<AccessType> {
+        match raw {
+            0 => Some(AccessType::NoAccess),
+            1 => Some(AccessType::ReadOnly),
+            2 => Some(AccessType::ReadExecute),
+            3 => Some(AccessType::ReadWrite),
+            4 => Some(AccessType::ReadWriteExecute),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RANGE_SIZE] {
+        let mut bytes = [0u8; RANGE_SIZE];
+
+        let words = [
+            self.guest_pa,
+            self.host_pa,
+            self.size,
+            self.page_size,
+            Self::access_type_to_raw(self.access_type),
+            self.memory_type as u64,
+        ];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    /// Parses a range that has been serialized with
+    /// [`MappedRange::to_bytes`]. Returns `None` if the range is malformed,
+    /// empty or doesn't fit into the address space.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RANGE_SIZE {
+            return None;
+        }
+
+        let mut chunks = bytes.chunks_exact(core::mem::size_of::<u64>());
+        let mut words = || {
+            chunks
+                .next()
+                .map(|chunk| {
+                    let mut word = [0u8; 8];
+                    word.copy_from_slice(chunk);
+                    u64::from_le_bytes(word)
+                })
+                .unwrap_or_default()
+        };
+
+        let range = Self {
+            guest_pa: words(),
+            host_pa: words(),
+            size: words(),
+            page_size: words(),
+            access_type: Self::access_type_from_raw(words())?,
+            memory_type: u8::try_from(words()).ok().and_then(MemoryType::from_raw)?,
+        };
+
+        // The end of the range is calculated without checks afterwards.
+        //
+        if range.size == 0
+            || range.guest_pa.checked_add(range.size).is_none()
+            || range.host_pa.checked_add(range.size).is_none()
+        {
+            return None;
+        }
+
+        Some(range)
+    }
+}
+
+impl fmt::Display for MappedRange {
+    /// Prints the range as a single line, for example:
+    ///
+    /// ```text
+    /// 0x00000000000000-0x00000040000000 -> 0x00000000000000 1G rwx WriteBack
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let page_size = match self.page_size {
+            size if size == _1GB => "1G",
+            size if size == _2MB as u64 => "2M",
+            size if size == BASE_PAGE_SIZE as u64 => "4K",
+            _ => "??",
+        };
+        let permission = |allowed: bool, c: char| if allowed { c } else { '-' };
+
+        write!(
+            f,
+            "{:#016x}-{:#016x} -> {:#016x} {} {}{}{} {:?}",
+            self.guest_pa,
+            self.guest_end(),
+            self.host_pa,
+            page_size,
+            permission(self.access_type.is_readable(), 'r'),
+            permission(self.access_type.is_writable(), 'w'),
+            permission(self.access_type.is_executable(), 'x'),
+            self.memory_type
+        )
+    }
+}
+
+/// A single page of the nested page table.
+struct Page {
+    guest_pa: u64,
+    entry: u64,
+    size: u64,
+    access_type: AccessType,
+}
+
+impl Page {
+    fn to_range(&self, pat: u64) -> MappedRange {
+        let (address_mask, pat_bit) = if self.size == BASE_PAGE_SIZE as u64 {
+            (PFN_MASK, PT_PAT_BIT)
+        } else {
+            (large_page_address_mask(self.size), LARGE_PAGE_PAT_BIT)
+        };
+
+        MappedRange {
+            guest_pa: self.guest_pa,
+            host_pa: self.entry & address_mask,
+            size: self.size,
+            page_size: self.size,
+            access_type: self.access_type,
+            memory_type: mtrr::pat_memory_type(pat, entry_pat_index(self.entry, pat_bit)),
+        }
+    }
+}
+
+/// Calls the closure for every page that is mapped, including the ones
+/// without any access, in ascending order of their guest physical addresses.
+fn for_each_page(npt: &NestedPageTable, mut callback: impl FnMut(Page)) {
+    for (pml4_index, pml4_entry) in npt.pml4.iter().enumerate() {
+        if !pml4_entry.is_present() {
+            continue;
+        }
+        let pml4_access_type = AccessType::from_entry(pml4_entry.0);
+        let pml4_base = pml4_index as u64 * _512GB;
+
+        let pdpt: &PDPT = unsafe { table(pml4_entry.address()) };
+        for (pdpt_index, pdpt_entry) in pdpt.iter().enumerate() {
+            if pdpt_entry.0 == 0 {
+                continue;
+            }
+            let pdpt_access_type =
+                pml4_access_type.intersection(AccessType::from_entry(pdpt_entry.0));
+            let pdpt_base = pml4_base + pdpt_index as u64 * _1GB;
+
+            if pdpt_entry.is_page() {
+                callback(Page {
+                    guest_pa: pdpt_base,
+                    entry: pdpt_entry.0,
+                    size: _1GB,
+                    access_type: pdpt_access_type,
+                });
+                continue;
+            }
+
+            let pd: &PD = unsafe { table(pdpt_entry.address()) };
+            for (pd_index, pd_entry) in pd.iter().enumerate() {
+                if pd_entry.0 == 0 {
+                    continue;
+                }
+                let pd_access_type =
+                    pdpt_access_type.intersection(AccessType::from_entry(pd_entry.0));
+                let pd_base = pdpt_base + (pd_index * _2MB) as u64;
+
+                if pd_entry.is_page() {
+                    callback(Page {
+                        guest_pa: pd_base,
+                        entry: pd_entry.0,
+                        size: _2MB as u64,
+                        access_type: pd_access_type,
+                    });
+                    continue;
+                }
+
+                let pt: &PT = unsafe { table(pd_entry.address()) };
+                for (pt_index, pt_entry) in pt.iter().enumerate() {
+                    if pt_entry.0 == 0 {
+                        continue;
+                    }
+
+                    callback(Page {
+                        guest_pa: pd_base + (pt_index * BASE_PAGE_SIZE) as u64,
+                        entry: pt_entry.0,
+                        size: BASE_PAGE_SIZE as u64,
+                        access_type: pd_access_type
+                            .intersection(AccessType::from_entry(pt_entry.0)),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// The mappings of a nested page table at a point in time.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NptSnapshot {
+    /// The mapped ranges, sorted by their guest physical address.
+    pub ranges: Vec<MappedRange>,
+}
+
+impl NptSnapshot {
+    /// Walks the nested page table and coalesces its pages into ranges.
+    ///
+    /// The PAT is needed to resolve the memory types of the pages. Use
+    /// [`mtrr::host_pat`] in the hypervisor and [`mtrr::DEFAULT_PAT`] where it
+    /// can't be read.
+    pub fn new(npt: &NestedPageTable, pat: u64) -> Self {
+        let mut ranges: Vec<MappedRange> = Vec::new();
+
+        for_each_page(npt, |page| {
+            let range = page.to_range(pat);
+
+            match ranges.last_mut() {
+                Some(last) if last.is_continued_by(&range) => last.size += range.size,
+                _ => ranges.push(range),
+            }
+        });
+
+        Self { ranges }
+    }
+
+    /// Returns the range that maps the specified guest physical address.
+    pub fn find(&self, guest_pa: u64) -> Option<&MappedRange> {
+        let index = self
+            .ranges
+            .partition_point(|range| range.guest_end() <= guest_pa);
+
+        self.ranges
+            .get(index)
+            .filter(|range| range.contains(guest_pa))
+    }
+
+    /// Serializes the ranges into the snapshot format described in the module
+    /// documentation.
+    pub fn export(&self) -> Vec<u8> {
+        let mut snapshot =
+            Vec::with_capacity(SNAPSHOT_HEADER_SIZE + self.ranges.len() * RANGE_SIZE);
+        snapshot.extend_from_slice(&SNAPSHOT_MAGIC.to_le_bytes());
+        snapshot.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        snapshot.extend_from_slice(&(self.ranges.len() as u32).to_le_bytes());
+
+        for range in &self.ranges {
+            snapshot.extend_from_slice(&range.to_bytes());
+        }
+
+        snapshot
+    }
+
+    /// Parses a snapshot that has been created with [`NptSnapshot::export`].
+    /// Returns `None` if it's malformed, truncated, has trailing bytes or the
+    /// ranges are not sorted.
+    pub fn parse(snapshot: &[u8]) -> Option<Self> {
+        if snapshot.len() < SNAPSHOT_HEADER_SIZE {
+            return None;
+        }
+
+        let field = |index: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&snapshot[index * 4..index * 4 + 4]);
+            u32::from_le_bytes(bytes)
+        };
+
+        if field(0) != SNAPSHOT_MAGIC || field(1) != SNAPSHOT_VERSION {
+            return None;
+        }
+
+        let size = (field(2) as usize)
+            .checked_mul(RANGE_SIZE)?
+            .checked_add(SNAPSHOT_HEADER_SIZE)?;
+        if snapshot.len() != size {
+            return None;
+        }
+
+        let ranges = snapshot[SNAPSHOT_HEADER_SIZE..]
+            .chunks_exact(RANGE_SIZE)
+            .map(MappedRange::from_bytes)
+            .collect::<Option<Vec<_>>>()?;
+
+        if ranges
+            .windows(2)
+            .any(|ranges| ranges[0].guest_end() > ranges[1].guest_pa)
+        {
+            return None;
+        }
+
+        Some(Self { ranges })
+    }
+
+    /// Returns the guest physical ranges whose mapping is different in the
+    /// other snapshot, sorted by their guest physical address.
+    pub fn diff(&self, other: &Self) -> Vec<RangeChange> {
+        // The mappings of both snapshots don't change between two boundaries.
+        //
+        let mut boundaries = self
+            .ranges
+            .iter()
+            .chain(&other.ranges)
+            .flat_map(|range| [range.guest_pa, range.guest_end()])
+            .collect::<Vec<_>>();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut changes: Vec<RangeChange> = Vec::new();
+        for window in boundaries.windows(2) {
+            let (start, size) = (window[0], window[1] - window[0]);
+            let slice =
+                |snapshot: &Self| snapshot.find(start).map(|range| range.slice(start, size));
+
+            let (old, new) = (slice(self), slice(other));
+            if old == new {
+                continue;
+            }
+
+            let change = RangeChange {
+                guest_pa: start,
+                size,
+                old,
+                new,
+            };
+            match changes.last_mut() {
+                Some(last) if last.is_continued_by(&change) => last.extend(size),
+                _ => changes.push(change),
+            }
+        }
+
+        changes
+    }
+}
+
+impl fmt::Display for NptSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for range in &self.ranges {
+            writeln!(f, "{}", range)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A guest physical range that is mapped differently in two snapshots. `None`
+/// means that the range is not mapped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RangeChange {
+    pub guest_pa: u64,
+    pub size: u64,
+    pub old: Option<MappedRange>,
+    pub new: Option<MappedRange>,
+}
+
+impl RangeChange {
+    fn is_continued_by(&self, other: &Self) -> bool {
+        let continues =
+            |range: &Option<MappedRange>, next: &Option<MappedRange>| match (range, next) {
+                (None, None) => true,
+                (Some(range), Some(next)) => range.is_continued_by(next),
+                _ => false,
+            };
+
+        self.guest_pa + self.size == other.guest_pa
+            && continues(&self.old, &other.old)
+            && continues(&self.new, &other.new)
+    }
+
+    fn extend(&mut self, size: u64) {
+        self.size += size;
+
+        for range in [&mut self.old, &mut self.new].into_iter().flatten() {
+            range.size += size;
+        }
+    }
+}
+
+impl fmt::Display for RangeChange {
+    /// Prints the old mapping prefixed with `-` and the new one prefixed with
+    /// `+`, like a unified diff.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(old) = &self.old {
+            writeln!(f, "- {}", old)?;
+        }
+        if let Some(new) = &self.new {
+            writeln!(f, "+ {}", new)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const _4KB: u64 = BASE_PAGE_SIZE as u64;
+
+    fn snapshot(npt: &NestedPageTable) -> NptSnapshot {
+        NptSnapshot::new(npt, mtrr::DEFAULT_PAT)
+    }
+
+    fn range(guest_pa: u64, host_pa: u64, size: u64, access_type: AccessType) -> MappedRange {
+        MappedRange {
+            guest_pa,
+            host_pa,
+            size,
+            page_size: _4KB,
+            access_type,
+            memory_type: MemoryType::WriteBack,
+        }
+    }
+
+    #[test]
+    fn contiguous_pages_are_merged() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_range(0x1000, 0x1000, 3 * _4KB, AccessType::ReadWrite)
+            .unwrap();
+        npt.map_4kb(0x4000, 0x8000, AccessType::ReadWrite).unwrap();
+        npt.map_4kb(0x5000, 0x9000, AccessType::ReadOnly).unwrap();
+
+        assert_eq!(
+            snapshot(&npt).ranges,
+            [
+                range(0x1000, 0x1000, 3 * _4KB, AccessType::ReadWrite),
+                range(0x4000, 0x8000, _4KB, AccessType::ReadWrite),
+                range(0x5000, 0x9000, _4KB, AccessType::ReadOnly),
+            ]
+        );
+        assert_eq!(snapshot(&npt).find(0x2345), Some(&snapshot(&npt).ranges[0]));
+        assert_eq!(snapshot(&npt).find(0x6000), None);
+    }
+
+    #[test]
+    fn export_and_parse() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_range(0, 0, 2 * _2MB as u64, AccessType::ReadWriteExecute)
+            .unwrap();
+        npt.map_4kb(0x4000_0000, 0x1000, AccessType::ReadOnly)
+            .unwrap();
+
+        let snapshot = snapshot(&npt);
+        let exported = snapshot.export();
+        assert_eq!(
+            exported.len(),
+            SNAPSHOT_HEADER_SIZE + snapshot.ranges.len() * RANGE_SIZE
+        );
+        assert_eq!(NptSnapshot::parse(&exported), Some(snapshot));
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let snapshot = NptSnapshot {
+            ranges: vec![
+                range(0x1000, 0x1000, _4KB, AccessType::ReadWrite),
+                range(0x2000, 0x5000, _4KB, AccessType::ReadOnly),
+            ],
+        };
+        let exported = snapshot.export();
+
+        // Truncated and trailing bytes.
+        //
+        assert_eq!(NptSnapshot::parse(&exported[..exported.len() - 1]), None);
+        assert_eq!(
+            NptSnapshot::parse(&exported[..SNAPSHOT_HEADER_SIZE - 1]),
+            None
+        );
+        let mut trailing = exported.clone();
+        trailing.push(0);
+        assert_eq!(NptSnapshot::parse(&trailing), None);
+
+        // Invalid magic and a range count that overflows.
+        //
+        let mut invalid = exported.clone();
+        invalid[0] ^= 1;
+        assert_eq!(NptSnapshot::parse(&invalid), None);
+        let mut invalid = exported.clone();
+        invalid[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(NptSnapshot::parse(&invalid), None);
+
+        // Unsorted ranges.
+        //
+        let unsorted = NptSnapshot {
+            ranges: snapshot.ranges.iter().rev().copied().collect(),
+        };
+        assert_eq!(NptSnapshot::parse(&unsorted.export()), None);
+    }
+
+    #[test]
+    fn parse_invalid_ranges() {
+        let parse = |range: MappedRange| {
+            NptSnapshot::parse(
+                &NptSnapshot {
+                    ranges: vec![range],
+                }
+                .export(),
+            )
+        };
+
+        assert!(parse(range(0x1000, 0x1000, _4KB, AccessType::ReadWrite)).is_some());
+        assert_eq!(parse(range(0x1000, 0x1000, 0, AccessType::ReadWrite)), None);
+        assert_eq!(
+            parse(range(u64::MAX - 0xFFF, 0x1000, _4KB, AccessType::ReadWrite)),
+            None
+        );
+        assert_eq!(
+            parse(range(0x1000, u64::MAX - 0xFFF, _4KB, AccessType::ReadWrite)),
+            None
+        );
+    }
+
+    #[test]
+    fn diff() {
+        let mut npt = NestedPageTable::empty();
+        npt.map_range(0x1000, 0x1000, 4 * _4KB, AccessType::ReadWrite)
+            .unwrap();
+        let old = snapshot(&npt);
+
+        npt.change_page_flags(0x2000, AccessType::ReadOnly).unwrap();
+        npt.map_4kb(0x8000, 0x8000, AccessType::ReadWrite).unwrap();
+        let new = snapshot(&npt);
+
+        assert_eq!(old.diff(&old), []);
+        assert_eq!(
+            old.diff(&new),
+            [
+                RangeChange {
+                    guest_pa: 0x2000,
+                    size: _4KB,
+                    old: Some(range(0x2000, 0x2000, _4KB, AccessType::ReadWrite)),
+                    new: Some(range(0x2000, 0x2000, _4KB, AccessType::ReadOnly)),
+                },
+                RangeChange {
+                    guest_pa: 0x8000,
+                    size: _4KB,
+                    old: None,
+                    new: Some(range(0x8000, 0x8000, _4KB, AccessType::ReadWrite)),
+                },
+            ]
+        );
+
+        let parsed = NptSnapshot::parse(&new.export()).unwrap();
+        assert_eq!(old.diff(&parsed), old.diff(&new));
+    }
+}
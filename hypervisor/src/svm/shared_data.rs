@@ -1,59 +1,113 @@
 use crate::{
-    svm::{msr_bitmap::MsrBitmap, nested_page_table::NestedPageTable},
+    svm::{
+        io_bitmap::IoBitmap,
+        msr_bitmap::MsrBitmap,
+        nested_page_table::NestedPageTable,
+        tlb::{AsidAllocator, PRIMARY_ASID},
+        vmexit::{SharedHandlers, VmExitHandlers},
+    },
     utils::{addresses::PhysicalAddress, alloc::PhysicalAllocator},
 };
 use alloc::boxed::Box;
+use core::any::Any;
+use spin::{Mutex, MutexGuard};
 use x86::msr::IA32_EFER;
 
 #[repr(C)]
 pub struct SharedData {
     pub msr_bitmap: Box<MsrBitmap, PhysicalAllocator>,
+    pub io_bitmap: Box<IoBitmap, PhysicalAllocator>,
 
-    pub primary_npt: Box<NestedPageTable>,
+    /// The vmexit handlers of this hypervisor instance.
+    pub handlers: SharedHandlers,
+
+    /// Custom data that can be accessed by the vmexit handlers. See
+    /// [`VcpuData::custom_data`](crate::svm::vcpu_data::VcpuData::custom_data).
+    pub data: Option<Box<dyn Any + Send + Sync>>,
+
+    /// Assigns the ASIDs of the nested page tables.
+    pub asids: AsidAllocator,
+
+    /// The nested page tables are modified by the nested page fault handlers of
+    /// all processors (e.g. to map pages on demand), so they are locked.
+    pub primary_npt: Mutex<Box<NestedPageTable>>,
     pub primary_pml4: PhysicalAddress,
+    pub primary_asid: u32,
 
     #[cfg(feature = "secondary-npt")]
-    pub secondary_npt: Box<NestedPageTable>,
+    pub secondary_npt: Mutex<Box<NestedPageTable>>,
     #[cfg(feature = "secondary-npt")]
     pub secondary_pml4: PhysicalAddress,
+    #[cfg(feature = "secondary-npt")]
+    pub secondary_asid: u32,
 }
 
 impl SharedData {
     #[cfg(feature = "secondary-npt")]
     pub fn new(
-        primary_npt: Box<NestedPageTable>, secondary_npt: Box<NestedPageTable>,
+        handlers: VmExitHandlers, primary_npt: Box<NestedPageTable>,
+        secondary_npt: Box<NestedPageTable>,
     ) -> Option<Box<Self>> {
         let primary_pml4 = PhysicalAddress::from_va(primary_npt.pml4.as_ptr() as u64);
         let secondary_pml4 = PhysicalAddress::from_va(secondary_npt.pml4.as_ptr() as u64);
 
+        // The secondary nested page table shares the ASID with the primary one if
+        // there's none left, which requires a flush on every switch.
+        //
+        let asids = AsidAllocator::new();
+        let secondary_asid = asids.allocate().unwrap_or(PRIMARY_ASID);
+
         Some(Box::new(Self {
             msr_bitmap: {
                 let mut bitmap = MsrBitmap::new();
-                bitmap.hook_msr(IA32_EFER);
+                bitmap.hook_msr(IA32_EFER).ok()?;
                 bitmap
             },
+            io_bitmap: IoBitmap::new(),
+            handlers: SharedHandlers::new(handlers),
+            data: None,
+            asids,
 
-            primary_npt,
+            primary_npt: Mutex::new(primary_npt),
             primary_pml4,
+            primary_asid: PRIMARY_ASID,
 
-            secondary_npt,
+            secondary_npt: Mutex::new(secondary_npt),
             secondary_pml4,
+            secondary_asid,
         }))
     }
 
     #[cfg(not(feature = "secondary-npt"))]
-    pub fn new(primary_npt: Box<NestedPageTable>) -> Option<Box<Self>> {
+    pub fn new(handlers: VmExitHandlers, primary_npt: Box<NestedPageTable>) -> Option<Box<Self>> {
         let primary_pml4 = PhysicalAddress::from_va(primary_npt.pml4.as_ptr() as u64);
 
         Some(Box::new(Self {
             msr_bitmap: {
                 let mut bitmap = MsrBitmap::new();
-                bitmap.hook_msr(IA32_EFER);
+                bitmap.hook_msr(IA32_EFER).ok()?;
                 bitmap
             },
+            io_bitmap: IoBitmap::new(),
+            handlers: SharedHandlers::new(handlers),
+            data: None,
+            asids: AsidAllocator::new(),
 
-            primary_npt,
+            primary_npt: Mutex::new(primary_npt),
             primary_pml4,
+            primary_asid: PRIMARY_ASID,
         }))
     }
+
+    /// Locks the nested page table with the specified physical address, which
+    /// is the one that is currently used if `ncr3` is passed.
+    #[cfg_attr(not(feature = "secondary-npt"), allow(unused_variables))]
+    pub fn active_npt(&self, ncr3: u64) -> MutexGuard<'_, Box<NestedPageTable>> {
+        #[cfg(feature = "secondary-npt")]
+        if ncr3 == self.secondary_pml4.as_u64() {
+            return self.secondary_npt.lock();
+        }
+
+        self.primary_npt.lock()
+    }
 }
@@ -0,0 +1,139 @@
+//! Address space ids and TLB flushes. See `15.16 TLB Control`.
+//!
+//! The TLB entries of the guest are tagged with the ASID of the vmcb, so
+//! every nested page table (view) can have its own ASID. Switching between
+//! views with different ASIDs doesn't require a flush, as long as the
+//! translations of the view haven't been modified.
+
+use crate::svm::{vcpu_data::VcpuData, vmcb::control_area::TlbControl};
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86::cpuid::CpuId;
+
+/// The ASID of the primary nested page table. ASID 0 is reserved for the host,
+/// and 1 is always supported.
+pub const PRIMARY_ASID: u32 = 1;
+
+/// See `CPUID Fn8000_000A_EBX SVM Revision and Feature Identification` and
+/// `CPUID Fn8000_000A_EDX SVM Feature Identification`.
+#[derive(Debug, Copy, Clone)]
+pub struct TlbCapabilities {
+    /// The number of ASIDs, including the one of the host.
+    pub asid_count: u32,
+
+    /// The TLB can be flushed for a single ASID (`FlushByAsid`). Otherwise,
+    /// the whole TLB has to be flushed.
+    pub flush_by_asid: bool,
+}
+
+impl TlbCapabilities {
+    pub fn detect() -> Self {
+        let svm_info = CpuId::new().get_svm_info();
+
+        Self {
+            asid_count: svm_info
+                .as_ref()
+                .map(|info| info.supported_asids())
+                .unwrap_or_default(),
+            flush_by_asid: svm_info
+                .as_ref()
+                .map(|info| info.has_flush_by_asid())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Assigns the ASIDs of the views.
+pub struct AsidAllocator {
+    capabilities: TlbCapabilities,
+    next: AtomicU32,
+}
+
+impl AsidAllocator {
+    pub fn new() -> Self {
+        let capabilities = TlbCapabilities::detect();
+        log::info!("Tlb capabilities: {:?}", capabilities);
+
+        Self {
+            capabilities,
+            next: AtomicU32::new(PRIMARY_ASID + 1),
+        }
+    }
+
+    pub fn capabilities(&self) -> TlbCapabilities {
+        self.capabilities
+    }
+
+    /// Returns a new ASID, or `None` if all of them are in use. Views without
+    /// their own ASID have to share [`PRIMARY_ASID`].
+    pub fn allocate(&self) -> Option<u32> {
+        let asid = self.next.fetch_add(1, Ordering::Relaxed);
+        if asid < self.capabilities.asid_count {
+            Some(asid)
+        } else {
+            log::warn!("No ASID left (supported: {})", self.capabilities.asid_count);
+            None
+        }
+    }
+}
+
+impl Default for AsidAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The TLB entries to flush on the next `vmrun`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlushScope {
+    /// All entries of the current ASID.
+    Guest,
+
+    /// The non-global entries of the current ASID.
+    GuestNonGlobal,
+
+    /// All entries of all ASIDs.
+    All,
+}
+
+/// Flushes the TLB entries of the guest on the next `vmrun`.
+///
+/// Processors without `FlushByAsid` only support flushing the whole TLB, which
+/// is used instead.
+pub fn flush_guest_tlb(vcpu: &mut VcpuData, scope: FlushScope) {
+    let flush_by_asid = vcpu.shared_data().asids.capabilities().flush_by_asid;
+
+    let tlb_control = match scope {
+        FlushScope::Guest if flush_by_asid => TlbControl::FLUSH_GUEST_TLB,
+        FlushScope::GuestNonGlobal if flush_by_asid => TlbControl::FLUSH_GUEST_NON_GLOBAL_TLB,
+        _ => TlbControl::FLUSH_ENTIRE_TLB,
+    };
+
+    // Multiple flushes before the next `vmrun` are combined into the widest one.
+    //
+    let current = vcpu.guest_vmcb.control_area.tlb_control;
+    let covered = current == TlbControl::FLUSH_ENTIRE_TLB
+        || (current == TlbControl::FLUSH_GUEST_TLB
+            && tlb_control == TlbControl::FLUSH_GUEST_NON_GLOBAL_TLB);
+    if !covered {
+        vcpu.guest_vmcb.control_area.tlb_control = tlb_control;
+    }
+}
+
+/// Switches the guest to the nested page table at `ncr3`, which uses the
+/// specified ASID.
+///
+/// If both views share the same ASID, the entries of the previous view are
+/// flushed. Entries of the new view are not flushed, so this has to be done
+/// by the caller if the view has been modified.
+pub fn switch_view(vcpu: &mut VcpuData, ncr3: u64, asid: u32) {
+    if vcpu.guest_vmcb.control_area.ncr3 == ncr3 {
+        return;
+    }
+
+    if vcpu.guest_vmcb.control_area.guest_asid == asid {
+        flush_guest_tlb(vcpu, FlushScope::Guest);
+    }
+
+    vcpu.guest_vmcb.set_ncr3(ncr3);
+    vcpu.guest_vmcb.set_guest_asid(asid);
+}
@@ -19,3 +19,31 @@ pub struct GuestRegs {
     pub rax: u64,
 }
 const_assert_eq!(core::mem::size_of::<GuestRegs>(), 0x80 /* 16 * 0x8 */);
+
+impl GuestRegs {
+    /// Returns the registers in the order in which they are stored (`r15`
+    /// first, `rax` last).
+    pub fn as_array(&self) -> &[u64; 16] {
+        unsafe { &*(self as *const _ as *const [u64; 16]) }
+    }
+
+    /// See [`GuestRegs::as_array`].
+    pub fn as_array_mut(&mut self) -> &mut [u64; 16] {
+        unsafe { &mut *(self as *mut _ as *mut [u64; 16]) }
+    }
+
+    /// Returns the general purpose register with the specified number, as it's
+    /// used in the instruction encoding (0 = `rax`, 1 = `rcx`, ..., 15 =
+    /// `r15`).
+    ///
+    /// Note: The guest `rsp` is stored in the save area of the vmcb, so the
+    /// value returned for register 4 is meaningless.
+    pub fn gpr(&self, index: u8) -> u64 {
+        self.as_array()[15 - (index & 0xf) as usize]
+    }
+
+    /// See [`GuestRegs::gpr`].
+    pub fn set_gpr(&mut self, index: u8, value: u64) {
+        self.as_array_mut()[15 - (index & 0xf) as usize] = value;
+    }
+}
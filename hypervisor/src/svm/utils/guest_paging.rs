@@ -0,0 +1,710 @@
+//! Translates guest virtual addresses by walking the page tables of the guest.
+//!
+//! The tables are read through the nested page table, exactly like the
+//! processor does it: every guest table is at a guest physical address, which
+//! is translated with [`NestedPageTable::translate`] first. The resulting
+//! guest physical address is translated the same way to get the host physical
+//! address, and its nested permissions are checked for the access.
+//!
+//! The host physical pages of the tables are accessed through a
+//! [`MappingWindow`], because the walk runs in the vmexit handlers.
+//!
+//! Only long mode paging is supported (4-level and 5-level). Protection keys
+//! are not checked. See `5.3 Long-Mode Page Translation` and `5.6 Page-
+//! Protection Checks` for the rules.
+
+use crate::{
+    svm::{
+        events::EventInjection,
+        nested_page_table::NestedPageTable,
+        utils::validation::{CR0_PG, CR0_WP, CR4_LA57, CR4_SMAP, CR4_SMEP, EFER_LMA, EFER_NXE},
+        vcpu_data::VcpuData,
+        vmcb::save_area::SaveArea,
+    },
+    utils::mapping_window::MappingWindow,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use snafu::prelude::*;
+use tinyvec::ArrayVec;
+use x86::cpuid::cpuid;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const ACCESSED: u64 = 1 << 5;
+const DIRTY: u64 = 1 << 6;
+const PAGE_SIZE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+
+/// The address bits of the entries. Bits above the physical address width are
+/// reserved.
+const ADDRESS_MASK: u64 = ((1 << 52) - 1) & !0xfff;
+
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// The bits of the #PF error code. See `8.4.2 Page-Fault Error Code`.
+pub const PF_PRESENT: u32 = 1 << 0;
+pub const PF_WRITE: u32 = 1 << 1;
+pub const PF_USER: u32 = 1 << 2;
+pub const PF_RESERVED: u32 = 1 << 3;
+pub const PF_INSTRUCTION: u32 = 1 << 4;
+
+#[derive(Debug, Snafu)]
+pub enum GuestWalkError {
+    #[snafu(display("Paging without long mode is not supported"))]
+    UnsupportedMode,
+
+    #[snafu(display("Address {:#x} is not canonical", address))]
+    NonCanonical { address: u64 },
+
+    /// The walk failed at the specified level (5 = PML5, 1 = PT). The error
+    /// code is the one of the #PF the processor would raise.
+    #[snafu(display(
+        "Page fault at {:#x} in level {} (error code {:#x})",
+        address,
+        level,
+        error_code
+    ))]
+    PageFault {
+        address: u64,
+        level: u8,
+        error_code: u32,
+    },
+
+    /// A guest table or the page itself is not mapped in the nested page
+    /// table, or the nested permissions don't allow the access. The processor
+    /// would cause a nested page fault instead.
+    #[snafu(display("Nested page fault at guest physical address {:#x}", guest_pa))]
+    NestedPageFault { guest_pa: u64 },
+
+    #[snafu(display("No mapping window has been reserved for this processor"))]
+    NoMappingWindow,
+
+    /// The guest range spans more pages than can be accessed at once.
+    #[snafu(display("Guest range of {:#x} bytes is too large", size))]
+    RangeTooLarge { size: usize },
+}
+
+impl GuestWalkError {
+    /// Injects the exception the guest would get for the same access: #PF for
+    /// page faults and #GP for non-canonical addresses.
+    ///
+    /// The other errors have no guest exception, so nothing is injected and
+    /// `false` is returned.
+    pub fn inject(&self, data: &mut VcpuData) -> bool {
+        match self {
+            Self::PageFault {
+                address,
+                error_code,
+                ..
+            } => EventInjection::page_fault(data, *address, *error_code),
+            Self::NonCanonical { .. } => EventInjection::gp().inject(data),
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+/// The result of a successful walk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Translation {
+    pub guest_pa: u64,
+    pub host_pa: u64,
+
+    /// The size of the guest page (4KB, 2MB or 1GB).
+    pub page_size: u64,
+
+    /// The effective permissions of all levels of the guest page tables.
+    pub writable: bool,
+    pub user: bool,
+    pub executable: bool,
+}
+
+/// The state of the guest that decides how its virtual addresses are
+/// translated.
+#[derive(Debug, Copy, Clone)]
+pub struct GuestPaging {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub rflags: u64,
+    pub cpl: u8,
+
+    /// Bits of the entries above this are reserved.
+    pub physical_address_bits: u8,
+}
+
+impl GuestPaging {
+    /// Takes the paging state of the guest from the save area.
+    pub fn new(save_area: &SaveArea) -> Self {
+        Self {
+            cr0: save_area.cr0,
+            cr3: save_area.cr3,
+            cr4: save_area.cr4,
+            efer: save_area.efer,
+            rflags: save_area.rflags,
+            cpl: save_area.cpl,
+            physical_address_bits: cpuid!(0x8000_0008).eax as u8,
+        }
+    }
+
+    /// Returns the number of levels of the guest page tables, or `None` if
+    /// paging is disabled.
+    pub fn levels(&self) -> Option<u8> {
+        if self.cr0 & CR0_PG == 0 {
+            return None;
+        }
+
+        Some(if self.cr4 & CR4_LA57 != 0 { 5 } else { 4 })
+    }
+
+    /// Translates the guest virtual address without modifying the guest page
+    /// tables.
+    pub fn translate(
+        &self, npt: &NestedPageTable, window: &mut MappingWindow, address: u64, access: AccessKind,
+    ) -> Result<Translation, GuestWalkError> {
+        self.walk(npt, window, address, access, false)
+    }
+
+    /// Translates the guest virtual address and sets the accessed bits of all
+    /// levels (and the dirty bit of the page for writes), like the processor
+    /// does when the guest accesses the address. The bits are only set if the
+    /// translation succeeds.
+    pub fn access(
+        &self, npt: &NestedPageTable, window: &mut MappingWindow, address: u64, access: AccessKind,
+    ) -> Result<Translation, GuestWalkError> {
+        self.walk(npt, window, address, access, true)
+    }
+
+    fn walk(
+        &self, npt: &NestedPageTable, window: &mut MappingWindow, address: u64, access: AccessKind,
+        update: bool,
+    ) -> Result<Translation, GuestWalkError> {
+        let Some(levels) = self.levels() else {
+            // Without paging, guest virtual and guest physical addresses are the same.
+            //
+            let host_pa = translate_nested(npt, address, access)?;
+
+            return Ok(Translation {
+                guest_pa: address,
+                host_pa,
+                page_size: 1 << 12,
+                writable: true,
+                user: true,
+                executable: true,
+            });
+        };
+        ensure!(self.efer & EFER_LMA != 0, UnsupportedModeSnafu);
+
+        let address_bits = 12 + 9 * levels as u32;
+        ensure!(
+            is_canonical(address, address_bits),
+            NonCanonicalSnafu { address }
+        );
+
+        let fault = |level: u8, flags: u32| GuestWalkError::PageFault {
+            address,
+            level,
+            error_code: self.error_code(access, flags),
+        };
+
+        let (mut writable, mut user, mut executable) = (true, true, true);
+        let mut entries = ArrayVec::<[u64; 5]>::new();
+        let mut table = self.cr3 & ADDRESS_MASK;
+
+        for level in (1..=levels).rev() {
+            let shift = 12 + 9 * (level as u64 - 1);
+            let entry_pa = table + ((address >> shift) & 0x1ff) * 8;
+
+            // The processor reads the guest tables like data.
+            //
+            let entry_host_pa = translate_nested(npt, entry_pa, AccessKind::Read)?;
+            let entry = window.read_u64(entry_host_pa);
+            if entry & PRESENT == 0 {
+                return Err(fault(level, 0));
+            }
+
+            // Only PDPT and PD entries can map pages.
+            //
+            let is_page = (level == 2 || level == 3) && entry & PAGE_SIZE != 0;
+            let page_size = 1u64 << shift;
+            if entry & self.reserved_bits(level, is_page, page_size) != 0 {
+                return Err(fault(level, PF_PRESENT | PF_RESERVED));
+            }
+
+            writable &= entry & WRITABLE != 0;
+            user &= entry & USER != 0;
+            executable &= self.efer & EFER_NXE == 0 || entry & NO_EXECUTE == 0;
+            entries.push(entry_host_pa);
+
+            if !is_page && level != 1 {
+                table = entry & ADDRESS_MASK;
+                continue;
+            }
+
+            if !self.is_allowed(access, writable, user, executable) {
+                return Err(fault(level, PF_PRESENT));
+            }
+
+            let guest_pa = (entry & ADDRESS_MASK & !(page_size - 1)) | (address & (page_size - 1));
+            let host_pa = translate_nested(npt, guest_pa, access)?;
+
+            if update {
+                let leaf = entries.len() - 1;
+                for (i, entry_host_pa) in entries.iter().enumerate() {
+                    let bits = match access {
+                        AccessKind::Write if i == leaf => ACCESSED | DIRTY,
+                        _ => ACCESSED,
+                    };
+
+                    // The guest could modify the entry at the same time on another processor.
+                    //
+                    window.with(*entry_host_pa, |entry_va| {
+                        let entry = unsafe { &*(entry_va as *const AtomicU64) };
+                        if entry.load(Ordering::Relaxed) & bits != bits {
+                            entry.fetch_or(bits, Ordering::SeqCst);
+                        }
+                    });
+                }
+            }
+
+            return Ok(Translation {
+                guest_pa,
+                host_pa,
+                page_size,
+                writable,
+                user,
+                executable,
+            });
+        }
+
+        unreachable!()
+    }
+
+    fn reserved_bits(&self, level: u8, is_page: bool, page_size: u64) -> u64 {
+        let mut reserved = ADDRESS_MASK & !((1 << self.physical_address_bits) - 1);
+        if self.efer & EFER_NXE == 0 {
+            reserved |= NO_EXECUTE;
+        }
+        if level >= 4 {
+            reserved |= PAGE_SIZE;
+        }
+
+        // The address of large pages has to be aligned. Bit 12 is the PAT bit.
+        //
+        if is_page {
+            reserved |= (page_size - 1) & !0x1fff;
+        }
+
+        reserved
+    }
+
+    /// Checks the effective permissions of a page, see `5.6.4 Summary of
+    /// Page-Protection Checks`.
+    fn is_allowed(&self, access: AccessKind, writable: bool, user: bool, executable: bool) -> bool {
+        let user_access = self.cpl == 3;
+        if user_access && !user {
+            return false;
+        }
+
+        match access {
+            AccessKind::Write if !writable && (user_access || self.cr0 & CR0_WP != 0) => false,
+            AccessKind::Execute if !executable => false,
+            AccessKind::Execute => user_access || !user || self.cr4 & CR4_SMEP == 0,
+            AccessKind::Read | AccessKind::Write => {
+                user_access || !user || self.cr4 & CR4_SMAP == 0 || self.rflags & RFLAGS_AC != 0
+            }
+        }
+    }
+
+    fn error_code(&self, access: AccessKind, flags: u32) -> u32 {
+        let mut error_code = flags;
+        if access == AccessKind::Write {
+            error_code |= PF_WRITE;
+        }
+        if self.cpl == 3 {
+            error_code |= PF_USER;
+        }
+        if access == AccessKind::Execute && (self.efer & EFER_NXE != 0 || self.cr4 & CR4_SMEP != 0)
+        {
+            error_code |= PF_INSTRUCTION;
+        }
+
+        error_code
+    }
+}
+
+/// Translates the guest physical address with the nested page table and checks
+/// whether its nested permissions allow the access.
+fn translate_nested(
+    npt: &NestedPageTable, guest_pa: u64, access: AccessKind,
+) -> Result<u64, GuestWalkError> {
+    let host_pa = npt
+        .translate(guest_pa)
+        .context(NestedPageFaultSnafu { guest_pa })?;
+
+    let allowed = npt
+        .effective_access_type(guest_pa)
+        .map_or(false, |access_type| match access {
+            AccessKind::Read => access_type.is_readable(),
+            AccessKind::Write => access_type.is_writable(),
+            AccessKind::Execute => access_type.is_executable(),
+        });
+    ensure!(allowed, NestedPageFaultSnafu { guest_pa });
+
+    Ok(host_pa)
+}
+
+/// Returns whether the upper bits of the address are copies of the highest
+/// bit that is translated.
+pub(crate) fn is_canonical(address: u64, address_bits: u32) -> bool {
+    let shift = 64 - address_bits;
+
+    ((address << shift) as i64 >> shift) as u64 == address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::utils::paging::AccessType;
+    use alloc::{boxed::Box, vec::Vec};
+    use x86::bits64::paging::BASE_PAGE_SIZE;
+
+    #[repr(C, align(4096))]
+    struct Table([u64; 512]);
+
+    /// The guest physical address of the first table. The tables are mapped
+    /// after each other and above the memory that is used by the tests.
+    const TABLES_GUEST_PA: u64 = 0x1_0000_0000;
+
+    /// Guest page tables in host memory. Every table is mapped to a synthetic
+    /// guest physical address, because the host addresses of the heap can be
+    /// above the guest physical address width of the nested page table.
+    struct GuestTables {
+        npt: Box<NestedPageTable>,
+        window: MappingWindow,
+        tables: Vec<Box<Table>>,
+        levels: u8,
+    }
+
+    impl GuestTables {
+        fn new(levels: u8) -> Self {
+            let mut tables = Self {
+                npt: NestedPageTable::empty(),
+                window: MappingWindow::new().unwrap(),
+                tables: Vec::new(),
+                levels,
+            };
+            tables.allocate();
+
+            tables
+        }
+
+        /// Allocates a new table and returns its guest physical address.
+        fn allocate(&mut self) -> u64 {
+            let table = Box::new(Table([0; 512]));
+            let host_pa = table.as_ref() as *const _ as u64;
+            let guest_pa = TABLES_GUEST_PA + (self.tables.len() * BASE_PAGE_SIZE) as u64;
+            self.npt
+                .map_4kb(guest_pa, host_pa, AccessType::ReadWriteExecute)
+                .unwrap();
+            self.tables.push(table);
+
+            guest_pa
+        }
+
+        /// Returns the host pointer to the table entry at the guest physical
+        /// address.
+        fn entry(&mut self, guest_pa: u64) -> *mut u64 {
+            let offset = (guest_pa - TABLES_GUEST_PA) as usize;
+            let table = &mut self.tables[offset / BASE_PAGE_SIZE];
+
+            &mut table.0[offset % BASE_PAGE_SIZE / 8]
+        }
+
+        /// Maps the guest virtual address to the guest physical address with a
+        /// page at the specified level (1 = 4KB, 2 = 2MB, 3 = 1GB). The flags
+        /// are only used for the page, the tables above allow every access.
+        fn map(&mut self, address: u64, guest_pa: u64, page_level: u8, flags: u64) -> u64 {
+            let mut table = self.cr3();
+            for level in (page_level..=self.levels).rev() {
+                let shift = 12 + 9 * (level as u64 - 1);
+                let entry = self.entry(table + ((address >> shift) & 0x1ff) * 8);
+
+                if level == page_level {
+                    let size = if level == 1 { 0 } else { PAGE_SIZE };
+                    unsafe { *entry = guest_pa | flags | size | PRESENT };
+                    return entry as u64;
+                }
+
+                if unsafe { *entry } & PRESENT == 0 {
+                    let next = self.allocate();
+                    unsafe { *entry = next | WRITABLE | USER | PRESENT };
+                }
+                table = unsafe { *entry } & ADDRESS_MASK;
+            }
+
+            unreachable!()
+        }
+
+        fn cr3(&self) -> u64 {
+            TABLES_GUEST_PA
+        }
+
+        fn paging(&self) -> GuestPaging {
+            GuestPaging {
+                cr0: CR0_PG | CR0_WP,
+                cr3: self.cr3(),
+                cr4: if self.levels == 5 { CR4_LA57 } else { 0 },
+                efer: EFER_LMA | EFER_NXE,
+                rflags: 0,
+                cpl: 0,
+                physical_address_bits: 48,
+            }
+        }
+
+        fn translate(
+            &mut self, paging: &GuestPaging, address: u64, access: AccessKind,
+        ) -> Result<Translation, GuestWalkError> {
+            paging.translate(&self.npt, &mut self.window, address, access)
+        }
+
+        fn access(
+            &mut self, paging: &GuestPaging, address: u64, access: AccessKind,
+        ) -> Result<Translation, GuestWalkError> {
+            paging.access(&self.npt, &mut self.window, address, access)
+        }
+    }
+
+    fn error_code(result: Result<Translation, GuestWalkError>) -> (u8, u32) {
+        match result {
+            Err(GuestWalkError::PageFault {
+                level, error_code, ..
+            }) => (level, error_code),
+            result => panic!("expected a page fault, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn paging_disabled() {
+        let mut tables = GuestTables::new(4);
+        tables
+            .npt
+            .map_4kb(0x1000, 0x5000, AccessType::ReadWrite)
+            .unwrap();
+
+        let mut paging = tables.paging();
+        paging.cr0 &= !CR0_PG;
+        let translation = tables
+            .translate(&paging, 0x1234, AccessKind::Write)
+            .unwrap();
+        assert_eq!(translation.guest_pa, 0x1234);
+        assert_eq!(translation.host_pa, 0x5234);
+    }
+
+    #[test]
+    fn translate_4_level() {
+        let mut tables = GuestTables::new(4);
+        tables.map(0x7fff_1234_5000, 0x8000, 1, WRITABLE | USER);
+        tables.map(0xffff_8000_0020_0000, 0x40_0000, 2, WRITABLE);
+        tables.map(0xffff_c000_0000_0000, 0x4000_0000, 3, NO_EXECUTE);
+        tables
+            .npt
+            .map_range(0, 0, 0x8000_0000, AccessType::ReadWriteExecute)
+            .unwrap();
+        let paging = tables.paging();
+
+        let translation = tables
+            .translate(&paging, 0x7fff_1234_5678, AccessKind::Write)
+            .unwrap();
+        assert_eq!(
+            translation,
+            Translation {
+                guest_pa: 0x8678,
+                host_pa: 0x8678,
+                page_size: 0x1000,
+                writable: true,
+                user: true,
+                executable: true,
+            }
+        );
+
+        let translation = tables
+            .translate(&paging, 0xffff_8000_0031_2345, AccessKind::Execute)
+            .unwrap();
+        assert_eq!(translation.guest_pa, 0x51_2345);
+        assert_eq!(translation.page_size, 0x20_0000);
+        assert!(!translation.user);
+
+        let translation = tables
+            .translate(&paging, 0xffff_c000_1234_5678, AccessKind::Read)
+            .unwrap();
+        assert_eq!(translation.guest_pa, 0x5234_5678);
+        assert_eq!(translation.page_size, 0x4000_0000);
+        assert!(!translation.writable);
+        assert!(!translation.executable);
+    }
+
+    #[test]
+    fn translate_5_level() {
+        let mut tables = GuestTables::new(5);
+        tables.map(0x00ab_cdef_1234_5000, 0x8000, 1, WRITABLE);
+        tables
+            .npt
+            .map_4kb(0x8000, 0x9000, AccessType::ReadWrite)
+            .unwrap();
+        let paging = tables.paging();
+
+        let translation = tables
+            .translate(&paging, 0x00ab_cdef_1234_5008, AccessKind::Read)
+            .unwrap();
+        assert_eq!(translation.guest_pa, 0x8008);
+        assert_eq!(translation.host_pa, 0x9008);
+
+        // The address is only canonical with 57 bits.
+        //
+        let mut paging = tables.paging();
+        paging.cr4 &= !CR4_LA57;
+        assert!(matches!(
+            tables.translate(&paging, 0x00ab_cdef_1234_5008, AccessKind::Read),
+            Err(GuestWalkError::NonCanonical { .. })
+        ));
+        assert!(matches!(
+            tables.translate(&tables.paging(), 0x0100_0000_0000_0000, AccessKind::Read),
+            Err(GuestWalkError::NonCanonical { .. })
+        ));
+    }
+
+    #[test]
+    fn page_fault_error_codes() {
+        let mut tables = GuestTables::new(4);
+        tables.map(0x1000, 0x1000, 1, 0);
+        tables.map(0x2000, 0x2000, 1, WRITABLE | USER | NO_EXECUTE);
+        tables.map(0x3000, 0x3000, 1, USER | (1 << 50));
+        tables.map(0x4000, 0x4000, 1, USER);
+        tables
+            .npt
+            .map_range(0, 0, 0x10_0000, AccessType::ReadWriteExecute)
+            .unwrap();
+
+        let kernel = tables.paging();
+        let mut user = tables.paging();
+        user.cpl = 3;
+
+        // Not present: the PD entry of 0x4000_0000 doesn't exist.
+        //
+        assert_eq!(
+            error_code(tables.translate(&kernel, 0x4000_0000, AccessKind::Read)),
+            (3, 0)
+        );
+        assert_eq!(
+            error_code(tables.translate(&user, 0x5000, AccessKind::Write)),
+            (1, PF_WRITE | PF_USER)
+        );
+
+        // Protection violations.
+        //
+        assert_eq!(
+            error_code(tables.translate(&kernel, 0x1000, AccessKind::Write)),
+            (1, PF_PRESENT | PF_WRITE)
+        );
+        assert_eq!(
+            error_code(tables.translate(&user, 0x1000, AccessKind::Read)),
+            (1, PF_PRESENT | PF_USER)
+        );
+        assert_eq!(
+            error_code(tables.translate(&user, 0x2000, AccessKind::Execute)),
+            (1, PF_PRESENT | PF_USER | PF_INSTRUCTION)
+        );
+
+        // Bit 50 is above the physical address width.
+        //
+        assert_eq!(
+            error_code(tables.translate(&user, 0x3000, AccessKind::Read)),
+            (1, PF_PRESENT | PF_USER | PF_RESERVED)
+        );
+
+        // Without CR0.WP, the kernel can write to read-only pages.
+        //
+        let mut paging = tables.paging();
+        paging.cr0 &= !CR0_WP;
+        assert!(tables.translate(&paging, 0x1000, AccessKind::Write).is_ok());
+
+        // SMEP and SMAP.
+        //
+        let mut paging = tables.paging();
+        paging.cr4 |= CR4_SMEP | CR4_SMAP;
+        assert_eq!(
+            error_code(tables.translate(&paging, 0x4000, AccessKind::Execute)),
+            (1, PF_PRESENT | PF_INSTRUCTION)
+        );
+        assert_eq!(
+            error_code(tables.translate(&paging, 0x2000, AccessKind::Read)),
+            (1, PF_PRESENT)
+        );
+        paging.rflags |= RFLAGS_AC;
+        assert!(tables.translate(&paging, 0x2000, AccessKind::Read).is_ok());
+    }
+
+    #[test]
+    fn nested_page_fault() {
+        let mut tables = GuestTables::new(4);
+        tables.map(0x1000, 0x1000, 1, WRITABLE);
+        let paging = tables.paging();
+
+        assert!(matches!(
+            tables.translate(&paging, 0x1000, AccessKind::Read),
+            Err(GuestWalkError::NestedPageFault { guest_pa: 0x1000 })
+        ));
+
+        // The nested permissions are checked as well.
+        //
+        tables
+            .npt
+            .map_4kb(0x1000, 0x1000, AccessType::ReadOnly)
+            .unwrap();
+        assert!(tables.translate(&paging, 0x1000, AccessKind::Read).is_ok());
+        assert!(matches!(
+            tables.translate(&paging, 0x1000, AccessKind::Write),
+            Err(GuestWalkError::NestedPageFault { guest_pa: 0x1000 })
+        ));
+        assert!(matches!(
+            tables.translate(&paging, 0x1000, AccessKind::Execute),
+            Err(GuestWalkError::NestedPageFault { guest_pa: 0x1000 })
+        ));
+    }
+
+    #[test]
+    fn accessed_and_dirty_bits() {
+        let mut tables = GuestTables::new(4);
+        let pt_entry = tables.map(0x1000, 0x1000, 1, WRITABLE) as *const u64;
+        tables
+            .npt
+            .map_4kb(0x1000, 0x1000, AccessType::ReadWrite)
+            .unwrap();
+        let pml4_entry = tables.entry(tables.cr3()) as *const u64;
+        let paging = tables.paging();
+
+        tables
+            .translate(&paging, 0x1000, AccessKind::Write)
+            .unwrap();
+        assert_eq!(unsafe { *pt_entry } & (ACCESSED | DIRTY), 0);
+
+        tables.access(&paging, 0x1000, AccessKind::Read).unwrap();
+        assert_eq!(unsafe { *pml4_entry } & ACCESSED, ACCESSED);
+        assert_eq!(unsafe { *pt_entry } & (ACCESSED | DIRTY), ACCESSED);
+
+        tables.access(&paging, 0x1000, AccessKind::Write).unwrap();
+        assert_eq!(unsafe { *pml4_entry } & DIRTY, 0);
+        assert_eq!(unsafe { *pt_entry } & (ACCESSED | DIRTY), ACCESSED | DIRTY);
+    }
+}
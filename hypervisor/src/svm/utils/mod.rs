@@ -1,4 +1,8 @@
 pub mod guest;
+pub mod guest_paging;
 pub mod msr;
+pub mod msr_table;
+pub mod mtrr;
 pub mod paging;
 pub mod segmentation;
+pub mod validation;
@@ -0,0 +1,206 @@
+//! The msrs that can be accessed on AMD processors.
+//!
+//! The table is based on `Memory Map - MSR` of the [Open-Source Register Reference for AMD CPUs](https://developer.amd.com/wp-content/resources/56255_3_03.PDF)
+//! and the `MSR Cross-Reference` in the AMD64 Architecture Programmer’s
+//! Manual Volume 2: System Programming.
+//!
+//! This is not a complete list of every msr. Only the architectural msrs that
+//! the hypervisor has to treat differently have their own entry, with the
+//! access, reserved bits and canonical check that are enforced before the
+//! processor is asked. Everything else is covered by ranges that are marked as
+//! model specific (see [`MsrEntry`]), because the exact set differs between the
+//! processor families. Their names list the documented msrs in the range.
+//!
+//! The table is only a filter that rejects msrs which don't exist on any
+//! processor. The actual check is done by the processor: Msrs that don't exist
+//! on the current one, or values that it rejects, cause a #GP. This is caught
+//! by the accessors in [`msr`](super::msr) with the [`MsrIdt`] and injected
+//! into the guest, like the processor would.
+//!
+//! [`MsrIdt`]: super::msr::MsrIdt
+//!
+//! The entries are sorted and don't overlap, so that they can be searched with
+//! a binary search.
+
+use core::cmp::Ordering;
+
+/// Whether the msr can be written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MsrAccess {
+    ReadWrite,
+    ReadOnly,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MsrEntry {
+    pub start: u32,
+    pub end: u32,
+
+    /// The name of the msr, or of the documented msrs in a model specific
+    /// range.
+    pub name: &'static str,
+    pub access: MsrAccess,
+
+    /// The bits that have to be zero when the msr is written.
+    pub reserved: u64,
+
+    /// The value is an address that has to be canonical.
+    pub canonical: bool,
+
+    /// The range contains model specific msrs, which are not checked beyond
+    /// the fact that they are in the range. See the module documentation.
+    pub model_specific: bool,
+}
+
+impl MsrEntry {
+    const fn new(start: u32, end: u32, name: &'static str) -> Self {
+        Self {
+            start,
+            end,
+            name,
+            access: MsrAccess::ReadWrite,
+            reserved: 0,
+            canonical: false,
+            model_specific: false,
+        }
+    }
+
+    const fn model_specific(mut self) -> Self {
+        self.model_specific = true;
+        self
+    }
+
+    const fn read_only(mut self) -> Self {
+        self.access = MsrAccess::ReadOnly;
+        self
+    }
+
+    const fn reserved(mut self, reserved: u64) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    const fn canonical(mut self) -> Self {
+        self.canonical = true;
+        self
+    }
+
+    pub fn contains(&self, msr: u32) -> bool {
+        (self.start..=self.end).contains(&msr)
+    }
+}
+
+#[rustfmt::skip]
+pub static MSR_TABLE: [MsrEntry; 41] = [
+    // MSR0000_xxxx
+    //
+    MsrEntry::new(0x0000_0000, 0x0000_0001, "MCA_ADDR, MCA_STATUS"),
+    MsrEntry::new(0x0000_0010, 0x0000_00FD, "TSC, APIC_BAR, MPERF, APERF").model_specific(),
+    MsrEntry::new(0x0000_00FE, 0x0000_00FE, "MTRRcap").read_only(),
+    MsrEntry::new(0x0000_00FF, 0x0000_0174, "SYSENTER_CS").model_specific(),
+    MsrEntry::new(0x0000_0175, 0x0000_0176, "SYSENTER_ESP, SYSENTER_EIP").canonical(),
+    MsrEntry::new(0x0000_0177, 0x0000_0276, "MCG_CAP, MCG_STAT, MCG_CTL, DBG_CTL_MSR, BR_FROM/TO, MTRRphysBase/Mask, MTRRfix").model_specific(),
+    MsrEntry::new(0x0000_0277, 0x0000_0277, "PAT").reserved(0xF8F8_F8F8_F8F8_F8F8),
+    MsrEntry::new(0x0000_0278, 0x0000_02FE, "model specific").model_specific(),
+    MsrEntry::new(0x0000_02FF, 0x0000_02FF, "MTRRdefType").reserved(!0xCFF),
+    MsrEntry::new(0x0000_0400, 0x0000_0443, "MC[0-16]_CTL/STATUS/ADDR/MISC"),
+    MsrEntry::new(0x0000_044C, 0x0000_045B, "MC[19-22]_CTL/STATUS/ADDR/MISC"),
+
+    // MSRC000_0xxx
+    //
+    MsrEntry::new(0xC000_0080, 0xC000_0081, "EFER, STAR"),
+    MsrEntry::new(0xC000_0082, 0xC000_0083, "STAR64, STARCOMPAT").canonical(),
+    MsrEntry::new(0xC000_0084, 0xC000_00E6, "SYSCALL_FLAG_MASK").model_specific(),
+    MsrEntry::new(0xC000_00E7, 0xC000_00E8, "MPerfReadOnly, APerfReadOnly").read_only(),
+    MsrEntry::new(0xC000_00E9, 0xC000_00FF, "IRPerfCount").model_specific(),
+    MsrEntry::new(0xC000_0100, 0xC000_0102, "FS_BASE, GS_BASE, KernelGSbase").canonical(),
+    MsrEntry::new(0xC000_0103, 0xC000_0103, "TSC_AUX").reserved(0xFFFF_FFFF_0000_0000),
+    MsrEntry::new(0xC000_0104, 0xC000_0410, "TscRateMsr, LWP_CFG, LWP_CBADDR, MC4_MISC1-3").model_specific(),
+    MsrEntry::new(0xC000_2000, 0xC000_2009, "MCA bank 0"),
+    MsrEntry::new(0xC000_2010, 0xC000_2016, "MCA bank 1"),
+    MsrEntry::new(0xC000_2020, 0xC000_2029, "MCA bank 2"),
+    MsrEntry::new(0xC000_2030, 0xC000_2036, "MCA bank 3"),
+    MsrEntry::new(0xC000_2040, 0xC000_2049, "MCA bank 4"),
+    MsrEntry::new(0xC000_2050, 0xC000_2056, "MCA bank 5"),
+    MsrEntry::new(0xC000_2060, 0xC000_2066, "MCA bank 6"),
+    MsrEntry::new(0xC000_2070, 0xC000_20E9, "MCA banks 7-14"),
+    MsrEntry::new(0xC000_20F0, 0xC000_210A, "MCA banks 15-16"),
+    MsrEntry::new(0xC000_2130, 0xC000_2136, "MCA bank 19"),
+    MsrEntry::new(0xC000_2140, 0xC000_2159, "MCA banks 20-21"),
+    MsrEntry::new(0xC000_2160, 0xC000_2169, "MCA bank 22"),
+
+    // MSRC001_0xxx
+    //
+    MsrEntry::new(0xC001_0000, 0xC001_0113, "PERF_CTL[0-3], PERF_CTR[0-3], SYS_CFG, HWCR, IORR, TOP_MEM, TOM2").model_specific(),
+    MsrEntry::new(0xC001_0114, 0xC001_0114, "VM_CR").read_only(),
+    MsrEntry::new(0xC001_0115, 0xC001_0116, "IGNNE, SMM_CTL"),
+    MsrEntry::new(0xC001_0117, 0xC001_0118, "VM_HSAVE_PA, SVM_KEY").read_only(),
+    MsrEntry::new(0xC001_0119, 0xC001_029B, "SMM_KEY, LocalSmiStatus, PERF_CTL/CTR[0-5]").model_specific(),
+    MsrEntry::new(0xC001_0400, 0xC001_040E, "MCA_CTL_MASK"),
+    MsrEntry::new(0xC001_0413, 0xC001_0416, "MCA_CTL_MASK"),
+
+    // MSRC001_1xxx
+    //
+    MsrEntry::new(0xC001_1002, 0xC001_103D, "CPUID features, LS_CFG, IC_CFG, DC_CFG, IBS").model_specific(),
+    MsrEntry::new(0xC001_1090, 0xC001_1090, "Processor Feedback Constants 0"),
+    MsrEntry::new(0xC001_10A1, 0xC001_10A1, "CU_CBBCFG"),
+];
+
+/// Returns the entry of the specified msr, or `None` if the msr doesn't exist.
+pub fn find_msr(msr: u32) -> Option<&'static MsrEntry> {
+    MSR_TABLE
+        .binary_search_by(|entry| {
+            if entry.end < msr {
+                Ordering::Less
+            } else if entry.start > msr {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        })
+        .ok()
+        .map(|index| &MSR_TABLE[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::utils::msr::{
+        SVM_MSR_SVM_KEY, SVM_MSR_TSC, SVM_MSR_VM_CR, SVM_MSR_VM_HSAVE_PA,
+    };
+
+    #[test]
+    fn entries_are_sorted() {
+        assert!(MSR_TABLE.iter().all(|entry| entry.start <= entry.end));
+        assert!(MSR_TABLE
+            .windows(2)
+            .all(|entries| entries[0].end < entries[1].start));
+    }
+
+    #[test]
+    fn find() {
+        assert_eq!(find_msr(0x0000_0000).unwrap().start, 0x0000_0000);
+        assert_eq!(find_msr(0x0000_0277).unwrap().name, "PAT");
+        assert_eq!(find_msr(0xC000_0103).unwrap().name, "TSC_AUX");
+        assert_eq!(find_msr(0xC001_10A1).unwrap().name, "CU_CBBCFG");
+
+        assert!(find_msr(0x0000_0002).is_none());
+        assert!(find_msr(0x4000_0000).is_none());
+        assert!(find_msr(0xC001_10A2).is_none());
+    }
+
+    #[test]
+    fn model_specific_ranges() {
+        assert!(find_msr(SVM_MSR_TSC).unwrap().model_specific);
+        assert!(find_msr(0x0000_0280).unwrap().model_specific);
+        assert!(!find_msr(0x0000_0277).unwrap().model_specific);
+        assert!(!find_msr(SVM_MSR_VM_CR).unwrap().model_specific);
+    }
+
+    #[test]
+    fn svm_msrs_are_read_only() {
+        for msr in [SVM_MSR_VM_CR, SVM_MSR_VM_HSAVE_PA, SVM_MSR_SVM_KEY] {
+            assert_eq!(find_msr(msr).unwrap().access, MsrAccess::ReadOnly);
+        }
+    }
+}
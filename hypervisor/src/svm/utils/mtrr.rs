@@ -0,0 +1,293 @@
+//! Memory types of the physical address space. See `7.7 Memory-Type Range
+//! Registers` and `7.8 Page-Attribute Table Mechanism`.
+//!
+//! The memory type of the nested page table entries is selected by their
+//! `PAT`, `PCD` and `PWT` bits, which index the host PAT. The processor then
+//! combines it with the type of the MTRRs and the one of the guest page
+//! tables.
+
+use alloc::vec::Vec;
+use x86::{cpuid::cpuid, msr::rdmsr};
+
+const MTRR_CAP: u32 = 0xfe;
+const MTRR_DEF_TYPE: u32 = 0x2ff;
+const MTRR_PHYS_BASE_0: u32 = 0x200;
+const MTRR_PHYS_MASK_0: u32 = 0x201;
+const PAT: u32 = 0x277;
+
+const MTRR_CAP_VCNT: u64 = 0xff;
+const MTRR_CAP_FIX: u64 = 1 << 8;
+const MTRR_DEF_TYPE_FE: u64 = 1 << 10;
+const MTRR_DEF_TYPE_E: u64 = 1 << 11;
+const MTRR_PHYS_MASK_VALID: u64 = 1 << 11;
+
+/// The fixed-range MTRRs: the msr, the start of the first range and the size
+/// of every range. Each msr holds 8 ranges.
+const FIXED_MTRRS: [(u32, u64, u64); 11] = [
+    (0x250, 0x00000, 0x10000),
+    (0x258, 0x80000, 0x4000),
+    (0x259, 0xA0000, 0x4000),
+    (0x268, 0xC0000, 0x1000),
+    (0x269, 0xC8000, 0x1000),
+    (0x26A, 0xD0000, 0x1000),
+    (0x26B, 0xD8000, 0x1000),
+    (0x26C, 0xE0000, 0x1000),
+    (0x26D, 0xE8000, 0x1000),
+    (0x26E, 0xF0000, 0x1000),
+    (0x26F, 0xF8000, 0x1000),
+];
+
+/// The end of the memory that is covered by the fixed-range MTRRs.
+const FIXED_MTRRS_END: u64 = 0x100000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtect = 5,
+    WriteBack = 6,
+
+    /// Only valid in the PAT. Can be overridden by the MTRRs.
+    UncacheableMinus = 7,
+}
+
+impl MemoryType {
+    pub fn from_raw(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Uncacheable,
+            1 => Self::WriteCombining,
+            4 => Self::WriteThrough,
+            5 => Self::WriteProtect,
+            6 => Self::WriteBack,
+            7 => Self::UncacheableMinus,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct VariableRange {
+    base: u64,
+    mask: u64,
+    memory_type: MemoryType,
+}
+
+impl VariableRange {
+    fn contains(&self, address: u64) -> bool {
+        address & self.mask == self.base & self.mask
+    }
+
+    /// Returns the first address after the range, assuming that the mask is
+    /// contiguous.
+    fn end(&self, address_mask: u64) -> u64 {
+        (self.base & self.mask) + (!self.mask & address_mask) + 1
+    }
+}
+
+/// A snapshot of the MTRRs of the current processor.
+#[derive(Debug)]
+pub struct Mtrrs {
+    enabled: bool,
+    default_type: MemoryType,
+
+    /// The type of every fixed range, if they are enabled.
+    fixed: Option<Vec<(u64, u64, MemoryType)>>,
+    variable: Vec<VariableRange>,
+
+    /// Covers all the bits of a physical address.
+    address_mask: u64,
+}
+
+impl Mtrrs {
+    pub fn read() -> Self {
+        let capabilities = unsafe { rdmsr(MTRR_CAP) };
+        let default_type = unsafe { rdmsr(MTRR_DEF_TYPE) };
+
+        let address_bits = cpuid!(0x8000_0008).eax & 0xff;
+        let address_mask = (1u64 << address_bits) - 1;
+
+        let fixed_enabled =
+            capabilities & MTRR_CAP_FIX != 0 && default_type & MTRR_DEF_TYPE_FE != 0;
+        let fixed = fixed_enabled.then(|| {
+            let mut ranges = Vec::new();
+            for (msr, start, size) in FIXED_MTRRS {
+                let types = unsafe { rdmsr(msr) };
+                for i in 0..8 {
+                    let memory_type = MemoryType::from_raw((types >> (i * 8)) as u8)
+                        .unwrap_or(MemoryType::Uncacheable);
+                    ranges.push((start + i * size, size, memory_type));
+                }
+            }
+            ranges
+        });
+
+        let variable = (0..(capabilities & MTRR_CAP_VCNT) as u32)
+            .filter_map(|i| {
+                let base = unsafe { rdmsr(MTRR_PHYS_BASE_0 + i * 2) };
+                let mask = unsafe { rdmsr(MTRR_PHYS_MASK_0 + i * 2) };
+                if mask & MTRR_PHYS_MASK_VALID == 0 {
+                    return None;
+                }
+
+                Some(VariableRange {
+                    base: base & address_mask & !0xfff,
+                    mask: mask & address_mask & !0xfff,
+                    memory_type: MemoryType::from_raw(base as u8)
+                        .unwrap_or(MemoryType::Uncacheable),
+                })
+            })
+            .collect();
+
+        Self {
+            enabled: default_type & MTRR_DEF_TYPE_E != 0,
+            default_type: MemoryType::from_raw(default_type as u8)
+                .unwrap_or(MemoryType::Uncacheable),
+            fixed,
+            variable,
+            address_mask,
+        }
+    }
+
+    /// Returns the memory type of the specified physical address.
+    ///
+    /// See `7.7.1 MTRR Type Fields` for the precedence of overlapping ranges.
+    pub fn memory_type(&self, address: u64) -> MemoryType {
+        if !self.enabled {
+            return MemoryType::Uncacheable;
+        }
+
+        if let Some(fixed) = self.fixed.as_ref().filter(|_| address < FIXED_MTRRS_END) {
+            if let Some((_, _, memory_type)) = fixed
+                .iter()
+                .find(|(start, size, _)| (*start..start + size).contains(&address))
+            {
+                return *memory_type;
+            }
+        }
+
+        let mut types = self
+            .variable
+            .iter()
+            .filter(|range| range.contains(address))
+            .map(|range| range.memory_type);
+
+        let Some(first) = types.next() else {
+            return self.default_type;
+        };
+        types.fold(first, |result, memory_type| match (result, memory_type) {
+            (a, b) if a == b => a,
+            (MemoryType::Uncacheable, _) | (_, MemoryType::Uncacheable) => MemoryType::Uncacheable,
+            (MemoryType::WriteThrough, MemoryType::WriteBack)
+            | (MemoryType::WriteBack, MemoryType::WriteThrough) => MemoryType::WriteThrough,
+
+            // Any other combination is undefined.
+            _ => MemoryType::Uncacheable,
+        })
+    }
+
+    /// Returns the addresses at which the memory type can change, in no
+    /// particular order. Every range between two of them has a single memory
+    /// type.
+    pub fn boundaries(&self) -> Vec<u64> {
+        self.boundaries_iter().collect()
+    }
+
+    /// Returns whether the whole range `start..end` has a single memory type.
+    /// Unlike [`Mtrrs::boundaries`], this doesn't allocate any memory.
+    pub fn has_single_type(&self, start: u64, end: u64) -> bool {
+        !self
+            .boundaries_iter()
+            .any(|boundary| start < boundary && boundary < end)
+    }
+
+    fn boundaries_iter(&self) -> impl Iterator<Item = u64> + '_ {
+        let fixed = self.fixed.iter().flat_map(|fixed| {
+            fixed
+                .iter()
+                .map(|(start, _, _)| *start)
+                .chain(core::iter::once(FIXED_MTRRS_END))
+        });
+        let variable = self
+            .variable
+            .iter()
+            .flat_map(|range| [range.base & range.mask, range.end(self.address_mask)]);
+
+        fixed.chain(variable)
+    }
+
+    /// MTRRs that are disabled, which makes all memory uncacheable. Used by
+    /// tests, where the MTRRs can't be read.
+    #[cfg(test)]
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            default_type: MemoryType::Uncacheable,
+            fixed: None,
+            variable: Vec::new(),
+            address_mask: u64::MAX,
+        }
+    }
+}
+
+/// The value of the PAT after a reset: `WB`, `WT`, `UC-`, `UC` and again the
+/// same four types. Useful where the PAT can't be read, like host-side tests.
+pub const DEFAULT_PAT: u64 = 0x0007_0406_0007_0406;
+
+/// Returns the PAT of the current processor, or [`DEFAULT_PAT`] in tests.
+pub fn host_pat() -> u64 {
+    if cfg!(test) {
+        DEFAULT_PAT
+    } else {
+        unsafe { rdmsr(PAT) }
+    }
+}
+
+/// Returns the memory type of the PAT entry with the specified index.
+/// Reserved encodings are treated as uncacheable.
+pub fn pat_memory_type(pat: u64, pat_index: u8) -> MemoryType {
+    MemoryType::from_raw((pat >> (pat_index * 8)) as u8 & 0x7).unwrap_or(MemoryType::Uncacheable)
+}
+
+/// Returns the index of the host PAT entry with the specified memory type.
+/// `PAT`, `PCD` and `PWT` are bit 2, 1 and 0 of the index.
+///
+/// If there's no such entry, uncacheable memory falls back to
+/// [`MemoryType::UncacheableMinus`] and everything else to the first entry.
+pub fn pat_index(memory_type: MemoryType) -> u8 {
+    let pat = host_pat();
+    let find = |memory_type: MemoryType| (0..8u8).find(|i| pat_memory_type(pat, *i) == memory_type);
+
+    find(memory_type)
+        .or_else(|| {
+            (memory_type == MemoryType::Uncacheable)
+                .then(|| find(MemoryType::UncacheableMinus))
+                .flatten()
+        })
+        .unwrap_or_else(|| {
+            log::warn!("No PAT entry for {:?}", memory_type);
+            0
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_type_ranges() {
+        let mut mtrrs = Mtrrs::disabled();
+        mtrrs.variable.push(VariableRange {
+            base: 0x8000_0000,
+            mask: 0xffff_c000_0000,
+            memory_type: MemoryType::WriteCombining,
+        });
+        mtrrs.address_mask = 0xffff_ffff_ffff;
+
+        assert!(mtrrs.has_single_type(0, 0x8000_0000));
+        assert!(mtrrs.has_single_type(0x8000_0000, 0xc000_0000));
+        assert!(!mtrrs.has_single_type(0x4000_0000, 0xc000_0000));
+        assert!(!mtrrs.has_single_type(0xa000_0000, 0x1_0000_0000));
+    }
+}
@@ -1,4 +1,7 @@
-use x86::bits64::paging::{PDFlags, PDPTFlags, PML4Flags, PTFlags, MAXPHYADDR};
+use x86::{
+    bits64::paging::{PDFlags, PDPTFlags, PML4Flags, MAXPHYADDR},
+    cpuid::{cpuid, CpuId},
+};
 
 pub const _512GB: u64 = 512 * 1024 * 1024 * 1024;
 pub const _1GB: u64 = 1024 * 1024 * 1024;
@@ -8,7 +11,12 @@ pub const _4KB: usize = 4 * 1024;
 pub const PAGE_SHIFT: u64 = 12;
 pub const PFN_MASK: u64 = ((1 << MAXPHYADDR) - 1) & !0xfff;
 
-const RW: u64 = 0b1;
+/// The number of address bits that can be translated by four-level paging.
+pub const FOUR_LEVEL_ADDRESS_BITS: u32 = 48;
+
+const P: u64 = 0b1;
+const RW: u64 = 0b1 << 1;
+const US: u64 = 0b1 << 2;
 
 /// The NX bit can only be set when the no-execute page-protection feature is
 /// enabled by setting EFER.NXE to 1 (see “Extended Feature Enable Register
@@ -17,74 +25,145 @@ const RW: u64 = 0b1;
 /// cleared to 0.
 const NX: u64 = 0b1 << 63;
 
+/// Marks pages that are mapped without any access. The present bit of these
+/// pages is cleared, so the processor ignores the other bits. This one (an
+/// `AVL` bit) is only used to tell them apart from unmapped entries.
+const NO_ACCESS: u64 = 0b1 << 9;
+
+/// All the bits that are changed by [`AccessType::apply`].
+const ACCESS_BITS: u64 = P | RW | US | NX | NO_ACCESS;
+
+/// The permissions of a nested page.
+///
+/// The nested page table can't express write-only or execute-only pages, so
+/// every access type other than [`AccessType::NoAccess`] is readable.
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
 pub enum AccessType {
+    NoAccess,
+    ReadOnly,
+    ReadExecute,
     ReadWrite,
     ReadWriteExecute,
 }
 
 impl AccessType {
-    pub(crate) fn pml4_flags(self) -> PML4Flags {
-        match self {
-            AccessType::ReadWrite => PML4Flags::P | PML4Flags::RW | PML4Flags::US | PML4Flags::XD,
-            AccessType::ReadWriteExecute => PML4Flags::P | PML4Flags::RW | PML4Flags::US,
+    /// Returns the access type with the specified permissions. Write and
+    /// execute access imply read access.
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        match (read || write || execute, write, execute) {
+            (false, _, _) => AccessType::NoAccess,
+            (true, false, false) => AccessType::ReadOnly,
+            (true, false, true) => AccessType::ReadExecute,
+            (true, true, false) => AccessType::ReadWrite,
+            (true, true, true) => AccessType::ReadWriteExecute,
         }
     }
 
-    pub(crate) fn pdpt_flags(self) -> PDPTFlags {
-        match self {
-            AccessType::ReadWrite => PDPTFlags::P | PDPTFlags::RW | PDPTFlags::US | PDPTFlags::XD,
-            AccessType::ReadWriteExecute => PDPTFlags::P | PDPTFlags::RW | PDPTFlags::US,
+    /// Returns the permissions of a raw entry of any level.
+    pub const fn from_entry(entry: u64) -> Self {
+        if entry & P == 0 {
+            return AccessType::NoAccess;
         }
+
+        Self::new(true, entry & RW != 0, entry & NX == 0)
     }
 
-    pub(crate) fn pd_flags(self) -> PDFlags {
-        match self {
-            AccessType::ReadWrite => PDFlags::P | PDFlags::RW | PDFlags::US | PDFlags::XD,
-            AccessType::ReadWriteExecute => PDFlags::P | PDFlags::RW | PDFlags::US,
-        }
+    pub const fn is_readable(self) -> bool {
+        !matches!(self, AccessType::NoAccess)
     }
 
-    pub(crate) fn pt_flags(self) -> PTFlags {
-        match self {
-            AccessType::ReadWrite => {
-                PTFlags::from_iter([PTFlags::P, PTFlags::RW, PTFlags::US, PTFlags::XD])
-            }
-            AccessType::ReadWriteExecute => {
-                PTFlags::from_iter([PTFlags::P, PTFlags::RW, PTFlags::US])
-            }
-        }
+    pub const fn is_writable(self) -> bool {
+        matches!(self, AccessType::ReadWrite | AccessType::ReadWriteExecute)
     }
 
-    pub(crate) fn modify_2mb(&self, mut flags: PDFlags) -> PDFlags {
-        match self {
-            AccessType::ReadWrite => {
-                flags.insert(PDFlags::RW);
-                flags.insert(PDFlags::XD);
-            }
-            AccessType::ReadWriteExecute => {
-                flags.insert(PDFlags::RW);
-                flags.remove(PDFlags::XD);
-            }
-        }
+    pub const fn is_executable(self) -> bool {
+        matches!(self, AccessType::ReadExecute | AccessType::ReadWriteExecute)
+    }
 
-        flags
+    /// Returns the access type that allows everything that is allowed by
+    /// either of them.
+    pub const fn union(self, other: Self) -> Self {
+        Self::new(
+            self.is_readable() || other.is_readable(),
+            self.is_writable() || other.is_writable(),
+            self.is_executable() || other.is_executable(),
+        )
     }
 
-    pub(crate) fn modify_4kb(&self, mut flags: PTFlags) -> PTFlags {
-        match self {
-            AccessType::ReadWrite => {
-                flags.insert(PTFlags::RW);
-                flags.insert(PTFlags::XD);
-            }
-            AccessType::ReadWriteExecute => {
-                flags.insert(PTFlags::RW);
-                flags.remove(PTFlags::XD);
-            }
+    /// Returns the access type that only allows what is allowed by both of
+    /// them. This is how the permissions of the different levels of a
+    /// translation are combined.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self::new(
+            self.is_readable() && other.is_readable(),
+            self.is_writable() && other.is_writable(),
+            self.is_executable() && other.is_executable(),
+        )
+    }
+
+    /// Returns the raw bits of a page (1GB, 2MB or 4KB) with this access type.
+    pub(crate) const fn page_bits(self) -> u64 {
+        if !self.is_readable() {
+            return NO_ACCESS;
         }
 
-        flags
+        let mut bits = P | US;
+        if self.is_writable() {
+            bits |= RW;
+        }
+        if !self.is_executable() {
+            bits |= NX;
+        }
+
+        bits
+    }
+
+    /// Returns the raw bits of an entry that references a table. Tables can't
+    /// be inaccessible, so [`AccessType::NoAccess`] is read-only here.
+    const fn table_bits(self) -> u64 {
+        self.union(AccessType::ReadOnly).page_bits()
     }
+
+    pub(crate) fn pml4_flags(self) -> PML4Flags {
+        PML4Flags::from_bits_truncate(self.table_bits())
+    }
+
+    pub(crate) fn pdpt_flags(self) -> PDPTFlags {
+        PDPTFlags::from_bits_truncate(self.table_bits())
+    }
+
+    pub(crate) fn pd_flags(self) -> PDFlags {
+        PDFlags::from_bits_truncate(self.table_bits())
+    }
+
+    /// Replaces the permissions of a raw page entry and keeps all the other
+    /// bits, like the address and the memory type.
+    pub(crate) const fn apply(self, entry: u64) -> u64 {
+        entry & !ACCESS_BITS | self.page_bits()
+    }
+}
+
+/// Returns the number of guest physical address bits that can be translated
+/// by a four-level nested page table.
+///
+/// See `CPUID Fn8000_0008_EAX Long Mode Size Identifiers`: `GuestPhysAddrSize`
+/// is zero if it's the same as `PhysAddrSize`.
+pub fn guest_physical_address_bits() -> u32 {
+    let eax = cpuid!(0x8000_0008).eax;
+
+    let bits = match (eax >> 16) & 0xff {
+        0 => eax & 0xff,
+        guest_bits => guest_bits,
+    };
+
+    bits.min(FOUR_LEVEL_ADDRESS_BITS)
+}
+
+/// Returns whether 1GB pages are supported. See `CPUID Fn8000_0001_EDX`.
+pub fn has_1gb_pages() -> bool {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_1gib_pages())
 }
 
 /// Calculates how many pages are required to hold the specified number of
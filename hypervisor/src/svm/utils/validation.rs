@@ -0,0 +1,463 @@
+//! Architectural checks for writes to `EFER`, `CR0`, `CR3`, `CR4` and `XCR0`.
+//!
+//! The processor checks these registers when the guest is resumed, and fails
+//! with `VMEXIT_INVALID` if they contain illegal values. On real hardware, the
+//! same write would cause a #GP instead. Every error returned here should thus
+//! be injected as #GP into the guest, and the register must not be modified.
+//!
+//! See `3.1 System-Control Registers` and `15.5.1 Basic Operation` for the
+//! rules.
+
+use crate::svm::{utils::msr::EFER_SVME, vmcb::save_area::SaveArea};
+use snafu::prelude::*;
+use x86::cpuid::{cpuid, CpuId};
+
+pub const EFER_SCE: u64 = 1 << 0;
+pub const EFER_LME: u64 = 1 << 8;
+pub const EFER_LMA: u64 = 1 << 10;
+pub const EFER_NXE: u64 = 1 << 11;
+
+pub const CR0_PE: u64 = 1 << 0;
+pub const CR0_ET: u64 = 1 << 4;
+pub const CR0_WP: u64 = 1 << 16;
+pub const CR0_NW: u64 = 1 << 29;
+pub const CR0_CD: u64 = 1 << 30;
+pub const CR0_PG: u64 = 1 << 31;
+
+/// Don't flush the TLB entries of the PCID. Only used if `CR4.PCIDE` is set.
+pub const CR3_NO_FLUSH: u64 = 1 << 63;
+
+pub const CR4_VME: u64 = 1 << 0;
+pub const CR4_PVI: u64 = 1 << 1;
+pub const CR4_TSD: u64 = 1 << 2;
+pub const CR4_DE: u64 = 1 << 3;
+pub const CR4_PSE: u64 = 1 << 4;
+pub const CR4_PAE: u64 = 1 << 5;
+pub const CR4_MCE: u64 = 1 << 6;
+pub const CR4_PGE: u64 = 1 << 7;
+pub const CR4_PCE: u64 = 1 << 8;
+pub const CR4_OSFXSR: u64 = 1 << 9;
+pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
+pub const CR4_UMIP: u64 = 1 << 11;
+pub const CR4_LA57: u64 = 1 << 12;
+pub const CR4_FSGSBASE: u64 = 1 << 16;
+pub const CR4_PCIDE: u64 = 1 << 17;
+pub const CR4_OSXSAVE: u64 = 1 << 18;
+pub const CR4_SMEP: u64 = 1 << 20;
+pub const CR4_SMAP: u64 = 1 << 21;
+pub const CR4_PKE: u64 = 1 << 22;
+pub const CR4_CET: u64 = 1 << 23;
+
+pub const XCR0_X87: u64 = 1 << 0;
+pub const XCR0_SSE: u64 = 1 << 1;
+pub const XCR0_YMM: u64 = 1 << 2;
+pub const XCR0_BNDREGS: u64 = 1 << 3;
+pub const XCR0_BNDCSR: u64 = 1 << 4;
+pub const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// `L` in the compressed segment attributes of the vmcb: 64-bit code segment.
+const SEGMENT_LONG_MODE: u16 = 1 << 9;
+
+#[derive(Debug, Snafu)]
+pub enum ValidationError {
+    #[snafu(display("{} value {:#x} sets reserved bits {:#x}", register, value, bits))]
+    ReservedBits {
+        register: &'static str,
+        value: u64,
+        bits: u64,
+    },
+
+    #[snafu(display("{} value {:#x} is invalid: {}", register, value, reason))]
+    InvalidValue {
+        register: &'static str,
+        value: u64,
+        reason: &'static str,
+    },
+}
+
+/// Fails if `value` sets any bit that is not `supported`.
+fn check_reserved(
+    register: &'static str, value: u64, supported: u64,
+) -> Result<(), ValidationError> {
+    let bits = value & !supported;
+    ensure!(
+        bits == 0,
+        ReservedBitsSnafu {
+            register,
+            value,
+            bits
+        }
+    );
+
+    Ok(())
+}
+
+fn long_mode_active(save_area: &SaveArea) -> bool {
+    save_area.efer & EFER_LMA != 0
+}
+
+/// Returns the `EFER` bits that are supported by the processor.
+fn supported_efer_bits() -> u64 {
+    let mut supported = EFER_LMA;
+
+    if let Some(info) = CpuId::new().get_extended_processor_and_feature_identifiers() {
+        let features = [
+            (info.has_syscall_sysret(), EFER_SCE),
+            (info.has_64bit_mode(), EFER_LME),
+            (info.has_execute_disable(), EFER_NXE),
+            (info.has_svm(), EFER_SVME),
+        ];
+        for (_, bits) in features.iter().filter(|(has, _)| *has) {
+            supported |= bits;
+        }
+    }
+
+    supported
+}
+
+/// Validates a write to the `EFER` msr and returns the value that has to be
+/// stored in the vmcb.
+///
+/// `EFER.LMA` is controlled by the processor, so the current value is kept.
+pub fn validate_efer(save_area: &SaveArea, value: u64) -> Result<u64, ValidationError> {
+    const REGISTER: &str = "EFER";
+
+    // Bits that are already set have been accepted by the processor before, even
+    // if they are not listed here (e.g. `FFXSR` or `TCE`).
+    //
+    let supported = supported_efer_bits() | save_area.efer;
+    check_reserved(REGISTER, value, supported)?;
+
+    // Long mode can't be enabled or disabled while paging is enabled.
+    //
+    let paging = save_area.cr0 & CR0_PG != 0;
+    ensure!(
+        !paging || (save_area.efer ^ value) & EFER_LME == 0,
+        InvalidValueSnafu {
+            register: REGISTER,
+            value,
+            reason: "EFER.LME changed while paging is enabled",
+        }
+    );
+
+    Ok((value & !EFER_LMA) | (save_area.efer & EFER_LMA))
+}
+
+/// Validates a write to `CR0` and returns the value that has to be stored in
+/// the vmcb. Use [`efer_after_cr0_write`] to update `EFER.LMA` afterwards.
+pub fn validate_cr0(save_area: &SaveArea, value: u64) -> Result<u64, ValidationError> {
+    const REGISTER: &str = "CR0";
+
+    check_reserved(REGISTER, value, u32::MAX as u64)?;
+
+    let reason = if value & CR0_PG != 0 && value & CR0_PE == 0 {
+        Some("paging enabled without protection")
+    } else if value & CR0_NW != 0 && value & CR0_CD == 0 {
+        Some("not-writethrough without cache-disable")
+    } else if value & CR0_PG != 0 && save_area.efer & EFER_LME != 0 && save_area.cr4 & CR4_PAE == 0
+    {
+        Some("long mode activated without PAE")
+    } else if value & CR0_PG == 0
+        && long_mode_active(save_area)
+        && save_area.cs_attrib & SEGMENT_LONG_MODE != 0
+    {
+        Some("paging disabled in 64-bit mode")
+    } else if value & CR0_WP == 0 && save_area.cr4 & CR4_CET != 0 {
+        Some("write-protect disabled while CET is enabled")
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        return InvalidValueSnafu {
+            register: REGISTER,
+            value,
+            reason,
+        }
+        .fail();
+    }
+
+    // `CR0.ET` is hardwired to 1.
+    //
+    Ok(value | CR0_ET)
+}
+
+/// Returns the `EFER` value after `cr0` has been written. Long mode is active
+/// when it's enabled and paging is enabled.
+pub fn efer_after_cr0_write(efer: u64, cr0: u64) -> u64 {
+    if efer & EFER_LME != 0 && cr0 & CR0_PG != 0 {
+        efer | EFER_LMA
+    } else {
+        efer & !EFER_LMA
+    }
+}
+
+/// Validates a write to `CR3` and returns the value that has to be stored in
+/// the vmcb.
+///
+/// [`CR3_NO_FLUSH`] is cleared if PCIDs are enabled. It's not part of the
+/// register, and `vmrun` fails if it's set.
+pub fn validate_cr3(save_area: &SaveArea, value: u64) -> Result<u64, ValidationError> {
+    const REGISTER: &str = "CR3";
+
+    let value = if save_area.cr4 & CR4_PCIDE != 0 {
+        value & !CR3_NO_FLUSH
+    } else {
+        value
+    };
+
+    // In long mode, the address can use all the bits of the physical address
+    // width. The bits above are reserved.
+    //
+    if long_mode_active(save_area) {
+        let address_bits = cpuid!(0x8000_0008).eax & 0xff;
+        check_reserved(REGISTER, value, (1 << address_bits) - 1)?;
+    }
+
+    Ok(value)
+}
+
+/// Returns the `CR4` bits that are supported by the processor.
+fn supported_cr4_bits() -> u64 {
+    let cpuid = CpuId::new();
+    let mut supported = CR4_TSD | CR4_PCE;
+
+    if let Some(info) = cpuid.get_feature_info() {
+        let features = [
+            (info.has_vme(), CR4_VME | CR4_PVI),
+            (info.has_de(), CR4_DE),
+            (info.has_pse(), CR4_PSE),
+            (info.has_pae(), CR4_PAE),
+            (info.has_mce(), CR4_MCE),
+            (info.has_pge(), CR4_PGE),
+            (info.has_fxsave_fxstor(), CR4_OSFXSR),
+            (info.has_sse(), CR4_OSXMMEXCPT),
+            (info.has_pcid(), CR4_PCIDE),
+            (info.has_xsave(), CR4_OSXSAVE),
+        ];
+        for (_, bits) in features.iter().filter(|(has, _)| *has) {
+            supported |= bits;
+        }
+    }
+
+    if let Some(info) = cpuid.get_extended_feature_info() {
+        let features = [
+            (info.has_umip(), CR4_UMIP),
+            (info.has_la57(), CR4_LA57),
+            (info.has_fsgsbase(), CR4_FSGSBASE),
+            (info.has_smep(), CR4_SMEP),
+            (info.has_smap(), CR4_SMAP),
+            (info.has_pku(), CR4_PKE),
+            (info.has_cet_ss(), CR4_CET),
+        ];
+        for (_, bits) in features.iter().filter(|(has, _)| *has) {
+            supported |= bits;
+        }
+    }
+
+    supported
+}
+
+/// Validates a write to `CR4` and returns the value that has to be stored in
+/// the vmcb.
+pub fn validate_cr4(save_area: &SaveArea, value: u64) -> Result<u64, ValidationError> {
+    const REGISTER: &str = "CR4";
+
+    // See `validate_efer` for why the current bits are allowed.
+    //
+    check_reserved(REGISTER, value, supported_cr4_bits() | save_area.cr4)?;
+
+    let long_mode = long_mode_active(save_area);
+    let changed = save_area.cr4 ^ value;
+
+    let reason = if long_mode && value & CR4_PAE == 0 {
+        Some("PAE disabled in long mode")
+    } else if long_mode && changed & CR4_LA57 != 0 {
+        Some("LA57 changed in long mode")
+    } else if changed & value & CR4_PCIDE != 0 && (!long_mode || save_area.cr3 & 0xfff != 0) {
+        Some("PCIDE enabled outside of long mode or with a non-zero PCID")
+    } else if value & CR4_CET != 0 && save_area.cr0 & CR0_WP == 0 {
+        Some("CET enabled while write-protect is disabled")
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        return InvalidValueSnafu {
+            register: REGISTER,
+            value,
+            reason,
+        }
+        .fail();
+    }
+
+    Ok(value)
+}
+
+/// Validates a write to `XCR0` with `XSETBV` and returns the value that has to
+/// be written. See `11.5.2 XFEATURE_ENABLED_MASK`.
+pub fn validate_xcr0(value: u64) -> Result<u64, ValidationError> {
+    const REGISTER: &str = "XCR0";
+
+    // `CPUID Fn0000_000D_EDX_EAX_x0`: The state components that can be enabled.
+    //
+    let result = cpuid!(0xd, 0);
+    let supported = (result.edx as u64) << 32 | result.eax as u64;
+    check_reserved(REGISTER, value, supported)?;
+
+    let avx512 = value & XCR0_AVX512;
+    let mpx = value & (XCR0_BNDREGS | XCR0_BNDCSR);
+
+    let reason = if value & XCR0_X87 == 0 {
+        Some("x87 state disabled")
+    } else if value & XCR0_YMM != 0 && value & XCR0_SSE == 0 {
+        Some("YMM state enabled without SSE state")
+    } else if avx512 != 0 && (avx512 != XCR0_AVX512 || value & XCR0_YMM == 0) {
+        Some("AVX-512 state partially enabled or enabled without YMM state")
+    } else if mpx != 0 && mpx != XCR0_BNDREGS | XCR0_BNDCSR {
+        Some("MPX state partially enabled")
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        return InvalidValueSnafu {
+            register: REGISTER,
+            value,
+            reason,
+        }
+        .fail();
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64-bit mode with paging enabled.
+    fn long_mode() -> SaveArea {
+        SaveArea {
+            efer: EFER_LME | EFER_LMA,
+            cr0: CR0_PE | CR0_PG | CR0_ET,
+            cr4: CR4_PAE,
+            cs_attrib: SEGMENT_LONG_MODE,
+            ..Default::default()
+        }
+    }
+
+    fn is_reserved<T>(result: Result<T, ValidationError>) -> bool {
+        matches!(result, Err(ValidationError::ReservedBits { .. }))
+    }
+
+    fn is_invalid<T>(result: Result<T, ValidationError>) -> bool {
+        matches!(result, Err(ValidationError::InvalidValue { .. }))
+    }
+
+    #[test]
+    fn efer() {
+        let save_area = long_mode();
+
+        // LMA is kept, even if the guest tries to clear it.
+        //
+        assert_eq!(
+            validate_efer(&save_area, EFER_LME).unwrap(),
+            EFER_LME | EFER_LMA
+        );
+        assert!(is_reserved(validate_efer(&save_area, EFER_LME | 1 << 63)));
+        assert!(is_invalid(validate_efer(&save_area, 0)));
+
+        let save_area = SaveArea::default();
+        assert_eq!(validate_efer(&save_area, EFER_LME).unwrap(), EFER_LME);
+    }
+
+    #[test]
+    fn cr0() {
+        let save_area = long_mode();
+
+        assert_eq!(
+            validate_cr0(&save_area, CR0_PE | CR0_PG).unwrap(),
+            CR0_PE | CR0_PG | CR0_ET
+        );
+        assert!(is_reserved(validate_cr0(&save_area, 1 << 32)));
+        assert!(is_invalid(validate_cr0(&save_area, CR0_PG)));
+        assert!(is_invalid(validate_cr0(
+            &save_area,
+            CR0_PE | CR0_PG | CR0_NW
+        )));
+        assert!(is_invalid(validate_cr0(&save_area, CR0_PE)));
+
+        let mut save_area = SaveArea {
+            efer: EFER_LME,
+            ..Default::default()
+        };
+        assert!(is_invalid(validate_cr0(&save_area, CR0_PE | CR0_PG)));
+
+        save_area.cr4 = CR4_CET;
+        save_area.efer = 0;
+        assert!(is_invalid(validate_cr0(&save_area, CR0_PE)));
+    }
+
+    #[test]
+    fn efer_after_cr0() {
+        assert_eq!(efer_after_cr0_write(EFER_LME, CR0_PG), EFER_LME | EFER_LMA);
+        assert_eq!(efer_after_cr0_write(EFER_LME | EFER_LMA, 0), EFER_LME);
+        assert_eq!(efer_after_cr0_write(0, CR0_PG), 0);
+    }
+
+    #[test]
+    fn cr3() {
+        let mut save_area = long_mode();
+
+        assert_eq!(validate_cr3(&save_area, 0x1000).unwrap(), 0x1000);
+        assert!(is_reserved(validate_cr3(&save_area, CR3_NO_FLUSH | 0x1000)));
+
+        save_area.cr4 |= CR4_PCIDE;
+        assert_eq!(
+            validate_cr3(&save_area, CR3_NO_FLUSH | 0x1001).unwrap(),
+            0x1001
+        );
+
+        // Outside of long mode, the upper bits are ignored.
+        //
+        let save_area = SaveArea::default();
+        assert_eq!(validate_cr3(&save_area, 1 << 40).unwrap(), 1 << 40);
+    }
+
+    #[test]
+    fn cr4() {
+        let save_area = long_mode();
+
+        assert_eq!(
+            validate_cr4(&save_area, CR4_PAE | CR4_PGE).unwrap(),
+            CR4_PAE | CR4_PGE
+        );
+        assert!(is_reserved(validate_cr4(&save_area, CR4_PAE | 1 << 31)));
+        assert!(is_invalid(validate_cr4(&save_area, 0)));
+
+        // Bits that are already set are accepted, even if the processor doesn't
+        // report them.
+        //
+        let mut save_area = long_mode();
+        save_area.cr4 |= 1 << 31;
+        assert!(validate_cr4(&save_area, CR4_PAE | 1 << 31).is_ok());
+
+        let mut save_area = long_mode();
+        save_area.cr4 |= CR4_LA57;
+        assert!(is_invalid(validate_cr4(&save_area, CR4_PAE)));
+
+        let mut save_area = long_mode();
+        save_area.cr3 = 0x1001;
+        save_area.cr4 |= CR4_CET;
+        assert!(is_invalid(validate_cr4(&save_area, CR4_PAE | CR4_PCIDE)));
+        assert!(is_invalid(validate_cr4(&save_area, CR4_PAE | CR4_CET)));
+    }
+
+    #[test]
+    fn xcr0() {
+        assert_eq!(
+            validate_xcr0(XCR0_X87 | XCR0_SSE).unwrap(),
+            XCR0_X87 | XCR0_SSE
+        );
+        assert!(is_reserved(validate_xcr0(XCR0_X87 | 1 << 63)));
+        assert!(is_invalid(validate_xcr0(XCR0_SSE)));
+    }
+}
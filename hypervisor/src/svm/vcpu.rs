@@ -4,8 +4,9 @@ use crate::{
         vmexit::cpuid::CPUID_DEVIRTUALIZE, vmlaunch::launch_vm,
     },
     utils::{
+        context::Context,
         debug::dbg_break,
-        nt::{Context, KeBugCheck, MANUALLY_INITIATED_CRASH},
+        platform::{CurrentPlatform, Platform},
     },
 };
 use alloc::boxed::Box;
@@ -80,7 +81,7 @@ impl Vcpu {
             // We should never continue the guest execution here.
             //
             dbg_break!();
-            unsafe { KeBugCheck(MANUALLY_INITIATED_CRASH) };
+            CurrentPlatform::bug_check();
         }
 
         true
@@ -106,4 +107,14 @@ impl Vcpu {
     pub fn id(&self) -> u32 {
         self.index
     }
+
+    /// Exports the recorded vmexits of this processor. Returns `None` if the
+    /// processor hasn't been virtualized yet.
+    ///
+    /// Note: The processor might still be recording while the log is exported.
+    /// To get a consistent log, call this after devirtualizing.
+    #[cfg(feature = "vmexit-recorder")]
+    pub fn vmexit_log(&self) -> Option<alloc::vec::Vec<u8>> {
+        self.data.get().map(|data| data.recorder.export(self.index))
+    }
 }
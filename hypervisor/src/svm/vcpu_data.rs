@@ -1,23 +1,33 @@
+#[cfg(feature = "vmexit-recorder")]
+use crate::svm::vmexit::recorder::VmExitRecorder;
 use crate::{
     svm::{
+        events::PendingEvents,
         shared_data::SharedData,
-        utils::msr::SVM_MSR_VM_HSAVE_PA,
+        tlb::PRIMARY_ASID,
+        utils::{
+            guest_paging::{AccessKind, GuestPaging, GuestWalkError, Translation},
+            msr::{MsrIdt, SVM_MSR_VM_HSAVE_PA},
+        },
         vmcb::{
             control_area::{
                 ExceptionVector, InterceptMisc1, InterceptMisc2, LbrVirt, NpEnable, VmExitCode,
+                VmcbClean,
             },
             Vmcb,
         },
-        vmexit::vmexit_installed,
+        vmexit::{vmexit_installed, VmExitHandlers},
         VmExitType,
     },
     utils::{
         addresses::physical_address,
-        nt::{Context, KTRAP_FRAME},
+        context::{Context, KTRAP_FRAME},
+        mapping_window::MappingWindow,
     },
 };
 use alloc::boxed::Box;
-use core::{any::Any, arch::asm, ptr, ptr::NonNull};
+use core::{any::Any, arch::asm, mem::MaybeUninit, ptr, ptr::NonNull};
+use tinyvec::ArrayVec;
 use x86::{
     bits64::paging::{PAddr, BASE_PAGE_SIZE},
     controlregs::cr3,
@@ -25,6 +35,10 @@ use x86::{
 };
 
 pub const KERNEL_STACK_SIZE: usize = 0x6000;
+
+/// The number of pages a single access to the guest memory can span. See
+/// [`VcpuData::copy_from_guest`].
+pub const MAX_GUEST_ACCESS_PAGES: usize = 16;
 pub const STACK_CONTENTS_SIZE: usize = KERNEL_STACK_SIZE
     - (core::mem::size_of::<*mut u64>() * 6)
     - core::mem::size_of::<KTRAP_FRAME>();
@@ -47,6 +61,23 @@ pub struct HostStackLayout {
 }
 const_assert_eq!(core::mem::size_of::<HostStackLayout>(), KERNEL_STACK_SIZE);
 
+/// Types that can be read from the guest memory with
+/// [`VcpuData::read_guest`].
+///
+/// # Safety
+///
+/// Every bit pattern of the size of the type has to be a valid value of it,
+/// which is the case for integers (and arrays of them), but not for `bool`,
+/// enums or references.
+pub unsafe trait FromGuestBytes: Copy {}
+
+macro impl_from_guest_bytes($($ty:ty),*) {
+    $(unsafe impl FromGuestBytes for $ty {})*
+}
+impl_from_guest_bytes!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+unsafe impl<T: FromGuestBytes, const N: usize> FromGuestBytes for [T; N] {}
+
 /// The utils for a single **virtual** processor.
 #[repr(C, align(4096))]
 pub struct VcpuData {
@@ -65,6 +96,20 @@ pub struct VcpuData {
 
     /// The previous vmexit code. Can be used by the vmexit handlers.
     pub prev_vmexit: VmExitCode,
+
+    /// The events that still have to be injected into the guest.
+    pub pending_events: PendingEvents,
+
+    /// Loaded while accessing msrs on behalf of the guest.
+    pub(crate) msr_idt: Box<MsrIdt>,
+
+    /// Maps the guest memory while it's accessed on behalf of the guest.
+    /// `None` if it couldn't be reserved.
+    pub(crate) mapping_window: Option<MappingWindow>,
+
+    /// Records all the vmexits of this processor.
+    #[cfg(feature = "vmexit-recorder")]
+    pub recorder: VmExitRecorder,
 }
 const_assert_eq!(
     core::mem::size_of::<VcpuData>(),
@@ -73,6 +118,41 @@ const_assert_eq!(
 
 impl VcpuData {
     pub(crate) fn new(shared_data: &mut SharedData, context: Context) -> Box<Self> {
+        let mut instance = Self::allocate(shared_data);
+
+        // Get physical addresses of important utils structures
+        //
+        let pml4_pa = PAddr::from(shared_data.primary_pml4.as_u64());
+        let msr_pm_pa = physical_address(shared_data.msr_bitmap.as_mut() as *mut _ as _);
+        let io_pm_pa = physical_address(shared_data.io_bitmap.as_mut() as *mut _ as _);
+
+        instance.configure_interceptions(shared_data.handlers.get());
+        instance.configure_npt(shared_data.handlers.get(), pml4_pa);
+        instance.configure_msr_bitmap(msr_pm_pa);
+        instance.configure_io_bitmap(io_pm_pa);
+        instance.configure_vmcb();
+        instance.msr_idt.capture();
+
+        // Enable `Hardware Acceleration for LBR Virtualization`. See 15.23 for more
+        // information. This will save the last branch msr in the guest save area.
+        // TODO: Check if it's supported (15.23.2)
+        instance
+            .guest_vmcb
+            .control_area
+            .lbr_virtualization_enable
+            .insert(LbrVirt::ENABLE_LBR);
+
+        // Setup guest state based on current system state.
+        //
+        instance.guest_vmcb.save_area.build(context);
+
+        instance
+    }
+
+    /// Creates a new instance with zeroed vmcbs. Unlike [`VcpuData::new`],
+    /// this doesn't touch any processor state, which also means that the
+    /// instance can't be used to run the guest.
+    pub(crate) fn allocate(shared_data: &mut SharedData) -> Box<Self> {
         // Create instance
         //
         let instance = Self {
@@ -90,6 +170,11 @@ impl VcpuData {
             host_vmcb: unsafe { core::mem::zeroed() },
             host_state_area: [0u8; BASE_PAGE_SIZE],
             prev_vmexit: VmExitCode::VMEXIT_INVALID,
+            pending_events: PendingEvents::default(),
+            msr_idt: unsafe { Box::new_zeroed().assume_init() },
+            mapping_window: MappingWindow::new(),
+            #[cfg(feature = "vmexit-recorder")]
+            recorder: VmExitRecorder::default(),
         };
         let mut instance = Box::new(instance);
 
@@ -99,29 +184,6 @@ impl VcpuData {
         instance.host_stack_layout.guest_vmcb_pa =
             physical_address(&instance.guest_vmcb as *const _ as _).as_u64();
 
-        // Get physical addresses of important utils structures
-        //
-        let pml4_pa = physical_address(shared_data.primary_npt.pml4.as_ptr() as _);
-        let msr_pm_pa = physical_address(shared_data.msr_bitmap.as_mut() as *mut _ as _);
-
-        instance.configure_interceptions();
-        instance.configure_npt(pml4_pa);
-        instance.configure_msr_bitmap(msr_pm_pa);
-        instance.configure_vmcb();
-
-        // Enable `Hardware Acceleration for LBR Virtualization`. See 15.23 for more
-        // information. This will save the last branch msr in the guest save area.
-        // TODO: Check if it's supported (15.23.2)
-        instance
-            .guest_vmcb
-            .control_area
-            .lbr_virtualization_enable
-            .insert(LbrVirt::ENABLE_LBR);
-
-        // Setup guest state based on current system state.
-        //
-        instance.guest_vmcb.save_area.build(context);
-
         instance
     }
 
@@ -149,71 +211,103 @@ impl VcpuData {
         unsafe { asm!("vmsave rax", in("rax") self.host_stack_layout.host_vmcb_pa) };
     }
 
-    fn configure_interceptions(&mut self) {
-        // TODO: Allow custom exceptions to be hooked. This is tricky, because we can't
-        // map ExceptionVector to VmExitCode.
-
-        if vmexit_installed!(VmExitType::Breakpoint) {
-            log::info!("Intercepting breakpoint");
-            self.guest_vmcb
-                .control_area
-                .intercept_exception
-                .insert(ExceptionVector::BREAKPOINT);
-        }
-
-        if vmexit_installed!(VmExitType::Cpuid(_)) {
-            log::info!("Intercepting cpuid");
-            self.guest_vmcb
-                .control_area
-                .intercept_misc1
-                .insert(InterceptMisc1::INTERCEPT_CPUID);
-        }
+    fn configure_interceptions(&mut self, handlers: &VmExitHandlers) {
+        let control_area = &mut self.guest_vmcb.control_area;
 
-        if vmexit_installed!(VmExitType::Rdtsc) {
-            log::info!("Intercepting rdtsc");
-            self.guest_vmcb
-                .control_area
-                .intercept_misc1
-                .insert(InterceptMisc1::INTERCEPT_RDTSC);
+        // Enables the intercepts if there's a handler for them. Otherwise they are
+        // cleared, in case the handler has been removed.
+        //
+        macro intercept($($field:ident: $vmexit_type:pat => $intercept:expr;)*) {
+            $(
+                if vmexit_installed!(handlers, $vmexit_type) {
+                    log::trace!("Intercepting {}", stringify!($vmexit_type));
+                    control_area.$field.insert($intercept);
+                } else {
+                    control_area.$field.remove($intercept);
+                }
+            )*
         }
 
-        if vmexit_installed!(VmExitType::Rdtscp) {
-            log::info!("Intercepting rdtscp");
-            self.guest_vmcb
-                .control_area
-                .intercept_misc2
-                .insert(InterceptMisc2::INTERCEPT_RDTSCP);
+        intercept! {
+            intercept_misc1: VmExitType::Intr => InterceptMisc1::INTERCEPT_INTR;
+            intercept_misc1: VmExitType::Nmi => InterceptMisc1::INTERCEPT_NMI;
+            intercept_misc1: VmExitType::Smi => InterceptMisc1::INTERCEPT_SMI;
+            intercept_misc1: VmExitType::Init => InterceptMisc1::INTERCEPT_INIT;
+            intercept_misc1: VmExitType::Rdtsc => InterceptMisc1::INTERCEPT_RDTSC;
+            intercept_misc1: VmExitType::Rdpmc => InterceptMisc1::INTERCEPT_RDPMC;
+            intercept_misc1: VmExitType::Cpuid(_) => InterceptMisc1::INTERCEPT_CPUID;
+            intercept_misc1: VmExitType::Invd => InterceptMisc1::INTERCEPT_INVD;
+            intercept_misc1: VmExitType::Pause => InterceptMisc1::INTERCEPT_PAUSE;
+            intercept_misc1: VmExitType::Hlt => InterceptMisc1::INTERCEPT_HLT;
+            intercept_misc1: VmExitType::Invlpg => InterceptMisc1::INTERCEPT_INVLPG;
+            intercept_misc1: VmExitType::Shutdown => InterceptMisc1::INTERCEPT_SHUTDOWN;
+
+            intercept_misc2: VmExitType::Vmcall => InterceptMisc2::INTERCEPT_VMCALL;
+            intercept_misc2: VmExitType::Vmload => InterceptMisc2::INTERCEPT_VMLOAD;
+            intercept_misc2: VmExitType::Vmsave => InterceptMisc2::INTERCEPT_VMSAVE;
+            intercept_misc2: VmExitType::Stgi => InterceptMisc2::INTERCEPT_STGI;
+            intercept_misc2: VmExitType::Clgi => InterceptMisc2::INTERCEPT_CLGI;
+            intercept_misc2: VmExitType::Skinit => InterceptMisc2::INTERCEPT_SKINIT;
+            intercept_misc2: VmExitType::Rdtscp => InterceptMisc2::INTERCEPT_RDTSCP;
+            intercept_misc2: VmExitType::Wbinvd => InterceptMisc2::INTERCEPT_WBINVD;
+            intercept_misc2: VmExitType::Monitor => InterceptMisc2::INTERCEPT_MONITOR;
+            intercept_misc2: VmExitType::Mwait => InterceptMisc2::INTERCEPT_MWAIT
+                | InterceptMisc2::INTERCEPT_MWAIT_CONDITIONAL;
+            intercept_misc2: VmExitType::Xsetbv => InterceptMisc2::INTERCEPT_XSETBV;
         }
 
-        if vmexit_installed!(VmExitType::Vmcall) {
-            log::info!("Intercepting vmcall");
-            self.guest_vmcb
-                .control_area
-                .intercept_misc2
-                .insert(InterceptMisc2::INTERCEPT_VMCALL);
+        // Control and debug registers and exceptions have one intercept bit per
+        // register/vector.
+        //
+        let register_bit = |register: u8| 1u16.checked_shl(register as u32).unwrap_or_default();
+
+        control_area.intercept_exception = ExceptionVector::empty();
+        control_area.intercept_cr_read = 0;
+        control_area.intercept_cr_write = 0;
+        control_area.intercept_dr_read = 0;
+        control_area.intercept_dr_write = 0;
+        for vmexit_type in handlers.types() {
+            match *vmexit_type {
+                VmExitType::Exception(vector) => {
+                    control_area.intercept_exception |= ExceptionVector::from_vector(vector)
+                }
+                VmExitType::CrRead(register) => {
+                    control_area.intercept_cr_read |= register_bit(register)
+                }
+                VmExitType::CrWrite(register) => {
+                    control_area.intercept_cr_write |= register_bit(register)
+                }
+                VmExitType::DrRead(register) => {
+                    control_area.intercept_dr_read |= register_bit(register)
+                }
+                VmExitType::DrWrite(register) => {
+                    control_area.intercept_dr_write |= register_bit(register)
+                }
+                _ => {}
+            }
         }
 
-        self.guest_vmcb
-            .control_area
+        // Nested virtualization is not supported, but `vmrun` has to be intercepted
+        // anyway.
+        //
+        control_area
             .intercept_misc2
             .insert(InterceptMisc2::INTERCEPT_VMRUN);
     }
 
-    fn configure_npt(&mut self, pml4_pa: PAddr) {
+    fn configure_npt(&mut self, handlers: &VmExitHandlers, pml4_pa: PAddr) {
         // Specify guest's address space ID (ASID). TLB is maintained by the ID for
         // guests. Use the same value for all processors since all of them run a
-        // single guest in our case. Use 1 as the most likely supported ASID by the
-        // processor. The actual the supported number of ASID can be obtained with
-        // CPUID. See "CPUID Fn8000_000A_EBX SVM Revision and Feature
-        // Identification". Zero of ASID is reserved and illegal.
+        // single guest in our case. Other nested page tables can use their own
+        // ASID, see `tlb`.
         //
         // See this for explanation of what an ASID is: https://stackoverflow.com/a/52725044
         //
-        self.guest_vmcb.control_area.guest_asid = 1;
+        self.guest_vmcb.control_area.guest_asid = PRIMARY_ASID;
 
         // Enable nested page tables (only when we also specify a page fault handler).
         //
-        if vmexit_installed!(VmExitType::NestedPageFault) {
+        if vmexit_installed!(handlers, VmExitType::NestedPageFault) {
             log::info!("Configuring nested page tables");
             self.guest_vmcb
                 .control_area
@@ -228,6 +322,22 @@ impl VcpuData {
         }
     }
 
+    /// Recomputes the intercepts based on the current vmexit handlers. Has to
+    /// be called from the vmexit handler of this processor.
+    ///
+    /// Note: This doesn't enable or disable nested paging.
+    pub(crate) fn update_interceptions(&mut self) {
+        let handlers = unsafe { self.host_stack_layout.shared_data.as_ref() }
+            .handlers
+            .get();
+        self.configure_interceptions(handlers);
+
+        // The intercept vectors and the permission maps might have changed, so the
+        // processor must not use the cached values.
+        //
+        self.guest_vmcb.mark_dirty(VmcbClean::I | VmcbClean::IOPM);
+    }
+
     /// Trigger #VMEXIT on MSR exit as defined in msr permission map.
     fn configure_msr_bitmap(&mut self, msr_pa: PAddr) {
         self.guest_vmcb
@@ -237,6 +347,16 @@ impl VcpuData {
 
         self.guest_vmcb.control_area.msrpm_base_pa = msr_pa.as_u64();
     }
+
+    /// Trigger #VMEXIT on IN/OUT as defined in io permission map.
+    fn configure_io_bitmap(&mut self, io_pa: PAddr) {
+        self.guest_vmcb
+            .control_area
+            .intercept_misc1
+            .insert(InterceptMisc1::INTERCEPT_IOIO_PROT);
+
+        self.guest_vmcb.control_area.iopm_base_pa = io_pa.as_u64();
+    }
 }
 
 // Helper functions to make life a little easier.
@@ -245,8 +365,276 @@ impl VcpuData {
         unsafe { self.host_stack_layout.shared_data.as_mut() }
     }
 
-    pub fn custom_data<T: 'static + Any>(&mut self) -> Option<&mut T> {
-        None
-        // self.custom_data.downcast_mut()
+    /// Returns the custom data that has been passed to
+    /// [`HypervisorBuilder::with_data`](crate::svm::HypervisorBuilder::with_data).
+    /// Returns `None` if there's no data or if it has a different type.
+    ///
+    /// Note: The other processors can access the data at the same time.
+    pub fn custom_data<T: Any + Send + Sync>(&self) -> Option<&T> {
+        unsafe { self.host_stack_layout.shared_data.as_ref() }
+            .data
+            .as_ref()?
+            .downcast_ref()
+    }
+}
+
+// Access to the guest memory. The vmexit handlers must not dereference guest
+// pointers directly, because the memory might not be mapped (or paged out) and
+// a page fault in the host can't be handled.
+impl VcpuData {
+    /// Reads the guest memory at the guest virtual address into the buffer.
+    ///
+    /// The address is translated page by page with the current paging state of
+    /// the guest and the active nested page table, so the buffer can cross
+    /// page boundaries (up to [`MAX_GUEST_ACCESS_PAGES`] pages). Sets the
+    /// accessed bits of the guest page tables like the processor would. If the
+    /// guest would fault, the error can be injected with
+    /// [`GuestWalkError::inject`].
+    ///
+    /// The nested page table has to allow the access as well, otherwise
+    /// [`GuestWalkError::NestedPageFault`] is returned.
+    pub fn copy_from_guest(
+        &mut self, address: u64, buffer: &mut [u8],
+    ) -> Result<(), GuestWalkError> {
+        self.access_guest(
+            address,
+            buffer.len(),
+            AccessKind::Read,
+            |offset, host_va, size| unsafe {
+                ptr::copy_nonoverlapping(host_va as *const u8, buffer[offset..].as_mut_ptr(), size)
+            },
+        )
+    }
+
+    /// Writes the buffer to the guest memory at the guest virtual address. See
+    /// [`VcpuData::copy_from_guest`].
+    ///
+    /// All the pages are translated before anything is written, so nothing is
+    /// written if any of them can't be written.
+    pub fn copy_to_guest(&mut self, address: u64, buffer: &[u8]) -> Result<(), GuestWalkError> {
+        self.access_guest(
+            address,
+            buffer.len(),
+            AccessKind::Write,
+            |offset, host_va, size| unsafe {
+                ptr::copy_nonoverlapping(buffer[offset..].as_ptr(), host_va as *mut u8, size)
+            },
+        )
+    }
+
+    /// Like [`VcpuData::copy_to_guest`], but the buffer is only filled after
+    /// all the pages have been translated. Used when producing the data has
+    /// side effects (e.g. reading a port), which must not happen if the guest
+    /// faults.
+    pub fn copy_to_guest_with(
+        &mut self, address: u64, buffer: &mut [u8], fill: impl FnOnce(&mut [u8]),
+    ) -> Result<(), GuestWalkError> {
+        let pages = self.translate_range(address, buffer.len(), AccessKind::Write)?;
+        fill(buffer);
+
+        self.access_pages(pages, |offset, host_va, size| unsafe {
+            ptr::copy_nonoverlapping(buffer[offset..].as_ptr(), host_va as *mut u8, size)
+        })
+    }
+
+    /// Reads a value from the guest memory. See [`VcpuData::copy_from_guest`].
+    pub fn read_guest<T: FromGuestBytes>(&mut self, address: u64) -> Result<T, GuestWalkError> {
+        let mut value = MaybeUninit::<T>::zeroed();
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(
+                value.as_mut_ptr() as *mut u8,
+                core::mem::size_of::<T>(),
+            )
+        };
+        self.copy_from_guest(address, buffer)?;
+
+        // SAFETY: Every bit pattern is a valid `T`, see `FromGuestBytes`.
+        //
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Translates all the pages of the guest range first. Afterwards, the
+    /// function is called with the offset, the host virtual address and the
+    /// size of every part of the range that is in a single page.
+    fn access_guest(
+        &mut self, address: u64, len: usize, access: AccessKind, f: impl FnMut(usize, u64, usize),
+    ) -> Result<(), GuestWalkError> {
+        let pages = self.translate_range(address, len, access)?;
+        self.access_pages(pages, f)
+    }
+
+    /// Returns the host physical address and the size of every part of the
+    /// guest range that is in a single page.
+    fn translate_range(
+        &mut self, address: u64, len: usize, access: AccessKind,
+    ) -> Result<ArrayVec<[(u64, usize); MAX_GUEST_ACCESS_PAGES]>, GuestWalkError> {
+        let page_count =
+            (address as usize % BASE_PAGE_SIZE + len + BASE_PAGE_SIZE - 1) / BASE_PAGE_SIZE;
+        if page_count > MAX_GUEST_ACCESS_PAGES {
+            return Err(GuestWalkError::RangeTooLarge { size: len });
+        }
+
+        // Guest pages are at least 4KB, so every 4KB of the guest virtual address
+        // space is contiguous in host physical memory.
+        //
+        let mut pages = ArrayVec::<[(u64, usize); MAX_GUEST_ACCESS_PAGES]>::new();
+        let mut offset = 0;
+        while offset < len {
+            let page_address = address.wrapping_add(offset as u64);
+            let page_offset = page_address as usize & (BASE_PAGE_SIZE - 1);
+            let size = usize::min(len - offset, BASE_PAGE_SIZE - page_offset);
+
+            let translation = self.translate_guest(page_address, access)?;
+            pages.push((translation.host_pa, size));
+            offset += size;
+        }
+
+        Ok(pages)
+    }
+
+    /// Maps the translated pages one after another, see
+    /// [`VcpuData::access_guest`].
+    fn access_pages(
+        &mut self, pages: ArrayVec<[(u64, usize); MAX_GUEST_ACCESS_PAGES]>,
+        mut f: impl FnMut(usize, u64, usize),
+    ) -> Result<(), GuestWalkError> {
+        let window = self
+            .mapping_window
+            .as_mut()
+            .ok_or(GuestWalkError::NoMappingWindow)?;
+
+        let mut offset = 0;
+        for (host_pa, size) in pages {
+            window.with(host_pa, |host_va| f(offset, host_va, size));
+            offset += size;
+        }
+
+        Ok(())
+    }
+
+    /// Translates the guest virtual address and sets the accessed and dirty
+    /// bits. Pages that aren't mapped yet in a lazy nested page table are
+    /// mapped, like the nested page fault handler does.
+    fn translate_guest(
+        &mut self, address: u64, access: AccessKind,
+    ) -> Result<Translation, GuestWalkError> {
+        let paging = GuestPaging::new(&self.guest_vmcb.save_area);
+        let ncr3 = self.guest_vmcb.control_area.ncr3;
+
+        let window = self
+            .mapping_window
+            .as_mut()
+            .ok_or(GuestWalkError::NoMappingWindow)?;
+        let mut npt = unsafe { self.host_stack_layout.shared_data.as_ref() }.active_npt(ncr3);
+
+        loop {
+            let result = paging.access(&npt, window, address, access);
+
+            // Pages without any access are mapped, but not present.
+            //
+            let guest_pa = match result {
+                Err(GuestWalkError::NestedPageFault { guest_pa })
+                    if npt.effective_access_type(guest_pa).is_none() =>
+                {
+                    guest_pa
+                }
+                _ => return result,
+            };
+            if npt.lazy_access_type().is_none() {
+                return result;
+            }
+
+            if let Err(error) = npt.map_on_demand(guest_pa) {
+                log::error!("Failed to map {:#x}: {}", guest_pa, error);
+                return result;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{utils::paging::AccessType, vmexit::harness::VmExitHarness};
+    use alloc::{vec, vec::Vec};
+
+    #[repr(C, align(4096))]
+    struct Page([u8; BASE_PAGE_SIZE]);
+
+    /// Maps the guest physical pages 0x1000 and 0x2000 to the returned host
+    /// pages, and 0x3000 as read-only. Paging is disabled in the guest, so the
+    /// guest virtual addresses are the same.
+    fn guest_memory(harness: &mut VmExitHarness) -> Vec<Box<Page>> {
+        let pages = (0..3)
+            .map(|_| Box::new(Page([0; BASE_PAGE_SIZE])))
+            .collect::<Vec<_>>();
+
+        let mut npt = harness.shared_data().primary_npt.lock();
+        for (i, page) in pages.iter().enumerate() {
+            let access_type = if i == 2 {
+                AccessType::ReadOnly
+            } else {
+                AccessType::ReadWrite
+            };
+            npt.map_4kb(0x1000 * (i as u64 + 1), page.0.as_ptr() as u64, access_type)
+                .unwrap();
+        }
+        drop(npt);
+
+        pages
+    }
+
+    #[test]
+    fn copy_across_pages() {
+        let mut harness = VmExitHarness::new();
+        let pages = guest_memory(&mut harness);
+        let data = harness.vcpu_data();
+
+        let buffer = (1..=16).collect::<Vec<u8>>();
+        data.copy_to_guest(0x1ff8, &buffer).unwrap();
+        assert_eq!(pages[0].0[0xff8..], buffer[..8]);
+        assert_eq!(pages[1].0[..8], buffer[8..]);
+
+        let mut copy = [0u8; 16];
+        data.copy_from_guest(0x1ff8, &mut copy).unwrap();
+        assert_eq!(copy[..], buffer[..]);
+        assert_eq!(
+            data.read_guest::<u32>(0x1ffe).unwrap(),
+            u32::from_le_bytes([7, 8, 9, 10])
+        );
+    }
+
+    #[test]
+    fn nested_permissions_are_checked() {
+        let mut harness = VmExitHarness::new();
+        let pages = guest_memory(&mut harness);
+        let data = harness.vcpu_data();
+
+        // The first page is writable, but nothing is written.
+        //
+        assert!(matches!(
+            data.copy_to_guest(0x2ff8, &[0xcc; 16]),
+            Err(GuestWalkError::NestedPageFault { guest_pa: 0x3000 })
+        ));
+        assert!(pages[1].0.iter().all(|byte| *byte == 0));
+
+        assert!(data.read_guest::<[u8; 16]>(0x2ff8).is_ok());
+        assert!(matches!(
+            data.read_guest::<u64>(0x4000),
+            Err(GuestWalkError::NestedPageFault { guest_pa: 0x4000 })
+        ));
+    }
+
+    #[test]
+    fn access_is_limited() {
+        let mut harness = VmExitHarness::new();
+        let _pages = guest_memory(&mut harness);
+        let data = harness.vcpu_data();
+
+        let mut buffer = vec![0u8; MAX_GUEST_ACCESS_PAGES * BASE_PAGE_SIZE];
+        assert!(matches!(
+            data.copy_from_guest(0x1001, &mut buffer),
+            Err(GuestWalkError::RangeTooLarge { .. })
+        ));
     }
 }
@@ -1,3 +1,4 @@
+use crate::svm::vmcb::exit_info::ExitInfo;
 use bitflags::bitflags;
 
 #[repr(C)]
@@ -21,7 +22,7 @@ pub struct ControlArea {
     pub vintr: u64,                      // +0x060
     pub interrupt_shadow: u64,           // +0x068
     pub exit_code: VmExitCode,           // +0x070
-    pub exit_info1: NptExitInfo,         // +0x078
+    pub exit_info1: u64,                 // +0x078
     pub exit_info2: u64,                 // +0x080
     pub exit_int_info: u64,              // +0x088
     pub np_enable: NpEnable,             // +0x090
@@ -50,6 +51,24 @@ pub struct ControlArea {
 }
 const_assert_eq!(core::mem::size_of::<ControlArea>(), 0x400);
 
+impl ControlArea {
+    /// Decodes the exit information of the current #VMEXIT.
+    pub fn exit_info(&self) -> ExitInfo {
+        ExitInfo::new(self)
+    }
+}
+
+impl ExceptionVector {
+    /// Returns the intercept bit for the exception with the specified vector.
+    /// Vectors above 31 can't be intercepted and result in an empty set.
+    pub const fn from_vector(vector: u8) -> Self {
+        match vector {
+            0..=31 => Self::from_bits_truncate(1 << vector),
+            _ => Self::empty(),
+        }
+    }
+}
+
 bitflags! {
     pub struct LbrVirt : u64 {
         /// Enable LBR virtualization hardware acceleration (0 –Disabled, 1- Enabled).
@@ -98,7 +117,73 @@ bitflags! {
         /// validity of an opcode often depends on the processor operating mode.
         const INVALID_OPCODE = 1 << 6;
 
-        // TODO: Add other exception types.
+        /// A #NM exception occurs when an x87 or media instruction is executed while CR0.TS or CR0.EM
+        /// prevents it.
+        const DEVICE_NOT_AVAILABLE = 1 << 7;
+
+        /// A #DF exception occurs when a second exception occurs during the handling of a prior exception,
+        /// if the two exceptions can't be handled serially.
+        const DOUBLE_FAULT = 1 << 8;
+
+        /// Reserved, the processor no longer generates this exception.
+        const COPROCESSOR_SEGMENT_OVERRUN = 1 << 9;
+
+        /// A #TS exception occurs when an invalid reference is made to a segment selector as part of a task
+        /// switch or when loading the TSS.
+        const INVALID_TSS = 1 << 10;
+
+        /// A #NP exception occurs when a segment or gate is loaded whose present bit is cleared to 0.
+        const SEGMENT_NOT_PRESENT = 1 << 11;
+
+        /// A #SS exception occurs when a stack reference violates a segment limit or is non-canonical, or
+        /// when SS is loaded with a not-present segment.
+        const STACK = 1 << 12;
+
+        /// A #GP exception occurs for any protection violation that doesn't cause another exception.
+        const GENERAL_PROTECTION = 1 << 13;
+
+        /// A #PF exception occurs when a page translation fails or violates the page protection. The
+        /// faulting address is stored in CR2.
+        const PAGE_FAULT = 1 << 14;
+
+        const RESERVED_15 = 1 << 15;
+
+        /// A #MF exception occurs when an unmasked x87 floating-point exception is pending.
+        const X87_FLOATING_POINT = 1 << 16;
+
+        /// An #AC exception occurs when an unaligned memory reference is made while alignment checking is
+        /// enabled.
+        const ALIGNMENT_CHECK = 1 << 17;
+
+        /// A #MC exception occurs when an uncorrectable hardware error is detected.
+        const MACHINE_CHECK = 1 << 18;
+
+        /// A #XF exception occurs when an unmasked SSE floating-point exception is detected.
+        const SIMD_FLOATING_POINT = 1 << 19;
+
+        const RESERVED_20 = 1 << 20;
+
+        /// A #CP exception occurs when a control-flow transfer violates the shadow stack rules.
+        const CONTROL_PROTECTION = 1 << 21;
+
+        const RESERVED_22 = 1 << 22;
+        const RESERVED_23 = 1 << 23;
+        const RESERVED_24 = 1 << 24;
+        const RESERVED_25 = 1 << 25;
+        const RESERVED_26 = 1 << 26;
+        const RESERVED_27 = 1 << 27;
+
+        /// A #HV exception is injected by the hypervisor to notify a guest with restricted injection
+        /// enabled.
+        const HYPERVISOR_INJECTION = 1 << 28;
+
+        /// A #VC exception occurs in SEV-ES guests when an intercepted event occurs.
+        const VMM_COMMUNICATION = 1 << 29;
+
+        /// A #SX exception occurs when a security-sensitive event occurs, such as an INIT redirection.
+        const SECURITY = 1 << 30;
+
+        const RESERVED_31 = 1 << 31;
     }
 
     /// See `15.15.2 Guidelines for Clearing VMCB Clean Bits`
@@ -172,7 +257,7 @@ bitflags! {
         const FLUSH_GUEST_TLB                   = 3;
 
         /// 07h—Flush this guest’s non-global TLB entries.
-        const FLUSH_GUEST_NON_GLOBAL_TLB        = 4;
+        const FLUSH_GUEST_NON_GLOBAL_TLB        = 7;
     }
 
     pub struct NpEnable: u64 {
@@ -0,0 +1,304 @@
+//! Decodes `EXITINFO1` and `EXITINFO2` based on the exit code.
+//!
+//! See `15.6.1 #VMEXIT Exit Information` and `Appendix C - SVM Intercept Exit
+//! Codes` for the meaning of the fields.
+
+use crate::svm::vmcb::{
+    control_area::{ControlArea, NptExitInfo, VmExitCode},
+    save_area::SaveArea,
+};
+
+/// Whether the value was read or written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Access to a control or debug register via `MOV CRx`/`MOV DRx`.
+///
+/// See `15.8.1 Decode Assists for MOV CRx and MOV DRx`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RegisterAccess {
+    /// The number of the control or debug register.
+    pub register: u8,
+    pub access: Access,
+
+    /// The number of the general purpose register that is used as the source
+    /// or destination. Only set if decode assists are supported and the
+    /// instruction was a `MOV`, otherwise (e.g. for `LMSW` and `CLTS`) it's
+    /// `None`.
+    pub gpr: Option<u8>,
+}
+
+/// See `15.12 Exception Intercepts`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExceptionInfo {
+    pub vector: u8,
+
+    /// The error code pushed by the exception. Only set for exceptions that
+    /// push one.
+    pub error_code: Option<u32>,
+
+    /// The faulting address (the value that would be written to CR2). Only set
+    /// for page faults.
+    pub fault_address: Option<u64>,
+}
+
+impl ExceptionInfo {
+    /// Returns `true` if the exception with the specified vector pushes an
+    /// error code.
+    pub const fn has_error_code(vector: u8) -> bool {
+        matches!(
+            vector,
+            8 /* #DF */
+                | 10 /* #TS */
+                | 11 /* #NP */
+                | 12 /* #SS */
+                | 13 /* #GP */
+                | 14 /* #PF */
+                | 17 /* #AC */
+                | 21 /* #CP */
+                | 29 /* #VC */
+                | 30 /* #SX */
+        )
+    }
+
+    /// Returns the cause of a #DB exception (the value of DR6). The processor
+    /// updates the guest DR6 before the #VMEXIT, so it has to be read from the
+    /// save area. Returns `None` for all other exceptions.
+    pub fn debug_status(&self, save_area: &SaveArea) -> Option<u64> {
+        if self.vector == 1 {
+            Some(save_area.dr6)
+        } else {
+            None
+        }
+    }
+}
+
+/// Direction of an `IN`/`OUT` instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoDirection {
+    In,
+    Out,
+}
+
+/// See `15.10.2 IN and OUT Behavior`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IoInfo {
+    pub port: u16,
+    pub direction: IoDirection,
+
+    /// The size of the operand in bytes (1, 2 or 4).
+    pub size: u8,
+
+    /// The size of the address in bits (16, 32 or 64).
+    pub address_size: u8,
+
+    /// `INS`/`OUTS` instruction.
+    pub string: bool,
+
+    /// Has a `REP` prefix.
+    pub rep: bool,
+
+    /// The effective segment of `INS`/`OUTS` (0 = ES, 1 = CS, ...).
+    pub segment: u8,
+
+    /// The rip of the instruction after the `IN`/`OUT` instruction.
+    pub next_rip: u64,
+}
+
+impl IoInfo {
+    fn new(exit_info1: u64, exit_info2: u64) -> Self {
+        let size = match (exit_info1 >> 4) & 0b111 {
+            0b001 => 1,
+            0b010 => 2,
+            _ => 4,
+        };
+        let address_size = match (exit_info1 >> 7) & 0b111 {
+            0b001 => 16,
+            0b010 => 32,
+            _ => 64,
+        };
+
+        Self {
+            port: (exit_info1 >> 16) as u16,
+            direction: if exit_info1 & 1 != 0 {
+                IoDirection::In
+            } else {
+                IoDirection::Out
+            },
+            size,
+            address_size,
+            string: exit_info1 & (1 << 2) != 0,
+            rep: exit_info1 & (1 << 3) != 0,
+            segment: ((exit_info1 >> 10) & 0b111) as u8,
+            next_rip: exit_info2,
+        }
+    }
+}
+
+/// See `15.25.6 Nested versus Guest Page Faults, Fault Ordering`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NptFaultInfo {
+    pub flags: NptExitInfo,
+
+    /// The guest physical address that caused the fault.
+    pub faulting_gpa: u64,
+}
+
+impl NptFaultInfo {
+    /// Returns `true` if the nested page was present (permission fault).
+    pub fn is_present(&self) -> bool {
+        self.flags.contains(NptExitInfo::PRESENT)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.flags.contains(NptExitInfo::RW)
+    }
+
+    pub fn is_execute(&self) -> bool {
+        self.flags.contains(NptExitInfo::ID)
+    }
+
+    /// Returns `true` if the fault occurred while translating the final guest
+    /// physical address.
+    pub fn is_final_translation(&self) -> bool {
+        self.flags.contains(NptExitInfo::GUEST_PA)
+    }
+
+    /// Returns `true` if the fault occurred while walking the guest page
+    /// tables.
+    pub fn is_table_walk(&self) -> bool {
+        self.flags.contains(NptExitInfo::GUEST_PAGE_TABLES)
+    }
+}
+
+/// The reason for a task switch.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TaskSwitchReason {
+    Iret,
+    Jmp,
+    /// `CALL`, an interrupt or an exception.
+    Other,
+}
+
+/// See `15.14.6 Task Switch Intercept`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TaskSwitchInfo {
+    /// The selector of the new task.
+    pub selector: u16,
+    pub reason: TaskSwitchReason,
+
+    /// The error code that has to be pushed after the task switch.
+    pub error_code: Option<u32>,
+}
+
+impl TaskSwitchInfo {
+    fn new(exit_info1: u64, exit_info2: u64) -> Self {
+        let reason = if exit_info2 & (1 << 36) != 0 {
+            TaskSwitchReason::Iret
+        } else if exit_info2 & (1 << 38) != 0 {
+            TaskSwitchReason::Jmp
+        } else {
+            TaskSwitchReason::Other
+        };
+
+        Self {
+            selector: exit_info1 as u16,
+            reason,
+            error_code: if exit_info2 & (1 << 44) != 0 {
+                Some(exit_info2 as u32)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// The decoded exit information of a #VMEXIT.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExitInfo {
+    Cr(RegisterAccess),
+    Dr(RegisterAccess),
+    Exception(ExceptionInfo),
+    Msr(Access),
+    Io(IoInfo),
+    NestedPageFault(NptFaultInfo),
+    TaskSwitch(TaskSwitchInfo),
+
+    /// The linear address of `INVLPG`. Only valid if decode assists are
+    /// supported.
+    Invlpg(u64),
+
+    /// The exit code has no (known) exit information.
+    None {
+        exit_info1: u64,
+        exit_info2: u64,
+    },
+}
+
+impl ExitInfo {
+    pub fn new(control_area: &ControlArea) -> Self {
+        let exit_code = control_area.exit_code.bits();
+        let exit_info1 = control_area.exit_info1;
+        let exit_info2 = control_area.exit_info2;
+
+        const CR_READ: u64 = VmExitCode::VMEXIT_CR0_READ.bits();
+        const CR_WRITE: u64 = VmExitCode::VMEXIT_CR0_WRITE.bits();
+        const DR_READ: u64 = VmExitCode::VMEXIT_DR0_READ.bits();
+        const DR_WRITE: u64 = VmExitCode::VMEXIT_DR0_WRITE.bits();
+        const EXCEPTION: u64 = VmExitCode::VMEXIT_EXCEPTION_DE.bits();
+
+        let register_access = |register: u64, access: Access| RegisterAccess {
+            register: register as u8,
+            access,
+            gpr: if exit_info1 & (1 << 63) != 0 {
+                Some((exit_info1 & 0xf) as u8)
+            } else {
+                None
+            },
+        };
+
+        match exit_code {
+            code @ CR_READ..=0x0f => ExitInfo::Cr(register_access(code - CR_READ, Access::Read)),
+            code @ CR_WRITE..=0x1f => ExitInfo::Cr(register_access(code - CR_WRITE, Access::Write)),
+            code @ DR_READ..=0x2f => ExitInfo::Dr(register_access(code - DR_READ, Access::Read)),
+            code @ DR_WRITE..=0x3f => ExitInfo::Dr(register_access(code - DR_WRITE, Access::Write)),
+            code @ EXCEPTION..=0x5f => {
+                let vector = (code - EXCEPTION) as u8;
+
+                ExitInfo::Exception(ExceptionInfo {
+                    vector,
+                    error_code: if ExceptionInfo::has_error_code(vector) {
+                        Some(exit_info1 as u32)
+                    } else {
+                        None
+                    },
+                    fault_address: if vector == 14 { Some(exit_info2) } else { None },
+                })
+            }
+            _ => match control_area.exit_code {
+                VmExitCode::VMEXIT_CR0_SEL_WRITE => ExitInfo::Cr(register_access(0, Access::Write)),
+                VmExitCode::VMEXIT_MSR => ExitInfo::Msr(if exit_info1 & 1 != 0 {
+                    Access::Write
+                } else {
+                    Access::Read
+                }),
+                VmExitCode::VMEXIT_IOIO => ExitInfo::Io(IoInfo::new(exit_info1, exit_info2)),
+                VmExitCode::VMEXIT_NPF => ExitInfo::NestedPageFault(NptFaultInfo {
+                    flags: NptExitInfo::from_bits_truncate(exit_info1),
+                    faulting_gpa: exit_info2,
+                }),
+                VmExitCode::VMEXIT_TASK_SWITCH => {
+                    ExitInfo::TaskSwitch(TaskSwitchInfo::new(exit_info1, exit_info2))
+                }
+                VmExitCode::VMEXIT_INVLPG => ExitInfo::Invlpg(exit_info1),
+                _ => ExitInfo::None {
+                    exit_info1,
+                    exit_info2,
+                },
+            },
+        }
+    }
+}
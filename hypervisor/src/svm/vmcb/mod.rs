@@ -1,14 +1,26 @@
 //! See `Appendix B - Layout of VMCB` in AMD64 Architecture Programmer’s Manual
 //! Volume 2: System Programming.
 
-use crate::svm::vmcb::{control_area::ControlArea, save_area::SaveArea};
+use crate::svm::vmcb::{
+    control_area::{ControlArea, VmcbClean},
+    save_area::SaveArea,
+};
 
 pub mod control_area;
+pub mod exit_info;
 pub mod save_area;
 
 const VMCB_RESERVED_SIZE: usize =
     0x1000 - core::mem::size_of::<ControlArea>() - core::mem::size_of::<SaveArea>();
 
+/// # Clean Bits
+///
+/// The processor caches parts of the vmcb between `vmrun`s. After every
+/// #VMEXIT, the whole vmcb is marked as clean, so the fields that are covered
+/// by the [`VmcbClean`] bits have to be modified through the setters below,
+/// which mark the cached state as dirty. Fields that are never cached (e.g.
+/// `rip`, `rsp`, `rax`, `rflags` or `event_inj`) can be written directly.
+///
 /// # Layout
 ///
 /// The VMCB is divided into two areas—the first one contains various control
@@ -30,3 +42,137 @@ pub struct Vmcb {
 
     pub reserved: [u8; VMCB_RESERVED_SIZE],
 }
+
+impl Vmcb {
+    /// Marks the whole vmcb as unmodified since the last #VMEXIT.
+    pub(crate) fn mark_clean(&mut self) {
+        self.control_area.vmcb_clean = VmcbClean::all();
+    }
+
+    /// Forces the processor to reload the specified fields on the next
+    /// `vmrun`.
+    pub fn mark_dirty(&mut self, bits: VmcbClean) {
+        self.control_area.vmcb_clean.remove(bits);
+    }
+
+    /// Modifies the intercept vectors, the TSC offset or the pause filter.
+    pub fn update_intercepts<R>(&mut self, f: impl FnOnce(&mut ControlArea) -> R) -> R {
+        let result = f(&mut self.control_area);
+        self.mark_dirty(VmcbClean::I);
+
+        result
+    }
+
+    pub fn set_tsc_offset(&mut self, value: u64) {
+        self.control_area.tsc_offset = value;
+        self.mark_dirty(VmcbClean::I);
+    }
+
+    pub fn set_iopm_base_pa(&mut self, value: u64) {
+        self.control_area.iopm_base_pa = value;
+        self.mark_dirty(VmcbClean::IOPM);
+    }
+
+    pub fn set_msrpm_base_pa(&mut self, value: u64) {
+        self.control_area.msrpm_base_pa = value;
+        self.mark_dirty(VmcbClean::IOPM);
+    }
+
+    pub fn set_guest_asid(&mut self, value: u32) {
+        self.control_area.guest_asid = value;
+        self.mark_dirty(VmcbClean::ASID);
+    }
+
+    /// Sets `V_TPR`, `V_IRQ`, `V_INTR_PRIO`, `V_IGN_TPR`, `V_INTR_MASKING`
+    /// and `V_INTR_VECTOR`.
+    pub fn set_vintr(&mut self, value: u64) {
+        self.control_area.vintr = value;
+        self.mark_dirty(VmcbClean::TPR);
+    }
+
+    pub fn set_ncr3(&mut self, value: u64) {
+        self.control_area.ncr3 = value;
+        self.mark_dirty(VmcbClean::NP);
+    }
+
+    pub fn set_gpat(&mut self, value: u64) {
+        self.save_area.gpat = value;
+        self.mark_dirty(VmcbClean::NP);
+    }
+
+    pub fn set_cr0(&mut self, value: u64) {
+        self.save_area.cr0 = value;
+        self.mark_dirty(VmcbClean::CR_X);
+    }
+
+    pub fn set_cr3(&mut self, value: u64) {
+        self.save_area.cr3 = value;
+        self.mark_dirty(VmcbClean::CR_X);
+    }
+
+    pub fn set_cr4(&mut self, value: u64) {
+        self.save_area.cr4 = value;
+        self.mark_dirty(VmcbClean::CR_X);
+    }
+
+    pub fn set_efer(&mut self, value: u64) {
+        self.save_area.efer = value;
+        self.mark_dirty(VmcbClean::CR_X);
+    }
+
+    pub fn set_cr2(&mut self, value: u64) {
+        self.save_area.cr2 = value;
+        self.mark_dirty(VmcbClean::CR2);
+    }
+
+    pub fn set_dr6(&mut self, value: u64) {
+        self.save_area.dr6 = value;
+        self.mark_dirty(VmcbClean::DR_X);
+    }
+
+    pub fn set_dr7(&mut self, value: u64) {
+        self.save_area.dr7 = value;
+        self.mark_dirty(VmcbClean::DR_X);
+    }
+
+    pub fn set_gdtr_base(&mut self, value: u64) {
+        self.save_area.gdtr_base = value;
+        self.mark_dirty(VmcbClean::DT);
+    }
+
+    pub fn set_gdtr_limit(&mut self, value: u32) {
+        self.save_area.gdtr_limit = value;
+        self.mark_dirty(VmcbClean::DT);
+    }
+
+    pub fn set_idtr_base(&mut self, value: u64) {
+        self.save_area.idtr_base = value;
+        self.mark_dirty(VmcbClean::DT);
+    }
+
+    pub fn set_idtr_limit(&mut self, value: u32) {
+        self.save_area.idtr_limit = value;
+        self.mark_dirty(VmcbClean::DT);
+    }
+
+    pub fn set_cpl(&mut self, value: u8) {
+        self.save_area.cpl = value;
+        self.mark_dirty(VmcbClean::SEG);
+    }
+
+    /// Modifies the `CS`, `DS`, `SS` or `ES` segment registers.
+    pub fn update_segments<R>(&mut self, f: impl FnOnce(&mut SaveArea) -> R) -> R {
+        let result = f(&mut self.save_area);
+        self.mark_dirty(VmcbClean::SEG);
+
+        result
+    }
+
+    /// Modifies `DbgCtlMsr` or the last branch and exception msrs.
+    pub fn update_lbr<R>(&mut self, f: impl FnOnce(&mut SaveArea) -> R) -> R {
+        let result = f(&mut self.save_area);
+        self.mark_dirty(VmcbClean::LBR);
+
+        result
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
         msr::DebugCtl,
         segmentation::{SegmentAttribute, SegmentDescriptor},
     },
-    utils::nt::Context,
+    utils::context::Context,
 };
 use core::arch::asm;
 use x86::{
@@ -19,3 +19,12 @@ pub const CPUID_DEVIRTUALIZE: u32 = 0x4321_1234;
 pub(crate) fn handle_devirtualize(_: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
     ExitType::ExitHypervisor
 }
+
+/// Used to apply the changes to the vmexit handlers. See
+/// [`Hypervisor::add_handler`](crate::svm::Hypervisor::add_handler).
+pub const CPUID_UPDATE_INTERCEPTS: u32 = 0x4321_1235;
+pub(crate) fn handle_update_intercepts(data: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    data.update_interceptions();
+
+    ExitType::IncrementRIP
+}
@@ -0,0 +1,684 @@
+//! Emulates accesses to the control and debug registers.
+//!
+//! The source or destination register is only known if the processor supports
+//! decode assists (see `15.33 Decode Assists`). Without them, the access can't
+//! be emulated and #GP is injected instead.
+//!
+//! `CLTS` and `LMSW` write CR0 without a general purpose register, so they are
+//! decoded from the instruction bytes.
+
+use crate::svm::{
+    events::EventInjection,
+    tlb::{self, FlushScope},
+    utils::{
+        guest::GuestRegs,
+        guest_paging::GuestWalkError,
+        validation::{
+            self, CR0_PE, CR0_PG, CR0_WP, CR3_NO_FLUSH, CR4_DE, CR4_PAE, CR4_PCIDE, CR4_PGE,
+            CR4_PSE, CR4_SMAP, CR4_SMEP, EFER_LMA,
+        },
+    },
+    vcpu_data::VcpuData,
+    vmcb::{
+        exit_info::{Access, ExitInfo, RegisterAccess},
+        save_area::SaveArea,
+    },
+    vmexit::ExitType,
+};
+use core::arch::asm;
+use snafu::prelude::*;
+
+/// The number of `rsp` in the instruction encoding.
+const RSP: u8 = 4;
+
+/// `V_INTR_MASKING` in `vintr`. If set, the guest uses the virtual `TPR`
+/// instead of the physical one.
+const V_INTR_MASKING: u64 = 1 << 24;
+
+fn read_gpr(data: &VcpuData, guest_regs: &GuestRegs, gpr: u8) -> u64 {
+    match gpr {
+        RSP => data.guest_vmcb.save_area.rsp,
+        _ => guest_regs.gpr(gpr),
+    }
+}
+
+fn write_gpr(data: &mut VcpuData, guest_regs: &mut GuestRegs, gpr: u8, value: u64) {
+    match gpr {
+        RSP => data.guest_vmcb.save_area.rsp = value,
+        _ => guest_regs.set_gpr(gpr, value),
+    }
+}
+
+/// The `CR0` and `CR4` bits that change how addresses are translated.
+/// Modifying them flushes the whole TLB.
+const CR0_TRANSLATION_BITS: u64 = CR0_PG | CR0_WP;
+const CR4_TRANSLATION_BITS: u64 = CR4_PSE | CR4_PAE | CR4_PGE | CR4_PCIDE | CR4_SMEP | CR4_SMAP;
+
+/// Returns the TLB entries that the processor would flush when writing the
+/// control register. See `5.5.3 TLB Management`.
+///
+/// The write is emulated, so the processor doesn't flush anything itself.
+fn flush_scope(
+    save_area: &SaveArea, register: u8, value: u64, raw_value: u64,
+) -> Option<FlushScope> {
+    match register {
+        0 if (save_area.cr0 ^ value) & CR0_TRANSLATION_BITS != 0 => Some(FlushScope::Guest),
+        3 if save_area.cr4 & CR4_PCIDE != 0 && raw_value & CR3_NO_FLUSH != 0 => None,
+        3 => Some(FlushScope::GuestNonGlobal),
+        4 if (save_area.cr4 ^ value) & CR4_TRANSLATION_BITS != 0 => Some(FlushScope::Guest),
+        _ => None,
+    }
+}
+
+/// `TS` in `CR0`. Cleared by `CLTS`.
+const CR0_TS: u64 = 1 << 3;
+
+/// The bits of `CR0` that are loaded by `LMSW` (`PE`, `MP`, `EM` and `TS`).
+const CR0_MSW: u64 = 0xf;
+
+/// `L` and `D` in the segment attributes of `CS`.
+const SEGMENT_LONG_MODE: u16 = 1 << 9;
+const SEGMENT_DEFAULT_SIZE: u16 = 1 << 10;
+
+const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+// The segment override prefixes.
+//
+const ES: u8 = 0x26;
+const CS: u8 = 0x2e;
+const SS: u8 = 0x36;
+const DS: u8 = 0x3e;
+const FS: u8 = 0x64;
+const GS: u8 = 0x65;
+
+#[derive(Debug, Snafu)]
+enum DecodeError {
+    #[snafu(display("Failed to access the guest memory: {}", source))]
+    GuestMemory { source: GuestWalkError },
+
+    #[snafu(display("Unsupported instruction at rip {:#x}", rip))]
+    UnsupportedInstruction { rip: u64 },
+}
+
+/// An instruction that writes `CR0` without a general purpose register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Cr0Write {
+    Clts,
+
+    /// `LMSW` from the general purpose register.
+    LmswRegister(u8),
+
+    /// `LMSW` from the linear address.
+    LmswMemory(u64),
+}
+
+/// Returns the register access of the #VMEXIT. Injects #GP and returns `None`
+/// if the exit information doesn't describe a register access.
+fn register_access(data: &mut VcpuData) -> Option<RegisterAccess> {
+    match data.guest_vmcb.control_area.exit_info() {
+        ExitInfo::Cr(access) | ExitInfo::Dr(access) => Some(access),
+        exit_info => {
+            log::error!("Unexpected exit information {:x?}", exit_info);
+            EventInjection::gp().inject(data);
+            None
+        }
+    }
+}
+
+/// Injects #GP, because the register access can't be emulated without the
+/// general purpose register.
+fn no_decode_assists(data: &mut VcpuData, access: &RegisterAccess) -> ExitType {
+    log::error!(
+        "Can't emulate access to register {} without decode assists",
+        access.register
+    );
+    EventInjection::gp().inject(data);
+
+    ExitType::Continue
+}
+
+pub fn handle_cr_default(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+    let Some(access) = register_access(data) else {
+        return ExitType::Continue;
+    };
+
+    let gpr = match (access.gpr, access.access) {
+        (Some(gpr), _) => gpr,
+        (None, Access::Write) if access.register == 0 => {
+            return match cr0_write_value(data, guest_regs) {
+                Ok(value) => write_cr(data, 0, value),
+                Err(DecodeError::GuestMemory { source }) => {
+                    if !source.inject(data) {
+                        log::error!("Failed to decode the CR0 write: {}", source);
+                        EventInjection::gp().inject(data);
+                    }
+                    ExitType::Continue
+                }
+                Err(error) => {
+                    log::error!("Failed to decode the CR0 write: {}", error);
+                    EventInjection::gp().inject(data);
+                    ExitType::Continue
+                }
+            };
+        }
+        (None, _) => return no_decode_assists(data, &access),
+    };
+
+    match access.access {
+        Access::Read => {
+            let uses_virtual_tpr = data.guest_vmcb.control_area.vintr & V_INTR_MASKING != 0;
+
+            let save_area = &data.guest_vmcb.save_area;
+            let value = match access.register {
+                0 => save_area.cr0,
+                2 => save_area.cr2,
+                3 => save_area.cr3,
+                4 => save_area.cr4,
+                8 if uses_virtual_tpr => data.guest_vmcb.control_area.vintr & 0xf,
+                8 => {
+                    let cr8: u64;
+                    unsafe { asm!("mov {}, cr8", out(reg) cr8) };
+                    cr8
+                }
+                _ => {
+                    EventInjection::ud().inject(data);
+                    return ExitType::Continue;
+                }
+            };
+
+            write_gpr(data, guest_regs, gpr, value);
+        }
+        Access::Write => {
+            let raw_value = read_gpr(data, guest_regs, gpr);
+            return write_cr(data, access.register, raw_value);
+        }
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Emulates writing the value to the control register.
+fn write_cr(data: &mut VcpuData, register: u8, raw_value: u64) -> ExitType {
+    let uses_virtual_tpr = data.guest_vmcb.control_area.vintr & V_INTR_MASKING != 0;
+
+    // Writing illegal values would cause VMEXIT_INVALID when resuming the
+    // guest.
+    //
+    let save_area = &data.guest_vmcb.save_area;
+    let value = match register {
+        0 => validation::validate_cr0(save_area, raw_value),
+        3 => validation::validate_cr3(save_area, raw_value),
+        4 => validation::validate_cr4(save_area, raw_value),
+        _ => Ok(raw_value),
+    };
+    let value = match value {
+        Ok(value) => value,
+        Err(error) => {
+            log::warn!("Injecting #GP: {}", error);
+            EventInjection::gp().inject(data);
+            return ExitType::Continue;
+        }
+    };
+    let flush = flush_scope(save_area, register, value, raw_value);
+
+    let vmcb = &mut data.guest_vmcb;
+    match register {
+        0 => {
+            vmcb.set_cr0(value);
+            vmcb.set_efer(validation::efer_after_cr0_write(vmcb.save_area.efer, value));
+        }
+        2 => vmcb.set_cr2(value),
+        3 => vmcb.set_cr3(value),
+        4 => vmcb.set_cr4(value),
+        8 if uses_virtual_tpr => vmcb.set_vintr((vmcb.control_area.vintr & !0xf) | (value & 0xf)),
+        8 => unsafe { asm!("mov cr8, {}", in(reg) value) },
+        _ => {
+            EventInjection::ud().inject(data);
+            return ExitType::Continue;
+        }
+    }
+
+    if let Some(scope) = flush {
+        tlb::flush_guest_tlb(data, scope);
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Returns the value that `CLTS` or `LMSW` writes to `CR0`.
+///
+/// The instruction is read from the guest memory. Its length is known from
+/// `nrip`, which is always provided together with decode assists.
+fn cr0_write_value(data: &mut VcpuData, guest_regs: &GuestRegs) -> Result<u64, DecodeError> {
+    let save_area = &data.guest_vmcb.save_area;
+    let rip = save_area.rip;
+    let long_mode = save_area.efer & EFER_LMA != 0 && save_area.cs_attrib & SEGMENT_LONG_MODE != 0;
+
+    let length = data.guest_vmcb.control_area.nrip.wrapping_sub(rip) as usize;
+    ensure!(
+        (1..=MAX_INSTRUCTION_LENGTH).contains(&length),
+        UnsupportedInstructionSnafu { rip }
+    );
+
+    let mut address = save_area.cs_base.wrapping_add(rip);
+    if !long_mode {
+        address &= 0xffff_ffff;
+    }
+    let mut bytes = [0u8; MAX_INSTRUCTION_LENGTH];
+    data.copy_from_guest(address, &mut bytes[..length])
+        .context(GuestMemorySnafu)?;
+
+    let instruction = decode_cr0_write(data, guest_regs, &bytes[..length], long_mode)
+        .context(UnsupportedInstructionSnafu { rip })?;
+
+    let cr0 = data.guest_vmcb.save_area.cr0;
+    let msw = match instruction {
+        Cr0Write::Clts => return Ok(cr0 & !CR0_TS),
+        Cr0Write::LmswRegister(gpr) => read_gpr(data, guest_regs, gpr),
+        Cr0Write::LmswMemory(address) => {
+            data.read_guest::<u16>(address).context(GuestMemorySnafu)? as u64
+        }
+    };
+
+    // `LMSW` can set `PE`, but not clear it.
+    //
+    Ok((cr0 & !CR0_MSW) | (msw & CR0_MSW) | (cr0 & CR0_PE))
+}
+
+/// Decodes `CLTS` (`0F 06`) or `LMSW` (`0F 01 /6`). See `Volume 3: General
+/// Purpose and System Instructions > 1 Instruction Formats`.
+///
+/// Memory operands with 16-bit addressing are not supported.
+fn decode_cr0_write(
+    data: &VcpuData, guest_regs: &GuestRegs, bytes: &[u8], long_mode: bool,
+) -> Option<Cr0Write> {
+    let save_area = &data.guest_vmcb.save_area;
+
+    // Legacy prefixes. Only the segment and address size overrides are relevant
+    // for `LMSW`.
+    //
+    let mut segment = None;
+    let mut address_size_override = false;
+    let mut index = 0;
+    loop {
+        match *bytes.get(index)? {
+            prefix @ (ES | CS | SS | DS | FS | GS) => segment = Some(prefix),
+            0x67 => address_size_override = true,
+            0x66 | 0xf0 | 0xf2 | 0xf3 => {}
+            _ => break,
+        }
+        index += 1;
+    }
+
+    let mut rex = 0;
+    if long_mode && *bytes.get(index)? & 0xf0 == 0x40 {
+        rex = bytes[index];
+        index += 1;
+    }
+
+    match bytes.get(index..index + 2)? {
+        [0x0f, 0x06] => return Some(Cr0Write::Clts),
+        [0x0f, 0x01] => {}
+        _ => return None,
+    }
+
+    let mut operand = &bytes[index + 2..];
+    let [modrm] = take::<1>(&mut operand)?;
+    let (mode, reg, rm) = (modrm >> 6, (modrm >> 3) & 0b111, modrm & 0b111);
+    if reg != 6 {
+        return None;
+    }
+
+    let rex_b = (rex & 0b0001) << 3;
+    let rex_x = (rex & 0b0010) << 2;
+    if mode == 0b11 {
+        return Some(Cr0Write::LmswRegister(rm | rex_b));
+    }
+
+    let address_size = match (long_mode, save_area.cs_attrib & SEGMENT_DEFAULT_SIZE != 0) {
+        (true, _) if address_size_override => 32,
+        (true, _) => 64,
+        (false, true) if address_size_override => 16,
+        (false, true) => 32,
+        (false, false) if address_size_override => 32,
+        (false, false) => 16,
+    };
+    if address_size == 16 {
+        return None;
+    }
+
+    let gpr = |gpr: u8| read_gpr(data, guest_regs, gpr);
+
+    // The scaled index and the base register, if there is one. Without a base
+    // register, a 32-bit displacement follows. In 64-bit mode, it's relative
+    // to the next instruction if there's no SIB byte either.
+    //
+    let mut address = 0u64;
+    let base = if rm == 0b100 {
+        let [sib] = take::<1>(&mut operand)?;
+        let (scale, sib_index, sib_base) = (sib >> 6, (sib >> 3) & 0b111 | rex_x, sib & 0b111);
+        if sib_index != 4 {
+            address = gpr(sib_index) << scale;
+        }
+
+        if sib_base == 0b101 && mode == 0 {
+            None
+        } else {
+            Some(sib_base | rex_b)
+        }
+    } else if rm == 0b101 && mode == 0 {
+        if long_mode {
+            address = data.guest_vmcb.control_area.nrip;
+        }
+        None
+    } else {
+        Some(rm | rex_b)
+    };
+
+    let displacement = match mode {
+        0b01 => i8::from_le_bytes(take::<1>(&mut operand)?) as u64,
+        0b10 => i32::from_le_bytes(take::<4>(&mut operand)?) as u64,
+        _ if base.is_none() => i32::from_le_bytes(take::<4>(&mut operand)?) as u64,
+        _ => 0,
+    };
+    if let Some(base) = base {
+        address = address.wrapping_add(gpr(base));
+    }
+    address = address.wrapping_add(displacement);
+    if address_size == 32 {
+        address &= 0xffff_ffff;
+    }
+
+    // `rsp` and `rbp` as the base register use `SS` by default. In 64-bit mode,
+    // only the bases of `FS` and `GS` are used.
+    //
+    let segment = segment.unwrap_or(match base {
+        Some(4 | 5) => SS,
+        _ => DS,
+    });
+    let segment_base = match segment {
+        ES if !long_mode => save_area.es_base,
+        CS if !long_mode => save_area.cs_base,
+        SS if !long_mode => save_area.ss_base,
+        DS if !long_mode => save_area.ds_base,
+        FS => save_area.fs_base,
+        GS => save_area.gs_base,
+        _ => 0,
+    };
+    let mut linear_address = segment_base.wrapping_add(address);
+    if !long_mode {
+        linear_address &= 0xffff_ffff;
+    }
+
+    Some(Cr0Write::LmswMemory(linear_address))
+}
+
+/// Removes the first `N` bytes from the slice and returns them.
+fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    let mut taken = [0u8; N];
+    taken.copy_from_slice(bytes.get(..N)?);
+    *bytes = &bytes[N..];
+
+    Some(taken)
+}
+
+pub fn handle_dr_default(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+    let Some(access) = register_access(data) else {
+        return ExitType::Continue;
+    };
+    let Some(gpr) = access.gpr else {
+        return no_decode_assists(data, &access);
+    };
+
+    // DR4 and DR5 are aliases of DR6 and DR7, unless debugging extensions are
+    // enabled.
+    //
+    let debugging_extensions = data.guest_vmcb.save_area.cr4 & CR4_DE != 0;
+    let register = match access.register {
+        4 | 5 if debugging_extensions => {
+            EventInjection::ud().inject(data);
+            return ExitType::Continue;
+        }
+        4 | 5 => access.register + 2,
+        register @ (0..=3 | 6 | 7) => register,
+        _ => {
+            EventInjection::ud().inject(data);
+            return ExitType::Continue;
+        }
+    };
+
+    // DR0-DR3 are not part of the vmcb, so the guest is using the physical
+    // registers.
+    //
+    match access.access {
+        Access::Read => {
+            let save_area = &data.guest_vmcb.save_area;
+            let value = match register {
+                6 => save_area.dr6,
+                7 => save_area.dr7,
+                _ => unsafe { read_dr(register) },
+            };
+
+            write_gpr(data, guest_regs, gpr, value);
+        }
+        Access::Write => {
+            // The upper half of DR6 and DR7 is reserved, and `vmrun` fails if it's
+            // set.
+            //
+            let value = read_gpr(data, guest_regs, gpr);
+            if (register == 6 || register == 7) && value >> 32 != 0 {
+                log::warn!(
+                    "Injecting #GP: DR{} value {:#x} sets reserved bits",
+                    register,
+                    value
+                );
+                EventInjection::gp().inject(data);
+                return ExitType::Continue;
+            }
+
+            match register {
+                6 => data.guest_vmcb.set_dr6(value),
+                7 => data.guest_vmcb.set_dr7(value),
+                _ => unsafe { write_dr(register, value) },
+            }
+        }
+    }
+
+    ExitType::IncrementRIP
+}
+
+unsafe fn read_dr(register: u8) -> u64 {
+    let value: u64;
+    match register {
+        0 => asm!("mov {}, dr0", out(reg) value),
+        1 => asm!("mov {}, dr1", out(reg) value),
+        2 => asm!("mov {}, dr2", out(reg) value),
+        _ => asm!("mov {}, dr3", out(reg) value),
+    }
+    value
+}
+
+unsafe fn write_dr(register: u8, value: u64) {
+    match register {
+        0 => asm!("mov dr0, {}", in(reg) value),
+        1 => asm!("mov dr1, {}", in(reg) value),
+        2 => asm!("mov dr2, {}", in(reg) value),
+        _ => asm!("mov dr3, {}", in(reg) value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{
+        utils::{
+            paging::AccessType,
+            validation::{CR0_ET, EFER_LME},
+        },
+        vmcb::control_area::{TlbControl, VmExitCode},
+        vmexit::harness::VmExitHarness,
+    };
+    use alloc::boxed::Box;
+    use x86::bits64::paging::BASE_PAGE_SIZE;
+
+    /// `rcx` in the instruction encoding.
+    const RCX: u8 = 1;
+
+    fn write_register(exit_code: VmExitCode, value: u64) -> VmExitHarness {
+        let mut harness = VmExitHarness::new();
+        harness
+            .exit_code(exit_code)
+            .exit_info1(1 << 63 | RCX as u64)
+            .nrip(0x1003);
+        harness.save_area().rip = 0x1000;
+        harness.save_area().cr0 = CR0_PE | CR0_PG;
+        harness.save_area().cr4 = CR4_PAE;
+        harness.save_area().efer = EFER_LME | EFER_LMA;
+        harness.regs().set_gpr(RCX, value);
+
+        harness
+    }
+
+    #[test]
+    fn cr3_write_flushes_the_tlb() {
+        let mut harness = write_register(VmExitCode::VMEXIT_CR3_WRITE, 0x1000);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.save_area().cr3, 0x1000);
+        assert_ne!(harness.control_area().tlb_control, TlbControl::DO_NOTHING);
+    }
+
+    #[test]
+    fn cr3_write_without_flush() {
+        let mut harness = write_register(VmExitCode::VMEXIT_CR3_WRITE, CR3_NO_FLUSH | 0x1001);
+        harness.save_area().cr4 |= CR4_PCIDE;
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.save_area().cr3, 0x1001);
+        assert_eq!(harness.control_area().tlb_control, TlbControl::DO_NOTHING);
+    }
+
+    #[test]
+    fn cr3_write_with_reserved_bits() {
+        let mut harness = write_register(VmExitCode::VMEXIT_CR3_WRITE, CR3_NO_FLUSH | 0x1000);
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.rip(), 0x1000);
+        assert_eq!(harness.save_area().cr3, 0);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+    }
+
+    #[test]
+    fn cr4_write_flushes_the_tlb_if_translations_change() {
+        let mut harness = write_register(VmExitCode::VMEXIT_CR4_WRITE, CR4_PAE);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.control_area().tlb_control, TlbControl::DO_NOTHING);
+
+        let mut harness = write_register(VmExitCode::VMEXIT_CR4_WRITE, CR4_PAE | CR4_PGE);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.save_area().cr4, CR4_PAE | CR4_PGE);
+        assert_ne!(harness.control_area().tlb_control, TlbControl::DO_NOTHING);
+    }
+
+    #[test]
+    fn dr7_write_with_reserved_bits() {
+        let mut harness = write_register(VmExitCode::VMEXIT_DR7_WRITE, 1 << 32 | 0x400);
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.save_area().dr7, 0);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+
+        let mut harness = write_register(VmExitCode::VMEXIT_DR7_WRITE, 0x401);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.save_area().dr7, 0x401);
+    }
+
+    #[repr(C, align(4096))]
+    struct Page([u8; BASE_PAGE_SIZE]);
+
+    /// Writes the instruction to the returned host page, which is mapped at the
+    /// guest physical address 0x1000. The guest executes it with paging
+    /// disabled and without decode assists for the source register.
+    fn cr0_write(instruction: &[u8]) -> (VmExitHarness, Box<Page>) {
+        let mut page = Box::new(Page([0; BASE_PAGE_SIZE]));
+        page.0[..instruction.len()].copy_from_slice(instruction);
+
+        let mut harness = VmExitHarness::new();
+        harness
+            .shared_data()
+            .primary_npt
+            .lock()
+            .map_4kb(0x1000, page.0.as_ptr() as u64, AccessType::ReadWrite)
+            .unwrap();
+        harness
+            .exit_code(VmExitCode::VMEXIT_CR0_WRITE)
+            .exit_info1(0)
+            .nrip(0x1000 + instruction.len() as u64);
+        harness.save_area().rip = 0x1000;
+        harness.save_area().cr0 = CR0_PE | CR0_TS;
+
+        (harness, page)
+    }
+
+    #[test]
+    fn clts() {
+        let (mut harness, _page) = cr0_write(&[0x0f, 0x06]);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.rip(), 0x1002);
+        assert_eq!(harness.save_area().cr0, CR0_PE | CR0_ET);
+    }
+
+    #[test]
+    fn lmsw_from_register() {
+        // lmsw cx
+        //
+        let (mut harness, _page) = cr0_write(&[0x0f, 0x01, 0xf1]);
+        harness.regs().set_gpr(RCX, 0xfff2);
+
+        // `PE` can't be cleared and only the lower 4 bits are loaded.
+        //
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.save_area().cr0, CR0_PE | CR0_ET | 0b0010);
+    }
+
+    #[test]
+    fn lmsw_from_memory() {
+        // lmsw word ptr fs:[ebx+0x10]
+        //
+        let (mut harness, mut page) = cr0_write(&[0x64, 0x0f, 0x01, 0x73, 0x10]);
+        harness.save_area().cs_attrib = SEGMENT_DEFAULT_SIZE;
+        harness.save_area().fs_base = 0x100;
+        harness.regs().rbx = 0x1000;
+        page.0[0x110..0x112].copy_from_slice(&0b1010u16.to_le_bytes());
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.rip(), 0x1005);
+        assert_eq!(harness.save_area().cr0, CR0_PE | CR0_ET | 0b1010);
+    }
+
+    #[test]
+    fn cr0_write_from_unmapped_memory() {
+        let (mut harness, _page) = cr0_write(&[0x0f, 0x06]);
+        harness.save_area().rip = 0x2000;
+        harness.nrip(0x2002);
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.rip(), 0x2000);
+        assert_eq!(harness.save_area().cr0, CR0_PE | CR0_TS);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+    }
+
+    #[test]
+    fn cr4_write_without_decode_assists() {
+        let mut harness = write_register(VmExitCode::VMEXIT_CR4_WRITE, CR4_PAE | CR4_PGE);
+        harness.exit_info1(0);
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.save_area().cr4, CR4_PAE);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+    }
+}
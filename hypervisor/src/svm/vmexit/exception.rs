@@ -0,0 +1,43 @@
+use crate::svm::{
+    events::EventInjection, utils::guest::GuestRegs, vcpu_data::VcpuData,
+    vmcb::exit_info::ExitInfo, vmexit::ExitType,
+};
+
+/// Vector of the #NMI exception.
+const NMI: u8 = 2;
+
+/// Vector of the #BP exception.
+const BREAKPOINT: u8 = 3;
+
+/// Vector of the #OF exception.
+const OVERFLOW: u8 = 4;
+
+/// Re-injects the intercepted exception into the guest, as if it had never
+/// been intercepted.
+pub fn handle_default(data: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    let ExitInfo::Exception(exception) = data.guest_vmcb.control_area.exit_info() else {
+        unreachable!()
+    };
+
+    // The processor doesn't update CR2 for intercepted page faults, so it has to
+    // be done before the exception is delivered. DR6 on the other hand has
+    // already been updated.
+    //
+    let fault = exception.fault_address.zip(exception.error_code);
+    if exception.vector == NMI {
+        EventInjection::nmi().inject(data);
+    } else if let Some((address, error_code)) = fault {
+        EventInjection::page_fault(data, address, error_code);
+    } else {
+        EventInjection::exception(exception.vector, exception.error_code).inject(data);
+    }
+
+    // #BP and #OF are traps caused by `INT3` and `INTO`. The rip still points to
+    // the instruction, but the exception has to be delivered with the rip of
+    // the next instruction.
+    //
+    match exception.vector {
+        BREAKPOINT | OVERFLOW => ExitType::IncrementRIP,
+        _ => ExitType::Continue,
+    }
+}
@@ -0,0 +1,251 @@
+//! Runs the vmexit handlers without having to load the hypervisor.
+//!
+//! The harness creates the [`VcpuData`] and [`GuestRegs`] in ordinary heap
+//! memory, so that the exit state can be fabricated by the caller. Afterwards
+//! [`VmExitHarness::run`] executes the same dispatch logic that is used by
+//! `handle_vmexit`, minus the parts that need to be executed in the host
+//! context (`vmload`, leaving the hypervisor and the bug check).
+//!
+//! ```ignore
+//! let mut harness = VmExitHarness::new();
+//! harness
+//!     .exit_code(VmExitCode::VMEXIT_CPUID)
+//!     .nrip(0x1002);
+//! harness.save_area().rip = 0x1000;
+//! harness.save_area().rax = 0x4321_1234;
+//!
+//! assert_eq!(harness.run(), Some(ExitType::ExitHypervisor));
+//! ```
+
+use crate::svm::{
+    events::EventInjection,
+    nested_page_table::NestedPageTable,
+    shared_data::SharedData,
+    utils::guest::GuestRegs,
+    vcpu_data::VcpuData,
+    vmcb::{
+        control_area::{ControlArea, VmExitCode},
+        save_area::SaveArea,
+    },
+    vmexit::{dispatch_vmexit, ExitType, VmExitHandlers},
+    VmExitType,
+};
+use alloc::boxed::Box;
+
+pub struct VmExitHarness {
+    // Field order matters: `data` points to `shared_data`, so it has to be
+    // dropped first.
+    data: Box<VcpuData>,
+    guest_regs: Box<GuestRegs>,
+    shared_data: Box<SharedData>,
+}
+
+impl VmExitHarness {
+    /// Creates a new harness with an empty nested page table.
+    pub fn new() -> Self {
+        Self::with_shared_data(Self::empty_shared_data())
+    }
+
+    /// Creates a new harness that uses the specified shared data.
+    pub fn with_shared_data(mut shared_data: Box<SharedData>) -> Self {
+        let data = VcpuData::allocate(shared_data.as_mut());
+        let guest_regs = Box::new(unsafe { core::mem::zeroed() });
+
+        Self {
+            data,
+            guest_regs,
+            shared_data,
+        }
+    }
+
+    fn empty_npt() -> Box<NestedPageTable> {
+        NestedPageTable::empty()
+    }
+
+    #[cfg(not(feature = "secondary-npt"))]
+    fn empty_shared_data() -> Box<SharedData> {
+        SharedData::new(VmExitHandlers::new(), Self::empty_npt())
+            .expect("failed to create shared data")
+    }
+
+    #[cfg(feature = "secondary-npt")]
+    fn empty_shared_data() -> Box<SharedData> {
+        SharedData::new(VmExitHandlers::new(), Self::empty_npt(), Self::empty_npt())
+            .expect("failed to create shared data")
+    }
+
+    /// Adds the handler for the specified type with the default priority. The
+    /// built-in handlers are already registered.
+    pub fn with_handler<F>(&mut self, vmexit_type: VmExitType, handler: F) -> &mut Self
+    where F: Fn(&mut VcpuData, &mut GuestRegs) -> ExitType + Send + Sync + 'static {
+        self.shared_data
+            .handlers
+            .get_mut()
+            .insert(vmexit_type, handler);
+        self
+    }
+
+    pub fn exit_code(&mut self, exit_code: VmExitCode) -> &mut Self {
+        self.control_area().exit_code = exit_code;
+        self
+    }
+
+    pub fn exit_info1(&mut self, exit_info1: u64) -> &mut Self {
+        self.control_area().exit_info1 = exit_info1;
+        self
+    }
+
+    pub fn exit_info2(&mut self, exit_info2: u64) -> &mut Self {
+        self.control_area().exit_info2 = exit_info2;
+        self
+    }
+
+    pub fn nrip(&mut self, nrip: u64) -> &mut Self {
+        self.control_area().nrip = nrip;
+        self
+    }
+
+    pub fn control_area(&mut self) -> &mut ControlArea {
+        &mut self.data.guest_vmcb.control_area
+    }
+
+    pub fn save_area(&mut self) -> &mut SaveArea {
+        &mut self.data.guest_vmcb.save_area
+    }
+
+    pub fn regs(&mut self) -> &mut GuestRegs {
+        &mut self.guest_regs
+    }
+
+    pub fn vcpu_data(&mut self) -> &mut VcpuData {
+        &mut self.data
+    }
+
+    /// Returns the vcpu data and the guest registers at the same time.
+    pub fn state(&mut self) -> (&mut VcpuData, &mut GuestRegs) {
+        (&mut self.data, &mut self.guest_regs)
+    }
+
+    pub fn shared_data(&mut self) -> &mut SharedData {
+        &mut self.shared_data
+    }
+
+    /// Dispatches the #VMEXIT to the handlers. Returns `None` if the exit code
+    /// isn't handled, which would result in a bug check in the hypervisor.
+    ///
+    /// Note: Events that couldn't be injected stay pending in
+    /// [`VcpuData::pending_events`] for the next exit.
+    pub fn run(&mut self) -> Option<ExitType> {
+        dispatch_vmexit(&mut self.data, &mut self.guest_regs)
+    }
+
+    /// Returns the guest rip after the #VMEXIT has been handled.
+    pub fn rip(&self) -> u64 {
+        self.data.guest_vmcb.save_area.rip
+    }
+
+    /// Returns the event that will be injected into the guest on the next
+    /// `vmrun`.
+    pub fn event_inj(&self) -> EventInjection {
+        EventInjection(self.data.guest_vmcb.control_area.event_inj)
+    }
+}
+
+impl Default for VmExitHarness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::vmexit::cpuid::CPUID_DEVIRTUALIZE;
+    use x86::cpuid::cpuid;
+
+    fn cpuid_exit(harness: &mut VmExitHarness, leaf: u32) {
+        harness.exit_code(VmExitCode::VMEXIT_CPUID).nrip(0x1002);
+        harness.save_area().rip = 0x1000;
+        harness.save_area().rax = leaf as u64;
+    }
+
+    #[test]
+    fn devirtualize() {
+        let mut harness = VmExitHarness::new();
+        cpuid_exit(&mut harness, CPUID_DEVIRTUALIZE);
+
+        assert_eq!(harness.run(), Some(ExitType::ExitHypervisor));
+        assert_eq!(harness.rip(), 0x1000);
+    }
+
+    #[test]
+    fn default_cpuid_handler() {
+        let mut harness = VmExitHarness::new();
+        cpuid_exit(&mut harness, 0);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.rip(), 0x1002);
+        assert_eq!(harness.regs().rbx, cpuid!(0).ebx as u64);
+    }
+
+    #[test]
+    fn handlers_are_called_before_the_default() {
+        let mut harness = VmExitHarness::new();
+        harness
+            .with_handler(VmExitType::Cpuid(0x1234), |_, regs| {
+                regs.rbx = 0x42;
+                ExitType::IncrementRIP
+            })
+            .with_handler(VmExitType::Cpuid(0x1234), |_, _| unreachable!());
+        cpuid_exit(&mut harness, 0x1234);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.regs().rbx, 0x42);
+        assert_eq!(harness.save_area().rax, 0x1234);
+    }
+
+    #[test]
+    fn pass_through_calls_the_default() {
+        let mut harness = VmExitHarness::new();
+        harness.with_handler(VmExitType::Cpuid(0), |_, _| ExitType::PassThrough);
+        cpuid_exit(&mut harness, 0);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.regs().rbx, cpuid!(0).ebx as u64);
+    }
+
+    #[test]
+    fn injected_events_are_written_to_the_vmcb() {
+        let mut harness = VmExitHarness::new();
+        harness.with_handler(VmExitType::Cpuid(0x1234), |data, _| {
+            EventInjection::gp().inject(data);
+            ExitType::Continue
+        });
+        cpuid_exit(&mut harness, 0x1234);
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.rip(), 0x1000);
+
+        let event = harness.event_inj();
+        assert_eq!(event.get_valid(), 1);
+        assert_eq!(event.get_vector(), 13);
+        assert_eq!(event.get_error_code_valid(), 1);
+    }
+
+    #[test]
+    fn custom_data() {
+        let mut harness = VmExitHarness::new();
+        harness.shared_data().data = Some(Box::new(0x1234u32));
+
+        assert_eq!(harness.vcpu_data().custom_data::<u32>(), Some(&0x1234));
+        assert_eq!(harness.vcpu_data().custom_data::<u64>(), None);
+    }
+
+    #[test]
+    fn unhandled_exit() {
+        let mut harness = VmExitHarness::new();
+        harness.exit_code(VmExitCode::VMEXIT_INVALID);
+
+        assert_eq!(harness.run(), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Default implementations for the physical interrupts, the interrupt window
+//! and `SHUTDOWN`.
+
+use crate::svm::{
+    utils::guest::GuestRegs,
+    vcpu_data::VcpuData,
+    vmcb::control_area::{InterceptMisc1, VmExitCode},
+    vmexit::ExitType,
+};
+
+/// Delivers the physical interrupt (`INTR`, `NMI`, `SMI` or `INIT`) to the
+/// guest.
+///
+/// The interrupt is still pending when the #VMEXIT occurs. Resuming the guest
+/// with the intercept enabled would immediately cause the next #VMEXIT, so the
+/// intercept is disabled for the next `vmrun`, which delivers the interrupt to
+/// the guest. The intercepts are restored on the next #VMEXIT, see
+/// [`restore_intercepts`].
+pub fn handle_physical_interrupt(data: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    let intercept = match data.guest_vmcb.control_area.exit_code {
+        VmExitCode::VMEXIT_INTR => InterceptMisc1::INTERCEPT_INTR,
+        VmExitCode::VMEXIT_NMI => InterceptMisc1::INTERCEPT_NMI,
+        VmExitCode::VMEXIT_SMI => InterceptMisc1::INTERCEPT_SMI,
+        _ => InterceptMisc1::INTERCEPT_INIT,
+    };
+    data.guest_vmcb
+        .update_intercepts(|control_area| control_area.intercept_misc1.remove(intercept));
+
+    ExitType::Continue
+}
+
+/// Computes the intercepts from the registered handlers again, if the previous
+/// #VMEXIT was caused by a physical interrupt. Has to be called before the
+/// handlers of the current #VMEXIT.
+pub(crate) fn restore_intercepts(data: &mut VcpuData) {
+    if matches!(
+        data.prev_vmexit,
+        VmExitCode::VMEXIT_INTR
+            | VmExitCode::VMEXIT_NMI
+            | VmExitCode::VMEXIT_SMI
+            | VmExitCode::VMEXIT_INIT
+    ) {
+        data.update_interceptions();
+    }
+}
+
+/// The interrupt window requested by the pending events is open. The events
+/// are injected after every #VMEXIT, so there's nothing left to do here.
+pub fn handle_vintr(_: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    ExitType::Continue
+}
+
+/// The guest caused a triple fault. On real hardware this would reset the
+/// processor, so there's no way to continue. What happens instead is decided
+/// by the dispatcher, see [`ExitType::Shutdown`].
+pub fn handle_shutdown(data: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    log::error!("Guest shutdown at rip {:#x}", data.guest_vmcb.save_area.rip);
+
+    ExitType::Shutdown
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::svm::{
+        events::EventInjection,
+        vmcb::control_area::{InterceptMisc1, VmExitCode},
+        vmexit::{harness::VmExitHarness, ExitType, VmExitType},
+    };
+
+    #[test]
+    fn intercept_is_restored_on_the_next_vmexit() {
+        let mut harness = VmExitHarness::new();
+        harness.with_handler(VmExitType::Intr, |_, _| ExitType::PassThrough);
+        harness.vcpu_data().update_interceptions();
+        assert!(harness
+            .control_area()
+            .intercept_misc1
+            .contains(InterceptMisc1::INTERCEPT_INTR));
+
+        harness.exit_code(VmExitCode::VMEXIT_INTR);
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert!(!harness
+            .control_area()
+            .intercept_misc1
+            .contains(InterceptMisc1::INTERCEPT_INTR));
+
+        harness.exit_code(VmExitCode::VMEXIT_HLT).nrip(0x1001);
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert!(harness
+            .control_area()
+            .intercept_misc1
+            .contains(InterceptMisc1::INTERCEPT_INTR));
+    }
+
+    #[test]
+    fn shutdown() {
+        let mut harness = VmExitHarness::new();
+        harness.exit_code(VmExitCode::VMEXIT_SHUTDOWN);
+        assert_eq!(harness.run(), Some(ExitType::Shutdown));
+
+        harness.with_handler(VmExitType::Shutdown, |_, _| ExitType::Continue);
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+    }
+
+    #[test]
+    fn triple_fault_shuts_down() {
+        let mut harness = VmExitHarness::new();
+        harness.with_handler(VmExitType::Hlt, |data, _| {
+            EventInjection::df().inject(data);
+            EventInjection::gp().inject(data);
+            ExitType::IncrementRIP
+        });
+        harness.exit_code(VmExitCode::VMEXIT_HLT).nrip(0x1001);
+
+        // The pending events are discarded and the SHUTDOWN handlers are called.
+        //
+        assert_eq!(harness.run(), Some(ExitType::Shutdown));
+        assert_eq!(harness.event_inj().get_valid(), 0);
+
+        harness.with_handler(VmExitType::Shutdown, |_, _| ExitType::Continue);
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.event_inj().get_valid(), 0);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::svm::{
+    events::EventInjection,
+    utils::guest::GuestRegs,
+    vcpu_data::VcpuData,
+    vmcb::{
+        exit_info::{ExitInfo, IoDirection, IoInfo},
+        save_area::SaveArea,
+    },
+    vmexit::ExitType,
+};
+use x86::io::{inb, inl, inw, outb, outl, outw};
+
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// Executes the `IN`/`OUT` instruction on behalf of the guest.
+///
+/// String instructions (`INS`/`OUTS`) are emulated one element at a time, see
+/// [`handle_string`].
+pub fn handle_default(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+    let ExitInfo::Io(io) = data.guest_vmcb.control_area.exit_info() else {
+        unreachable!()
+    };
+
+    if io.string {
+        return handle_string(data, guest_regs, &io);
+    }
+
+    match io.direction {
+        IoDirection::In => {
+            // Only the bytes of the operand are written, except for 32-bit
+            // operands which zero-extend the register.
+            //
+            let value = read_port(io.port, io.size) as u64;
+            guest_regs.rax = match io.size {
+                1 => (guest_regs.rax & !0xff) | value,
+                2 => (guest_regs.rax & !0xffff) | value,
+                _ => value,
+            };
+        }
+        IoDirection::Out => write_port(io.port, io.size, guest_regs.rax as u32),
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Emulates a single element of `INS`/`OUTS`. The guest memory is accessed
+/// with [`VcpuData::copy_to_guest`] and [`VcpuData::copy_from_guest`], so
+/// faults are injected like the processor would.
+///
+/// Instructions with a `REP` prefix are continued by not advancing RIP until
+/// the count reaches zero. The guest executes them again, which causes the
+/// next #VMEXIT for the remaining elements.
+fn handle_string(data: &mut VcpuData, guest_regs: &mut GuestRegs, io: &IoInfo) -> ExitType {
+    let address_mask = match io.address_size {
+        16 => 0xffff,
+        32 => 0xffff_ffff,
+        _ => u64::MAX,
+    };
+    if io.rep && guest_regs.rcx & address_mask == 0 {
+        return ExitType::IncrementRIP;
+    }
+
+    // `INS` always writes to `ES:rDI`, the segment of `OUTS` can be overridden.
+    //
+    let save_area = &data.guest_vmcb.save_area;
+    let (register, base) = match io.direction {
+        IoDirection::In => (&mut guest_regs.rdi, save_area.es_base),
+        IoDirection::Out => (&mut guest_regs.rsi, segment_base(save_area, io.segment)),
+    };
+    let step = if save_area.rflags & RFLAGS_DF != 0 {
+        (io.size as u64).wrapping_neg()
+    } else {
+        io.size as u64
+    };
+
+    let mut address = base.wrapping_add(*register & address_mask);
+    if io.address_size != 64 {
+        address &= 0xffff_ffff;
+    }
+
+    let size = io.size as usize;
+    let result = match io.direction {
+        // The port is only read once the destination is known to be writable,
+        // because reading it can have side effects.
+        IoDirection::In => {
+            let mut value = [0u8; 4];
+            data.copy_to_guest_with(address, &mut value[..size], |buffer| {
+                buffer.copy_from_slice(&read_port(io.port, io.size).to_le_bytes()[..size])
+            })
+        }
+        IoDirection::Out => {
+            let mut value = [0u8; 4];
+            data.copy_from_guest(address, &mut value[..size])
+                .map(|_| write_port(io.port, io.size, u32::from_le_bytes(value)))
+        }
+    };
+    if let Err(error) = result {
+        if !error.inject(data) {
+            log::error!(
+                "Failed to emulate string io on port {:#x}: {}",
+                io.port,
+                error
+            );
+            EventInjection::gp().inject(data);
+        }
+
+        return ExitType::Continue;
+    }
+
+    add_to_register(register, step, io.address_size);
+    if io.rep {
+        add_to_register(&mut guest_regs.rcx, 1u64.wrapping_neg(), io.address_size);
+        if guest_regs.rcx & address_mask != 0 {
+            return ExitType::Continue;
+        }
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Returns the base of the segment with the specified index (0 = ES, 1 = CS,
+/// ...).
+fn segment_base(save_area: &SaveArea, segment: u8) -> u64 {
+    match segment {
+        0 => save_area.es_base,
+        1 => save_area.cs_base,
+        2 => save_area.ss_base,
+        4 => save_area.fs_base,
+        5 => save_area.gs_base,
+        _ => save_area.ds_base,
+    }
+}
+
+/// Adds the value to the register like an instruction with the specified
+/// address size: 16-bit writes keep the upper bits, 32-bit writes clear them.
+fn add_to_register(register: &mut u64, value: u64, address_size: u8) {
+    let value = register.wrapping_add(value);
+    *register = match address_size {
+        16 => (*register & !0xffff) | (value & 0xffff),
+        32 => value & 0xffff_ffff,
+        _ => value,
+    };
+}
+
+fn read_port(port: u16, size: u8) -> u32 {
+    unsafe {
+        match size {
+            1 => inb(port) as u32,
+            2 => inw(port) as u32,
+            _ => inl(port),
+        }
+    }
+}
+
+fn write_port(port: u16, size: u8, value: u32) {
+    unsafe {
+        match size {
+            1 => outb(port, value as u8),
+            2 => outw(port, value as u16),
+            _ => outl(port, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{
+        vmcb::control_area::VmExitCode,
+        vmexit::{harness::VmExitHarness, VmExitType},
+    };
+
+    const IN: u64 = 1 << 0;
+    const STRING: u64 = 1 << 2;
+    const REP: u64 = 1 << 3;
+    const SIZE_8: u64 = 1 << 4;
+    const SIZE_32: u64 = 1 << 6;
+    const ADDRESS_64: u64 = 1 << 9;
+
+    fn io_exit(harness: &mut VmExitHarness, port: u16, flags: u64) {
+        harness
+            .exit_code(VmExitCode::VMEXIT_IOIO)
+            .exit_info1((port as u64) << 16 | ADDRESS_64 | flags)
+            .exit_info2(0x1002)
+            .nrip(0x1002);
+    }
+
+    #[test]
+    fn handlers_of_all_covered_ports_are_called() {
+        let mut harness = VmExitHarness::new();
+        harness
+            .with_handler(VmExitType::Io(0x60), |_, _| ExitType::ExitHypervisor)
+            .with_handler(VmExitType::Io(0x61), |_, _| ExitType::IncrementRIP);
+
+        io_exit(&mut harness, 0x61, IN | SIZE_8);
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+
+        // Covers 0x5F to 0x62.
+        //
+        io_exit(&mut harness, 0x5F, IN | SIZE_32);
+        assert_eq!(harness.run(), Some(ExitType::ExitHypervisor));
+    }
+
+    #[test]
+    fn string_io_with_zero_count() {
+        let mut harness = VmExitHarness::new();
+        io_exit(&mut harness, 0x60, STRING | REP | SIZE_8);
+        harness.regs().rcx = 0;
+        harness.regs().rsi = 0x1000;
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.regs().rsi, 0x1000);
+    }
+
+    #[test]
+    fn string_io_with_unmapped_memory() {
+        let mut harness = VmExitHarness::new();
+        io_exit(&mut harness, 0x60, STRING | REP | SIZE_8);
+        harness.save_area().rip = 0x1000;
+        harness.regs().rcx = 2;
+        harness.regs().rsi = 0x1000;
+
+        // The guest memory isn't mapped in the nested page table, so the element
+        // can't be read and the instruction isn't advanced.
+        //
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.rip(), 0x1000);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+        assert_eq!(harness.regs().rsi, 0x1000);
+        assert_eq!(harness.regs().rcx, 2);
+    }
+
+    #[test]
+    fn ins_to_unmapped_memory_does_not_read_the_port() {
+        let mut harness = VmExitHarness::new();
+        io_exit(&mut harness, 0x60, IN | STRING | SIZE_8);
+        harness.save_area().rip = 0x1000;
+        harness.regs().rdi = 0x1000;
+
+        // Reading the port would fault in the test, so this only passes if the
+        // destination is translated first.
+        //
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.rip(), 0x1000);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+        assert_eq!(harness.regs().rdi, 0x1000);
+    }
+
+    #[test]
+    fn registers_are_updated_with_the_address_size() {
+        let mut register = 0xffff_ffff_0000_ffff;
+        add_to_register(&mut register, 1, 16);
+        assert_eq!(register, 0xffff_ffff_0000_0000);
+
+        add_to_register(&mut register, 1u64.wrapping_neg(), 32);
+        assert_eq!(register, 0xffff_ffff);
+
+        add_to_register(&mut register, 1, 64);
+        assert_eq!(register, 0x1_0000_0000);
+    }
+}
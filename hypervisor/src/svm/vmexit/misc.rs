@@ -0,0 +1,177 @@
+//! Default implementations for the instruction intercepts that don't need any
+//! special handling.
+
+use crate::svm::{
+    events::EventInjection,
+    utils::{
+        guest::GuestRegs,
+        validation::{self, CR4_PCE},
+    },
+    vcpu_data::VcpuData,
+    vmcb::exit_info::ExitInfo,
+    vmexit::ExitType,
+};
+use core::arch::asm;
+use x86::cpuid::cpuid;
+
+/// `CPUID Fn8000_0001_ECX`: The additional core, northbridge and last level
+/// cache performance counters are supported.
+const PERF_CTR_EXT_CORE: u32 = 1 << 23;
+const PERF_CTR_EXT_NB: u32 = 1 << 24;
+const PERF_CTR_EXT_LLC: u32 = 1 << 28;
+
+/// The hypervisor can't halt the processor, because interrupts are disabled
+/// in the host context. Resuming the guest right away is the same as being
+/// woken up by a spurious interrupt, which the guest has to handle anyway.
+pub fn handle_hlt(_: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    ExitType::IncrementRIP
+}
+
+pub fn handle_pause(_: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    core::hint::spin_loop();
+
+    ExitType::IncrementRIP
+}
+
+/// Invalidates the TLB entry of the guest's address space.
+pub fn handle_invlpg(data: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    let ExitInfo::Invlpg(address) = data.guest_vmcb.control_area.exit_info() else {
+        unreachable!()
+    };
+    let asid = data.guest_vmcb.control_area.guest_asid;
+
+    unsafe { asm!("invlpga rax, ecx", in("rax") address, in("ecx") asid) };
+
+    ExitType::IncrementRIP
+}
+
+/// The guest and the host share `XCR0`, so the instruction can be executed
+/// on behalf of the guest.
+pub fn handle_xsetbv(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+    // Only `XCR0` is supported.
+    //
+    if guest_regs.rcx as u32 != 0 {
+        EventInjection::gp().inject(data);
+        return ExitType::Continue;
+    }
+
+    // Invalid values would cause a #GP in the host.
+    //
+    let value = (guest_regs.rdx as u32 as u64) << 32 | guest_regs.rax as u32 as u64;
+    let value = match validation::validate_xcr0(value) {
+        Ok(value) => value,
+        Err(error) => {
+            log::warn!("Injecting #GP: {}", error);
+            EventInjection::gp().inject(data);
+            return ExitType::Continue;
+        }
+    };
+
+    unsafe {
+        asm!(
+            "xsetbv",
+            in("ecx") 0u32,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+        )
+    };
+
+    ExitType::IncrementRIP
+}
+
+/// Also used for `INVD`, because invalidating the caches without writing them
+/// back would also discard the modifications of the host.
+pub fn handle_wbinvd(_: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    unsafe { asm!("wbinvd") };
+
+    ExitType::IncrementRIP
+}
+
+/// `MONITOR` and `MWAIT` are treated as `NOP`. Just like `HLT`, `MWAIT` is
+/// allowed to return at any time.
+pub fn handle_monitor(_: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    ExitType::IncrementRIP
+}
+
+/// Returns whether the performance counter exists. See `RDPMC` and `13.2
+/// Performance Monitoring Counters` for the counter numbers.
+fn is_valid_counter(counter: u64) -> bool {
+    let features = cpuid!(0x8000_0001).ecx;
+
+    match counter {
+        0..=3 => true,
+        4 | 5 => features & PERF_CTR_EXT_CORE != 0,
+        6..=9 => features & PERF_CTR_EXT_NB != 0,
+        10..=15 => features & PERF_CTR_EXT_LLC != 0,
+        _ => false,
+    }
+}
+
+/// Reads the performance counter in the host. Counters that don't exist would
+/// cause a #GP in the host, so it's injected into the guest instead.
+pub fn handle_rdpmc(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+    let counter = guest_regs.rcx & 0xffff_ffff;
+
+    // Outside of ring 0, `RDPMC` has to be enabled with `CR4.PCE`.
+    //
+    let save_area = &data.guest_vmcb.save_area;
+    let allowed = save_area.cpl == 0 || save_area.cr4 & CR4_PCE != 0;
+    if !allowed || !is_valid_counter(counter) {
+        log::warn!("Injecting #GP: rdpmc of counter {:#x}", counter);
+        EventInjection::gp().inject(data);
+        return ExitType::Continue;
+    }
+
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdpmc", in("ecx") counter as u32, out("eax") low, out("edx") high) };
+
+    guest_regs.rax = low as u64;
+    guest_regs.rdx = high as u64;
+
+    ExitType::IncrementRIP
+}
+
+/// Nested virtualization is not supported.
+pub fn handle_vmrun(data: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    EventInjection::gp().inject(data);
+
+    ExitType::Continue
+}
+
+/// The guest doesn't know that SVM is supported (see `msr::handle_efer`), so
+/// the SVM instructions raise #UD.
+pub fn handle_svm_instruction(data: &mut VcpuData, _: &mut GuestRegs) -> ExitType {
+    EventInjection::ud().inject(data);
+
+    ExitType::Continue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{vmcb::control_area::VmExitCode, vmexit::harness::VmExitHarness};
+
+    #[test]
+    fn rdpmc_with_invalid_counter() {
+        let mut harness = VmExitHarness::new();
+        harness.exit_code(VmExitCode::VMEXIT_RDPMC).nrip(0x1002);
+        harness.save_area().rip = 0x1000;
+        harness.regs().rcx = 0x100;
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.rip(), 0x1000);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+    }
+
+    #[test]
+    fn rdpmc_from_user_mode() {
+        let mut harness = VmExitHarness::new();
+        harness.exit_code(VmExitCode::VMEXIT_RDPMC).nrip(0x1002);
+        harness.save_area().rip = 0x1000;
+        harness.save_area().cpl = 3;
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.rip(), 0x1000);
+        assert_eq!(harness.event_inj().get_vector(), 13);
+    }
+}
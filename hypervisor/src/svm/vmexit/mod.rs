@@ -1,62 +1,249 @@
 use crate::{
     svm::{
         events::EventInjection,
-        utils::{guest::GuestRegs, msr::EFER_SVME},
+        utils::{
+            guest::GuestRegs,
+            msr::{EFER_SVME, SVM_MSR_SVM_KEY, SVM_MSR_VM_CR, SVM_MSR_VM_HSAVE_PA},
+        },
         vcpu_data::VcpuData,
-        vmcb::control_area::VmExitCode,
-        vmexit::cpuid::CPUID_DEVIRTUALIZE,
+        vmcb::{
+            control_area::{TlbControl, VmExitCode},
+            exit_info::{Access, ExceptionInfo, ExitInfo, IoInfo, RegisterAccess},
+        },
+        vmexit::cpuid::{CPUID_DEVIRTUALIZE, CPUID_UPDATE_INTERCEPTS},
         VmExitType,
     },
     utils::{
         addresses::physical_address,
         debug::dbg_break,
-        nt::{KeBugCheck, MANUALLY_INITIATED_CRASH},
+        platform::{CurrentPlatform, FatalError, Platform},
     },
 };
-use core::{arch::asm, ptr::NonNull};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    arch::asm,
+    ptr::NonNull,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 use fnv::FnvBuildHasher;
 use hashbrown::HashMap;
-use lazy_static::lazy_static;
-use spin::RwLock;
 use x86::msr::{rdmsr, wrmsr, IA32_EFER};
 
 pub mod cpuid;
+pub mod cr;
+pub mod exception;
+#[cfg(not(target_os = "windows"))] pub mod harness;
+pub mod interrupts;
+pub mod io;
+pub mod misc;
 pub mod msr;
 pub mod npt;
 pub mod rdtsc;
+#[cfg(feature = "vmexit-recorder")] pub mod recorder;
+#[cfg(all(feature = "vmexit-recorder", not(target_os = "windows")))]
+pub mod replay;
+
+/// Signature of a handler without any state. Can be converted into a
+/// [`VmExitHandler`].
+pub type VmExitHandlerFn = fn(&mut VcpuData, &mut GuestRegs) -> ExitType;
+
+/// A vmexit handler. The handlers are called from all processors at the same
+/// time, so any state has to be synchronized.
+pub type VmExitHandler = Arc<dyn Fn(&mut VcpuData, &mut GuestRegs) -> ExitType + Send + Sync>;
+
+/// The priority of handlers that have been added without specifying one.
+pub const DEFAULT_PRIORITY: i32 = 0;
+
+/// The priority of the handlers that are registered by the hypervisor itself.
+/// They are called after all the other handlers.
+pub const BUILTIN_PRIORITY: i32 = i32::MIN;
 
-pub type VmExitHandler = fn(&mut VcpuData, &mut GuestRegs) -> ExitType;
+#[derive(Clone)]
+struct HandlerEntry {
+    priority: i32,
+    handler: VmExitHandler,
+}
+
+/// The handlers of a single hypervisor instance.
+///
+/// Multiple handlers can be registered for the same type. They are called in
+/// the order of their priority (highest first) until one of them returns
+/// something other than [`ExitType::PassThrough`]. Handlers with the same
+/// priority are called in the order in which they have been added.
+#[derive(Clone)]
+pub struct VmExitHandlers {
+    handlers: HashMap<VmExitType, Vec<HandlerEntry>, FnvBuildHasher>,
+}
 
-lazy_static! {
-    pub static ref VMEXIT_HANDLERS: RwLock<HashMap<VmExitType, VmExitHandler, FnvBuildHasher>> = {
-        let map = RwLock::new(HashMap::with_hasher(FnvBuildHasher::default()));
+impl VmExitHandlers {
+    /// Creates a new table with the default implementations.
+    pub fn new() -> Self {
+        let mut handlers = Self::empty();
 
         // Default implementations
         //
-        macro add_handler($vmexit_type: expr, $handler: expr) {
-            let _ = map.write().insert($vmexit_type, $handler as VmExitHandler);
+        macro add_handler($vmexit_type:expr, $handler:expr) {
+            handlers.insert_with_priority($vmexit_type, BUILTIN_PRIORITY, $handler);
         }
 
         add_handler!(VmExitType::Msr(IA32_EFER), msr::handle_efer);
-        add_handler!(VmExitType::Cpuid(CPUID_DEVIRTUALIZE), cpuid::handle_devirtualize);
+        add_handler!(VmExitType::Msr(SVM_MSR_VM_CR), msr::handle_svm);
+        add_handler!(VmExitType::Msr(SVM_MSR_VM_HSAVE_PA), msr::handle_svm);
+        add_handler!(VmExitType::Msr(SVM_MSR_SVM_KEY), msr::handle_svm);
+        add_handler!(
+            VmExitType::Cpuid(CPUID_DEVIRTUALIZE),
+            cpuid::handle_devirtualize
+        );
+        add_handler!(
+            VmExitType::Cpuid(CPUID_UPDATE_INTERCEPTS),
+            cpuid::handle_update_intercepts
+        );
         add_handler!(VmExitType::NestedPageFault, npt::handle_default);
 
-        map
-    };
+        handlers
+    }
+
+    /// Creates a new table without any handlers.
+    pub fn empty() -> Self {
+        Self {
+            handlers: HashMap::with_hasher(FnvBuildHasher::default()),
+        }
+    }
+
+    /// Adds the handler for the specified type with [`DEFAULT_PRIORITY`].
+    pub fn insert<F>(&mut self, vmexit_type: VmExitType, handler: F)
+    where F: Fn(&mut VcpuData, &mut GuestRegs) -> ExitType + Send + Sync + 'static {
+        self.insert_with_priority(vmexit_type, DEFAULT_PRIORITY, handler);
+    }
+
+    /// Adds the handler for the specified type. Handlers with a higher priority
+    /// are called first.
+    pub fn insert_with_priority<F>(&mut self, vmexit_type: VmExitType, priority: i32, handler: F)
+    where F: Fn(&mut VcpuData, &mut GuestRegs) -> ExitType + Send + Sync + 'static {
+        let chain = self.handlers.entry(vmexit_type).or_default();
+
+        let index = chain
+            .iter()
+            .position(|entry| entry.priority < priority)
+            .unwrap_or(chain.len());
+        chain.insert(
+            index,
+            HandlerEntry {
+                priority,
+                handler: Arc::new(handler),
+            },
+        );
+    }
+
+    /// Removes the handlers for the specified type. Returns the number of
+    /// removed handlers.
+    ///
+    /// Note: The handlers with [`BUILTIN_PRIORITY`] are kept, because the
+    /// hypervisor can't work without them (e.g. devirtualizing the processors).
+    pub fn remove(&mut self, vmexit_type: VmExitType) -> usize {
+        let Some(chain) = self.handlers.get_mut(&vmexit_type) else {
+            return 0;
+        };
+
+        let count = chain.len();
+        chain.retain(|entry| entry.priority == BUILTIN_PRIORITY);
+        let removed = count - chain.len();
+
+        if chain.is_empty() {
+            self.handlers.remove(&vmexit_type);
+        }
+
+        removed
+    }
+
+    /// Returns the handlers for the specified type, in the order in which
+    /// they are called.
+    pub fn chain(&self, vmexit_type: VmExitType) -> impl Iterator<Item = &VmExitHandler> + '_ {
+        self.handlers
+            .get(&vmexit_type)
+            .into_iter()
+            .flatten()
+            .map(|entry| &entry.handler)
+    }
+
+    /// Returns the types of all registered handlers.
+    pub fn types(&self) -> impl Iterator<Item = &VmExitType> + '_ {
+        self.handlers.keys()
+    }
+}
+
+impl Default for VmExitHandlers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The handlers that are currently used by the processors.
+///
+/// The processors only ever read the handlers. To modify them while the
+/// processors are virtualized, a modified copy is created and swapped in.
+pub struct SharedHandlers {
+    current: AtomicPtr<VmExitHandlers>,
+}
+
+impl SharedHandlers {
+    pub fn new(handlers: VmExitHandlers) -> Self {
+        Self {
+            current: AtomicPtr::new(Box::into_raw(Box::new(handlers))),
+        }
+    }
+
+    pub fn get(&self) -> &VmExitHandlers {
+        unsafe { &*self.current.load(Ordering::Acquire) }
+    }
+
+    pub fn get_mut(&mut self) -> &mut VmExitHandlers {
+        unsafe { &mut *self.current.load(Ordering::Acquire) }
+    }
+
+    /// Replaces the current handlers and returns the previous ones.
+    ///
+    /// # Safety
+    ///
+    /// The processors might still be using the previous handlers. They must
+    /// not be dropped until every processor has left the vmexit handler at
+    /// least once.
+    pub(crate) unsafe fn replace(&self, handlers: VmExitHandlers) -> Box<VmExitHandlers> {
+        let previous = self
+            .current
+            .swap(Box::into_raw(Box::new(handlers)), Ordering::AcqRel);
+
+        Box::from_raw(previous)
+    }
 }
 
-pub macro vmexit_installed($vmexit_type:pat) {
-    $crate::svm::vmexit::VMEXIT_HANDLERS
-        .read()
-        .iter()
-        .any(|(key, _)| matches!(key, $vmexit_type))
+impl Drop for SharedHandlers {
+    fn drop(&mut self) {
+        core::mem::drop(unsafe { Box::from_raw(*self.current.get_mut()) });
+    }
 }
 
-#[derive(PartialOrd, PartialEq)]
+pub macro vmexit_installed($handlers:expr, $vmexit_type:pat) {
+    $handlers.types().any(|key| matches!(key, $vmexit_type))
+}
+
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Eq)]
 pub enum ExitType {
     ExitHypervisor,
     IncrementRIP,
     Continue,
+
+    /// Calls the next handler in the chain. If this was the last handler, the
+    /// built-in default implementation is called.
+    PassThrough,
+
+    /// Skips the remaining handlers and calls the built-in default
+    /// implementation.
+    Default,
+
+    /// The guest can't continue, e.g. because of a triple fault. The system
+    /// is stopped with [`FatalError::GuestShutdown`].
+    Shutdown,
 }
 
 unsafe fn exit_hypervisor(data: &mut VcpuData, guest_regs: &mut GuestRegs) {
@@ -109,90 +296,382 @@ unsafe extern "stdcall" fn handle_vmexit(
     asm!("vmload rax", in("rax") data.host_stack_layout.host_vmcb_pa);
     assert_eq!(data.host_stack_layout.reserved_1, u64::MAX);
 
+    let Some(exit_type) = dispatch_vmexit(data, guest_regs) else {
+        // Invalid #VMEXIT. This should never happen.
+        //
+
+        dbg_break!();
+
+        CurrentPlatform::bug_check();
+    };
+
+    if exit_type == ExitType::Shutdown {
+        dbg_break!();
+
+        CurrentPlatform::fatal_error(FatalError::GuestShutdown, data.guest_vmcb.save_area.rip);
+    }
+
+    if exit_type == ExitType::ExitHypervisor {
+        exit_hypervisor(data, guest_regs);
+    }
+
+    (exit_type == ExitType::ExitHypervisor) as u8
+}
+
+/// Handles the #VMEXIT based on the state stored in the guest vmcb.
+///
+/// This contains everything that `handle_vmexit` does, except for the parts
+/// that have to be executed on the processor (loading the host state and
+/// leaving the hypervisor). Returns `None` if there's no handler for the exit
+/// code.
+pub(crate) fn dispatch_vmexit(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> Option<ExitType> {
     // Guest's RAX is overwritten by the host's value on #VMEXIT and saved in
     // the VMCB instead. Reflect the guest RAX to the context.
     //
     guest_regs.rax = data.guest_vmcb.save_area.rax;
 
+    // The processor's cached copy of the vmcb is up to date after the #VMEXIT.
+    // The handlers modify the cached fields through the setters of the vmcb,
+    // which mark them as dirty again.
+    //
+    data.guest_vmcb.mark_clean();
+
+    // The TLB has been flushed by the previous `vmrun`, if requested.
+    //
+    data.guest_vmcb.control_area.tlb_control = TlbControl::DO_NOTHING;
+
     // Update the trap frame
     //
     data.host_stack_layout.trap_frame.rsp = data.guest_vmcb.save_area.rsp;
     data.host_stack_layout.trap_frame.rip = data.guest_vmcb.control_area.nrip;
 
+    // The event that was being delivered when the #VMEXIT occurred would be lost
+    // otherwise. Events injected by the handlers are merged with it.
+    //
+    if let Some(event) = EventInjection::interrupted(data) {
+        event.inject(data);
+    }
+
+    // The intercept of the previous physical interrupt has been disabled to
+    // deliver it to the guest.
+    //
+    interrupts::restore_intercepts(data);
+
+    #[cfg(feature = "vmexit-recorder")]
+    let (before, prev_vmexit) = (
+        recorder::GuestState::capture(data, guest_regs),
+        data.prev_vmexit.bits(),
+    );
+
     // Handle #VMEXIT
     //
+    let mut exit_type = call_handler(data, guest_regs);
+
+    // An event that has been injected by the handlers caused a triple fault,
+    // which is handled like a `SHUTDOWN` #VMEXIT.
+    //
+    if data.pending_events.take_shutdown() {
+        exit_type = Some(call_shutdown_handler(data, guest_regs));
+    }
+    if let Some(exit_type) = exit_type {
+        data.prev_vmexit = data.guest_vmcb.control_area.exit_code;
+
+        // Handle the exit status of the vmexit handlers
+        //
+        match exit_type {
+            ExitType::IncrementRIP => {
+                // Reflect potentially updated guest's RAX to VMCB. Again, unlike other GPRs,
+                // RAX is loaded from VMCB on VMRUN. Afterwards, advance RIP to "complete" the
+                // instruction.
+                //
+                data.guest_vmcb.save_area.rax = guest_regs.rax;
+                data.guest_vmcb.save_area.rip = data.guest_vmcb.control_area.nrip;
+
+                // The interrupt shadow only covers the instruction that has just been
+                // completed.
+                //
+                data.guest_vmcb.control_area.interrupt_shadow &= !1;
+            }
+            _ => {}
+        }
+
+        // Only one event can be injected per `vmrun`, the others stay pending.
+        //
+        if exit_type != ExitType::ExitHypervisor {
+            data.pending_events.inject(&mut data.guest_vmcb);
+        }
+    }
+
+    // Record the #VMEXIT before returning, so that unhandled exits also end up
+    // in the log.
+    //
+    #[cfg(feature = "vmexit-recorder")]
+    {
+        let after = recorder::GuestState::capture(data, guest_regs);
+        let record = recorder::VmExitRecord::new(data, prev_vmexit, exit_type, before, after);
+        data.recorder.record(&record);
+    }
+
+    exit_type
+}
+
+/// Calls the handlers in `chain` until one of them handles the #VMEXIT. If
+/// none does, the default implementation is called. Returns `None` if there's
+/// no default implementation.
+fn call_chain<'a>(
+    chain: impl Iterator<Item = &'a VmExitHandler>, default: Option<VmExitHandlerFn>,
+    data: &mut VcpuData, guest_regs: &mut GuestRegs,
+) -> Option<ExitType> {
+    for handler in chain {
+        match handler(data, guest_regs) {
+            ExitType::PassThrough => continue,
+            ExitType::Default => break,
+            exit_type => return Some(exit_type),
+        }
+    }
+
+    default.map(|default| default(data, guest_regs))
+}
+
+/// Calls the `SHUTDOWN` handlers, regardless of the exit code.
+fn call_shutdown_handler(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+    // See `call_handler`.
+    //
+    let handlers = unsafe { data.host_stack_layout.shared_data.as_ref() }
+        .handlers
+        .get();
+
+    let exit_type = call_chain(
+        handlers.chain(VmExitType::Shutdown),
+        Some(interrupts::handle_shutdown as VmExitHandlerFn),
+        data,
+        guest_regs,
+    );
+
+    // There's always a default implementation.
+    //
+    exit_type.unwrap_or(ExitType::Shutdown)
+}
+
+/// Calls the registered handlers for the #VMEXIT, or the default
+/// implementation if there's none. Returns `None` if neither exists.
+fn call_handler(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> Option<ExitType> {
+    // The handlers are owned by the shared data, which can also be accessed
+    // (and modified) by the handlers. The table itself is never modified while
+    // it's in use (see `SharedHandlers`), so it's fine to not tie the lifetime
+    // to `data`.
+    //
+    let handlers = unsafe { data.host_stack_layout.shared_data.as_ref() }
+        .handlers
+        .get();
+
     macro_rules! call_handler {
         ($handler:expr) => {
-            if let Some(handler) = VMEXIT_HANDLERS.read().get(&$handler) {
-                handler(data, guest_regs)
-            } else {
-                unreachable!()
-            }
+            call_chain(handlers.chain($handler), None, data, guest_regs)
         };
 
         ($handler:expr, $default:expr) => {
-            if let Some(handler) = VMEXIT_HANDLERS.read().get(&$handler) {
-                handler(data, guest_regs)
-            } else {
-                $default(data, guest_regs)
-            }
+            call_chain(
+                handlers.chain($handler),
+                Some($default as VmExitHandlerFn),
+                data,
+                guest_regs,
+            )
         };
     }
 
-    let exit_type = match data.guest_vmcb.control_area.exit_code {
-        VmExitCode::VMEXIT_RDTSC => call_handler!(VmExitType::Rdtsc),
-        VmExitCode::VMEXIT_RDTSCP => call_handler!(VmExitType::Rdtscp),
-        VmExitCode::VMEXIT_EXCEPTION_BP => call_handler!(VmExitType::Breakpoint),
-        VmExitCode::VMEXIT_VMMCALL => call_handler!(VmExitType::Vmcall),
+    match data.guest_vmcb.control_area.exit_code {
+        VmExitCode::VMEXIT_RDTSC => call_handler!(VmExitType::Rdtsc, rdtsc::handle_default),
+        VmExitCode::VMEXIT_RDTSCP => {
+            call_handler!(VmExitType::Rdtscp, rdtsc::handle_rdtscp_default)
+        }
+        VmExitCode::VMEXIT_RDPMC => call_handler!(VmExitType::Rdpmc, misc::handle_rdpmc),
         VmExitCode::VMEXIT_NPF => call_handler!(VmExitType::NestedPageFault, npt::handle_default),
         VmExitCode::VMEXIT_CPUID => call_handler!(
             VmExitType::Cpuid(guest_regs.rax as u32 /* leaf */),
             cpuid::handle_default
         ),
-        VmExitCode::VMEXIT_MSR => {
-            let msr = guest_regs.rcx as u32;
+        VmExitCode::VMEXIT_IOIO => {
+            let ExitInfo::Io(IoInfo { port, size, .. }) = data.guest_vmcb.control_area.exit_info()
+            else {
+                unreachable!()
+            };
 
-            let map = VMEXIT_HANDLERS.read();
-            if let Some(handler) = map.get(&VmExitType::Msr(msr)) {
-                handler(data, guest_regs)
-            } else if let Some(handler) = map.get(&VmExitType::Rdmsr(msr)) {
-                handler(data, guest_regs)
-            } else if let Some(handler) = map.get(&VmExitType::Wrmsr(msr)) {
-                handler(data, guest_regs)
-            } else {
-                msr::handle_default(data, guest_regs)
-            }
+            // Accesses of more than one byte are intercepted if any of the ports they
+            // cover is hooked, so the handlers of all of them are called.
+            //
+            let chain = (0..size as u16)
+                .filter_map(|offset| port.checked_add(offset))
+                .flat_map(|port| handlers.chain(VmExitType::Io(port)));
+            call_chain(
+                chain,
+                Some(io::handle_default as VmExitHandlerFn),
+                data,
+                guest_regs,
+            )
         }
-        VmExitCode::VMEXIT_VMRUN => {
-            EventInjection::gp().inject(data);
-            ExitType::Continue
+        VmExitCode::VMEXIT_HLT => call_handler!(VmExitType::Hlt, misc::handle_hlt),
+        VmExitCode::VMEXIT_PAUSE => call_handler!(VmExitType::Pause, misc::handle_pause),
+        VmExitCode::VMEXIT_INVLPG => call_handler!(VmExitType::Invlpg, misc::handle_invlpg),
+        VmExitCode::VMEXIT_XSETBV => call_handler!(VmExitType::Xsetbv, misc::handle_xsetbv),
+        VmExitCode::VMEXIT_WBINVD => call_handler!(VmExitType::Wbinvd, misc::handle_wbinvd),
+        VmExitCode::VMEXIT_INVD => call_handler!(VmExitType::Invd, misc::handle_wbinvd),
+        VmExitCode::VMEXIT_MONITOR => call_handler!(VmExitType::Monitor, misc::handle_monitor),
+        VmExitCode::VMEXIT_MWAIT | VmExitCode::VMEXIT_MWAIT_CONDITIONAL => {
+            call_handler!(VmExitType::Mwait, misc::handle_monitor)
         }
-        _ => {
-            // Invalid #VMEXIT. This should never happen.
-            //
+        VmExitCode::VMEXIT_INTR => {
+            call_handler!(VmExitType::Intr, interrupts::handle_physical_interrupt)
+        }
+        VmExitCode::VMEXIT_NMI => {
+            call_handler!(VmExitType::Nmi, interrupts::handle_physical_interrupt)
+        }
+        VmExitCode::VMEXIT_SMI => {
+            call_handler!(VmExitType::Smi, interrupts::handle_physical_interrupt)
+        }
+        VmExitCode::VMEXIT_INIT => {
+            call_handler!(VmExitType::Init, interrupts::handle_physical_interrupt)
+        }
+        VmExitCode::VMEXIT_VINTR => call_handler!(VmExitType::Vintr, interrupts::handle_vintr),
+        VmExitCode::VMEXIT_SHUTDOWN => {
+            call_handler!(VmExitType::Shutdown, interrupts::handle_shutdown)
+        }
+        VmExitCode::VMEXIT_VMMCALL => {
+            call_handler!(VmExitType::Vmcall, misc::handle_svm_instruction)
+        }
+        VmExitCode::VMEXIT_VMRUN => call_handler!(VmExitType::Vmrun, misc::handle_vmrun),
+        VmExitCode::VMEXIT_VMLOAD => {
+            call_handler!(VmExitType::Vmload, misc::handle_svm_instruction)
+        }
+        VmExitCode::VMEXIT_VMSAVE => {
+            call_handler!(VmExitType::Vmsave, misc::handle_svm_instruction)
+        }
+        VmExitCode::VMEXIT_STGI => call_handler!(VmExitType::Stgi, misc::handle_svm_instruction),
+        VmExitCode::VMEXIT_CLGI => call_handler!(VmExitType::Clgi, misc::handle_svm_instruction),
+        VmExitCode::VMEXIT_SKINIT => {
+            call_handler!(VmExitType::Skinit, misc::handle_svm_instruction)
+        }
+        VmExitCode::VMEXIT_MSR => {
+            let msr = guest_regs.rcx as u32;
 
-            dbg_break!();
+            // The handlers for both read and write access are called before the
+            // ones for the specific access.
+            //
+            let specific = match data.guest_vmcb.control_area.exit_info() {
+                ExitInfo::Msr(Access::Write) => VmExitType::Wrmsr(msr),
+                _ => VmExitType::Rdmsr(msr),
+            };
+            let chain = handlers
+                .chain(VmExitType::Msr(msr))
+                .chain(handlers.chain(specific));
 
-            KeBugCheck(MANUALLY_INITIATED_CRASH);
+            call_chain(
+                chain,
+                Some(msr::handle_default as VmExitHandlerFn),
+                data,
+                guest_regs,
+            )
         }
-    };
+        // The control and debug registers and the exceptions have an exit code per
+        // register/vector. This also includes the selective CR0 write intercept.
+        _ => match data.guest_vmcb.control_area.exit_info() {
+            ExitInfo::Exception(ExceptionInfo { vector, .. }) => {
+                call_handler!(VmExitType::Exception(vector), exception::handle_default)
+            }
+            ExitInfo::Cr(RegisterAccess {
+                register,
+                access: Access::Read,
+                ..
+            }) => call_handler!(VmExitType::CrRead(register), cr::handle_cr_default),
+            ExitInfo::Cr(RegisterAccess { register, .. }) => {
+                call_handler!(VmExitType::CrWrite(register), cr::handle_cr_default)
+            }
+            ExitInfo::Dr(RegisterAccess {
+                register,
+                access: Access::Read,
+                ..
+            }) => call_handler!(VmExitType::DrRead(register), cr::handle_dr_default),
+            ExitInfo::Dr(RegisterAccess { register, .. }) => {
+                call_handler!(VmExitType::DrWrite(register), cr::handle_dr_default)
+            }
+            _ => None,
+        },
+    }
+}
 
-    data.prev_vmexit = data.guest_vmcb.control_area.exit_code;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{vmcb::control_area::InterceptMisc1, vmexit::harness::VmExitHarness};
 
-    // Handle the exit status of the vmexit handlers
-    //
-    match exit_type {
-        ExitType::ExitHypervisor => exit_hypervisor(data, guest_regs),
-        ExitType::IncrementRIP => {
-            // Reflect potentially updated guest's RAX to VMCB. Again, unlike other GPRs,
-            // RAX is loaded from VMCB on VMRUN. Afterwards, advance RIP to "complete" the
-            // instruction.
-            //
-            data.guest_vmcb.save_area.rax = guest_regs.rax;
-            data.guest_vmcb.save_area.rip = data.guest_vmcb.control_area.nrip;
-        }
-        ExitType::Continue => {}
+    #[test]
+    fn update_intercepts() {
+        let mut harness = VmExitHarness::new();
+        harness.with_handler(VmExitType::Rdtsc, rdtsc::handle_default);
+        harness.exit_code(VmExitCode::VMEXIT_CPUID).nrip(0x1002);
+        harness.save_area().rax = CPUID_UPDATE_INTERCEPTS as u64;
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.rip(), 0x1002);
+        assert!(harness
+            .control_area()
+            .intercept_misc1
+            .contains(InterceptMisc1::INTERCEPT_RDTSC | InterceptMisc1::INTERCEPT_CPUID));
+        assert!(!harness
+            .control_area()
+            .intercept_misc1
+            .contains(InterceptMisc1::INTERCEPT_HLT));
     }
 
-    (exit_type == ExitType::ExitHypervisor) as u8
+    #[test]
+    fn builtin_handlers_are_not_removed() {
+        let mut handlers = VmExitHandlers::new();
+        handlers.insert(VmExitType::Msr(IA32_EFER), msr::handle_default);
+
+        assert_eq!(handlers.remove(VmExitType::Msr(IA32_EFER)), 1);
+        assert_eq!(handlers.chain(VmExitType::Msr(IA32_EFER)).count(), 1);
+
+        assert_eq!(handlers.remove(VmExitType::Cpuid(CPUID_DEVIRTUALIZE)), 0);
+        assert_eq!(
+            handlers
+                .chain(VmExitType::Cpuid(CPUID_DEVIRTUALIZE))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn removed_handlers() {
+        let mut handlers = VmExitHandlers::empty();
+        handlers.insert(VmExitType::Hlt, misc::handle_hlt);
+        handlers.insert_with_priority(VmExitType::Hlt, 10, misc::handle_hlt);
+
+        assert_eq!(handlers.remove(VmExitType::Hlt), 2);
+        assert_eq!(handlers.types().count(), 0);
+        assert_eq!(handlers.remove(VmExitType::Hlt), 0);
+    }
+
+    #[test]
+    fn chain_is_ordered_by_priority() {
+        let mut handlers = VmExitHandlers::empty();
+        handlers.insert(VmExitType::Hlt, |_, _| ExitType::Continue);
+        handlers.insert_with_priority(VmExitType::Hlt, 10, |_, _| ExitType::IncrementRIP);
+        handlers.insert(VmExitType::Hlt, |_, _| ExitType::ExitHypervisor);
+
+        let mut harness = VmExitHarness::new();
+        let (data, regs) = harness.state();
+        let results = handlers
+            .chain(VmExitType::Hlt)
+            .map(|handler| handler(data, regs))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            [
+                ExitType::IncrementRIP,
+                ExitType::Continue,
+                ExitType::ExitHypervisor
+            ]
+        );
+    }
 }
@@ -1,13 +1,17 @@
 use crate::svm::{
     events::EventInjection,
-    utils::{guest::GuestRegs, msr::EFER_SVME},
+    utils::{
+        guest::GuestRegs,
+        msr::{read_msr, write_msr, EFER_SVME, SVM_MSR_VM_CR, VM_CR_LOCK, VM_CR_SVMDIS},
+        validation,
+    },
     vcpu_data::VcpuData,
+    vmcb::exit_info::{Access, ExitInfo},
     vmexit::ExitType,
 };
-use x86::msr::{rdmsr, wrmsr};
 
 pub(crate) fn handle_efer(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
-    let write_access = data.guest_vmcb.control_area.exit_info1.bits() != 0;
+    let write_access = data.guest_vmcb.control_area.exit_info() == ExitInfo::Msr(Access::Write);
 
     if write_access {
         let low_part = guest_regs.rax as u32;
@@ -16,26 +20,28 @@ pub(crate) fn handle_efer(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> Ex
 
         // The guest is trying to enable SVM.
         //
-        // Inject a #GP exception if the guest attempts to clear the flag. The
-        // protection of this bit is required because clearing it would result
-        // in undefined behavior.
+        // Inject a #GP exception, because nested virtualization is not supported.
         //
         if value & EFER_SVME != 0 {
             EventInjection::gp().inject(data);
+            return ExitType::Continue;
         }
 
-        // Otherwise, update the msr as requested.
+        // Otherwise, update the msr as requested. The value has to be checked
+        // beforehand, otherwise resuming the guest with an invalid EFER value
+        // immediately fails with VMEXIT_INVALID.
         //
-        // Note: The value should be checked beforehand to not allow any illegal values
-        // and inject a #GP as needed. If that is not done, the hypervisor attempts to
-        // resume the guest with an invalid EFER value and immediately receives
-        // #VMEXIT due to VMEXIT_INVALID. This would in this case, result in a
-        // bug check.
+        // SVME has to stay enabled, because it's hidden from the guest.
         //
-        // See `Extended Feature Enable Register (EFER)` for what values are allowed.
-        // TODO: Implement this check
-        //
-        data.guest_vmcb.save_area.efer = value;
+        let save_area = &data.guest_vmcb.save_area;
+        match validation::validate_efer(save_area, value | EFER_SVME) {
+            Ok(value) => data.guest_vmcb.set_efer(value),
+            Err(error) => {
+                log::warn!("Injecting #GP: {}", error);
+                EventInjection::gp().inject(data);
+                return ExitType::Continue;
+            }
+        }
     } else {
         // The EFER msr, without the SVME flag.
         //
@@ -48,33 +54,116 @@ pub(crate) fn handle_efer(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> Ex
     ExitType::IncrementRIP
 }
 
-pub fn handle_default(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+/// Handles the msrs of SVM itself (`VM_CR`, `VM_HSAVE_PA` and `SVM_KEY`).
+///
+/// Nested virtualization is not supported, so SVM is reported as disabled and
+/// locked. Writes inject a #GP, they would otherwise change the state of the
+/// host (e.g. the host save area).
+pub(crate) fn handle_svm(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
     let msr = guest_regs.rcx as u32;
-    let write_access = data.guest_vmcb.control_area.exit_info1.bits() != 0;
+    let write_access = data.guest_vmcb.control_area.exit_info() == ExitInfo::Msr(Access::Write);
 
-    // if msr::is_valid_msr(msr) {
-    //     log::warn!("Found invalid msr: {:x}", msr);
-    // }
+    if write_access {
+        log::warn!("Injecting #GP: write to svm msr {:#x}", msr);
+        EventInjection::gp().inject(data);
+        return ExitType::Continue;
+    }
 
-    // Execute rdmsr or wrmsr as requested by the guest.
-    //
-    // Important: This can bug check if the guest tries to access an MSR that is not
-    // supported by            the host. See SimpleSvm for more information
-    // on how to handle this correctly.
+    let value = match msr {
+        SVM_MSR_VM_CR => VM_CR_LOCK | VM_CR_SVMDIS,
+        _ => 0,
+    };
+    guest_regs.rax = (value as u32) as u64;
+    guest_regs.rdx = value >> 32;
+
+    ExitType::IncrementRIP
+}
+
+pub fn handle_default(data: &mut VcpuData, guest_regs: &mut GuestRegs) -> ExitType {
+    let msr = guest_regs.rcx as u32;
+    let write_access = data.guest_vmcb.control_area.exit_info() == ExitInfo::Msr(Access::Write);
+
+    // Execute rdmsr or wrmsr as requested by the guest. Msrs that don't exist and
+    // invalid values would cause a #GP in the host, so they are checked first and
+    // the #GP is injected into the guest instead.
     //
-    if write_access {
+    let result = if write_access {
         let low_part = guest_regs.rax as u32;
         let high_part = guest_regs.rdx as u32;
 
         let value = (high_part as u64) << 32 | low_part as u64;
 
-        unsafe { wrmsr(msr, value) };
+        write_msr(data, msr, value)
     } else {
-        let value = unsafe { rdmsr(msr) };
+        read_msr(data, msr).map(|value| {
+            guest_regs.rax = (value as u32) as u64;
+            guest_regs.rdx = (value >> 32) as u64;
+        })
+    };
 
-        guest_regs.rax = (value as u32) as u64;
-        guest_regs.rdx = (value >> 32) as u64;
+    if let Err(error) = result {
+        log::warn!("Injecting #GP: {}", error);
+        EventInjection::gp().inject(data);
+
+        return ExitType::Continue;
     }
 
     ExitType::IncrementRIP
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{
+        utils::msr::{SVM_MSR_SVM_KEY, SVM_MSR_VM_HSAVE_PA},
+        vmcb::control_area::VmExitCode,
+        vmexit::harness::VmExitHarness,
+        VmExitType,
+    };
+
+    fn msr_exit(msr: u32, access: Access) -> VmExitHarness {
+        let mut harness = VmExitHarness::new();
+        harness
+            .exit_code(VmExitCode::VMEXIT_MSR)
+            .exit_info1((access == Access::Write) as u64)
+            .nrip(0x1002);
+        harness.save_area().rip = 0x1000;
+        harness.regs().rcx = msr as u64;
+
+        harness
+    }
+
+    #[test]
+    fn svm_msrs_are_read_only() {
+        for msr in [SVM_MSR_VM_CR, SVM_MSR_VM_HSAVE_PA, SVM_MSR_SVM_KEY] {
+            let mut harness = msr_exit(msr, Access::Write);
+
+            assert_eq!(harness.run(), Some(ExitType::Continue));
+            assert_eq!(harness.rip(), 0x1000);
+            assert_eq!(harness.event_inj().get_vector(), 13);
+        }
+    }
+
+    #[test]
+    fn svm_is_disabled() {
+        let mut harness = msr_exit(SVM_MSR_VM_CR, Access::Read);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.regs().rax, VM_CR_LOCK | VM_CR_SVMDIS);
+        assert_eq!(harness.regs().rdx, 0);
+
+        let mut harness = msr_exit(SVM_MSR_VM_HSAVE_PA, Access::Read);
+
+        assert_eq!(harness.run(), Some(ExitType::IncrementRIP));
+        assert_eq!(harness.regs().rax, 0);
+    }
+
+    #[test]
+    fn unknown_msr() {
+        let mut harness = msr_exit(0x4000_0000, Access::Read);
+        harness.with_handler(VmExitType::Msr(0x4000_0000), handle_default);
+
+        assert_eq!(harness.run(), Some(ExitType::Continue));
+        assert_eq!(harness.event_inj().get_vector(), 13);
+    }
+}
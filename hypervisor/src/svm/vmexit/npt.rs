@@ -1,16 +1,18 @@
 use crate::{
     svm::{
-        events::EventInjection,
-        utils::{guest::GuestRegs, paging::AccessType},
-        vcpu_data::VcpuData,
-        vmcb::control_area::NptExitInfo,
-        vmexit::ExitType,
+        events::EventInjection, utils::guest::GuestRegs, vcpu_data::VcpuData,
+        vmcb::exit_info::ExitInfo, vmexit::ExitType,
     },
-    utils::addresses::PhysicalAddress,
+    utils::platform::{CurrentPlatform, FatalError, Platform},
 };
 
+/// The bits of the nested page fault info that are also part of the #PF error
+/// code (`P`, `RW`, `US`, `RSV` and `ID`).
+const PF_ERROR_CODE_MASK: u32 = 0x1f;
+
 pub fn handle_default(data: &mut VcpuData, _regs: &mut GuestRegs) -> ExitType {
-    let npt = unsafe { &mut data.host_stack_layout.shared_data.as_mut().primary_npt };
+    let ncr3 = data.guest_vmcb.control_area.ncr3;
+    let mut npt = unsafe { data.host_stack_layout.shared_data.as_ref() }.active_npt(ncr3);
 
     // From the AMD manual: `15.25.6 Nested versus Guest Page Faults, Fault
     // Ordering`
@@ -20,22 +22,90 @@ pub fn handle_default(data: &mut VcpuData, _regs: &mut GuestRegs) -> ExitType {
     // faulting guest physical address is saved in the VMCB's EXITINFO2 field;
     // EXITINFO1 delivers an error code similar to a #PF error code.
     //
-    let faulting_pa = data.guest_vmcb.control_area.exit_info2;
-    let exit_info = data.guest_vmcb.control_area.exit_info1;
+    let ExitInfo::NestedPageFault(fault) = data.guest_vmcb.control_area.exit_info() else {
+        unreachable!()
+    };
+    let faulting_pa = fault.faulting_gpa;
 
-    // Page was not present so we have to map it.
+    // Page was not present so we have to map it. Lazy nested page tables map
+    // the page with their own access type. Pages that are mapped without any
+    // access aren't present either, but they are handled like any other
+    // permission violation.
     //
-    if !exit_info.contains(NptExitInfo::PRESENT) {
-        let faulting_pa = PhysicalAddress::from_pa(faulting_pa)
-            .align_down_to_base_page()
-            .as_u64();
-
-        npt.map_4kb(faulting_pa, faulting_pa, AccessType::ReadWriteExecute);
+    if !fault.is_present() && npt.effective_access_type(faulting_pa).is_none() {
+        // The guest can't continue without the page, it would fault on it forever.
+        // Devirtualizing isn't possible either: Nested page faults don't provide the
+        // next rip, and the other processors would still be virtualized.
+        //
+        if let Err(error) = npt.map_on_demand(faulting_pa) {
+            log::error!("Failed to map {:#x}: {}", faulting_pa, error);
+            CurrentPlatform::fatal_error(FatalError::NestedPageTableMapping, faulting_pa);
+        }
 
         ExitType::Continue
     } else {
-        EventInjection::pf().inject(data);
+        drop(npt);
+
+        // The bits of the error code match the ones of a #PF. The guest linear
+        // address isn't known, so CR2 can't be updated.
+        //
+        EventInjection::pf(fault.flags.bits() as u32 & PF_ERROR_CODE_MASK).inject(data);
 
         ExitType::Continue
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{
+        utils::paging::AccessType, vmcb::control_area::VmExitCode, vmexit::harness::VmExitHarness,
+    };
+
+    /// `RW` of the fault info, the page isn't present.
+    const WRITE: u64 = 1 << 1;
+
+    fn npf(harness: &mut VmExitHarness, guest_pa: u64) -> Option<ExitType> {
+        harness
+            .exit_code(VmExitCode::VMEXIT_NPF)
+            .exit_info1(WRITE)
+            .exit_info2(guest_pa);
+
+        harness.run()
+    }
+
+    #[test]
+    fn missing_pages_are_mapped() {
+        let mut harness = VmExitHarness::new();
+
+        assert_eq!(npf(&mut harness, 0x1234), Some(ExitType::Continue));
+        assert_eq!(harness.event_inj().get_valid(), 0);
+
+        let npt = harness.shared_data().active_npt(0);
+        assert_eq!(npt.translate(0x1234), Some(0x1234));
+        assert_eq!(
+            npt.effective_access_type(0x1000),
+            Some(AccessType::ReadWriteExecute)
+        );
+    }
+
+    #[test]
+    fn no_access_pages_are_not_mapped() {
+        let mut harness = VmExitHarness::new();
+        {
+            let mut npt = harness.shared_data().active_npt(0);
+            npt.map_4kb(0x1000, 0x1000, AccessType::ReadWrite).unwrap();
+            npt.change_page_flags(0x1000, AccessType::NoAccess).unwrap();
+        }
+
+        assert_eq!(npf(&mut harness, 0x1234), Some(ExitType::Continue));
+        assert_eq!(harness.event_inj().get_vector(), 14);
+        assert_eq!(
+            harness
+                .shared_data()
+                .active_npt(0)
+                .effective_access_type(0x1000),
+            Some(AccessType::NoAccess)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::svm::{utils::guest::GuestRegs, vcpu_data::VcpuData, vmexit::ExitType};
+use core::arch::x86_64::__rdtscp;
 use x86::time::rdtsc;
 
 pub fn handle_default(_vcpu: &mut VcpuData, regs: &mut GuestRegs) -> ExitType {
@@ -9,3 +10,14 @@ pub fn handle_default(_vcpu: &mut VcpuData, regs: &mut GuestRegs) -> ExitType {
 
     ExitType::IncrementRIP
 }
+
+pub fn handle_rdtscp_default(_vcpu: &mut VcpuData, regs: &mut GuestRegs) -> ExitType {
+    let mut aux = 0;
+    let rdtscp = unsafe { __rdtscp(&mut aux) };
+
+    regs.rax = (rdtscp as u32) as u64;
+    regs.rdx = (rdtscp >> 32) as u64;
+    regs.rcx = aux as u64;
+
+    ExitType::IncrementRIP
+}
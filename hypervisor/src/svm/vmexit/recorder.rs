@@ -0,0 +1,404 @@
+//! Records the #VMEXITs of a virtual processor, so that they can be replayed
+//! on another machine (see [`replay`](super::replay)).
+//!
+//! Every processor has its own ring buffer that is allocated up front, because
+//! we can't allocate memory while handling a #VMEXIT. If the buffer is full,
+//! the oldest records are overwritten.
+//!
+//! ## Log format
+//!
+//! All values are stored as little-endian.
+//!
+//! ```text
+//! +--------------------+
+//! | magic (u32)        |  "VXRL"
+//! | version (u32)      |
+//! | processor (u32)    |
+//! | record count (u32) |
+//! +--------------------+
+//! | record 0           |  RECORD_SIZE bytes
+//! | ...                |
+//! +--------------------+
+//! ```
+
+use crate::svm::{utils::guest::GuestRegs, vcpu_data::VcpuData, vmexit::ExitType};
+use alloc::{boxed::Box, vec, vec::Vec};
+
+/// The number of records that are kept per processor.
+pub const DEFAULT_CAPACITY: usize = 512;
+
+pub const LOG_MAGIC: u32 = u32::from_le_bytes(*b"VXRL");
+pub const LOG_VERSION: u32 = 1;
+pub const LOG_HEADER_SIZE: usize = 4 * core::mem::size_of::<u32>();
+
+const GUEST_STATE_WORDS: usize = 16 /* registers */ + 10;
+const RECORD_WORDS: usize = 7 + 2 * GUEST_STATE_WORDS;
+
+/// The size of a single serialized [`VmExitRecord`].
+pub const RECORD_SIZE: usize = RECORD_WORDS * core::mem::size_of::<u64>();
+
+/// The names of the registers in [`GuestRegs::as_array`].
+pub const REGISTER_NAMES: [&str; 16] = [
+    "r15", "r14", "r13", "r12", "r11", "r10", "r9", "r8", "rdi", "rsi", "rbp", "rsp", "rbx", "rdx",
+    "rcx", "rax",
+];
+
+/// The guest state that is relevant for the vmexit handlers.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct GuestState {
+    pub regs: [u64; 16],
+
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub rax: u64,
+    pub efer: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+
+    pub event_inj: u64,
+}
+
+impl GuestState {
+    pub fn capture(data: &VcpuData, guest_regs: &GuestRegs) -> Self {
+        let save_area = &data.guest_vmcb.save_area;
+
+        Self {
+            regs: *guest_regs.as_array(),
+            rip: save_area.rip,
+            rsp: save_area.rsp,
+            rflags: save_area.rflags,
+            rax: save_area.rax,
+            efer: save_area.efer,
+            cr0: save_area.cr0,
+            cr2: save_area.cr2,
+            cr3: save_area.cr3,
+            cr4: save_area.cr4,
+            event_inj: data.guest_vmcb.control_area.event_inj,
+        }
+    }
+
+    pub fn restore(&self, data: &mut VcpuData, guest_regs: &mut GuestRegs) {
+        let save_area = &mut data.guest_vmcb.save_area;
+
+        *guest_regs.as_array_mut() = self.regs;
+        save_area.rip = self.rip;
+        save_area.rsp = self.rsp;
+        save_area.rflags = self.rflags;
+        save_area.rax = self.rax;
+        save_area.efer = self.efer;
+        save_area.cr0 = self.cr0;
+        save_area.cr2 = self.cr2;
+        save_area.cr3 = self.cr3;
+        save_area.cr4 = self.cr4;
+        data.guest_vmcb.control_area.event_inj = self.event_inj;
+    }
+
+    /// Returns the name and value of every field. Used to find the field that
+    /// differs between two states.
+    pub fn fields(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        let named = [
+            ("rip", self.rip),
+            ("rsp", self.rsp),
+            ("rflags", self.rflags),
+            ("vmcb.rax", self.rax),
+            ("efer", self.efer),
+            ("cr0", self.cr0),
+            ("cr2", self.cr2),
+            ("cr3", self.cr3),
+            ("cr4", self.cr4),
+            ("event_inj", self.event_inj),
+        ];
+
+        REGISTER_NAMES
+            .iter()
+            .copied()
+            .zip(self.regs.iter().copied())
+            .chain(named.into_iter())
+    }
+
+    fn write(&self, words: &mut impl FnMut(u64)) {
+        self.fields().for_each(|(_, value)| words(value));
+    }
+
+    fn read(words: &mut impl FnMut() -> u64) -> Self {
+        let mut regs = [0u64; 16];
+        regs.iter_mut().for_each(|reg| *reg = words());
+
+        Self {
+            regs,
+            rip: words(),
+            rsp: words(),
+            rflags: words(),
+            rax: words(),
+            efer: words(),
+            cr0: words(),
+            cr2: words(),
+            cr3: words(),
+            cr4: words(),
+            event_inj: words(),
+        }
+    }
+}
+
+/// A single #VMEXIT with the guest state before and after the handler has been
+/// called.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct VmExitRecord {
+    pub exit_code: u64,
+    pub exit_info1: u64,
+    pub exit_info2: u64,
+    pub exit_int_info: u64,
+    pub nrip: u64,
+    pub prev_vmexit: u64,
+
+    /// The value returned by the handler. `None` if there was no handler.
+    pub exit_type: Option<ExitType>,
+
+    pub before: GuestState,
+    pub after: GuestState,
+}
+
+impl VmExitRecord {
+    /// Creates a new record from the exit information in the vmcb.
+    ///
+    /// Note: `prev_vmexit` is updated by the dispatcher, so it has to be passed
+    /// separately.
+    pub fn new(
+        data: &VcpuData, prev_vmexit: u64, exit_type: Option<ExitType>, before: GuestState,
+        after: GuestState,
+    ) -> Self {
+        let control_area = &data.guest_vmcb.control_area;
+
+        Self {
+            exit_code: control_area.exit_code.bits(),
+            exit_info1: control_area.exit_info1,
+            exit_info2: control_area.exit_info2,
+            exit_int_info: control_area.exit_int_info,
+            nrip: control_area.nrip,
+            prev_vmexit,
+            exit_type,
+            before,
+            after,
+        }
+    }
+
+    fn exit_type_to_raw(exit_type: Option<ExitType>) -> u64 {
+        match exit_type {
+            None => 0,
+            Some(ExitType::ExitHypervisor) => 1,
+            Some(ExitType::IncrementRIP) => 2,
+            Some(ExitType::Continue) => 3,
+            Some(ExitType::PassThrough) => 4,
+            Some(ExitType::Default) => 5,
+            Some(ExitType::Shutdown) => 6,
+        }
+    }
+
+    fn exit_type_from_raw(raw: u64) -> Option<Option<ExitType>> {
+        match raw {
+            0 => Some(None),
+            1 => Some(Some(ExitType::ExitHypervisor)),
+            2 => Some(Some(ExitType::IncrementRIP)),
+            3 => Some(Some(ExitType::Continue)),
+            4 => Some(Some(ExitType::PassThrough)),
+            5 => Some(Some(ExitType::Default)),
+            6 => Some(Some(ExitType::Shutdown)),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+
+        let mut chunks = bytes.chunks_exact_mut(core::mem::size_of::<u64>());
+        let mut words = |value: u64| {
+            if let Some(chunk) = chunks.next() {
+                chunk.copy_from_slice(&value.to_le_bytes());
+            }
+        };
+
+        words(self.exit_code);
+        words(self.exit_info1);
+        words(self.exit_info2);
+        words(self.exit_int_info);
+        words(self.nrip);
+        words(self.prev_vmexit);
+        words(Self::exit_type_to_raw(self.exit_type));
+        self.before.write(&mut words);
+        self.after.write(&mut words);
+
+        bytes
+    }
+
+    /// Parses a record that has been serialized with
+    /// [`VmExitRecord::to_bytes`]. Returns `None` if the record is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+
+        let mut chunks = bytes.chunks_exact(core::mem::size_of::<u64>());
+        let mut words = || {
+            chunks
+                .next()
+                .map(|chunk| {
+                    let mut word = [0u8; 8];
+                    word.copy_from_slice(chunk);
+                    u64::from_le_bytes(word)
+                })
+                .unwrap_or_default()
+        };
+
+        Some(Self {
+            exit_code: words(),
+            exit_info1: words(),
+            exit_info2: words(),
+            exit_int_info: words(),
+            nrip: words(),
+            prev_vmexit: words(),
+            exit_type: Self::exit_type_from_raw(words())?,
+            before: GuestState::read(&mut words),
+            after: GuestState::read(&mut words),
+        })
+    }
+}
+
+/// Ring buffer of the serialized records of a single processor.
+pub struct VmExitRecorder {
+    buffer: Box<[u8]>,
+
+    /// The total number of records, including the overwritten ones.
+    count: usize,
+}
+
+impl VmExitRecorder {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: vec![0u8; capacity * RECORD_SIZE].into_boxed_slice(),
+            count: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len() / RECORD_SIZE
+    }
+
+    /// Returns the number of records that are currently stored.
+    pub fn len(&self) -> usize {
+        self.count.min(self.capacity())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of records that have been overwritten.
+    pub fn dropped(&self) -> usize {
+        self.count - self.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    pub fn record(&mut self, record: &VmExitRecord) {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return;
+        }
+
+        let offset = (self.count % capacity) * RECORD_SIZE;
+        self.buffer[offset..offset + RECORD_SIZE].copy_from_slice(&record.to_bytes());
+        self.count += 1;
+    }
+
+    /// Returns the serialized records, oldest first.
+    fn raw_records(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        let capacity = self.capacity().max(1);
+        let first = self.dropped() % capacity;
+
+        (0..self.len()).map(move |i| {
+            let offset = ((first + i) % capacity) * RECORD_SIZE;
+            &self.buffer[offset..offset + RECORD_SIZE]
+        })
+    }
+
+    /// Returns the stored records, oldest first.
+    pub fn records(&self) -> impl Iterator<Item = VmExitRecord> + '_ {
+        self.raw_records().filter_map(VmExitRecord::from_bytes)
+    }
+
+    /// Serializes the stored records into the log format described in the
+    /// module documentation.
+    pub fn export(&self, processor: u32) -> Vec<u8> {
+        let mut log = Vec::with_capacity(LOG_HEADER_SIZE + self.len() * RECORD_SIZE);
+        log.extend_from_slice(&LOG_MAGIC.to_le_bytes());
+        log.extend_from_slice(&LOG_VERSION.to_le_bytes());
+        log.extend_from_slice(&processor.to_le_bytes());
+        log.extend_from_slice(&(self.len() as u32).to_le_bytes());
+
+        for record in self.raw_records() {
+            log.extend_from_slice(record);
+        }
+
+        log
+    }
+}
+
+impl Default for VmExitRecorder {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+/// A log that has been exported with [`VmExitRecorder::export`].
+pub struct VmExitLog<'a> {
+    pub processor: u32,
+    records: &'a [u8],
+}
+
+impl<'a> VmExitLog<'a> {
+    /// Parses the header of the log. Returns `None` if the header is invalid or
+    /// the log is truncated.
+    pub fn parse(log: &'a [u8]) -> Option<Self> {
+        if log.len() < LOG_HEADER_SIZE {
+            return None;
+        }
+
+        let field = |index: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&log[index * 4..index * 4 + 4]);
+            u32::from_le_bytes(bytes)
+        };
+
+        if field(0) != LOG_MAGIC || field(1) != LOG_VERSION {
+            return None;
+        }
+
+        let processor = field(2);
+        let count = field(3) as usize;
+
+        let records = log.get(LOG_HEADER_SIZE..LOG_HEADER_SIZE + count * RECORD_SIZE)?;
+
+        Some(Self { processor, records })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len() / RECORD_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns the records, oldest first. A malformed record is returned as
+    /// `None`.
+    pub fn records(&self) -> impl Iterator<Item = Option<VmExitRecord>> + 'a {
+        self.records
+            .chunks_exact(RECORD_SIZE)
+            .map(VmExitRecord::from_bytes)
+    }
+}
@@ -0,0 +1,121 @@
+//! Feeds a log that has been captured by the [`recorder`](super::recorder)
+//! back through the registered vmexit handlers.
+//!
+//! Every record is loaded into a [`VmExitHarness`], dispatched and the
+//! resulting guest state is compared with the recorded one. The replay stops
+//! at the first record that doesn't match.
+
+use crate::svm::{
+    vmcb::control_area::VmExitCode,
+    vmexit::{
+        harness::VmExitHarness,
+        recorder::{GuestState, VmExitLog, VmExitRecord},
+        ExitType,
+    },
+};
+use snafu::prelude::*;
+
+#[derive(Debug, Snafu)]
+pub enum ReplayError {
+    #[snafu(display("The log is invalid or truncated"))]
+    InvalidLog,
+
+    #[snafu(display("Record {} is malformed", index))]
+    InvalidRecord { index: usize },
+
+    #[snafu(display(
+        "Record {} returned {:?}, but {:?} was recorded",
+        index,
+        actual,
+        expected
+    ))]
+    ExitTypeMismatch {
+        index: usize,
+        expected: Option<ExitType>,
+        actual: Option<ExitType>,
+    },
+
+    #[snafu(display(
+        "Record {} diverged at {}: expected {:#x}, got {:#x}",
+        index,
+        field,
+        expected,
+        actual
+    ))]
+    Diverged {
+        index: usize,
+        field: &'static str,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+/// Replays all records of the log with a new harness. Returns the number of
+/// replayed records.
+pub fn replay(log: &[u8]) -> Result<usize, ReplayError> {
+    replay_with(&mut VmExitHarness::new(), log)
+}
+
+/// Replays all records of the log with the specified harness. This can be
+/// used to provide the same nested page tables that were used when the log
+/// was recorded.
+pub fn replay_with(harness: &mut VmExitHarness, log: &[u8]) -> Result<usize, ReplayError> {
+    let log = VmExitLog::parse(log).context(InvalidLogSnafu)?;
+
+    for (index, record) in log.records().enumerate() {
+        let record = record.context(InvalidRecordSnafu { index })?;
+
+        replay_record(harness, index, &record)?;
+    }
+
+    Ok(log.len())
+}
+
+/// Replays a single record and compares the result with the recorded state.
+pub fn replay_record(
+    harness: &mut VmExitHarness, index: usize, record: &VmExitRecord,
+) -> Result<(), ReplayError> {
+    // The exit codes are not really flags, so they must not be truncated.
+    //
+    harness
+        .exit_code(unsafe { VmExitCode::from_bits_unchecked(record.exit_code) })
+        .exit_info1(record.exit_info1)
+        .exit_info2(record.exit_info2)
+        .nrip(record.nrip);
+    harness.control_area().exit_int_info = record.exit_int_info;
+    harness.vcpu_data().prev_vmexit =
+        unsafe { VmExitCode::from_bits_unchecked(record.prev_vmexit) };
+
+    let (data, guest_regs) = harness.state();
+    record.before.restore(data, guest_regs);
+
+    let exit_type = harness.run();
+    if exit_type != record.exit_type {
+        return ExitTypeMismatchSnafu {
+            index,
+            expected: record.exit_type,
+            actual: exit_type,
+        }
+        .fail();
+    }
+
+    let (data, guest_regs) = harness.state();
+    let after = GuestState::capture(data, guest_regs);
+
+    if let Some(((field, expected), (_, actual))) = record
+        .after
+        .fields()
+        .zip(after.fields())
+        .find(|((_, expected), (_, actual))| expected != actual)
+    {
+        return DivergedSnafu {
+            index,
+            field,
+            expected,
+            actual,
+        }
+        .fail();
+    }
+
+    Ok(())
+}
@@ -1,6 +1,5 @@
-use crate::utils::nt::{MmGetPhysicalAddress, MmGetVirtualForPhysical};
+use crate::utils::platform::{CurrentPlatform, Platform};
 use core::ops::{Deref, DerefMut};
-use winapi::shared::ntdef::PHYSICAL_ADDRESS;
 use x86::bits64::paging::{PAddr, BASE_PAGE_SHIFT};
 
 pub struct PhysicalAddress(PAddr);
@@ -31,14 +30,11 @@ impl PhysicalAddress {
     }
 
     fn pa_from_va(va: u64) -> u64 {
-        unsafe { *MmGetPhysicalAddress(va as _).QuadPart() as u64 }
+        CurrentPlatform::pa_from_va(va)
     }
 
     fn va_from_pa(pa: u64) -> u64 {
-        let mut physical_address: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
-        unsafe { *(physical_address.QuadPart_mut()) = pa as i64 };
-
-        unsafe { MmGetVirtualForPhysical(physical_address) as u64 }
+        CurrentPlatform::va_from_pa(pa)
     }
 }
 
@@ -1,36 +1,15 @@
-use crate::utils::nt::{
-    MmAllocateContiguousMemorySpecifyCacheNode, MmFreeContiguousMemory,
-    MEMORY_CACHING_TYPE::MmCached, MM_ANY_NODE_OK,
-};
+use crate::utils::platform::{CurrentPlatform, Platform};
 use core::{
     alloc::{AllocError, Allocator, Layout},
     ptr::NonNull,
 };
-use winapi::shared::ntdef::PHYSICAL_ADDRESS;
 
 /// Allocates **contiguous** physical memory.
 pub struct PhysicalAllocator;
 
 unsafe impl Allocator for PhysicalAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let mut boundary: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
-        let mut lowest: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
-        let mut highest: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
-
-        unsafe { *(boundary.QuadPart_mut()) = 0 };
-        unsafe { *(lowest.QuadPart_mut()) = 0 };
-        unsafe { *(highest.QuadPart_mut()) = -1 };
-
-        let memory = unsafe {
-            MmAllocateContiguousMemorySpecifyCacheNode(
-                layout.size(),
-                lowest,
-                highest,
-                boundary,
-                MmCached,
-                MM_ANY_NODE_OK,
-            )
-        } as *mut u8;
+        let memory = CurrentPlatform::allocate_contiguous(layout.size());
         if memory.is_null() {
             Err(AllocError)
         } else {
@@ -39,7 +18,7 @@ unsafe impl Allocator for PhysicalAllocator {
         }
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
-        MmFreeContiguousMemory(ptr.cast().as_ptr());
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        CurrentPlatform::free_contiguous(ptr.as_ptr(), layout.size());
     }
 }
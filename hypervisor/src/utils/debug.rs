@@ -2,8 +2,7 @@ use core::arch::asm;
 
 /// Breaks if a kernel debugger is present on the system.
 pub macro dbg_break() {
-    #[allow(unused_unsafe)]
-    if unsafe { !*crate::utils::nt::KdDebuggerNotPresent } {
+    if <crate::utils::platform::CurrentPlatform as crate::utils::platform::Platform>::is_debugger_present() {
         #[allow(unused_unsafe)]
         unsafe {
             asm!("int 3")
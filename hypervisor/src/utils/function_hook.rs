@@ -1,13 +1,9 @@
-use crate::utils::nt::{
-    IoAllocateMdl, IoFreeMdl, KeInvalidateAllCaches, MmProbeAndLockPages, MmUnlockPages,
-    RtlCopyMemory, LOCK_OPERATION::IoReadAccess,
-};
+use crate::utils::platform::{CurrentPlatform, Platform};
 use alloc::{boxed::Box, vec, vec::Vec};
 use iced_x86::{
     BlockEncoder, BlockEncoderOptions, Decoder, DecoderOptions, FlowControl, InstructionBlock,
 };
 use snafu::prelude::*;
-use winapi::km::{ndis::PMDL, wdm::KPROCESSOR_MODE::KernelMode};
 use x86::bits64::paging::BASE_PAGE_SIZE;
 
 pub const JMP_SHELLCODE_LEN: usize = 14;
@@ -46,11 +42,16 @@ pub struct FunctionHook {
     hook_address: u64,
     handler: u64,
 
-    mdl: PMDL,
+    page_lock: Option<<CurrentPlatform as Platform>::PageLock>,
 
     hook_type: HookType,
 }
 
+// The page lock is only used to unlock the pages when the hook is dropped, and
+// the hook isn't modified after it has been created.
+unsafe impl Send for FunctionHook {}
+unsafe impl Sync for FunctionHook {}
+
 impl FunctionHook {
     /// Creates a new inline hook (not yet enabled) for the specified function.
     ///
@@ -118,26 +119,13 @@ impl FunctionHook {
         // Memory Manager at any time. We need to prevent that because we assume
         // permanent 1:1 mapping of the hook virtual and physical addresses.
         //
-        let mdl = unsafe {
-            IoAllocateMdl(
-                original_address as _,
-                BASE_PAGE_SIZE as _,
-                false,
-                false,
-                0 as _,
-            )
-        };
-        if mdl.is_null() {
-            log::warn!("Failed to allocate mdl");
-            return None;
-        }
-        unsafe { MmProbeAndLockPages(mdl, KernelMode, IoReadAccess) };
+        let page_lock = CurrentPlatform::lock_pages(original_address, BASE_PAGE_SIZE)?;
 
         Some(Self {
             trampoline,
             hook_type,
             hook_address,
-            mdl,
+            page_lock: Some(page_lock),
             handler: handler as u64,
         })
     }
@@ -160,14 +148,14 @@ impl FunctionHook {
         // that, all the variables will be set to 0.
         //
         unsafe {
-            RtlCopyMemory(
-                self.hook_address as *mut u64,
-                jmp_to_handler.as_ptr() as _,
+            core::ptr::copy_nonoverlapping(
+                jmp_to_handler.as_ptr(),
+                self.hook_address as *mut u8,
                 jmp_to_handler.len(),
             );
         }
 
-        unsafe { KeInvalidateAllCaches() };
+        CurrentPlatform::invalidate_caches();
     }
 
     /// Creates the jmp shellcode.
@@ -332,11 +320,8 @@ impl FunctionHook {
 
 impl Drop for FunctionHook {
     fn drop(&mut self) {
-        if !self.mdl.is_null() {
-            unsafe {
-                MmUnlockPages(self.mdl);
-                IoFreeMdl(self.mdl);
-            };
+        if let Some(page_lock) = self.page_lock.take() {
+            CurrentPlatform::unlock_pages(page_lock);
         }
     }
 }
@@ -0,0 +1,33 @@
+//! Access to physical memory from the vmexit handlers.
+//!
+//! Every processor reserves its own window when it's virtualized, so the
+//! windows can be used without any locking. Only a single page can be mapped
+//! at a time.
+
+use crate::utils::platform::{CurrentPlatform, Platform};
+
+pub struct MappingWindow(<CurrentPlatform as Platform>::MappingWindow);
+
+impl MappingWindow {
+    /// Reserves a new window. See [`Platform::reserve_mapping_window`].
+    pub fn new() -> Option<Self> {
+        CurrentPlatform::reserve_mapping_window().map(Self)
+    }
+
+    /// Maps the page of the physical address and calls the closure with the
+    /// virtual address of it. The page is unmapped afterwards.
+    pub fn with<R>(&mut self, pa: u64, f: impl FnOnce(u64) -> R) -> R {
+        let va = unsafe { CurrentPlatform::map_window(&mut self.0, pa) };
+        let result = f(va);
+        unsafe { CurrentPlatform::unmap_window(&mut self.0) };
+
+        result
+    }
+
+    /// Reads the 8 byte aligned value at the physical address.
+    pub fn read_u64(&mut self, pa: u64) -> u64 {
+        self.with(pa, |va| unsafe {
+            core::ptr::read_volatile(va as *const u64)
+        })
+    }
+}
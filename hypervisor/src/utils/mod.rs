@@ -3,7 +3,9 @@ pub mod alloc;
 pub mod context;
 pub mod debug;
 pub mod function_hook;
-pub mod nt;
+pub mod mapping_window;
+#[cfg(target_os = "windows")] pub mod nt;
 pub mod physmem_descriptor;
+pub mod platform;
 pub mod processor;
-pub mod stack;
+#[cfg(target_os = "windows")] pub mod stack;
@@ -6,7 +6,7 @@
 use winapi::{
     km::{
         ndis::PMDL,
-        wdm::{KIRQL, KPROCESSOR_MODE, PIRP, POOL_TYPE},
+        wdm::{KPROCESSOR_MODE, PIRP, POOL_TYPE},
     },
     shared::{
         basetsd::SIZE_T,
@@ -47,6 +47,8 @@ extern "system" {
 
     pub fn RtlSetBits(BitMapHeader: PRTL_BITMAP, StartingIndex: u32, NumberToSet: u32);
 
+    pub fn RtlClearBits(BitMapHeader: PRTL_BITMAP, StartingIndex: u32, NumberToClear: u32);
+
     pub fn KeQueryActiveProcessorCountEx(GroupNumber: u16) -> u32;
 
     pub fn KeGetProcessorNumberFromIndex(ProcIndex: u32, ProcNumber: PPROCESSOR_NUMBER)
@@ -70,8 +72,17 @@ extern "system" {
 
     pub fn MmFreeContiguousMemory(BaseAddress: PVOID);
 
+    pub fn MmAllocateMappingAddress(NumberOfBytes: SIZE_T, PoolTag: ULONG) -> PVOID;
+
+    pub fn MmFreeMappingAddress(BaseAddress: PVOID, PoolTag: ULONG);
+
     pub fn KeBugCheck(BugCheckCode: u32) -> !;
 
+    pub fn KeBugCheckEx(
+        BugCheckCode: u32, BugCheckParameter1: u64, BugCheckParameter2: u64,
+        BugCheckParameter3: u64, BugCheckParameter4: u64,
+    ) -> !;
+
     pub fn ZwYieldExecution() -> NTSTATUS;
 
     pub fn MmGetPhysicalMemoryRanges() -> *mut PHYSICAL_MEMORY_RANGE;
@@ -105,6 +116,7 @@ extern "system" {
 
 // See: https://docs.microsoft.com/en-us/windows-hardware/drivers/debugger/bug-check-code-reference2#bug-check-codes
 pub const MANUALLY_INITIATED_CRASH: u32 = 0x000000E2;
+pub const HYPERVISOR_ERROR: u32 = 0x00020001;
 
 pub const MM_ANY_NODE_OK: u32 = 0x80000000;
 pub type NODE_REQUIREMENT = u32;
@@ -122,144 +134,6 @@ pub struct RTL_BITMAP {
 }
 pub type PRTL_BITMAP = *mut RTL_BITMAP;
 
-/// Size is 0x190 (400)
-#[repr(C)]
-pub struct KTRAP_FRAME {
-    /*
-     * Home address for the parameter registers.
-     */
-    pub p1_home: u64,
-    pub p2_home: u64,
-    pub p3_home: u64,
-    pub p4_home: u64,
-    pub p5: u64,
-    /*
-     * Previous processor mode (system services only) and previous IRQL
-     * (interrupts only).
-     */
-    pub previous_mode: KPROCESSOR_MODE,
-    pub previous_irql: KIRQL,
-    /*
-     * Page fault load/store indicator.
-     */
-    pub fault_indicator: u8,
-    /*
-     * Exception active indicator.
-     *
-     *    0 - interrupt frame.
-     *    1 - exception frame.
-     *    2 - service frame.
-     */
-    pub exception_active: u8,
-    /*
-     * Floating point state.
-     */
-    pub mx_csr: u32,
-    /*
-     *  Volatile registers.
-     *
-     * N.B. These registers are only saved on exceptions and interrupts. They
-     *      are not saved for system calls.
-     */
-    pub rax: u64,
-    pub rcx: u64,
-    pub rdx: u64,
-    pub r8: u64,
-    pub r9: u64,
-    pub r10: u64,
-    pub r11: u64,
-    /*
-     * Gsbase is only used if the previous mode was kernel.
-     *
-     * GsSwap is only used if the previous mode was user.
-     *
-     * Note: This was originally an union (GsSwap).
-     */
-    pub gs_base: u64,
-    /*
-     * Volatile floating registers.
-     *
-     * N.B. These registers are only saved on exceptions and interrupts. They
-     *      are not saved for system calls.
-     */
-    pub xmm0: u128,
-    pub xmm1: u128,
-    pub xmm2: u128,
-    pub xmm3: u128,
-    pub xmm4: u128,
-    pub xmm5: u128,
-    /*
-     * First parameter, page fault address, context record address if user APC
-     * bypass.
-     *
-     * Note: This was originally an union (ContextRecord).
-     */
-    pub fault_address: u64,
-    /*
-     *  Debug registers.
-     */
-    pub dr0: u64,
-    pub dr1: u64,
-    pub dr2: u64,
-    pub dr3: u64,
-    pub dr6: u64,
-    pub dr7: u64,
-    /*
-     * Special debug registers.
-     *
-     * Note: This was originally in its own structure.
-     */
-    pub debug_control: u64,
-    pub last_branch_to_rip: u64,
-    pub last_branch_from_rip: u64,
-    pub last_exception_to_rip: u64,
-    pub last_exception_from_rip: u64,
-    /*
-     *  Segment registers
-     */
-    pub seg_ds: u16,
-    pub seg_es: u16,
-    pub seg_fs: u16,
-    pub seg_gs: u16,
-    /*
-     * Previous trap frame address.
-     */
-    pub trap_frame: u64,
-    /*
-     * Saved nonvolatile registers RBX, RDI and RSI. These registers are only
-     * saved in system service trap frames.
-     */
-    pub rbx: u64,
-    pub rdi: u64,
-    pub rsi: u64,
-    /*
-     * Saved nonvolatile register RBP. This register is used as a frame
-     * pointer during trap processing and is saved in all trap frames.
-     */
-    pub rbp: u64,
-    /*
-     * Information pushed by hardware.
-     *
-     * N.B. The error code is not always pushed by hardware. For those cases
-     *      where it is not pushed by hardware a dummy error code is allocated
-     *      on the stack.
-     *
-     * Note: This was originally an union (ExceptionFrame).
-     */
-    pub error_code: u64,
-    pub rip: u64,
-    pub seg_cs: u16,
-    pub fill_0: u8,
-    pub logging: u8,
-    pub fill_1: [u16; 2],
-    pub e_flags: u32,
-    pub fill_2: u32,
-    pub rsp: u64,
-    pub seg_ss: u16,
-    pub fill_3: u16,
-    pub fill_4: u32,
-}
-
 #[repr(C)]
 pub enum MEMORY_CACHING_TYPE {
     MmNonCached = 0,
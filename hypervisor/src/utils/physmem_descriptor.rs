@@ -1,11 +1,13 @@
 use crate::{
     svm::utils::paging::{bytes_to_pages, _1GB},
-    utils::nt::{ExFreePool, MmGetPhysicalMemoryRanges},
+    utils::platform::{CurrentPlatform, Platform},
 };
 use tinyvec::ArrayVec;
 use x86::{bits32::paging::BASE_PAGE_SIZE, bits64::paging::BASE_PAGE_SHIFT};
 
-const MAX_RANGE_COUNT: usize = 32;
+pub const MAX_RANGE_COUNT: usize = 32;
+
+pub type PhysicalMemoryRanges = ArrayVec<[PhysicalMemoryRange; MAX_RANGE_COUNT]>;
 
 #[derive(Debug, Default)]
 pub struct PhysicalMemoryRange {
@@ -38,48 +40,16 @@ impl PhysicalMemoryRange {
 /// Thanks for @PDBDream
 #[derive(Debug)]
 pub struct PhysicalMemoryDescriptor {
-    ranges: ArrayVec<[PhysicalMemoryRange; MAX_RANGE_COUNT]>,
+    ranges: PhysicalMemoryRanges,
     count: usize,
 }
 
 impl PhysicalMemoryDescriptor {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        // See: https://doxygen.reactos.org/d1/d6d/dynamic_8c_source.html#l00073
-        //
-        let memory_range = unsafe { MmGetPhysicalMemoryRanges() };
-        if memory_range.is_null() {
-            log::error!("MmGetPhysicalMemoryRanges() returned null");
-            unreachable!()
-        }
-
-        // Count the number of pages and runs
-        //
-        let mut ranges = ArrayVec::new();
-
-        let mut count = 0;
-        for i in 0..MAX_RANGE_COUNT {
-            let current = unsafe { memory_range.add(i) };
-            if current.is_null() {
-                break;
-            }
-
-            let base_address = unsafe { *(*current).base_address.QuadPart() as u64 };
-            let number_of_bytes = unsafe { *(*current).number_of_bytes.QuadPart() as u64 };
-            if base_address == 0 && number_of_bytes == 0 {
-                break;
-            }
-
-            ranges.push(PhysicalMemoryRange {
-                base_address,
-                number_of_bytes,
-            });
-
-            count += 1;
-        }
-
-        unsafe { ExFreePool(memory_range as *mut _) };
+        let ranges = CurrentPlatform::physical_memory_ranges();
 
+        let count = ranges.len();
         if count == 0 {
             log::error!("PhysicalMemoryDescriptor::new(): no memory ranges found");
             unreachable!()
@@ -0,0 +1,213 @@
+//! In-memory backend that is used when the crate is compiled for a hosted
+//! target (e.g. for `cargo test` on Linux).
+//!
+//! - Virtual and physical addresses are identical, so the nested page tables
+//!   and bitmaps can be walked like on real hardware. Mapping windows don't
+//!   need to map anything for the same reason.
+//! - Contiguous memory is allocated from the global allocator.
+//! - The processor topology and the physical memory map can be configured by
+//!   the caller.
+
+use crate::{
+    svm::utils::paging::_1GB,
+    utils::{
+        context::Context,
+        physmem_descriptor::{PhysicalMemoryRange, PhysicalMemoryRanges},
+        platform::{FatalError, Platform},
+    },
+};
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+static PROCESSOR_COUNT: AtomicU32 = AtomicU32::new(1);
+static CURRENT_PROCESSOR: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    static ref MEMORY_RANGES: Mutex<PhysicalMemoryRanges> = {
+        let mut ranges = PhysicalMemoryRanges::new();
+        ranges.push(PhysicalMemoryRange {
+            base_address: 0,
+            number_of_bytes: 4 * _1GB,
+        });
+
+        Mutex::new(ranges)
+    };
+}
+
+pub struct MockPlatform;
+
+impl MockPlatform {
+    /// Sets the number of processors that will be reported by
+    /// [`Platform::processor_count`].
+    pub fn set_processor_count(count: u32) {
+        PROCESSOR_COUNT.store(count, Ordering::Relaxed);
+    }
+
+    /// Replaces the physical memory map. Each range is specified as `(base,
+    /// size)`. By default, a single range covering the first 4 GB is reported.
+    pub fn set_physical_memory_ranges(ranges: &[(u64, u64)]) {
+        let mut memory_ranges = MEMORY_RANGES.lock();
+
+        memory_ranges.clear();
+        for (base_address, number_of_bytes) in ranges {
+            memory_ranges.push(PhysicalMemoryRange {
+                base_address: *base_address,
+                number_of_bytes: *number_of_bytes,
+            });
+        }
+    }
+
+    fn layout(size: usize) -> Option<Layout> {
+        Layout::from_size_align(size, BASE_PAGE_SIZE).ok()
+    }
+}
+
+impl Platform for MockPlatform {
+    type Affinity = u32;
+    type MappingWindow = ();
+    type PageLock = ();
+
+    fn pa_from_va(va: u64) -> u64 {
+        va
+    }
+
+    fn va_from_pa(pa: u64) -> u64 {
+        pa
+    }
+
+    fn allocate_contiguous(size: usize) -> *mut u8 {
+        match Self::layout(size) {
+            Some(layout) if size != 0 => unsafe { alloc_zeroed(layout) },
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    unsafe fn free_contiguous(ptr: *mut u8, size: usize) {
+        if let Some(layout) = Self::layout(size) {
+            dealloc(ptr, layout);
+        }
+    }
+
+    fn lock_pages(_va: u64, _size: usize) -> Option<Self::PageLock> {
+        Some(())
+    }
+
+    fn unlock_pages(_lock: Self::PageLock) {}
+
+    fn reserve_mapping_window() -> Option<Self::MappingWindow> {
+        Some(())
+    }
+
+    unsafe fn map_window(_window: &mut Self::MappingWindow, pa: u64) -> u64 {
+        pa
+    }
+
+    unsafe fn unmap_window(_window: &mut Self::MappingWindow) {}
+
+    fn invalidate_caches() {}
+
+    fn physical_memory_ranges() -> PhysicalMemoryRanges {
+        let mut ranges = PhysicalMemoryRanges::new();
+        for range in MEMORY_RANGES.lock().iter() {
+            ranges.push(PhysicalMemoryRange {
+                base_address: range.base_address,
+                number_of_bytes: range.number_of_bytes,
+            });
+        }
+
+        ranges
+    }
+
+    fn system_routine_address(_name: &str) -> Option<u64> {
+        None
+    }
+
+    fn processor_count() -> u32 {
+        PROCESSOR_COUNT.load(Ordering::Relaxed)
+    }
+
+    fn current_processor_index() -> u32 {
+        CURRENT_PROCESSOR.load(Ordering::Relaxed)
+    }
+
+    fn set_processor_affinity(index: u32) -> Option<Self::Affinity> {
+        Some(CURRENT_PROCESSOR.swap(index, Ordering::Relaxed))
+    }
+
+    fn revert_processor_affinity(affinity: Self::Affinity) {
+        CURRENT_PROCESSOR.store(affinity, Ordering::Relaxed);
+    }
+
+    fn capture_context() -> Context {
+        unsafe { core::mem::zeroed() }
+    }
+
+    fn is_debugger_present() -> bool {
+        false
+    }
+
+    fn bug_check() -> ! {
+        panic!("The hypervisor requested a bug check");
+    }
+
+    fn fatal_error(error: FatalError, argument: u64) -> ! {
+        panic!("Fatal hypervisor error: {:?} ({:#x})", error, argument);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contiguous_memory_is_zeroed_and_page_aligned() {
+        let memory = MockPlatform::allocate_contiguous(3 * BASE_PAGE_SIZE);
+        assert!(!memory.is_null());
+        assert_eq!(memory as usize % BASE_PAGE_SIZE, 0);
+
+        let slice = unsafe { core::slice::from_raw_parts(memory, 3 * BASE_PAGE_SIZE) };
+        assert!(slice.iter().all(|byte| *byte == 0));
+
+        unsafe { MockPlatform::free_contiguous(memory, 3 * BASE_PAGE_SIZE) };
+    }
+
+    #[test]
+    fn empty_allocation_fails() {
+        assert!(MockPlatform::allocate_contiguous(0).is_null());
+    }
+
+    #[test]
+    fn addresses_are_identity_mapped() {
+        assert_eq!(MockPlatform::pa_from_va(0x1234_5000), 0x1234_5000);
+        assert_eq!(MockPlatform::va_from_pa(0x1234_5000), 0x1234_5000);
+    }
+
+    #[test]
+    fn bitmap_helpers() {
+        let mut bitmap = [0u8; 4];
+
+        MockPlatform::set_bits(&mut bitmap, 6, 4);
+        assert_eq!(bitmap, [0b1100_0000, 0b0000_0011, 0, 0]);
+
+        MockPlatform::clear_bits(&mut bitmap, 7, 2);
+        assert_eq!(bitmap, [0b0100_0000, 0b0000_0010, 0, 0]);
+
+        MockPlatform::clear_all_bits(&mut bitmap);
+        assert_eq!(bitmap, [0; 4]);
+    }
+
+    #[test]
+    fn processor_affinity_can_be_reverted() {
+        let previous = MockPlatform::set_processor_affinity(3).unwrap();
+        assert_eq!(MockPlatform::current_processor_index(), 3);
+
+        MockPlatform::revert_processor_affinity(previous);
+        assert_eq!(MockPlatform::current_processor_index(), previous);
+    }
+}
@@ -0,0 +1,161 @@
+//! Abstracts the services that the hypervisor needs from the underlying
+//! platform.
+//!
+//! The hypervisor is loaded as a Windows kernel driver, but most of its logic
+//! (nested page tables, msr bitmap, vmexit dispatch, ...) doesn't care about
+//! that. Everything that has to call into the kernel goes through the
+//! [`Platform`] trait, so that the same code can be compiled and tested on a
+//! hosted target (e.g. `cargo test` on Linux) with an in-memory backend.
+
+use crate::utils::{context::Context, physmem_descriptor::PhysicalMemoryRanges};
+
+#[cfg(not(target_os = "windows"))] pub mod mock;
+#[cfg(target_os = "windows")] pub mod nt;
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "windows")] {
+        /// The backend used when running as a Windows kernel driver.
+        pub type CurrentPlatform = nt::NtPlatform;
+    } else {
+        /// The backend used when running on a hosted target.
+        pub type CurrentPlatform = mock::MockPlatform;
+    }
+}
+
+/// Errors that the hypervisor can't recover from. See
+/// [`Platform::fatal_error`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u64)]
+pub enum FatalError {
+    /// A guest physical page couldn't be mapped or remapped in the nested page
+    /// table, so the guest would fault on it forever. The argument is the
+    /// guest physical address.
+    NestedPageTableMapping = 1,
+
+    /// The guest shut down (e.g. because of a triple fault) and no handler
+    /// could recover from it. The argument is the guest rip.
+    GuestShutdown = 2,
+}
+
+pub trait Platform {
+    /// State that is needed to restore the processor affinity of the current
+    /// thread.
+    type Affinity;
+
+    /// Handle to a range of virtual memory that has been locked in place.
+    type PageLock;
+
+    /// A page of virtual memory that can be mapped to any physical page by
+    /// the host. It's released when it's dropped. See
+    /// [`Platform::reserve_mapping_window`].
+    type MappingWindow;
+
+    /// Translates the virtual address to the physical address.
+    fn pa_from_va(va: u64) -> u64;
+
+    /// Translates the physical address to the virtual address.
+    fn va_from_pa(pa: u64) -> u64;
+
+    /// Allocates **contiguous** physical memory. The returned memory is page
+    /// aligned. Returns a null pointer if the allocation failed.
+    fn allocate_contiguous(size: usize) -> *mut u8;
+
+    /// Frees memory that has been allocated with
+    /// [`Platform::allocate_contiguous`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate_contiguous` with the same
+    /// `size`.
+    unsafe fn free_contiguous(ptr: *mut u8, size: usize);
+
+    /// Locks the pages of the specified virtual memory range, so that the
+    /// mapping to the physical pages can't be changed.
+    fn lock_pages(va: u64, size: usize) -> Option<Self::PageLock>;
+
+    /// Unlocks the pages that have been locked with [`Platform::lock_pages`].
+    fn unlock_pages(lock: Self::PageLock);
+
+    /// Reserves a page of kernel virtual memory without mapping it.
+    ///
+    /// [`Platform::va_from_pa`] can't be used in the vmexit handlers: the
+    /// address it returns doesn't have to be mapped in the address space of
+    /// the host, and it's not safe to call at that point. Physical memory is
+    /// mapped into the window with [`Platform::map_window`] instead.
+    fn reserve_mapping_window() -> Option<Self::MappingWindow>;
+
+    /// Maps the page that contains the physical address into the window and
+    /// returns the virtual address of the physical address. Can be called
+    /// from the host.
+    ///
+    /// # Safety
+    ///
+    /// The window must only be used by the current processor, and the
+    /// returned address must not be used after [`Platform::unmap_window`].
+    unsafe fn map_window(window: &mut Self::MappingWindow, pa: u64) -> u64;
+
+    /// Removes the mapping of the window and flushes it from the TLB.
+    ///
+    /// # Safety
+    ///
+    /// See [`Platform::map_window`].
+    unsafe fn unmap_window(window: &mut Self::MappingWindow);
+
+    /// Flushes the caches of all processors.
+    fn invalidate_caches();
+
+    /// Returns the physical memory ranges that are available on the system.
+    fn physical_memory_ranges() -> PhysicalMemoryRanges;
+
+    /// Returns the address of the exported system routine with the specified
+    /// name.
+    fn system_routine_address(name: &str) -> Option<u64>;
+
+    /// Returns the number of active processors.
+    fn processor_count() -> u32;
+
+    /// Returns the index of the processor the current thread is running on.
+    fn current_processor_index() -> u32;
+
+    /// Moves the current thread to the processor with the specified index.
+    /// Returns the previous affinity, which has to be passed to
+    /// [`Platform::revert_processor_affinity`].
+    fn set_processor_affinity(index: u32) -> Option<Self::Affinity>;
+
+    /// Restores the affinity returned by
+    /// [`Platform::set_processor_affinity`].
+    fn revert_processor_affinity(affinity: Self::Affinity);
+
+    /// Captures the register context of the caller.
+    fn capture_context() -> Context;
+
+    /// Checks whether a debugger is attached.
+    fn is_debugger_present() -> bool;
+
+    /// Stops the execution of the whole system.
+    fn bug_check() -> !;
+
+    /// Stops the execution of the whole system because of an error of the
+    /// hypervisor. On Windows, this is the `HYPERVISOR_ERROR` bug check with
+    /// the error and the argument as the first two parameters.
+    fn fatal_error(error: FatalError, argument: u64) -> !;
+
+    /// Sets `count` bits in the bitmap, starting at bit `start`.
+    fn set_bits(bitmap: &mut [u8], start: u32, count: u32) {
+        for bit in start..start + count {
+            bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Clears `count` bits in the bitmap, starting at bit `start`.
+    fn clear_bits(bitmap: &mut [u8], start: u32, count: u32) {
+        for bit in start..start + count {
+            bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+        }
+    }
+
+    /// Clears all the bits in the bitmap.
+    fn clear_all_bits(bitmap: &mut [u8]) {
+        bitmap.fill(0);
+    }
+}
@@ -0,0 +1,339 @@
+//! The backend that is used when the hypervisor is running as a Windows kernel
+//! driver.
+
+use crate::{
+    svm::utils::{paging::PFN_MASK, validation::CR4_LA57},
+    utils::{
+        context::Context,
+        nt::{
+            ExFreePool, IoAllocateMdl, IoFreeMdl, KdDebuggerNotPresent, KeBugCheck, KeBugCheckEx,
+            KeGetCurrentProcessorNumberEx, KeGetProcessorNumberFromIndex, KeInvalidateAllCaches,
+            KeQueryActiveProcessorCountEx, KeRevertToUserGroupAffinityThread,
+            KeSetSystemGroupAffinityThread, MmAllocateContiguousMemorySpecifyCacheNode,
+            MmAllocateMappingAddress, MmFreeContiguousMemory, MmFreeMappingAddress,
+            MmGetPhysicalAddress, MmGetPhysicalMemoryRanges, MmGetSystemRoutineAddress,
+            MmGetVirtualForPhysical, MmProbeAndLockPages, MmUnlockPages, RtlCaptureContext,
+            RtlClearAllBits, RtlClearBits, RtlInitializeBitMap, RtlSetBits, ZwYieldExecution,
+            HYPERVISOR_ERROR, LOCK_OPERATION::IoReadAccess, MANUALLY_INITIATED_CRASH,
+            MEMORY_CACHING_TYPE::MmCached, MM_ANY_NODE_OK, RTL_BITMAP,
+        },
+        physmem_descriptor::{PhysicalMemoryRange, PhysicalMemoryRanges, MAX_RANGE_COUNT},
+        platform::{FatalError, Platform},
+    },
+};
+use core::mem::MaybeUninit;
+use winapi::{
+    km::{ndis::PMDL, wdm::KPROCESSOR_MODE::KernelMode},
+    shared::ntdef::{
+        ALL_PROCESSOR_GROUPS, GROUP_AFFINITY, NT_SUCCESS, PHYSICAL_ADDRESS, PROCESSOR_NUMBER,
+    },
+};
+use windy::{UnicodeString, WStr};
+use x86::{
+    bits64::paging::{PAddr, PDFlags, PTEntry, PTFlags, BASE_PAGE_SIZE},
+    controlregs::{cr3, cr4},
+    tlb,
+};
+
+/// The pool tag of the mapping windows.
+const MAPPING_WINDOW_TAG: u32 = u32::from_le_bytes(*b"HvMw");
+
+/// A page that has been reserved with `MmAllocateMappingAddress`. It's in the
+/// kernel part of the address space, which is the same for all processes, so
+/// the host can use it as well.
+pub struct NtMappingWindow {
+    va: u64,
+
+    /// The page table entry of `va`, which is changed directly instead of
+    /// going through `MmMapLockedPagesWithReservedMapping`.
+    pte: *mut PTEntry,
+}
+
+impl Drop for NtMappingWindow {
+    fn drop(&mut self) {
+        unsafe { MmFreeMappingAddress(self.va as _, MAPPING_WINDOW_TAG) };
+    }
+}
+
+pub struct NtPlatform;
+
+impl NtPlatform {
+    /// Returns the processor number for the specified index.
+    fn processor_number_from_index(index: u32) -> Option<PROCESSOR_NUMBER> {
+        let mut processor_number = MaybeUninit::uninit();
+
+        let status = unsafe { KeGetProcessorNumberFromIndex(index, processor_number.as_mut_ptr()) };
+        if NT_SUCCESS(status) {
+            Some(unsafe { processor_number.assume_init() })
+        } else {
+            None
+        }
+    }
+
+    fn bitmap_header(bitmap: &mut [u8]) -> RTL_BITMAP {
+        let mut bitmap_header: MaybeUninit<RTL_BITMAP> = MaybeUninit::uninit();
+
+        unsafe {
+            RtlInitializeBitMap(
+                bitmap_header.as_mut_ptr(),
+                bitmap.as_mut_ptr() as _,
+                (bitmap.len() * 8) as u32,
+            )
+        }
+
+        unsafe { bitmap_header.assume_init() }
+    }
+
+    /// Walks the page tables of the current address space and returns the
+    /// page table entry of the virtual address. Returns `None` if one of the
+    /// tables is not present or the address is mapped by a large page.
+    fn pte_address(va: u64) -> Option<*mut PTEntry> {
+        let levels = if unsafe { cr4() }.bits() as u64 & CR4_LA57 != 0 {
+            5
+        } else {
+            4
+        };
+
+        let mut table = unsafe { cr3() } & PFN_MASK;
+        for level in (1..=levels).rev() {
+            let table_va = Self::va_from_pa(table) as *mut u64;
+            if table_va.is_null() {
+                return None;
+            }
+
+            let index = (va >> (12 + 9 * (level - 1))) & 0x1ff;
+            let entry = unsafe { table_va.add(index as usize) };
+            if level == 1 {
+                return Some(entry as *mut PTEntry);
+            }
+
+            let entry = unsafe { entry.read_volatile() };
+            if entry & PTFlags::P.bits() == 0 || entry & PDFlags::PS.bits() != 0 {
+                return None;
+            }
+            table = entry & PFN_MASK;
+        }
+
+        None
+    }
+}
+
+impl Platform for NtPlatform {
+    type Affinity = GROUP_AFFINITY;
+    type MappingWindow = NtMappingWindow;
+    type PageLock = PMDL;
+
+    fn pa_from_va(va: u64) -> u64 {
+        unsafe { *MmGetPhysicalAddress(va as _).QuadPart() as u64 }
+    }
+
+    fn va_from_pa(pa: u64) -> u64 {
+        let mut physical_address: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
+        unsafe { *(physical_address.QuadPart_mut()) = pa as i64 };
+
+        unsafe { MmGetVirtualForPhysical(physical_address) as u64 }
+    }
+
+    fn allocate_contiguous(size: usize) -> *mut u8 {
+        let mut boundary: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
+        let mut lowest: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
+        let mut highest: PHYSICAL_ADDRESS = unsafe { core::mem::zeroed() };
+
+        unsafe { *(boundary.QuadPart_mut()) = 0 };
+        unsafe { *(lowest.QuadPart_mut()) = 0 };
+        unsafe { *(highest.QuadPart_mut()) = -1 };
+
+        let memory = unsafe {
+            MmAllocateContiguousMemorySpecifyCacheNode(
+                size,
+                lowest,
+                highest,
+                boundary,
+                MmCached,
+                MM_ANY_NODE_OK,
+            )
+        };
+
+        memory as *mut u8
+    }
+
+    unsafe fn free_contiguous(ptr: *mut u8, _size: usize) {
+        MmFreeContiguousMemory(ptr as _);
+    }
+
+    fn lock_pages(va: u64, size: usize) -> Option<Self::PageLock> {
+        let mdl = unsafe { IoAllocateMdl(va as _, size as _, false, false, 0 as _) };
+        if mdl.is_null() {
+            log::warn!("Failed to allocate mdl");
+            return None;
+        }
+        unsafe { MmProbeAndLockPages(mdl, KernelMode, IoReadAccess) };
+
+        Some(mdl)
+    }
+
+    fn unlock_pages(lock: Self::PageLock) {
+        if !lock.is_null() {
+            unsafe {
+                MmUnlockPages(lock);
+                IoFreeMdl(lock);
+            };
+        }
+    }
+
+    fn reserve_mapping_window() -> Option<Self::MappingWindow> {
+        let va = unsafe { MmAllocateMappingAddress(BASE_PAGE_SIZE, MAPPING_WINDOW_TAG) } as u64;
+        if va == 0 {
+            log::warn!("Failed to reserve a mapping window");
+            return None;
+        }
+
+        let mut window = NtMappingWindow {
+            va,
+            pte: core::ptr::null_mut(),
+        };
+        window.pte = match Self::pte_address(va) {
+            Some(pte) => pte,
+            None => {
+                log::warn!("Failed to find the page table entry of {:#x}", va);
+                return None;
+            }
+        };
+
+        Some(window)
+    }
+
+    unsafe fn map_window(window: &mut Self::MappingWindow, pa: u64) -> u64 {
+        let flags = PTFlags::P | PTFlags::RW | PTFlags::A | PTFlags::D | PTFlags::XD;
+        window
+            .pte
+            .write_volatile(PTEntry::new(PAddr::from(pa & PFN_MASK), flags));
+        tlb::flush(window.va as usize);
+
+        window.va + (pa & (BASE_PAGE_SIZE as u64 - 1))
+    }
+
+    unsafe fn unmap_window(window: &mut Self::MappingWindow) {
+        window.pte.write_volatile(PTEntry(0));
+        tlb::flush(window.va as usize);
+    }
+
+    fn invalidate_caches() {
+        unsafe { KeInvalidateAllCaches() };
+    }
+
+    fn physical_memory_ranges() -> PhysicalMemoryRanges {
+        // See: https://doxygen.reactos.org/d1/d6d/dynamic_8c_source.html#l00073
+        //
+        let memory_range = unsafe { MmGetPhysicalMemoryRanges() };
+        if memory_range.is_null() {
+            log::error!("MmGetPhysicalMemoryRanges() returned null");
+            return PhysicalMemoryRanges::new();
+        }
+
+        let mut ranges = PhysicalMemoryRanges::new();
+        for i in 0..MAX_RANGE_COUNT {
+            let current = unsafe { memory_range.add(i) };
+            if current.is_null() {
+                break;
+            }
+
+            let base_address = unsafe { *(*current).base_address.QuadPart() as u64 };
+            let number_of_bytes = unsafe { *(*current).number_of_bytes.QuadPart() as u64 };
+            if base_address == 0 && number_of_bytes == 0 {
+                break;
+            }
+
+            ranges.push(PhysicalMemoryRange {
+                base_address,
+                number_of_bytes,
+            });
+        }
+
+        unsafe { ExFreePool(memory_range as *mut _) };
+
+        ranges
+    }
+
+    fn system_routine_address(name: &str) -> Option<u64> {
+        let wide_string = widestring::U16CString::from_str(name).ok()?;
+        let wide_string = unsafe { WStr::from_raw(wide_string.as_ptr()) };
+
+        let mut wide_string = UnicodeString::new(wide_string);
+        let address = unsafe { MmGetSystemRoutineAddress(wide_string.as_mut_ptr() as _) };
+        if address.is_null() {
+            None
+        } else {
+            Some(address as u64)
+        }
+    }
+
+    fn processor_count() -> u32 {
+        unsafe { KeQueryActiveProcessorCountEx(ALL_PROCESSOR_GROUPS) }
+    }
+
+    fn current_processor_index() -> u32 {
+        unsafe { KeGetCurrentProcessorNumberEx(core::ptr::null_mut()) }
+    }
+
+    fn set_processor_affinity(index: u32) -> Option<Self::Affinity> {
+        let processor_number = Self::processor_number_from_index(index)?;
+
+        let mut old_affinity = MaybeUninit::uninit();
+        let mut affinity: GROUP_AFFINITY = unsafe { core::mem::zeroed() };
+
+        affinity.Group = processor_number.Group;
+        affinity.Mask = 1 << processor_number.Number;
+        affinity.Reserved[0] = 0;
+        affinity.Reserved[1] = 0;
+        affinity.Reserved[2] = 0;
+
+        unsafe { KeSetSystemGroupAffinityThread(&mut affinity, old_affinity.as_mut_ptr()) };
+
+        log::trace!("Yielding execution");
+        if !NT_SUCCESS(unsafe { ZwYieldExecution() }) {
+            return None;
+        }
+
+        Some(unsafe { old_affinity.assume_init() })
+    }
+
+    fn revert_processor_affinity(mut affinity: Self::Affinity) {
+        unsafe { KeRevertToUserGroupAffinityThread(&mut affinity) };
+    }
+
+    fn capture_context() -> Context {
+        let mut context: MaybeUninit<Context> = MaybeUninit::uninit();
+
+        unsafe { RtlCaptureContext(context.as_mut_ptr() as _) };
+
+        unsafe { context.assume_init() }
+    }
+
+    fn is_debugger_present() -> bool {
+        unsafe { !*KdDebuggerNotPresent }
+    }
+
+    fn bug_check() -> ! {
+        unsafe { KeBugCheck(MANUALLY_INITIATED_CRASH) }
+    }
+
+    fn fatal_error(error: FatalError, argument: u64) -> ! {
+        unsafe { KeBugCheckEx(HYPERVISOR_ERROR, error as u64, argument, 0, 0) }
+    }
+
+    fn set_bits(bitmap: &mut [u8], start: u32, count: u32) {
+        let mut bitmap_header = Self::bitmap_header(bitmap);
+        unsafe { RtlSetBits(&mut bitmap_header as *mut _, start, count) };
+    }
+
+    fn clear_bits(bitmap: &mut [u8], start: u32, count: u32) {
+        let mut bitmap_header = Self::bitmap_header(bitmap);
+        unsafe { RtlClearBits(&mut bitmap_header as *mut _, start, count) };
+    }
+
+    fn clear_all_bits(bitmap: &mut [u8]) {
+        let mut bitmap_header = Self::bitmap_header(bitmap);
+        unsafe { RtlClearAllBits(&mut bitmap_header as *mut _) };
+    }
+}
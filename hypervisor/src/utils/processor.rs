@@ -1,35 +1,18 @@
 //! Handles everything related to the physical processors.
 
-use crate::utils::nt::{
-    KeGetCurrentProcessorNumberEx, KeGetProcessorNumberFromIndex, KeQueryActiveProcessorCountEx,
-    KeRevertToUserGroupAffinityThread, KeSetSystemGroupAffinityThread, ZwYieldExecution,
-};
-use core::mem::MaybeUninit;
-use winapi::shared::ntdef::{ALL_PROCESSOR_GROUPS, GROUP_AFFINITY, NT_SUCCESS, PROCESSOR_NUMBER};
+use crate::utils::platform::{CurrentPlatform, Platform};
 
 pub fn processor_count() -> u32 {
-    unsafe { KeQueryActiveProcessorCountEx(ALL_PROCESSOR_GROUPS) }
+    CurrentPlatform::processor_count()
 }
 
 pub fn current_processor_index() -> u32 {
-    unsafe { KeGetCurrentProcessorNumberEx(core::ptr::null_mut()) }
-}
-
-/// Returns the processor number for the specified index.
-fn processor_number_from_index(index: u32) -> Option<PROCESSOR_NUMBER> {
-    let mut processor_number = MaybeUninit::uninit();
-
-    let status = unsafe { KeGetProcessorNumberFromIndex(index, processor_number.as_mut_ptr()) };
-    if NT_SUCCESS(status) {
-        Some(unsafe { processor_number.assume_init() })
-    } else {
-        None
-    }
+    CurrentPlatform::current_processor_index()
 }
 
 /// Switches execution to a specific processor until dropped.
 pub struct ProcessorExecutor {
-    old_affinity: MaybeUninit<GROUP_AFFINITY>,
+    old_affinity: Option<<CurrentPlatform as Platform>::Affinity>,
 }
 
 impl ProcessorExecutor {
@@ -39,34 +22,20 @@ impl ProcessorExecutor {
             return None;
         }
 
-        let processor_number = processor_number_from_index(i)?;
-
-        let mut old_affinity = MaybeUninit::uninit();
-        let mut affinity: GROUP_AFFINITY = unsafe { core::mem::zeroed() };
-
-        affinity.Group = processor_number.Group;
-        affinity.Mask = 1 << processor_number.Number;
-        affinity.Reserved[0] = 0;
-        affinity.Reserved[1] = 0;
-        affinity.Reserved[2] = 0;
-
         log::trace!("Switching execution to processor {}", i);
-        unsafe { KeSetSystemGroupAffinityThread(&mut affinity, old_affinity.as_mut_ptr()) };
-
-        log::trace!("Yielding execution");
-        if !NT_SUCCESS(unsafe { ZwYieldExecution() }) {
-            return None;
-        }
+        let old_affinity = CurrentPlatform::set_processor_affinity(i)?;
 
-        Some(Self { old_affinity })
+        Some(Self {
+            old_affinity: Some(old_affinity),
+        })
     }
 }
 
 impl Drop for ProcessorExecutor {
     fn drop(&mut self) {
         log::trace!("Switching execution back to previous processor");
-        unsafe {
-            KeRevertToUserGroupAffinityThread(self.old_affinity.as_mut_ptr());
+        if let Some(old_affinity) = self.old_affinity.take() {
+            CurrentPlatform::revert_processor_affinity(old_affinity);
         }
     }
 }
@@ -1,4 +1,7 @@
-use crate::utils::nt::{MmIsAddressValid, RtlCaptureContext};
+use crate::{
+    svm::vcpu_data::VcpuData,
+    utils::nt::{MmIsAddressValid, RtlCaptureContext},
+};
 use alloc::vec::Vec;
 use core::mem::MaybeUninit;
 use winapi::um::winnt::{RtlLookupFunctionEntry, RtlVirtualUnwind, CONTEXT, UNW_FLAG_NHANDLER};
@@ -78,24 +81,37 @@ pub fn top_return_address(rsp: u64) -> Option<u64> {
     }
 }
 
-/// Tries to find the return address based on the stack pointer.
+/// Tries to find the return addresses on the guest stack. The stack is read
+/// with [`VcpuData::read_guest`], so this can be used in the vmexit handlers.
+/// Stops at the first address that can't be read, and returns `None` if that's
+/// the stack pointer itself.
 ///
 /// ## References
 ///
 /// - https://hikalium.github.io/opv86/?q=call
 /// - https://www.felixcloutier.com/x86/call
-pub fn find_return_addresses(rsp: u64) -> Option<Vec<u64>> {
-    const MAX_DEPTH: usize = 15;
+pub fn find_return_addresses(data: &mut VcpuData, rsp: u64) -> Option<Vec<u64>> {
+    const MAX_DEPTH: u64 = 15;
 
-    let stack = unsafe { core::slice::from_raw_parts(rsp as *const u64, MAX_DEPTH) };
-    log::info!("stack: {:x?}", stack);
-
-    let mut return_addresses = Vec::new();
-    for item in stack {
-        if *item > 0x7FFF_FFFF_FFFF {
-            return_addresses.push(*item);
+    let mut stack = Vec::new();
+    for i in 0..MAX_DEPTH {
+        match data.read_guest::<u64>(rsp.wrapping_add(i * 8)) {
+            Ok(item) => stack.push(item),
+            Err(error) => {
+                log::warn!("Failed to read the stack: {}", error);
+                break;
+            }
         }
     }
+    log::trace!("stack: {:x?}", stack);
+    if stack.is_empty() {
+        return None;
+    }
+
+    let return_addresses = stack
+        .into_iter()
+        .filter(|item| *item > 0x7FFF_FFFF_FFFF)
+        .collect();
 
     Some(return_addresses)
 }